@@ -0,0 +1,55 @@
+/* A small C API for routing with od2net's cost model. Build with `cargo build --release -p
+ * c-od2net`, then link against libod2net_c from target/release. Every function is safe to call
+ * from any thread, but one router must not be used by two threads at once. */
+
+#ifndef OD2NET_H
+#define OD2NET_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A network with a contraction hierarchy, ready to route */
+typedef struct Od2netRouter Od2netRouter;
+
+/* One route. Free it with od2net_route_free. */
+typedef struct {
+    /* The total cost of the route, in the units of the cost function. Includes turn costs, and the
+     * parts of edges used when snapping to edges. */
+    uint64_t cost;
+    double length_meters;
+    /* The probability of the trip being made, from the config's uptake model */
+    double uptake;
+    /* A GeoJSON FeatureCollection with one LineString per whole edge */
+    char *geojson;
+} Od2netRoute;
+
+/* Loads a network and builds its contraction hierarchy. network_path is either an
+ * intermediate/network.bin from a normal run, or an osm.pbf or OSM XML file to build from.
+ * config_json has the same format as config.json. Relative paths in it are resolved against
+ * directory, or the current directory if that's NULL. Returns NULL on failure. */
+Od2netRouter *od2net_router_load(const char *network_path, const char *config_json,
+                                 const char *directory);
+
+/* Routes between two points, in WGS84, snapping them like the config says. Returns NULL if there's
+ * no route, both points snap to the same place, either snaps farther than max_snap_meters, or
+ * something went wrong. Only od2net_last_error tells these apart. */
+Od2netRoute *od2net_route(Od2netRouter *router, double x1, double y1, double x2, double y2);
+
+/* NULL is ignored */
+void od2net_route_free(Od2netRoute *route);
+
+/* NULL is ignored */
+void od2net_router_free(Od2netRouter *router);
+
+/* Describes why the last call to od2net_router_load or od2net_route on this thread failed, or
+ * returns NULL if it succeeded. Only valid until the next one of those calls on this thread. */
+const char *od2net_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,305 @@
+//! A small C API, so tools written in other languages can route with od2net's cost model. See
+//! `od2net.h` for how to use it.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+use anyhow::{bail, Result};
+
+use od2net::config::InputConfig;
+use od2net::network::{GeoTiffFiles, Network};
+use od2net::timer::Timer;
+use od2net::NetworkBuilder;
+
+/// A network with a contraction hierarchy, ready to route. Opaque to C.
+pub struct Od2netRouter {
+    router: od2net::Router,
+}
+
+/// One route. Everything is owned by Rust; free it with `od2net_route_free`.
+#[repr(C)]
+pub struct Od2netRoute {
+    /// The total cost of the route, in the units of the cost function. Includes turn costs, and
+    /// the parts of edges used when snapping to edges.
+    pub cost: u64,
+    pub length_meters: f64,
+    /// The probability of the trip being made, from the config's uptake model
+    pub uptake: f64,
+    /// A GeoJSON FeatureCollection with one LineString per whole edge
+    pub geojson: *mut c_char,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Loads a network and builds its contraction hierarchy. `network_path` is either an
+/// `intermediate/network.bin` from a normal run, or an osm.pbf or OSM XML file to build from.
+/// `config_json` has the same format as `config.json`. Relative paths in it are resolved against
+/// `directory`, or the current directory if that's NULL. Returns NULL on failure; see
+/// `od2net_last_error`.
+///
+/// # Safety
+///
+/// The strings must be valid and NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn od2net_router_load(
+    network_path: *const c_char,
+    config_json: *const c_char,
+    directory: *const c_char,
+) -> *mut Od2netRouter {
+    handle_errors(ptr::null_mut(), || {
+        let network_path = to_str(network_path, "network_path")?;
+        let config_json = to_str(config_json, "config_json")?;
+        let directory = if directory.is_null() {
+            "."
+        } else {
+            to_str(directory, "directory")?
+        };
+
+        let mut config: InputConfig = serde_json::from_str(config_json)?;
+        config.load_uptake_tables(directory)?;
+        let mut timer = Timer::new();
+        let mut network = if network_path.ends_with(".bin") {
+            Network::load_from_bin(network_path)?
+        } else {
+            NetworkBuilder::from_config(&config)
+                .build_from_osm(&[&fs_err::read(network_path)?], &mut timer)?
+        };
+        // The cost may differ from the one the network was built with
+        if network_path.ends_with(".bin") {
+            network.recalculate_cost(&mut config.cost)?;
+        }
+        od2net::api::prepare_network(
+            &mut network,
+            &mut config,
+            directory,
+            &mut GeoTiffFiles::default(),
+            &mut timer,
+        )?;
+
+        let router = od2net::Router::new(network, config, &mut timer)?;
+        Ok(Box::into_raw(Box::new(Od2netRouter { router })))
+    })
+}
+
+/// Routes between two points, in WGS84, snapping them like the config says. Returns NULL if there's
+/// no route, both points snap to the same place, either snaps farther than `max_snap_meters`, or
+/// something went wrong. Only `od2net_last_error` tells these apart.
+///
+/// # Safety
+///
+/// `router` must come from `od2net_router_load` and not be freed yet. It must not be used from
+/// two threads at once.
+#[no_mangle]
+pub unsafe extern "C" fn od2net_route(
+    router: *mut Od2netRouter,
+    x1: f64,
+    y1: f64,
+    x2: f64,
+    y2: f64,
+) -> *mut Od2netRoute {
+    handle_errors(ptr::null_mut(), || {
+        let Some(router) = router.as_mut() else {
+            bail!("router is NULL");
+        };
+        let router = &mut router.router;
+        let Some(route) = router.route(x1, y1, x2, y2) else {
+            return Ok(ptr::null_mut());
+        };
+
+        let network = router.network();
+        let mut length_meters = route.start.map_or(0.0, |(_, meters)| meters)
+            + route.end.map_or(0.0, |(_, meters)| meters);
+        for pair in route.nodes.windows(2) {
+            let edge = match network.edges.get(&(pair[0], pair[1])) {
+                Some(edge) => edge,
+                None => &network.edges[&(pair[1], pair[0])],
+            };
+            length_meters += edge.length_meters;
+        }
+        let uptake =
+            od2net::plugins::uptake::calculate_uptake(&router.config().uptake, length_meters, None);
+        let gj = od2net::detailed_route_output::route_to_geojson(
+            &route.nodes,
+            network,
+            &router.config().uptake,
+            None,
+            None,
+        );
+
+        Ok(Box::into_raw(Box::new(Od2netRoute {
+            cost: route.cost as u64,
+            length_meters,
+            uptake,
+            geojson: CString::new(gj.to_string())?.into_raw(),
+        })))
+    })
+}
+
+/// # Safety
+///
+/// `route` must come from `od2net_route` and not be freed yet. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn od2net_route_free(route: *mut Od2netRoute) {
+    if route.is_null() {
+        return;
+    }
+    let route = Box::from_raw(route);
+    drop(CString::from_raw(route.geojson));
+}
+
+/// # Safety
+///
+/// `router` must come from `od2net_router_load` and not be freed yet. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn od2net_router_free(router: *mut Od2netRouter) {
+    if !router.is_null() {
+        drop(Box::from_raw(router));
+    }
+}
+
+/// Describes why the last call to `od2net_router_load` or `od2net_route` on this thread failed, or
+/// returns NULL if it succeeded. The string is owned by od2net and only valid until the next one of
+/// those calls on this thread.
+#[no_mangle]
+pub extern "C" fn od2net_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| match *last_error.borrow() {
+        Some(ref message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Runs `f`, recording any error or panic for `od2net_last_error` and returning `on_error`
+/// instead. Panics mustn't unwind into C.
+fn handle_errors<T, F: FnOnce() -> Result<T>>(on_error: T, f: F) -> T {
+    let message = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(result)) => {
+            set_last_error(None);
+            return result;
+        }
+        Ok(Err(err)) => format!("{err:#}"),
+        Err(panic) => match panic.downcast_ref::<&str>() {
+            Some(message) => format!("panic: {message}"),
+            None => match panic.downcast_ref::<String>() {
+                Some(message) => format!("panic: {message}"),
+                None => "panic".to_string(),
+            },
+        },
+    };
+    set_last_error(Some(message));
+    on_error
+}
+
+fn set_last_error(message: Option<String>) {
+    // Messages with NUL bytes are cut short
+    let message = message.map(|message| {
+        let bytes: Vec<u8> = message
+            .into_bytes()
+            .into_iter()
+            .take_while(|b| *b != 0)
+            .collect();
+        CString::new(bytes).unwrap()
+    });
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+}
+
+unsafe fn to_str<'a>(string: *const c_char, name: &str) -> Result<&'a str> {
+    if string.is_null() {
+        bail!("{name} is NULL");
+    }
+    Ok(CStr::from_ptr(string).to_str()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A straight cycleway, about 222m long
+    const OSM: &str = r#"<osm version="0.6">
+  <node id="1" lat="0" lon="0"/>
+  <node id="2" lat="0" lon="0.001"/>
+  <node id="3" lat="0" lon="0.002"/>
+  <way id="10">
+    <nd ref="1"/><nd ref="2"/><nd ref="3"/>
+    <tag k="highway" v="cycleway"/>
+  </way>
+</osm>"#;
+
+    const CONFIG: &str = r#"{
+        "requests": {
+            "description": "test",
+            "pattern": "FromEveryOriginToOneDestination",
+            "origins_path": "origins.geojson",
+            "destinations_path": "destinations.geojson"
+        },
+        "cost": "Distance",
+        "uptake": "Identity",
+        "lts": "BikeOttawa"
+    }"#;
+
+    unsafe fn last_error() -> String {
+        let message = od2net_last_error();
+        assert!(!message.is_null());
+        CStr::from_ptr(message).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_load_route_free() {
+        let directory = std::env::temp_dir().join("od2net_test_c_api");
+        fs_err::create_dir_all(&directory).unwrap();
+        let osm_path = directory.join("input.osm");
+        fs_err::write(&osm_path, OSM).unwrap();
+        let osm_path = CString::new(osm_path.display().to_string()).unwrap();
+        let config = CString::new(CONFIG).unwrap();
+
+        unsafe {
+            let router = od2net_router_load(osm_path.as_ptr(), config.as_ptr(), ptr::null());
+            assert!(!router.is_null());
+            assert!(od2net_last_error().is_null());
+
+            let route = od2net_route(router, 0.0, 0.0, 0.002, 0.0);
+            assert!(!route.is_null());
+            assert_eq!((*route).cost, 222);
+            assert!(((*route).length_meters - 222.4).abs() < 0.1);
+            assert_eq!((*route).uptake, 1.0);
+            let gj = CStr::from_ptr((*route).geojson).to_str().unwrap();
+            assert!(gj.contains("FeatureCollection"));
+            od2net_route_free(route);
+
+            // Both points snap to the same intersection, which isn't an error
+            assert!(od2net_route(router, 0.0, 0.0, 0.0001, 0.0).is_null());
+            assert!(od2net_last_error().is_null());
+
+            od2net_router_free(router);
+        }
+        fs_err::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let config = CString::new(CONFIG).unwrap();
+        let missing = CString::new("/does/not/exist.osm.pbf").unwrap();
+        let invalid_config = CString::new("{}").unwrap();
+
+        unsafe {
+            assert!(od2net_router_load(ptr::null(), config.as_ptr(), ptr::null()).is_null());
+            assert_eq!(last_error(), "network_path is NULL");
+            assert!(od2net_router_load(missing.as_ptr(), config.as_ptr(), ptr::null()).is_null());
+            assert!(last_error().contains("/does/not/exist.osm.pbf"));
+            assert!(
+                od2net_router_load(missing.as_ptr(), invalid_config.as_ptr(), ptr::null())
+                    .is_null()
+            );
+            assert!(last_error().contains("requests"));
+
+            assert!(od2net_route(ptr::null_mut(), 0.0, 0.0, 0.0, 0.0).is_null());
+            assert_eq!(last_error(), "router is NULL");
+
+            od2net_route_free(ptr::null_mut());
+            od2net_router_free(ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufWriter, Write};
+
+use anyhow::Result;
+use fs_err::File;
+use geojson::FeatureWriter;
+use indicatif::HumanCount;
+use osm_reader::NodeID;
+use rayon::prelude::*;
+
+use super::dijkstra::PlainGraph;
+use super::network::{Network, Position};
+use super::node_map::NodeMap;
+use super::requests::Request;
+use super::router::build_closest_intersection;
+use super::timer::Timer;
+use super::utils;
+
+/// Everything reachable from one origin within a cost budget
+pub struct Reachable {
+    /// The cheapest cost to reach each intersection
+    pub nodes: HashMap<NodeID, f64>,
+    /// Edges that can be crossed completely within the budget, in at least one direction. These
+    /// are keys of `Network::edges`.
+    pub edges: HashSet<(NodeID, NodeID)>,
+}
+
+impl Reachable {
+    /// Searches the network from one intersection, using edge costs
+    pub fn new(graph: &PlainGraph, network: &Network, start: NodeID, budget: f64) -> Self {
+        Self::from_tree(
+            graph,
+            network,
+            graph.shortest_path_tree(start, budget),
+            budget,
+        )
+    }
+
+    /// Finds the reachable edges, given the cost to every intersection within `budget`
+    pub fn from_tree(
+        graph: &PlainGraph,
+        network: &Network,
+        nodes: HashMap<NodeID, f64>,
+        budget: f64,
+    ) -> Self {
+        let mut edges = HashSet::new();
+        for (node, cost) in &nodes {
+            for (next, edge_cost) in graph.neighbors(*node) {
+                if cost + edge_cost as f64 > budget {
+                    continue;
+                }
+                if network.edges.contains_key(&(*node, next)) {
+                    edges.insert((*node, next));
+                } else {
+                    edges.insert((next, *node));
+                }
+            }
+        }
+        Self { nodes, edges }
+    }
+
+    pub fn total_meters(&self, network: &Network) -> f64 {
+        self.edges
+            .iter()
+            .map(|key| network.edges[key].length_meters)
+            .sum()
+    }
+}
+
+/// Origins are searched from in batches of this size. Each row of `accessibility.csv` is
+/// written once its batch is done, so only one batch of reachable sets is kept at a time.
+const ORIGINS_PER_BATCH: usize = 1000;
+
+/// Finds everything reachable within `budget` from each distinct origin of the requests. Writes
+/// `accessibility.csv` with one row per origin, and `accessibility.geojson` with every reachable
+/// edge and how many origins reach it.
+pub fn run(
+    network: &Network,
+    requests: Vec<Request>,
+    budget: f64,
+    output_directory: String,
+    timer: &mut Timer,
+) -> Result<()> {
+    if budget.is_nan() || budget <= 0.0 {
+        bail!("The accessibility budget must be positive, not {budget}");
+    }
+    let graph = PlainGraph::new(network);
+    let origins = snap_distinct_origins(network, &requests, timer);
+
+    timer.start(format!(
+        "Searching from every origin and writing {output_directory}/accessibility.csv"
+    ));
+    let mut file = BufWriter::new(File::create(format!(
+        "{output_directory}/accessibility.csv"
+    ))?);
+    writeln!(
+        file,
+        "x,y,snapped_node,reachable_intersections,reachable_edges,reachable_meters"
+    )?;
+    let mut origins_per_edge: HashMap<(NodeID, NodeID), usize> = HashMap::new();
+    let progress = utils::progress_bar_for_count(origins.len());
+    for batch in origins.chunks(ORIGINS_PER_BATCH) {
+        let results: Vec<Reachable> = batch
+            .par_iter()
+            .map(|(_, node)| Reachable::new(&graph, network, *node, budget))
+            .collect();
+        for ((pt, node), reachable) in batch.iter().zip(results) {
+            let (x, y) = pt.to_degrees();
+            writeln!(
+                file,
+                "{x},{y},{},{},{},{}",
+                node.0,
+                reachable.nodes.len(),
+                reachable.edges.len(),
+                reachable.total_meters(network)
+            )?;
+            for key in reachable.edges {
+                *origins_per_edge.entry(key).or_insert(0) += 1;
+            }
+        }
+        progress.inc(batch.len() as u64);
+    }
+    progress.finish();
+    file.flush()?;
+    timer.stop();
+
+    timer.start(format!("Writing {output_directory}/accessibility.geojson"));
+    let mut edges: Vec<_> = origins_per_edge.into_iter().collect();
+    edges.sort_by_key(|((node1, node2), _)| (node1.0, node2.0));
+    let mut writer = FeatureWriter::from_writer(BufWriter::new(File::create(format!(
+        "{output_directory}/accessibility.geojson"
+    ))?));
+    for ((node1, node2), num_origins) in edges {
+        let mut feature =
+            network.edges[&(node1, node2)].to_geojson_for_detailed_output(node1, node2, true);
+        feature.set_property("origins", num_origins);
+        writer.write_feature(&feature)?;
+    }
+    writer.finish()?;
+    timer.stop();
+
+    Ok(())
+}
+
+/// Returns each distinct origin of the requests, with the intersection it snaps to
+pub fn snap_distinct_origins(
+    network: &Network,
+    requests: &[Request],
+    timer: &mut Timer,
+) -> Vec<(Position, NodeID)> {
+    let mut node_map = NodeMap::new();
+    for id in network.intersections.keys() {
+        node_map.get_or_insert(*id);
+    }
+    let closest_intersection = build_closest_intersection(network, &node_map, timer);
+
+    let mut origins: Vec<Position> = requests
+        .iter()
+        .map(|req| Position::from_degrees(req.x1, req.y1))
+        .collect();
+    origins.sort_by(|a, b| a.to_degrees().partial_cmp(&b.to_degrees()).unwrap());
+    origins.dedup();
+    println!(
+        "Searching from {} distinct origins",
+        HumanCount(origins.len() as u64)
+    );
+
+    origins
+        .into_iter()
+        .map(|pt| {
+            let node = node_map.translate_id(
+                closest_intersection
+                    .nearest_neighbor(&pt.to_degrees_array())
+                    .unwrap()
+                    .data,
+            );
+            (pt, node)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::make_network;
+
+    #[test]
+    fn test_reachable() {
+        // A straight road, about 111m between each node
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.003, 0.0),
+            ],
+            &[(1, 2), (2, 3), (3, 4)],
+        );
+        let graph = PlainGraph::new(&network);
+
+        let reachable = Reachable::new(&graph, &network, NodeID(2), 150.0);
+        let mut nodes: Vec<i64> = reachable.nodes.keys().map(|node| node.0).collect();
+        nodes.sort();
+        assert_eq!(nodes, vec![1, 2, 3]);
+        assert_eq!(reachable.edges.len(), 2);
+        assert!(reachable.edges.contains(&(NodeID(1), NodeID(2))));
+        assert!(reachable.edges.contains(&(NodeID(2), NodeID(3))));
+        assert_eq!(reachable.nodes[&NodeID(2)], 0.0);
+    }
+
+    #[test]
+    fn test_invalid_budget() {
+        let network = make_network(&[(1, 0.0, 0.0), (2, 0.001, 0.0)], &[(1, 2)]);
+        for budget in [0.0, -1.0, f64::NAN] {
+            let mut timer = Timer::new();
+            let err = run(&network, Vec::new(), budget, String::new(), &mut timer).unwrap_err();
+            assert!(err.to_string().contains("must be positive"));
+        }
+    }
+}
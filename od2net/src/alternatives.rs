@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+
+use osm_reader::NodeID;
+
+use super::config::{Alternatives, FlowSplit};
+use super::dijkstra::{undirected, PlainGraph};
+
+/// Starting with the cheapest route through `best` intersections, finds up to `k` distinct
+/// routes between the same two intersections, by penalizing the edges of each route found so
+/// far. The routes begin at `start` in `graph` (see `PlainGraph::start`), so they follow the same
+/// turn restrictions as the CH, and their costs include the same turn costs. Returns each route
+/// with its share of the request's count.
+pub fn find(
+    graph: &PlainGraph,
+    start: usize,
+    best: Vec<NodeID>,
+    config: &Alternatives,
+) -> Vec<(Vec<NodeID>, f64)> {
+    let end = *best.last().unwrap();
+    let best_costs = graph.path_costs(start, &best);
+    let mut routes = vec![(best, best_costs)];
+    let mut penalties: HashMap<(NodeID, NodeID), f64> = HashMap::new();
+    let mut last = 0;
+    // Repeatedly finding the same route just penalizes it more, but give up eventually
+    for _ in 0..2 * config.k {
+        if routes.len() >= config.k {
+            break;
+        }
+        for pair in routes[last].0.windows(2) {
+            *penalties.entry(undirected(pair[0], pair[1])).or_insert(1.0) *= config.penalty;
+        }
+        // The routes found so far are still possible, so there's no need to search any further
+        // than the cheapest of them, plus a little slack for rounding
+        let max_cost = routes
+            .iter()
+            .map(|(route, costs)| penalized_cost(route, costs, &penalties))
+            .fold(f64::INFINITY, f64::min)
+            + 1.0;
+        let Some(route) = graph.shortest_path(start, end, &penalties, max_cost) else {
+            break;
+        };
+        last = match routes.iter().position(|(x, _)| *x == route) {
+            Some(idx) => idx,
+            None => {
+                let costs = graph.path_costs(start, &route);
+                routes.push((route, costs));
+                routes.len() - 1
+            }
+        };
+    }
+
+    let costs: Vec<f64> = routes
+        .iter()
+        .map(|(_, costs)| costs.iter().sum::<usize>() as f64)
+        .collect();
+    let path_sizes = match config.split {
+        FlowSplit::PathSizeLogit { .. } => path_sizes(&routes),
+        _ => vec![1.0; routes.len()],
+    };
+    let shares = shares(&costs, &path_sizes, config.split);
+    routes
+        .into_iter()
+        .map(|(route, _)| route)
+        .zip(shares)
+        .collect()
+}
+
+fn penalized_cost(
+    route: &[NodeID],
+    costs: &[usize],
+    penalties: &HashMap<(NodeID, NodeID), f64>,
+) -> f64 {
+    route
+        .windows(2)
+        .zip(costs)
+        .map(|(pair, cost)| {
+            let penalty = penalties
+                .get(&undirected(pair[0], pair[1]))
+                .copied()
+                .unwrap_or(1.0);
+            *cost as f64 * penalty
+        })
+        .sum()
+}
+
+/// For each route, the sum over its edges of the edge's fraction of the route's cost, divided
+/// by how many routes use that edge. A route sharing nothing with the others has a path size
+/// of 1, and two identical routes would each have 0.5. Each route comes with the cost of every
+/// step along it.
+fn path_sizes(routes: &[(Vec<NodeID>, Vec<usize>)]) -> Vec<f64> {
+    let mut uses: HashMap<(NodeID, NodeID), usize> = HashMap::new();
+    for (route, _) in routes {
+        for pair in route.windows(2) {
+            *uses.entry(undirected(pair[0], pair[1])).or_insert(0) += 1;
+        }
+    }
+    routes
+        .iter()
+        .map(|(route, costs)| {
+            let total = costs.iter().sum::<usize>().max(1) as f64;
+            route
+                .windows(2)
+                .zip(costs)
+                .map(|(pair, cost)| {
+                    *cost as f64 / total / uses[&undirected(pair[0], pair[1])] as f64
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/// Splits a count between routes with these costs and path sizes. The shares sum to 1.
+fn shares(costs: &[f64], path_sizes: &[f64], split: FlowSplit) -> Vec<f64> {
+    match split {
+        FlowSplit::Equal => vec![1.0 / costs.len() as f64; costs.len()],
+        FlowSplit::Logit { theta } | FlowSplit::PathSizeLogit { theta } => {
+            let cheapest = costs.iter().cloned().fold(f64::MAX, f64::min).max(1.0);
+            // The same as exp(ln(path_size) - theta * relative_cost)
+            let weights: Vec<f64> = costs
+                .iter()
+                .zip(path_sizes)
+                .map(|(cost, path_size)| path_size * (-theta * (cost / cheapest - 1.0)).exp())
+                .collect();
+            let total: f64 = weights.iter().sum();
+            weights.into_iter().map(|x| x / total).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use osm_reader::WayID;
+
+    use super::*;
+    use crate::network::tests::make_network;
+    use crate::network::{Network, TurnRestriction};
+
+    #[test]
+    fn test_shares() {
+        assert_eq!(
+            shares(&[10.0, 20.0], &[1.0, 1.0], FlowSplit::Equal),
+            vec![0.5, 0.5]
+        );
+
+        let logit = shares(
+            &[10.0, 10.0, 20.0],
+            &[1.0, 1.0, 1.0],
+            FlowSplit::Logit { theta: 2.0 },
+        );
+        assert!((logit.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert_eq!(logit[0], logit[1]);
+        assert!(logit[2] < logit[0]);
+    }
+
+    #[test]
+    fn test_path_size_logit() {
+        // Two routes from 1 to 4 that only differ in the middle, and one via node 7 that shares
+        // nothing with them
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.003, 0.0),
+                (5, 0.0015, 0.0001),
+                (6, 0.0015, -0.0001),
+                (7, 0.0015, 0.001),
+            ],
+            &[
+                (1, 2),
+                (2, 5),
+                (5, 3),
+                (2, 6),
+                (6, 3),
+                (3, 4),
+                (1, 7),
+                (7, 4),
+            ],
+        );
+        let graph = PlainGraph::new(&network);
+        let start = graph.start(NodeID(1), None);
+        let route = |ids: &[i64]| {
+            let nodes: Vec<NodeID> = ids.iter().map(|id| NodeID(*id)).collect();
+            let costs = graph.path_costs(start, &nodes);
+            (nodes, costs)
+        };
+        let routes = vec![
+            route(&[1, 2, 5, 3, 4]),
+            route(&[1, 2, 6, 3, 4]),
+            route(&[1, 7, 4]),
+        ];
+        let path_sizes = path_sizes(&routes);
+        assert!(path_sizes[0] > 0.5 && path_sizes[0] < 1.0);
+        assert_eq!(path_sizes[0], path_sizes[1]);
+        assert!((path_sizes[2] - 1.0).abs() < 1e-9);
+
+        // With equal costs, plain logit would give each route a third. The overlapping routes act
+        // more like one, so the distinct route gets more.
+        let split = shares(
+            &[100.0, 100.0, 100.0],
+            &path_sizes,
+            FlowSplit::PathSizeLogit { theta: 1.0 },
+        );
+        assert!((split.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!(split[2] > 1.0 / 3.0);
+        assert!(split[0] < 1.0 / 3.0);
+    }
+
+    #[test]
+    fn test_turn_restrictions() {
+        // Two ways around a block, with the one via node 4 a bit longer
+        let mut network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.001, 0.001),
+                (4, 0.0, 0.0012),
+            ],
+            &[(1, 2), (2, 3), (1, 4), (4, 3)],
+        );
+        let config = Alternatives {
+            k: 2,
+            penalty: 1.5,
+            split: FlowSplit::Equal,
+        };
+        let nodes = |ids: &[i64]| ids.iter().map(|id| NodeID(*id)).collect::<Vec<_>>();
+        let find_routes = |network: &Network, best: &[i64]| -> Vec<Vec<NodeID>> {
+            let graph = PlainGraph::new(network);
+            find(&graph, graph.start(NodeID(1), None), nodes(best), &config)
+                .into_iter()
+                .map(|(route, _)| route)
+                .collect()
+        };
+        assert_eq!(
+            find_routes(&network, &[1, 2, 3]),
+            vec![nodes(&[1, 2, 3]), nodes(&[1, 4, 3])]
+        );
+
+        // No left turn from 1-2 onto 2-3, so the only route is via 4
+        network.turn_restrictions.push(TurnRestriction {
+            from: WayID(1),
+            via: NodeID(2),
+            to: WayID(2),
+            only: false,
+        });
+        assert_eq!(find_routes(&network, &[1, 4, 3]), vec![nodes(&[1, 4, 3])]);
+    }
+}
@@ -0,0 +1,557 @@
+//! A stable API for embedding od2net in other Rust tools. Everything here works in memory, without
+//! config files, input directories, or the CLI.
+//!
+//! ```ignore
+//! let network = NetworkBuilder::new()
+//!     .cost(CostFunction::Distance)
+//!     .build_from_osm(&[&osm_pbf_bytes], &mut timer)?;
+//! let mut router = Router::new(network, config, &mut timer)?;
+//! router.set_uptake(UptakeModel::lookup_table(csv_bytes.as_slice())?);
+//! let route = router.route(-1.08, 53.95, -1.09, 53.96);
+//! let counts = router.route_all(requests);
+//! ```
+
+use std::io::Read;
+
+use anyhow::Result;
+use fast_paths::PathCalculator;
+use geo::MultiPolygon;
+use geojson::FeatureCollection;
+use indicatif::HumanCount;
+use osm_reader::NodeID;
+use rstar::RTree;
+
+use super::config::{
+    Barriers, CostFunction, GeojsonNetwork, InputConfig, LtsMapping, Oneway, PathDifficulty,
+    Profile, Uptake, UptakeTable,
+};
+use super::detailed_route_output::route_to_geojson;
+use super::network::{Counts, ElevationSource, GeoTiffFiles, Network, SampleSource};
+use super::plugins::uptake;
+use super::requests::Request;
+use super::router::{
+    build_closest_intersection, just_build_ch, route_all, route_between_edges, snap_distance,
+    snap_request, split_alternatives, IntersectionLocation, PreparedCH, Route, RoutingExtras, Snap,
+};
+use super::timer::Timer;
+
+/// Builds a `Network` from OSM or GeoJSON bytes. Every setting defaults to what a config without
+/// that section would use, with the `BikeOttawa` LTS and `Distance` cost.
+pub struct NetworkBuilder {
+    lts: LtsMapping,
+    profile: Profile,
+    path_difficulty: PathDifficulty,
+    clip_boundary: Option<MultiPolygon<f64>>,
+    cost: CostFunction,
+    exposure_layers: Vec<String>,
+    oneway: Oneway,
+    barriers: Barriers,
+    elevation: Option<Box<dyn ElevationSource>>,
+}
+
+impl Default for NetworkBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NetworkBuilder {
+    pub fn new() -> Self {
+        Self {
+            lts: LtsMapping::BikeOttawa,
+            profile: Profile::default(),
+            path_difficulty: PathDifficulty::default(),
+            clip_boundary: None,
+            cost: CostFunction::Distance,
+            exposure_layers: Vec::new(),
+            oneway: Oneway::default(),
+            barriers: Barriers::default(),
+            elevation: None,
+        }
+    }
+
+    /// Uses the sections of a config that affect the network. The clip boundary and elevation
+    /// refer to files, so they still need to be set separately.
+    pub fn from_config(config: &InputConfig) -> Self {
+        Self {
+            lts: config.lts.clone(),
+            profile: config.profile,
+            path_difficulty: config.path_difficulty.clone(),
+            clip_boundary: None,
+            cost: config.cost.clone(),
+            exposure_layers: config
+                .exposure_layers
+                .iter()
+                .map(|layer| layer.name.clone())
+                .collect(),
+            oneway: config.oneway.clone(),
+            barriers: config.barriers.clone(),
+            elevation: None,
+        }
+    }
+
+    pub fn lts(mut self, lts: LtsMapping) -> Self {
+        self.lts = lts;
+        self
+    }
+
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn path_difficulty(mut self, path_difficulty: PathDifficulty) -> Self {
+        self.path_difficulty = path_difficulty;
+        self
+    }
+
+    /// Only keeps roads inside this area. Only used for OSM input.
+    pub fn clip_boundary(mut self, clip_boundary: MultiPolygon<f64>) -> Self {
+        self.clip_boundary = Some(clip_boundary);
+        self
+    }
+
+    pub fn cost(mut self, cost: CostFunction) -> Self {
+        self.cost = cost;
+        self
+    }
+
+    /// The names of the exposure layers a cost expression can use. Their values are sampled
+    /// separately, with `Network::set_exposure`.
+    pub fn exposure_layers(mut self, exposure_layers: Vec<String>) -> Self {
+        self.exposure_layers = exposure_layers;
+        self
+    }
+
+    pub fn oneway(mut self, oneway: Oneway) -> Self {
+        self.oneway = oneway;
+        self
+    }
+
+    pub fn barriers(mut self, barriers: Barriers) -> Self {
+        self.barriers = barriers;
+        self
+    }
+
+    /// Looks up heights for slopes. Only used for OSM input.
+    pub fn elevation(mut self, elevation: Box<dyn ElevationSource>) -> Self {
+        self.elevation = Some(elevation);
+        self
+    }
+
+    /// Each input is the bytes of an osm.pbf or OSM XML file, optionally gzipped
+    pub fn build_from_osm(mut self, inputs: &[&[u8]], timer: &mut Timer) -> Result<Network> {
+        Network::make_from_osm(
+            inputs,
+            &self.lts,
+            self.profile,
+            &self.path_difficulty,
+            self.clip_boundary.as_ref(),
+            &mut self.cost,
+            &self.exposure_layers,
+            &self.oneway,
+            &self.barriers,
+            timer,
+            self.elevation,
+        )
+    }
+
+    /// `input_bytes` is a GeoJSON FeatureCollection of LineStrings. The path in `columns` is
+    /// ignored.
+    pub fn build_from_geojson(
+        mut self,
+        input_bytes: &[u8],
+        columns: &GeojsonNetwork,
+        timer: &mut Timer,
+    ) -> Result<Network> {
+        Network::make_from_geojson(
+            input_bytes,
+            columns,
+            &mut self.cost,
+            &self.exposure_layers,
+            timer,
+        )
+    }
+
+    /// Reads road centerlines from the GeoPackage at `path`, ignoring the one in `columns`
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn build_from_geopackage(
+        mut self,
+        path: &str,
+        columns: &GeojsonNetwork,
+        timer: &mut Timer,
+    ) -> Result<Network> {
+        Network::make_from_geopackage(path, columns, &mut self.cost, &self.exposure_layers, timer)
+    }
+}
+
+/// Applies the sections of a config that aren't saved with a network: turn costs, surface
+/// multipliers, cost overrides, lighting, traffic counts, and exposure layers. Recalculates the
+/// cost if any of those affect it. Relative paths are resolved against `directory`, like
+/// `InputConfig::resolve_path`. Networks from `NetworkBuilder` or `network.bin` need this to route
+/// like a normal run.
+pub fn prepare_network(
+    network: &mut Network,
+    config: &mut InputConfig,
+    directory: &str,
+    geotiffs: &mut GeoTiffFiles,
+    timer: &mut Timer,
+) -> Result<()> {
+    network.turn_costs = config.turn_costs.clone();
+    network.surface_multipliers = config.surface_multipliers.clone();
+    if let Some(ref path) = config.cost_overrides {
+        network.cost_overrides =
+            super::network::load_cost_overrides(&config.resolve_path(directory, path))?;
+    }
+    network.lighting = config.lighting.clone();
+    if let (true, Some(path)) = (config.lighting.night, &config.lighting.lit_areas) {
+        let lit_areas = super::od::load_area(&config.resolve_path(directory, path))?;
+        for key in network.edges_in_area(&lit_areas) {
+            network.edges.get_mut(&key).unwrap().lit_override = true;
+        }
+    }
+    if let Some(ref traffic_counts) = config.traffic_counts {
+        let path = config.resolve_path(directory, &traffic_counts.path);
+        let matched = network.match_traffic_counts(&path, traffic_counts, timer)?;
+        println!(
+            "Matched traffic counts to {} edges",
+            HumanCount(matched as u64)
+        );
+    }
+    network.exposure_layers = config
+        .exposure_layers
+        .iter()
+        .map(|layer| layer.name.clone())
+        .collect();
+    for layer in &config.exposure_layers {
+        let path = config.resolve_path(directory, &layer.path);
+        timer.start(format!("Sampling exposure layer {}", layer.name));
+        let mut source: Box<dyn SampleSource> =
+            if path.ends_with(".geojson") || path.ends_with(".json") {
+                let Some(ref property) = layer.property else {
+                    bail!("Exposure layer {} needs a property for {path}", layer.name);
+                };
+                Box::new(super::network::load_polygon_values(&path, property)?)
+            } else {
+                Box::new(geotiffs.load(&path)?)
+            };
+        let count = network.set_exposure(&layer.name, &mut *source);
+        timer.stop();
+        println!(
+            "Exposure layer {} covers {} edges",
+            layer.name,
+            HumanCount(count as u64)
+        );
+    }
+    if !network.surface_multipliers.is_empty()
+        || network.lighting.night
+        || config.traffic_counts.is_some()
+        || !config.exposure_layers.is_empty()
+        || !network.cost_overrides.is_empty()
+    {
+        timer.start(
+            "Recalculate cost with surface, lighting, traffic, exposure, and cost overrides",
+        );
+        network.recalculate_cost(&mut config.cost)?;
+        timer.stop();
+    }
+    Ok(())
+}
+
+/// Decides how likely a trip is to be made by bike, given its route
+#[derive(Clone)]
+pub struct UptakeModel(Uptake);
+
+impl From<Uptake> for UptakeModel {
+    /// Any `Uptake::LookupTable` must already have its curves loaded
+    fn from(uptake: Uptake) -> Self {
+        Self(uptake)
+    }
+}
+
+impl From<UptakeModel> for Uptake {
+    fn from(model: UptakeModel) -> Self {
+        model.0
+    }
+}
+
+impl UptakeModel {
+    /// Reads a lookup table from CSV, in the same format as an `Uptake::LookupTable` file
+    pub fn lookup_table<R: Read>(csv: R) -> Result<Self> {
+        let path = "<in memory>".to_string();
+        let curves = uptake::read_table_from(csv, &path)?;
+        Ok(Self(Uptake::LookupTable(UptakeTable { path, curves })))
+    }
+
+    /// Returns the probability of a trip being made, between 0 and 1. `purpose` is only used by
+    /// `Uptake::ByPurpose`.
+    pub fn probability(&self, total_distance_meters: f64, purpose: Option<&str>) -> f64 {
+        uptake::calculate_uptake(&self.0, total_distance_meters, purpose)
+    }
+}
+
+/// Routes requests over a network with a contraction hierarchy, using the cost already calculated
+/// for each edge and the snapping, uptake, alternatives, and other options from a config. The
+/// requests pattern and output options in the config are ignored.
+pub struct Router {
+    network: Network,
+    config: InputConfig,
+    prepared_ch: PreparedCH,
+    closest_intersection: RTree<IntersectionLocation>,
+    extras: RoutingExtras<'static>,
+    path_calc: PathCalculator,
+}
+
+/// One route found by `Router::route`
+#[derive(Debug, PartialEq)]
+pub struct FoundRoute {
+    /// The intersections passed through
+    pub nodes: Vec<NodeID>,
+    /// When `snap_to` is `Edges`, the directed edges partly used before the first intersection and
+    /// after the last, with the meters used on each
+    pub start: Option<((NodeID, NodeID), f64)>,
+    pub end: Option<((NodeID, NodeID), f64)>,
+    /// The total cost, in the units of the cost function. Includes partial edges and turn costs.
+    pub cost: usize,
+    /// When the config has `alternatives`, the routes through intersections that a trip is split
+    /// between, with each one's share. Otherwise just `nodes`, with all of it.
+    pub alternatives: Vec<(Vec<NodeID>, f64)>,
+}
+
+impl Router {
+    /// Builds the contraction hierarchy, which can take a while for big networks. Fails if a
+    /// lookup table in the config's uptake hasn't been loaded.
+    pub fn new(network: Network, config: InputConfig, timer: &mut Timer) -> Result<Self> {
+        check_uptake_tables(&config)?;
+        let prepared_ch = just_build_ch(&network, timer);
+        Self::with_ch(network, config, prepared_ch, timer)
+    }
+
+    /// Uses a contraction hierarchy built earlier for this network, like one from
+    /// `router::build_ch`
+    pub fn with_ch(
+        network: Network,
+        config: InputConfig,
+        prepared_ch: PreparedCH,
+        timer: &mut Timer,
+    ) -> Result<Self> {
+        check_uptake_tables(&config)?;
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, timer);
+        let extras = RoutingExtras::new(&network, &config, timer);
+        let path_calc = fast_paths::create_calculator(&prepared_ch.ch);
+        Ok(Self {
+            network,
+            config,
+            prepared_ch,
+            closest_intersection,
+            extras,
+            path_calc,
+        })
+    }
+
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    pub fn config(&self) -> &InputConfig {
+        &self.config
+    }
+
+    /// Changes the uptake model used by `route_geojson` and `route_all`
+    pub fn set_uptake(&mut self, uptake: UptakeModel) {
+        self.config.uptake = uptake.into();
+    }
+
+    /// The closest intersection to a point in WGS84, or `None` if the network is empty. This
+    /// ignores `snap_to` and `max_snap_meters`.
+    pub fn snap(&self, x: f64, y: f64) -> Option<NodeID> {
+        let location = self.closest_intersection.nearest_neighbor(&[x, y])?;
+        Some(self.prepared_ch.node_map.translate_id(location.data))
+    }
+
+    /// Finds the cheapest route between two points in WGS84, snapping them like a normal run.
+    /// Returns `None` if both points snap to the same place, either snaps farther than
+    /// `max_snap_meters`, or there's no route.
+    pub fn route(&mut self, x1: f64, y1: f64, x2: f64, y2: f64) -> Option<FoundRoute> {
+        let req = Request::new(x1, y1, x2, y2);
+        let (start, end) = snap_request(
+            &req,
+            &self.closest_intersection,
+            &self.extras,
+            &self.network,
+        )?;
+        if let Some(max) = self.config.max_snap_meters {
+            if snap_distance(x1, y1, start.position()) > max
+                || snap_distance(x2, y2, end.position()) > max
+            {
+                return None;
+            }
+        }
+
+        let (route, cost) = match (&start, &end) {
+            (Snap::Intersection(start), Snap::Intersection(end)) => {
+                if start.data == end.data {
+                    return None;
+                }
+                let (nodes, cost) = self.prepared_ch.calc_path_with_cost(
+                    &mut self.path_calc,
+                    start.data,
+                    end.data,
+                )?;
+                (Route::from_path(nodes.into()), cost)
+            }
+            (Snap::Edge(start), Snap::Edge(end)) => {
+                if start.edge == end.edge && start.fraction == end.fraction {
+                    return None;
+                }
+                route_between_edges(
+                    start,
+                    end,
+                    &mut self.path_calc,
+                    &self.prepared_ch,
+                    &self.network,
+                )?
+            }
+            _ => unreachable!(),
+        };
+
+        let nodes = route.nodes.to_vec();
+        let (start, end) = (route.start, route.end);
+        let alternatives =
+            split_alternatives(route, self.extras.alternatives.as_ref(), &self.config)
+                .into_iter()
+                .map(|(route, share)| (route.nodes.to_vec(), share))
+                .collect();
+        Some(FoundRoute {
+            nodes,
+            start,
+            end,
+            cost,
+            alternatives,
+        })
+    }
+
+    /// Like `route`, but returns one LineString per edge, with the route's uptake and length. Only
+    /// whole edges between intersections are included, so with `snap_to` set to `Edges`, the
+    /// partial edges at each end are missing.
+    pub fn route_geojson(
+        &mut self,
+        x1: f64,
+        y1: f64,
+        x2: f64,
+        y2: f64,
+        purpose: Option<&str>,
+    ) -> Option<FeatureCollection> {
+        let route = self.route(x1, y1, x2, y2)?;
+        Some(route_to_geojson(
+            &route.nodes,
+            &self.network,
+            &self.config.uptake,
+            purpose,
+            None,
+        ))
+    }
+
+    /// Routes every request in parallel, summing up the counts like a normal run
+    pub fn route_all(&self, requests: Vec<Request>) -> Counts {
+        if requests.is_empty() {
+            return Counts::new();
+        }
+        route_all(
+            &self.prepared_ch,
+            &self.closest_intersection,
+            &self.extras,
+            &self.network,
+            requests,
+            &self.config,
+            None,
+        )
+    }
+}
+
+fn check_uptake_tables(config: &InputConfig) -> Result<()> {
+    uptake::check_tables_loaded(&config.uptake)?;
+    for uptake in config.uptake_scenarios.values() {
+        uptake::check_tables_loaded(uptake)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config_with;
+    use crate::network::tests::make_network;
+
+    #[test]
+    fn test_router() {
+        let network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (2, 3)],
+        );
+        let mut timer = Timer::new();
+        let mut router = Router::new(network, config_with(""), &mut timer).unwrap();
+
+        assert_eq!(router.snap(0.0021, 0.0), Some(NodeID(3)));
+        let route = router.route(0.0, 0.0, 0.002, 0.0).unwrap();
+        let nodes = vec![NodeID(1), NodeID(2), NodeID(3)];
+        assert_eq!(route.nodes, nodes);
+        // Each edge is about 111m
+        assert_eq!(route.cost, 222);
+        assert_eq!(route.alternatives, vec![(nodes, 1.0)]);
+        assert_eq!(router.route(0.0, 0.0, 0.0001, 0.0), None);
+
+        // Only trips under 150m are made, so the longer one doesn't count
+        router.set_uptake(
+            UptakeModel::lookup_table("distance_meters,probability\n150,1\n151,0\n".as_bytes())
+                .unwrap(),
+        );
+        let request = |x2| Request::new(0.0, 0.0, x2, 0.0);
+        let counts = router.route_all(vec![request(0.001), request(0.002)]);
+        assert_eq!(counts.count_per_edge[&(NodeID(1), NodeID(2))], 1.0);
+        assert_eq!(counts.count_per_edge.get(&(NodeID(2), NodeID(3))), None);
+        assert!(router.route_all(Vec::new()).count_per_edge.is_empty());
+    }
+
+    #[test]
+    fn test_router_snapping() {
+        let network = || {
+            make_network(
+                &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+                &[(1, 2), (2, 3)],
+            )
+        };
+        let mut timer = Timer::new();
+
+        // Starting and ending halfway along the edges only uses half of each
+        let config = config_with(r#", "snap_to": "Edges""#);
+        let mut router = Router::new(network(), config, &mut timer).unwrap();
+        let route = router.route(0.0005, 0.0, 0.0015, 0.0).unwrap();
+        assert_eq!(route.nodes, vec![NodeID(2)]);
+        assert!(route.start.is_some() && route.end.is_some());
+        assert!((110..=112).contains(&route.cost));
+
+        let config = config_with(r#", "max_snap_meters": 100.0"#);
+        let mut router = Router::new(network(), config, &mut timer).unwrap();
+        assert!(router.route(0.0, 0.0, 0.002, 0.0).is_some());
+        // About 1km north of the network
+        assert_eq!(router.route(0.0, 0.01, 0.002, 0.0), None);
+    }
+
+    #[test]
+    fn test_router_needs_uptake_tables() {
+        let network = make_network(&[(1, 0.0, 0.0), (2, 0.001, 0.0)], &[(1, 2)]);
+        let mut config = config_with("");
+        config.uptake = Uptake::ByPurpose {
+            purposes: [(
+                "school".to_string(),
+                Uptake::LookupTable("school.csv".to_string().into()),
+            )]
+            .into_iter()
+            .collect(),
+            default: Box::new(Uptake::Identity),
+        };
+        assert!(Router::new(network, config, &mut Timer::new()).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use fs_err::File;
+use serde::Serialize;
+
+use super::OutputMetadata;
+
+/// One row of the summary written by `od2net batch`, for one config or one of its scenarios. Only
+/// `config_path` and `error` are filled out if the config failed.
+#[derive(Serialize)]
+pub struct BatchSummaryRow {
+    pub config_path: String,
+    pub output_directory: Option<String>,
+    /// Empty if the config ran successfully
+    pub error: String,
+    pub num_requests: Option<usize>,
+    pub num_succeeded_requests: Option<usize>,
+    pub num_failed_requests: Option<usize>,
+    pub num_edges_with_count: Option<usize>,
+    pub total_km_lts1: Option<f64>,
+    pub total_km_lts2: Option<f64>,
+    pub total_km_lts3: Option<f64>,
+    pub total_km_lts4: Option<f64>,
+    pub routing_time_seconds: Option<f32>,
+    pub total_time_seconds: Option<f32>,
+}
+
+impl BatchSummaryRow {
+    pub fn new(config_path: &str, output_directory: &str, metadata: &OutputMetadata) -> Self {
+        Self {
+            config_path: config_path.to_string(),
+            output_directory: Some(output_directory.to_string()),
+            error: String::new(),
+            num_requests: Some(metadata.num_requests),
+            num_succeeded_requests: Some(metadata.num_succeeded_requests),
+            num_failed_requests: Some(
+                metadata.num_failed_requests_same_endpoints
+                    + metadata.num_failed_requests_no_path
+                    + metadata.num_failed_requests_far_snap,
+            ),
+            num_edges_with_count: Some(metadata.num_edges_with_count),
+            total_km_lts1: Some(metadata.total_meters_lts1 / 1000.0),
+            total_km_lts2: Some(metadata.total_meters_lts2 / 1000.0),
+            total_km_lts3: Some(metadata.total_meters_lts3 / 1000.0),
+            total_km_lts4: Some(metadata.total_meters_lts4 / 1000.0),
+            routing_time_seconds: Some(metadata.routing_time_seconds),
+            total_time_seconds: metadata.total_time_seconds,
+        }
+    }
+
+    pub fn failed(config_path: &str, error: &anyhow::Error) -> Self {
+        Self {
+            config_path: config_path.to_string(),
+            output_directory: None,
+            // Include the causes, but keep the row on one line
+            error: format!("{error:#}").replace('\n', " "),
+            num_requests: None,
+            num_succeeded_requests: None,
+            num_failed_requests: None,
+            num_edges_with_count: None,
+            total_km_lts1: None,
+            total_km_lts2: None,
+            total_km_lts3: None,
+            total_km_lts4: None,
+            routing_time_seconds: None,
+            total_time_seconds: None,
+        }
+    }
+}
+
+pub fn write_summary(path: &str, rows: &[BatchSummaryRow]) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(File::create(path)?);
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Every config normally writes to `output/` in its own directory. When several configs in a batch
+/// share a directory, each one instead writes to `output/<config filename without extension>/`,
+/// so they don't overwrite each other. The paths must be absolute.
+pub fn output_names(config_paths: &[PathBuf]) -> Vec<Option<String>> {
+    let mut configs_per_directory: HashMap<&Path, usize> = HashMap::new();
+    for path in config_paths {
+        *configs_per_directory
+            .entry(path.parent().unwrap())
+            .or_insert(0) += 1;
+    }
+    config_paths
+        .iter()
+        .map(|path| {
+            if configs_per_directory[path.parent().unwrap()] == 1 {
+                None
+            } else {
+                Some(path.file_stem().unwrap().to_string_lossy().to_string())
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_names() {
+        let paths: Vec<PathBuf> = [
+            "/areas/york/config.json",
+            "/areas/configs/leeds.json",
+            "/areas/configs/bradford.json",
+        ]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(
+            output_names(&paths),
+            vec![
+                None,
+                Some("leeds".to_string()),
+                Some("bradford".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_summary() {
+        let path = std::env::temp_dir().join("od2net_test_batch_summary.csv");
+        let path = path.to_str().unwrap();
+        let rows = vec![BatchSummaryRow::failed(
+            "york/config.json",
+            &anyhow!("No OSM input found\nsomewhere"),
+        )];
+        write_summary(path, &rows).unwrap();
+        let contents = fs_err::read_to_string(path).unwrap();
+        let mut lines = contents.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("config_path,output_directory,error,num_requests,"));
+        assert_eq!(
+            lines.next().unwrap(),
+            "york/config.json,,No OSM input found somewhere,,,,,,,,,,"
+        );
+        assert_eq!(lines.next(), None);
+    }
+}
@@ -0,0 +1,291 @@
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+
+use anyhow::Result;
+use fs_err::File;
+use serde::{Deserialize, Serialize};
+
+use super::config::InputConfig;
+use super::network::{Counts, Network};
+use super::requests::Request;
+
+/// Periodically saves the counts while routing, so a long run can resume after being interrupted
+pub struct Checkpoints {
+    pub path: String,
+    /// Route this many requests between saving
+    pub every_n_requests: usize,
+}
+
+/// A checkpoint file starts with a key identifying the network, requests, and config, so one from
+/// different inputs isn't resumed. Then every chunk routed so far is appended, so saving only
+/// writes the newest chunk's counts.
+#[derive(Serialize, Deserialize)]
+struct Chunk {
+    /// The requests are always routed in the same order, so this many after the previous chunks
+    /// are done
+    num_requests: usize,
+    counts: Counts,
+}
+
+/// Calls `route` on chunks of requests in order, combining the counts. If `checkpoints` is set,
+/// the counts of every chunk are saved, and routing resumes after the last saved chunk for the
+/// same inputs. The checkpoint is removed once everything is routed. Without `checkpoints`, all
+/// requests are routed at once.
+pub fn route_in_chunks<F: FnMut(Vec<Request>) -> Counts>(
+    checkpoints: Option<&Checkpoints>,
+    mut requests: Vec<Request>,
+    network: &Network,
+    config: &InputConfig,
+    mut route: F,
+) -> Result<Counts> {
+    let Some(checkpoints) = checkpoints else {
+        return Ok(route(requests));
+    };
+    if checkpoints.every_n_requests == 0 {
+        bail!("Checkpoints must be at least one request apart");
+    }
+
+    let key = cache_key(&requests, network, config)?;
+    let (mut counts, num_requests_done, mut file) = open(&checkpoints.path, key)?;
+    if num_requests_done > requests.len() {
+        bail!("{} is for more requests than there are", checkpoints.path);
+    }
+
+    let mut remaining = requests.split_off(num_requests_done);
+    while !remaining.is_empty() {
+        let rest = remaining.split_off(checkpoints.every_n_requests.min(remaining.len()));
+        let chunk = std::mem::replace(&mut remaining, rest);
+        let chunk = Chunk {
+            num_requests: chunk.len(),
+            counts: route(chunk),
+        };
+        append(&mut file, &chunk)?;
+        counts.combine(chunk.counts);
+    }
+    drop(file);
+    fs_err::remove_file(&checkpoints.path)?;
+
+    // combine doesn't touch these
+    counts.uptake_scenarios = config.uptake_scenarios.keys().cloned().collect();
+    counts.set_flow_groups(config.flow_breakdown.as_ref());
+    Ok(counts)
+}
+
+/// Combines the chunks already saved for the same key, returning their counts, how many requests
+/// they cover, and the file to append more to. Otherwise starts a new checkpoint.
+fn open(path: &str, key: u32) -> Result<(Counts, usize, File)> {
+    let mut counts = Counts::new();
+    let mut num_requests_done = 0;
+    // The length of the file up to the last complete chunk, or 0 to start over
+    let mut valid_bytes = 0;
+    if let Ok(file) = File::open(path) {
+        let mut reader = BufReader::new(file);
+        match bincode::deserialize_from::<_, u32>(&mut reader) {
+            Ok(x) if x == key => {
+                valid_bytes = reader.stream_position()?;
+                // A chunk cut short by an interruption while saving it is dropped
+                while let Ok(chunk) = bincode::deserialize_from::<_, Chunk>(&mut reader) {
+                    counts.combine(chunk.counts);
+                    num_requests_done += chunk.num_requests;
+                    valid_bytes = reader.stream_position()?;
+                }
+                println!("Resuming from {path}, with {num_requests_done} requests already routed");
+            }
+            Ok(_) => {
+                println!("Ignoring {path}, because it's from different inputs or config");
+            }
+            Err(err) => {
+                println!("Ignoring {path}, because it can't be read: {err}");
+            }
+        }
+    }
+
+    let mut file = fs_err::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    file.set_len(valid_bytes)?;
+    if valid_bytes == 0 {
+        bincode::serialize_into(&mut file, &key)?;
+    } else {
+        file.seek(SeekFrom::End(0))?;
+    }
+    Ok((counts, num_requests_done, file))
+}
+
+fn append(file: &mut File, chunk: &Chunk) -> Result<()> {
+    let mut writer = BufWriter::new(file);
+    bincode::serialize_into(&mut writer, chunk)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn cache_key(requests: &[Request], network: &Network, config: &InputConfig) -> Result<u32> {
+    let mut crc = flate2::Crc::new();
+    crc.update(&network.cache_key.to_le_bytes());
+    // Serializing the config directly would list any HashMap, like the one in
+    // CostFunction::OsmHighwayType, in a different order each process. Value sorts keys.
+    crc.update(serde_json::to_value(config)?.to_string().as_bytes());
+    // Not part of the config file, but changes what's counted
+    crc.update(format!("{:?}", config.collect).as_bytes());
+    for req in requests {
+        for x in [req.x1, req.y1, req.x2, req.y2, req.weight] {
+            crc.update(&x.to_le_bytes());
+        }
+        crc.update(req.purpose.as_deref().unwrap_or("").as_bytes());
+    }
+    Ok(crc.sum())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config_with;
+    use crate::config::CostFunction;
+    use crate::network::tests::make_network;
+    use osm_reader::NodeID;
+
+    fn request(x: f64) -> Request {
+        Request::new(x, 0.0, x, 1.0)
+    }
+
+    /// Counts one edge per request, keyed by its x
+    fn fake_route(requests: Vec<Request>) -> Counts {
+        let mut counts = Counts::new();
+        for req in requests {
+            counts
+                .count_per_edge
+                .insert((NodeID(req.x1 as i64), NodeID(0)), 1.0);
+        }
+        counts
+    }
+
+    /// Writes a checkpoint with these chunks, as if a previous run was interrupted
+    fn save(path: &str, key: u32, chunks: &[&[Request]]) {
+        let mut file = File::create(path).unwrap();
+        bincode::serialize_into(&mut file, &key).unwrap();
+        for chunk in chunks {
+            let chunk = Chunk {
+                num_requests: chunk.len(),
+                counts: fake_route(chunk.to_vec()),
+            };
+            append(&mut file, &chunk).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_resume() {
+        let path = std::env::temp_dir().join("od2net_test_checkpoint.bin");
+        let checkpoints = Checkpoints {
+            path: path.to_str().unwrap().to_string(),
+            every_n_requests: 2,
+        };
+        let network = make_network(&[(1, 0.0, 0.0), (2, 0.001, 0.0)], &[(1, 2)]);
+        let config = config_with("");
+        let requests: Vec<Request> = (0..7).map(|x| request(x as f64)).collect();
+        let key = cache_key(&requests, &network, &config).unwrap();
+
+        // The previous run was interrupted while saving the third chunk
+        save(&checkpoints.path, key, &[&requests[0..2], &requests[2..4]]);
+        let mut file = fs_err::OpenOptions::new()
+            .append(true)
+            .open(&checkpoints.path)
+            .unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+
+        let mut chunk_sizes = Vec::new();
+        let counts = route_in_chunks(
+            Some(&checkpoints),
+            requests.clone(),
+            &network,
+            &config,
+            |chunk| {
+                chunk_sizes.push(chunk.len());
+                fake_route(chunk)
+            },
+        )
+        .unwrap();
+        assert_eq!(chunk_sizes, vec![2, 1]);
+        assert_eq!(counts.count_per_edge.len(), 7);
+        // The finished run cleans up
+        assert!(!path.exists());
+
+        // A checkpoint for a different config or network is ignored
+        let mut other_network = make_network(&[(1, 0.0, 0.0), (2, 0.001, 0.0)], &[(1, 2)]);
+        other_network.cache_key = 1;
+        for (network, config) in [
+            (&network, config_with(r#", "sort_output": true"#)),
+            (&other_network, config_with("")),
+        ] {
+            save(&checkpoints.path, key, &[&requests[0..2]]);
+            let mut num_routed = 0;
+            route_in_chunks(
+                Some(&checkpoints),
+                requests.clone(),
+                network,
+                &config,
+                |chunk| {
+                    num_routed += chunk.len();
+                    fake_route(chunk)
+                },
+            )
+            .unwrap();
+            assert_eq!(num_routed, 7);
+        }
+    }
+
+    #[test]
+    fn test_resume_with_highway_costs() {
+        let path = std::env::temp_dir().join("od2net_test_checkpoint_highway_costs.bin");
+        let checkpoints = Checkpoints {
+            path: path.to_str().unwrap().to_string(),
+            every_n_requests: 2,
+        };
+        let network = make_network(&[(1, 0.0, 0.0), (2, 0.001, 0.0)], &[(1, 2)]);
+        // Each HashMap is seeded differently, like in a resumed process
+        let config = || {
+            let mut config = config_with("");
+            config.cost = CostFunction::OsmHighwayType(
+                [
+                    "cycleway",
+                    "footway",
+                    "living_street",
+                    "path",
+                    "primary",
+                    "residential",
+                    "secondary",
+                    "service",
+                    "tertiary",
+                    "track",
+                    "trunk",
+                    "unclassified",
+                ]
+                .into_iter()
+                .enumerate()
+                .map(|(idx, highway)| (highway.to_string(), 1.0 + idx as f64))
+                .collect(),
+            );
+            config
+        };
+        let requests: Vec<Request> = (0..7).map(|x| request(x as f64)).collect();
+        let key = cache_key(&requests, &network, &config()).unwrap();
+        save(&checkpoints.path, key, &[&requests[0..2], &requests[2..4]]);
+
+        let mut num_routed = 0;
+        let counts = route_in_chunks(
+            Some(&checkpoints),
+            requests.clone(),
+            &network,
+            &config(),
+            |chunk| {
+                num_routed += chunk.len();
+                fake_route(chunk)
+            },
+        )
+        .unwrap();
+        assert_eq!(num_routed, 3);
+        assert_eq!(counts.count_per_edge.len(), 7);
+        assert!(!path.exists());
+    }
+}
@@ -1,24 +1,211 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
+use lts::{Tags, LTS};
+
+use crate::utils;
+
 /// Everything needed to run the pipeline.
 ///
-/// All paths are relative to the `input/` directory.
-#[derive(Serialize, Deserialize)]
+/// Relative paths are resolved against the `input/` directory next to the config file, or the
+/// config file's own directory if `paths_relative_to_config` is set. Absolute paths are used
+/// as-is.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct InputConfig {
     pub requests: Requests,
 
     pub cost: CostFunction,
 
+    /// Who's travelling. Cycling by default.
+    #[serde(default)]
+    pub profile: Profile,
+
+    /// Extra costs for turning and crossing traffic signals at intersections. None by default.
+    #[serde(default)]
+    pub turn_costs: TurnCosts,
+
+    /// How `oneway` tags limit the direction of travel. By default, every road can be used both
+    /// ways.
+    #[serde(default)]
+    pub oneway: Oneway,
+
+    /// Costs for passing `barrier` nodes, like bollards and gates. Barriers that deny bicycle
+    /// access are never passable.
+    #[serde(default)]
+    pub barriers: Barriers,
+
+    /// Multiply cost by the road's `surface` and `smoothness`
+    #[serde(default)]
+    pub surface_multipliers: SurfaceMultipliers,
+
+    /// Penalize unlit roads, for night-time scenarios
+    #[serde(default)]
+    pub lighting: Lighting,
+
+    /// Motor traffic counts to match to roads, setting `aadt` on edges
+    pub traffic_counts: Option<TrafficCounts>,
+
+    /// Raster or polygon layers, like air pollution or noise, averaged along each edge. They can
+    /// be used in cost expressions and appear in the output.
+    #[serde(default)]
+    pub exposure_layers: Vec<ExposureLayer>,
+
+    /// Path to a CSV file adjusting the cost of specific OSM ways, with a `way_id` column and
+    /// optional `cost` and `multiplier` columns. Applied after the cost function.
+    pub cost_overrides: Option<String>,
+
     pub uptake: Uptake,
 
+    /// Extra uptake functions evaluated on the same routes. Each one adds a `count_<name>` column
+    /// to the edges in `counts.csv` and `output.geojson`.
+    #[serde(default)]
+    pub uptake_scenarios: BTreeMap<String, Uptake>,
+
     pub lts: LtsMapping,
 
-    /// Path to a GeoTIFF file with elevation data. It must use WGS84 coordinates and have heights
-    /// in units of meters.
+    /// Paths to OSM files to combine into one network, like neighbouring extracts. If unset,
+    /// `input.osm.pbf` (or an XML variant) is used.
+    pub osm_inputs: Option<Vec<String>>,
+
+    /// Download the OSM input automatically, instead of needing `input.osm.pbf`. Can't be used
+    /// along with `osm_inputs`.
+    pub osm: Option<OsmDownload>,
+
+    /// Build the network from road centerlines in GeoJSON, instead of OSM
+    pub geojson_network: Option<GeojsonNetwork>,
+
+    /// Path to a GeoJSON file with polygons. Edges entirely outside of it are left out of the
+    /// network, so a large OSM input doesn't need to be clipped first.
+    pub clip_boundary: Option<String>,
+
+    /// Leave out paths that're too difficult to cycle on
+    #[serde(default)]
+    pub path_difficulty: PathDifficulty,
+
+    /// Path to a GeoTIFF file with elevation data, or a directory of them covering different
+    /// areas. They must use WGS84 coordinates and have heights in units of meters.
     pub elevation_geotiff: Option<String>,
+
+    /// Fetch elevation data from a web map tile server instead of a GeoTIFF. Can't be used along
+    /// with `elevation_geotiff`.
+    pub elevation_tiles: Option<ElevationTiles>,
+
+    /// When neither `elevation_geotiff` nor `elevation_tiles` is set, download global elevation
+    /// tiles for the edges in the network, so slopes work without finding a DEM first
+    #[serde(default)]
+    pub download_elevation: bool,
+
+    /// Resolve relative paths against the directory containing the config file, instead of its
+    /// `input/` subdirectory.
+    #[serde(default)]
+    pub paths_relative_to_config: bool,
+
+    /// Controls how `rnet.pmtiles` is generated
+    #[serde(default)]
+    pub pmtiles: PmtilesOptions,
+
+    /// Named cost functions to compare routes between, with `--compare-profiles-per-od`
+    #[serde(default)]
+    pub profiles: BTreeMap<String, CostFunction>,
+
+    /// Route the same requests several times with different settings, writing the results for
+    /// each to `output/<name>/` instead of `output/`
+    #[serde(default)]
+    pub scenarios: Vec<Scenario>,
+
+    /// What to do with requests whose origin and destination snap to the same intersection
+    #[serde(default)]
+    pub same_endpoints: SameEndpoints,
+
+    /// Where request endpoints snap to on the network
+    #[serde(default)]
+    pub snap_to: SnapTo,
+
+    /// How to find routes
+    #[serde(default)]
+    pub router: Router,
+
+    /// Path to a GeoJSON file with polygons. If set, only edges overlapping this area get counts
+    /// and appear in the output. Routes still use the full network.
+    pub count_area: Option<String>,
+
+    /// Down-weight how much each route adds to `count_per_origin` and `count_per_destination` when
+    /// its endpoints snap far away from the network. Edge and intersection counts aren't affected.
+    pub snap_distance_decay: Option<SnapDistanceDecay>,
+
+    /// Reject requests with an origin or destination farther than this from the intersection it
+    /// snaps to. They're recorded as failures, along with the snap distances.
+    pub max_snap_meters: Option<f64>,
+
+    /// Route each request along several alternative routes, splitting its count between them,
+    /// instead of only using the cheapest route
+    pub alternatives: Option<Alternatives>,
+
+    /// Also split each edge's count by the kind of route crossing it, like its length or purpose,
+    /// to see who uses each street
+    pub flow_breakdown: Option<FlowBreakdown>,
+
+    /// Write edges and points to `output.geojson` and `counts.csv` in a fixed order, so outputs
+    /// from runs over the same network can be compared line by line
+    #[serde(default)]
+    pub sort_output: bool,
+
+    /// If set, only these properties are written for each edge in `output.geojson` and
+    /// `rnet.pmtiles`, like `["count", "lts"]`. Everything is written by default.
+    pub output_properties: Option<Vec<String>>,
+
+    /// Compress `counts.csv` and `output.geojson` while writing them, adding `.gz` or `.zst` to
+    /// their names
+    pub output_compression: Option<OutputCompression>,
+
+    /// Extra details to collect while routing. These cost memory, so the caller chooses them
+    /// instead of `config.json`.
+    #[serde(skip)]
+    pub collect: Collect,
+}
+
+impl InputConfig {
+    /// Returns the directory that relative paths in this config are resolved against, given the
+    /// directory containing the config file.
+    pub fn input_directory(&self, config_directory: &str) -> String {
+        if self.paths_relative_to_config {
+            config_directory.to_string()
+        } else {
+            format!("{config_directory}/input")
+        }
+    }
+
+    /// Resolves a path referenced by this config, given the directory containing the config file.
+    pub fn resolve_path(&self, config_directory: &str, path: &str) -> String {
+        utils::resolve_path(&self.input_directory(config_directory), path)
+    }
+
+    /// Reads the CSV files for any `Uptake::LookupTable`, including in scenarios and
+    /// `uptake_scenarios`
+    pub fn load_uptake_tables(&mut self, config_directory: &str) -> Result<()> {
+        let input_directory = self.input_directory(config_directory);
+        crate::plugins::uptake::load_tables(&mut self.uptake, &input_directory)?;
+        for uptake in self.uptake_scenarios.values_mut() {
+            crate::plugins::uptake::load_tables(uptake, &input_directory)?;
+        }
+        for scenario in &mut self.scenarios {
+            if let Some(ref mut uptake) = scenario.uptake {
+                crate::plugins::uptake::load_tables(uptake, &input_directory)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Extra details to collect in `Counts` while routing. None by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Collect {
+    /// Keep a `RouteSummary` for every successful request
+    pub route_summaries: bool,
+    /// Count turning movements at each intersection
+    pub movements: bool,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -30,6 +217,29 @@ pub struct Requests {
     pub origins_path: String,
     /// Path to a GeoJSON file with points to use as destinations
     pub destinations_path: String,
+    /// Don't create requests with a straight-line distance shorter than this
+    pub min_od_distance_meters: Option<f64>,
+    /// Don't create requests with a straight-line distance longer than this
+    pub max_od_distance_meters: Option<f64>,
+    /// When a zone centroid is used as an origin or destination, move it by a random offset up
+    /// to this far away for each request, spreading demand over the nearby network
+    pub centroid_jitter_meters: Option<f64>,
+    /// For patterns between zones, what to do with trips starting and ending in the same zone
+    #[serde(default)]
+    pub intrazonal: Intrazonal,
+    /// For patterns between zones, sample one OD pair for up to this many trips, and weight it by
+    /// the number of trips it represents. By default, every trip gets its own OD pair.
+    pub max_per_od: Option<usize>,
+    /// For patterns between zones, pick origin and destination points within each zone in
+    /// proportion to this numeric property, instead of uniformly. Points without the property have
+    /// a weight of 1.
+    pub point_weight_property: Option<String>,
+    /// Use the cells of a raster, like population counts, as origins instead of `origins_path`
+    pub origin_raster: Option<OriginRaster>,
+    /// For `BetweenZones`, path to a CSV file with 3 columns "name", "origins", and
+    /// "destinations". The OD matrix is adjusted with iterative proportional fitting, so the
+    /// number of trips starting and ending in each zone matches.
+    pub ipf_margins_path: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -38,6 +248,13 @@ pub enum ODPattern {
     FromEveryOriginToOneDestination,
     /// One trip from every origin to the closest (as the crow flies) destination
     FromEveryOriginToNearestDestination,
+    /// One trip from every origin to each of the `n` closest (as the crow flies) amenities from
+    /// OSM of one kind. `destinations_path` isn't used.
+    ToNearestAmenities {
+        /// The value of the OSM `amenity` tag, like `school` or `doctors`. Use `shop` for shops.
+        amenity: String,
+        n: usize,
+    },
     /// Trips between named zones
     BetweenZones {
         /// Path to a GeoJSON file containing Polygons and MultiPolygons with a "name" property
@@ -45,6 +262,9 @@ pub enum ODPattern {
         /// Path to a CSV file that must have 3 columns "from", "to", and "count". The first
         /// two must match zone names. "count" must be an integer.
         csv_path: String,
+        /// How `csv_path` is laid out. By default, it has one row per pair of zones.
+        #[serde(default)]
+        csv_format: OdCsvFormat,
         /// If a zone doesn't have any matching origin points, use the zone's centroid instead.
         origin_zone_centroid_fallback: bool,
         /// If a zone doesn't have any matching destination points, use the zone's centroid instead.
@@ -61,13 +281,120 @@ pub enum ODPattern {
         /// If a zone doesn't have any matching origin points, use the zone's centroid instead.
         origin_zone_centroid_fallback: bool,
     },
-    /// Just read GeoJSON LineStrings from this path
+    /// Synthesize trips between named zones from the number of trips starting and ending in each,
+    /// using a gravity model. Useful without an observed OD matrix.
+    Gravity {
+        /// Path to a GeoJSON file containing Polygons and MultiPolygons with a "name" property
+        zones_path: String,
+        /// Path to a CSV file that must have 3 columns "name", "origins", and "destinations".
+        /// "name" must match zone names. The other two are the number of trips starting and
+        /// ending in the zone.
+        totals_csv_path: String,
+        /// How quickly trips become less likely over the distance between zone centroids
+        deterrence: Deterrence,
+        /// If a zone doesn't have any matching origin points, use the zone's centroid instead.
+        origin_zone_centroid_fallback: bool,
+        /// If a zone doesn't have any matching destination points, use the zone's centroid instead.
+        destination_zone_centroid_fallback: bool,
+    },
+    /// Synthesize trips between named zones from their populations, using the radiation model.
+    /// Unlike `Gravity`, there are no parameters to calibrate.
+    Radiation {
+        /// Path to a GeoJSON file containing Polygons and MultiPolygons with a "name" property
+        zones_path: String,
+        /// Path to a CSV file that must have 3 columns "name", "population", and "origins".
+        /// "name" must match zone names, and "origins" is the number of trips starting in the
+        /// zone.
+        populations_csv_path: String,
+        /// If a zone doesn't have any matching origin points, use the zone's centroid instead.
+        origin_zone_centroid_fallback: bool,
+        /// If a zone doesn't have any matching destination points, use the zone's centroid instead.
+        destination_zone_centroid_fallback: bool,
+    },
+    /// Just read GeoJSON LineStrings from this path. Each may have a numeric "count" property,
+    /// saying how many trips it represents.
     LineStrings(String),
+    /// Read a CSV file with columns "x1", "y1", "x2", and "y2" in WGS84. "count" (how many trips
+    /// each row represents) and "purpose" are optional.
+    Csv(String),
     /// One trip from every intersection to every other intersection. This is likely a very
     /// unrealistic pattern.
     AllPairsIntersections,
 }
 
+impl ODPattern {
+    /// The GeoJSON file with named zones, for patterns that use one
+    pub fn zones_path(&self) -> Option<&str> {
+        match self {
+            ODPattern::BetweenZones { zones_path, .. }
+            | ODPattern::ZoneToPoint { zones_path, .. }
+            | ODPattern::Gravity { zones_path, .. }
+            | ODPattern::Radiation { zones_path, .. } => Some(zones_path),
+            _ => None,
+        }
+    }
+}
+
+/// A raster used as an origin weighting surface
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OriginRaster {
+    /// Path to a single-band GeoTIFF in EPSG:4326, like population counts from WorldPop or GHSL.
+    /// The center of every cell with a positive value becomes an origin, weighted by that value.
+    pub path: String,
+    /// Instead of using every cell once, sample this many origins across the whole raster, in
+    /// proportion to the cell values. Patterns like `FromEveryOriginToNearestDestination` need
+    /// this to weight origins.
+    pub num_samples: Option<usize>,
+}
+
+/// What to do with trips starting and ending in the same zone
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Intrazonal {
+    /// Pick the origin and destination points independently, so they might be the same
+    #[default]
+    SampleIndependently,
+    /// Don't create these trips
+    Skip,
+    /// Pick a destination point different from the origin. If every point in the zone is the
+    /// same, the trip is skipped.
+    DistinctPoints,
+    /// Put the destination in a random direction from the origin, at the average distance between
+    /// two points in the zone. This is estimated from the zone's area, treating it as a circle.
+    AverageDistance,
+}
+
+/// The layout of a CSV file describing trips between zones
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub enum OdCsvFormat {
+    /// One row per pair of zones, with "from", "to", and "count" columns
+    #[default]
+    Long,
+    /// A matrix with one row per origin zone and one column per destination zone. The first
+    /// column has the origin zone name, and the header has the destination zone names. Every
+    /// other cell is an integer count, or empty for 0.
+    Wide,
+}
+
+/// Weights trips by the distance between zones, in kilometers. Distances under 100 meters are
+/// treated as 100 meters.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Deterrence {
+    /// `distance ^ -exponent`
+    Power { exponent: f64 },
+    /// `e ^ (-beta * distance)`
+    Exponential { beta: f64 },
+}
+
+impl Deterrence {
+    pub fn weight(self, distance_meters: f64) -> f64 {
+        let distance_km = distance_meters.max(100.0) / 1000.0;
+        match self {
+            Deterrence::Power { exponent } => distance_km.powf(-exponent),
+            Deterrence::Exponential { beta } => (-beta * distance_km).exp(),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub enum CostFunction {
     /// Just find the most direct path, minimizing distance. This is equivalent to ByLTS with all
@@ -89,12 +416,40 @@ pub enum CostFunction {
     /// nearby_amenities, lts). The output must be an equally sized JSON array of integers,
     /// representing the cost for that edge.
     ExternalCommand(String),
+    /// Load a WebAssembly module from this path to calculate edge cost. It gets the same input
+    /// as `ExternalCommand`, one edge at a time. See the docs for the functions it must export.
+    WasmPlugin(String),
+    /// Calculate each edge's cost with an arithmetic expression, like
+    /// `length_meters * slope_factor * lts_weight[lts] / (1 + 0.1 * nearby_amenities)`. Edges
+    /// with LTS 0, or where the result isn't a positive number, aren't allowed.
+    Expression {
+        expression: String,
+        /// Lists of numbers the expression can index into, like `"lts_weight": [0, 1, 1.5, 3, 10]`
+        #[serde(default)]
+        tables: BTreeMap<String, Vec<f64>>,
+    },
+    /// Calculate cost with another cost function, but using hill penalties for an e-bike, which
+    /// are much gentler. Like `{ "EBike": "Distance" }`.
+    EBike(Box<CostFunction>),
 }
 
 impl CostFunction {
-    pub fn normalize(&mut self) -> Result<()> {
-        if let CostFunction::Generalized(ref mut params) = self {
-            params.normalize()?;
+    /// `exposure_layers` are the names of the layers sampled along every edge, which cost
+    /// expressions can use as `exposure_<name>`
+    pub fn normalize(&mut self, exposure_layers: &[String]) -> Result<()> {
+        match self {
+            CostFunction::Generalized(ref mut params) => params.normalize()?,
+            CostFunction::Expression { expression, tables } => {
+                let exposure_variables: Vec<String> = exposure_layers
+                    .iter()
+                    .map(|name| format!("exposure_{name}"))
+                    .collect();
+                let mut variables = crate::plugins::cost::EXPRESSION_VARIABLES.to_vec();
+                variables.extend(exposure_variables.iter().map(|x| x.as_str()));
+                crate::plugins::expression::Expr::parse(expression)?.check(&variables, tables)?;
+            }
+            CostFunction::EBike(inner) => inner.normalize(exposure_layers)?,
+            _ => {}
         }
         Ok(())
     }
@@ -137,7 +492,31 @@ impl GeneralizedCostFunction {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Overrides some of the config for one run. Anything unset comes from the main config.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Scenario {
+    /// Used for the output directory and the cached CH, so must be unique
+    pub name: String,
+    pub cost: Option<CostFunction>,
+    pub lts: Option<LtsMapping>,
+    pub uptake: Option<Uptake>,
+}
+
+/// Changes which roads can be used and how slopes, one-way streets, and barriers affect them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Profile {
+    #[default]
+    Cycling,
+    /// Cargo bikes, tricycles, handcycles, and other adapted cycles. Steps, narrow paths, and
+    /// barriers like kerbs and cycle barriers are impassable, other narrow paths and barriers
+    /// cost more, and hills are harder.
+    CargoBike,
+    /// Footways, paths, and crossings are preferred, one-way streets can be walked both ways,
+    /// barriers and turn restrictions are ignored, and slopes use Tobler's hiking function
+    Walking,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Uptake {
     /// Don't do anything -- every route counts for 1
     Identity,
@@ -147,9 +526,693 @@ pub enum Uptake {
     GovTargetPCT,
     /// Defined by https://github.com/ITSLeeds/pct/blob/HEAD/R/uptake.R
     GoDutchPCT,
+    /// GoDutchPCT, with the e-bike adjustments from
+    /// https://github.com/ITSLeeds/pct/blob/HEAD/R/uptake.R
+    EBikePCT,
+    /// For walking trips: 1 at 0 meters, halving every this many meters
+    WalkingHalfDistanceMeters(f64),
+    /// Use a different uptake function for each trip purpose. Requests without a purpose, or with
+    /// one not listed, use `default`.
+    ByPurpose {
+        purposes: BTreeMap<String, Uptake>,
+        default: Box<Uptake>,
+    },
+    /// A logistic model of distance and gradient, like the PCT ones, with your own coefficients
+    Logistic(LogisticUptake),
+    /// Interpolate between probabilities from a CSV file, like a calibrated distance-decay curve
+    LookupTable(UptakeTable),
+}
+
+/// Coefficients for the log-odds of a trip, in terms of its distance in kilometers and gradient in
+/// percent. Unset coefficients are 0.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogisticUptake {
+    pub intercept: f64,
+    pub distance: f64,
+    pub sqrt_distance: f64,
+    pub distance_squared: f64,
+    pub gradient: f64,
+    pub distance_gradient: f64,
+    pub sqrt_distance_gradient: f64,
+    /// Longer trips are treated as this distance
+    pub max_distance_km: Option<f64>,
+}
+
+/// A CSV file with `distance_meters` and `probability` columns, and optionally
+/// `gradient_percent`. In the config, this is just the path.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct UptakeTable {
+    pub path: String,
+    /// One curve per gradient, each with `(distance_meters, probability)` points. Both are sorted
+    /// ascending. Empty until `InputConfig::load_uptake_tables` is called.
+    pub curves: Vec<(f64, Vec<(f64, f64)>)>,
+}
+
+impl From<String> for UptakeTable {
+    fn from(path: String) -> Self {
+        Self {
+            path,
+            curves: Vec::new(),
+        }
+    }
+}
+
+impl From<UptakeTable> for String {
+    fn from(table: UptakeTable) -> Self {
+        table.path
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PmtilesOptions {
+    /// The name of the vector tile layer with the edges. Defaults to `rnet`, which the web app
+    /// expects if the output metadata is missing. Origins and destinations are always in layers
+    /// called `origins` and `destinations`.
+    #[serde(default = "default_pmtiles_layer_name")]
+    pub layer_name: String,
+    /// Origins and destinations only appear from this zoom level. There can be many more of them
+    /// than edges, and they're only readable zoomed in. Defaults to 10. Lowered to `max_zoom` if
+    /// that's smaller, and when tippecanoe guesses the highest zoom, it guesses at least this.
+    #[serde(default = "default_points_min_zoom")]
+    pub points_min_zoom: u8,
+    /// The lowest zoom level to make tiles for. Defaults to 0.
+    pub min_zoom: Option<u8>,
+    /// The highest zoom level to make tiles for. By default, this is guessed from the extent and
+    /// density of the data, and increased further if features are still being dropped.
+    pub max_zoom: Option<u8>,
+    /// Drop features from tiles bigger than this many bytes. Defaults to tippecanoe's limit of
+    /// 500KB.
+    pub max_tile_bytes: Option<u64>,
+    /// Order features in each tile by this property, like `count`, smallest first. Maps draw later
+    /// features on top, so the biggest values stay visible where lines overlap.
+    pub sort_key: Option<String>,
+    /// Compress every tile with gzip. Uncompressed tiles are bigger, but faster to decode.
+    #[serde(default = "default_true")]
+    pub compress_tiles: bool,
+    /// Round `count` to an integer, instead of storing a floating point number. This shrinks
+    /// tiles, but loses precision for uptake models producing fractional counts.
+    #[serde(default)]
+    pub integer_counts: bool,
+}
+
+impl Default for PmtilesOptions {
+    fn default() -> Self {
+        Self {
+            layer_name: default_pmtiles_layer_name(),
+            points_min_zoom: default_points_min_zoom(),
+            min_zoom: None,
+            max_zoom: None,
+            max_tile_bytes: None,
+            sort_key: None,
+            compress_tiles: true,
+            integer_counts: false,
+        }
+    }
+}
+
+fn default_pmtiles_layer_name() -> String {
+    "rnet".to_string()
+}
+
+fn default_points_min_zoom() -> u8 {
+    10
+}
+
+impl PmtilesOptions {
+    /// Arguments to pass to tippecanoe, besides the inputs and output path
+    pub fn tippecanoe_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(zoom) = self.min_zoom {
+            args.push(format!("--minimum-zoom={zoom}"));
+        }
+        if let Some(zoom) = self.max_zoom {
+            args.push(format!("--maximum-zoom={zoom}"));
+        } else {
+            args.push("--maximum-zoom=g".to_string());
+            args.push("--extend-zooms-if-still-dropping".to_string());
+        }
+        if let Some(bytes) = self.max_tile_bytes {
+            args.push(format!("--maximum-tile-bytes={bytes}"));
+        }
+        // TODO Drop based on low counts
+        args.push("--drop-fraction-as-needed".to_string());
+        if let Some(ref key) = self.sort_key {
+            args.push(format!("--order-by={key}"));
+        }
+        if !self.compress_tiles {
+            args.push("--no-tile-compression".to_string());
+        }
+        if self.integer_counts {
+            args.push("--attribute-type=count:int".to_string());
+        }
+        args
+    }
+
+    /// Arguments to pass to tippecanoe for the input files: the edges, then the origin and
+    /// destination files that exist, each in their own layer.
+    pub fn tippecanoe_layer_args(
+        &self,
+        edges_path: &str,
+        origins_path: Option<&str>,
+        destinations_path: Option<&str>,
+    ) -> Vec<String> {
+        let mut args = vec![
+            "-L".to_string(),
+            format!("{}:{edges_path}", self.layer_name),
+        ];
+        // Points must still appear at the highest zoom
+        let points_min_zoom = match self.max_zoom {
+            Some(max_zoom) => self.points_min_zoom.min(max_zoom),
+            None => self.points_min_zoom,
+        };
+        let mut filter = BTreeMap::new();
+        for (layer, path) in [
+            ("origins", origins_path),
+            ("destinations", destinations_path),
+        ] {
+            if let Some(path) = path {
+                args.push("-L".to_string());
+                args.push(format!("{layer}:{path}"));
+                filter.insert(
+                    layer.to_string(),
+                    serde_json::json!([">=", "$zoom", points_min_zoom]),
+                );
+            }
+        }
+        if !filter.is_empty() && points_min_zoom > 0 {
+            args.push("-j".to_string());
+            args.push(serde_json::to_string(&filter).unwrap());
+            if self.max_zoom.is_none() {
+                args.push(format!("--smallest-maximum-zoom-guess={points_min_zoom}"));
+            }
+        }
+        args
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Raster tiles with heights encoded in the pixel colors, downloaded as needed and cached in
+/// `intermediate/elevation/`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ElevationTiles {
+    /// A URL containing `{z}`, `{x}`, and `{y}`, like `https://example.com/{z}/{x}/{y}.png`
+    pub url_template: String,
+    pub encoding: TileEncoding,
+    /// Which zoom level to fetch tiles at. Defaults to 12.
+    #[serde(default = "default_elevation_zoom")]
+    pub zoom: u32,
+}
+
+fn default_elevation_zoom() -> u32 {
+    12
+}
+
+impl ElevationTiles {
+    /// Global Terrarium tiles hosted as AWS open data, built mostly from SRTM. See
+    /// <https://registry.opendata.aws/terrain-tiles/>.
+    pub fn aws_terrain_tiles() -> Self {
+        Self {
+            url_template: "https://s3.amazonaws.com/elevation-tiles-prod/terrarium/{z}/{x}/{y}.png"
+                .to_string(),
+            encoding: TileEncoding::Terrarium,
+            zoom: default_elevation_zoom(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum TileEncoding {
+    /// `(R * 256 + G + B / 256) - 32768`, as used by
+    /// <https://github.com/tilezen/joerd/blob/master/docs/formats.md#terrarium>
+    Terrarium,
+    /// `-10000 + (R * 256 * 256 + G * 256 + B) * 0.1`, as used by
+    /// <https://docs.mapbox.com/data/tilesets/reference/mapbox-terrain-rgb-v1/>
+    TerrainRgb,
+}
+
+/// Extra costs for passing through an intersection, in the same units as edge costs. With the
+/// `Distance` cost function, these are meters. Turns are only penalized at intersections with at
+/// least 3 roads.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TurnCosts {
+    pub straight: usize,
+    pub left: usize,
+    pub right: usize,
+    pub u_turn: usize,
+    /// Added for going through an intersection tagged `highway=traffic_signals`, whatever the
+    /// turn
+    pub traffic_signals: usize,
+}
+
+impl TurnCosts {
+    pub fn is_zero(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// For a turn angle from -180 to 180 degrees, with right turns positive. Within 30 degrees
+    /// of straight is straight, and within 30 degrees of turning back is a U-turn.
+    pub fn cost(&self, angle: f64) -> usize {
+        if angle.abs() <= 30.0 {
+            self.straight
+        } else if angle.abs() >= 150.0 {
+            self.u_turn
+        } else if angle > 0.0 {
+            self.right
+        } else {
+            self.left
+        }
+    }
+}
+
+/// How `oneway`, `oneway:bicycle`, and contraflow cycleway tags translate into `forward_cost`
+/// and `backward_cost`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Oneway {
+    /// Only route in the allowed direction along one-way roads. Roads tagged `oneway:bicycle=no`
+    /// or with an `opposite` cycleway stay two-way.
+    pub respect: bool,
+    /// Still allow riding against the flow of one-way roads with at most this LTS
+    pub contraflow_max_lts: Option<LTS>,
+    /// Multiplies the cost of riding against the flow when `contraflow_max_lts` allows it
+    pub contraflow_penalty: f64,
+}
+
+impl Default for Oneway {
+    fn default() -> Self {
+        Self {
+            respect: false,
+            contraflow_max_lts: None,
+            contraflow_penalty: 2.0,
+        }
+    }
+}
+
+/// Factors to multiply edge cost by, keyed by `surface` and `smoothness` tag values, like
+/// `"gravel": 1.5`. Roads without the tag, or with a value not listed, aren't changed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SurfaceMultipliers {
+    pub surface: BTreeMap<String, f64>,
+    pub smoothness: BTreeMap<String, f64>,
+}
+
+impl SurfaceMultipliers {
+    pub fn is_empty(&self) -> bool {
+        self.surface.is_empty() && self.smoothness.is_empty()
+    }
+
+    /// Both multipliers for a road, combined
+    pub fn multiplier(&self, tags: &Tags) -> f64 {
+        let lookup = |key: &str, multipliers: &BTreeMap<String, f64>| {
+            tags.get(key)
+                .and_then(|value| multipliers.get(value))
+                .cloned()
+                .unwrap_or(1.0)
+        };
+        lookup("surface", &self.surface) * lookup("smoothness", &self.smoothness)
+    }
+}
+
+/// Whether roads are lit comes from the `lit` tag, or from an optional layer of lit areas
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Lighting {
+    /// Penalize unlit roads. Nothing else here has any effect unless this is set.
+    pub night: bool,
+    /// Multiplies the cost of roads tagged `lit=no`. Defaults to 2.
+    pub unlit_multiplier: f64,
+    /// Multiplies the cost of roads without a `lit` tag. Defaults to 1.
+    pub unknown_multiplier: f64,
+    /// Path to a GeoJSON file with Polygons covering areas with street lighting. Roads overlapping
+    /// them count as lit, whatever their tags say.
+    pub lit_areas: Option<String>,
+}
+
+impl Default for Lighting {
+    fn default() -> Self {
+        Self {
+            night: false,
+            unlit_multiplier: 2.0,
+            unknown_multiplier: 1.0,
+            lit_areas: None,
+        }
+    }
+}
+
+impl Lighting {
+    /// `lit_override` means the road is in one of `lit_areas`
+    pub fn multiplier(&self, tags: &Tags, lit_override: bool) -> f64 {
+        if !self.night || lit_override {
+            return 1.0;
+        }
+        match tags.get("lit").map(|x| x.as_str()) {
+            Some("no" | "disused") => self.unlit_multiplier,
+            // Values like "yes", "24/7", "automatic", or times are all lit at least some of
+            // the night
+            Some(_) => 1.0,
+            None => self.unknown_multiplier,
+        }
+    }
+}
+
+/// Annual average daily traffic (AADT) counts, matched to the network on every run
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TrafficCounts {
+    /// A GeoJSON file with Points or LineStrings, or a CSV file with `way_id` and `aadt` columns
+    pub path: String,
+    /// The GeoJSON property with the count. Defaults to `aadt`.
+    #[serde(default = "default_aadt_property")]
+    pub property: String,
+    /// Counts further than this from any road aren't used. Defaults to 30.
+    #[serde(default = "default_traffic_count_distance")]
+    pub max_distance_meters: f64,
+    /// Roads with at least this much daily traffic are at least LTS 2, 3, and 4, whatever their
+    /// tags say
+    pub lts_thresholds: Option<[f64; 3]>,
+}
+
+fn default_aadt_property() -> String {
+    "aadt".to_string()
+}
+
+fn default_traffic_count_distance() -> f64 {
+    30.0
+}
+
+/// An environmental layer to sample along every edge on every run
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ExposureLayer {
+    /// Edges get an `exposure_<name>` value
+    pub name: String,
+    /// A GeoTIFF file, a directory of them, or a GeoJSON file with polygons
+    pub path: String,
+    /// The numeric property of each polygon to use. Required for GeoJSON.
+    pub property: Option<String>,
+}
+
+/// How `barrier` nodes in the middle of an edge affect its cost. Barriers at intersections are
+/// ignored.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Barriers {
+    /// Extra cost for passing a barrier, keyed by its `barrier` value, like `"gate": 30`
+    pub penalties: BTreeMap<String, usize>,
+    /// Extra cost for barriers without an entry in `penalties`
+    pub default_penalty: usize,
+    /// `barrier` values that can never be passed, like `full-height_turnstile`
+    pub blocked: Vec<String>,
+}
+
+/// Road centerlines from a non-OSM source. LTS and cost come from feature properties, instead of
+/// OSM tags.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GeojsonNetwork {
+    /// Path to a GeoJSON file or a GeoPackage (ending in `.gpkg`) with LineStrings or
+    /// MultiLineStrings. Lines are split into edges wherever they share a point.
+    pub path: String,
+    /// The GeoPackage layer to read. Only needed if there's more than one.
+    pub layer: Option<String>,
+    /// The property with the LTS of each road, from 0 (not allowed) to 4
+    pub lts_property: String,
+    /// The property with the cost to cross the whole feature in the direction it's drawn. If
+    /// unset, `cost` is calculated as usual.
+    pub forward_cost_property: Option<String>,
+    /// The property with the cost in the opposite direction. If unset, the forward cost is used
+    /// both ways. A null cost means the road can't be used in that direction.
+    pub backward_cost_property: Option<String>,
+}
+
+/// Where to download an OSM extract from
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OsmDownload {
+    /// A region from <https://download.geofabrik.de>, like `europe/portugal`
+    Geofabrik(String),
+}
+
+impl OsmDownload {
+    pub fn url(&self) -> String {
+        match self {
+            OsmDownload::Geofabrik(region) => {
+                format!("https://download.geofabrik.de/{region}-latest.osm.pbf")
+            }
+        }
+    }
+
+    /// Where to cache the download, relative to `input/`
+    pub fn filename(&self) -> String {
+        match self {
+            OsmDownload::Geofabrik(region) => {
+                format!(
+                    "geofabrik_{}.osm.pbf",
+                    region.trim_matches('/').replace('/', "_")
+                )
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum SnapTo {
+    /// The closest intersection. This is fast, but distorts short trips.
+    #[default]
+    Intersections,
+    /// The closest point along an edge, splitting it there. Routes start and end partway along
+    /// an edge, so route lengths and counts near the endpoints are accurate. Only used for the
+    /// main counts, not by `--detailed-routes` or other outputs.
+    Edges,
+}
+
+/// How to find alternative routes and split a request's count between them
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Alternatives {
+    /// The most routes to use per request, including the cheapest one
+    pub k: usize,
+    /// After finding each route, multiply the cost of every edge on it by this, then search again.
+    /// Higher values find more different routes. Defaults to 1.5.
+    #[serde(default = "default_alternatives_penalty")]
+    pub penalty: f64,
+    pub split: FlowSplit,
+}
+
+fn default_alternatives_penalty() -> f64 {
+    1.5
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum OutputCompression {
+    Gzip,
+    /// Smaller and faster than gzip, but fewer tools can read it directly
+    Zstd,
+}
+
+impl OutputCompression {
+    /// Adds the extension for the compression, if any, to a path
+    pub fn path(compression: Option<OutputCompression>, path: &str) -> String {
+        match compression {
+            None => path.to_string(),
+            Some(OutputCompression::Gzip) => format!("{path}.gz"),
+            Some(OutputCompression::Zstd) => format!("{path}.zst"),
+        }
+    }
+}
+
+/// How to group routes when breaking down the count on each edge
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub enum FlowBreakdown {
+    /// Group routes by their length. Each number is the upper limit of one band in meters, in
+    /// strictly increasing order, with one more band for longer routes. `[2000, 5000]` makes the groups
+    /// `0-2000m`, `2000-5000m`, and `5000m+`.
+    LengthBands(Vec<f64>),
+    /// Group routes by the request's purpose. Requests without one are grouped as `unknown`.
+    Purpose,
+}
+
+impl FlowBreakdown {
+    pub fn validate(&self) -> Result<()> {
+        if let FlowBreakdown::LengthBands(limits) = self {
+            if limits.iter().any(|x| x.is_nan() || *x <= 0.0)
+                || limits.windows(2).any(|pair| pair[0] >= pair[1])
+            {
+                bail!("The LengthBands limits must be positive and increasing, not {limits:?}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The index of the `FlowBreakdown::LengthBands` band for a route of this length
+pub fn length_band(limits: &[f64], route_meters: f64) -> usize {
+    limits
+        .iter()
+        .position(|upper| route_meters < *upper)
+        .unwrap_or(limits.len())
+}
+
+/// The name of every band for `FlowBreakdown::LengthBands`, in order
+pub fn length_band_names(limits: &[f64]) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut lower = 0.0;
+    for upper in limits {
+        names.push(format!("{lower}-{upper}m"));
+        lower = *upper;
+    }
+    names.push(format!("{lower}m+"));
+    names
+}
+
+/// How to split a request's count between its alternative routes
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FlowSplit {
+    /// Every route gets the same share
+    Equal,
+    /// Each route's share is proportional to `exp(-theta * (cost / cheapest_cost - 1))`, so
+    /// routes much costlier than the cheapest get little flow. Higher `theta` concentrates more
+    /// flow on the cheapest route.
+    Logit { theta: f64 },
+    /// Like `Logit`, but also scales each route's share by its path size, which is lower for
+    /// routes overlapping the others. Without this, several routes differing only by a short
+    /// detour each get a full share, pulling too much flow onto the corridor they share.
+    PathSizeLogit { theta: f64 },
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Router {
+    /// Build a contraction hierarchy once, saved in `intermediate/ch.bin`, then route very
+    /// quickly. Best for many requests.
+    #[default]
+    ContractionHierarchy,
+    /// Like `ContractionHierarchy`, but the node ordering only depends on the shape of the network,
+    /// and every shortcut is kept. Queries are slower, but when only costs change, updating
+    /// `intermediate/ch.bin` is much faster. Best for sweeps over cost function parameters.
+    CustomizableContractionHierarchy,
+    /// Route directly on the network with Dijkstra's algorithm. Nothing needs to be prepared, but
+    /// each route is much slower, so this is only worth it for small areas or few requests.
+    /// Endpoints can only snap to intersections.
+    Dijkstra,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum SameEndpoints {
+    /// Record the request as failed, so it appears in `failed_requests.geojson`
+    #[default]
+    Fail,
+    /// Skip the request, without treating it as a failure
+    Drop,
+    /// Treat the request as a successful trip with zero distance. It contributes to origin,
+    /// destination, and intersection counts, but not to any edge.
+    ZeroDistance,
+}
+
+/// Thresholds for excluding off-road paths from the network, based on OSM tags. Ways without the
+/// tag, or with an unrecognized value, are kept.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct PathDifficulty {
+    /// Exclude ways with a harder `sac_scale` than this
+    pub max_sac_scale: Option<SacScale>,
+    /// Exclude ways with a worse `trail_visibility` than this
+    pub max_trail_visibility: Option<TrailVisibility>,
+    /// Exclude ways with a higher `mtb:scale` than this, from 0 to 6. Suffixes like `2+` are
+    /// ignored.
+    pub max_mtb_scale: Option<u8>,
+}
+
+impl PathDifficulty {
+    pub fn excludes(&self, tags: &Tags) -> bool {
+        if let (Some(max), Some(value)) = (
+            self.max_sac_scale,
+            tags.get("sac_scale").and_then(|x| SacScale::parse(x)),
+        ) {
+            if value > max {
+                return true;
+            }
+        }
+        if let (Some(max), Some(value)) = (
+            self.max_trail_visibility,
+            tags.get("trail_visibility")
+                .and_then(|x| TrailVisibility::parse(x)),
+        ) {
+            if value > max {
+                return true;
+            }
+        }
+        if let (Some(max), Some(value)) = (
+            self.max_mtb_scale,
+            tags.get("mtb:scale")
+                .and_then(|x| x.trim_end_matches(['+', '-']).parse::<u8>().ok()),
+        ) {
+            if value > max {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Values of <https://wiki.openstreetmap.org/wiki/Key:sac_scale>, from easiest to hardest
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SacScale {
+    Hiking,
+    MountainHiking,
+    DemandingMountainHiking,
+    AlpineHiking,
+    DemandingAlpineHiking,
+    DifficultAlpineHiking,
+}
+
+impl SacScale {
+    fn parse(value: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(value.to_string())).ok()
+    }
+}
+
+/// Values of <https://wiki.openstreetmap.org/wiki/Key:trail_visibility>, from best to worst
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailVisibility {
+    Excellent,
+    Good,
+    Intermediate,
+    Bad,
+    Horrible,
+    No,
+}
+
+impl TrailVisibility {
+    fn parse(value: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(value.to_string())).ok()
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum SnapDistanceDecay {
+    /// Multiply by `exp(-distance / meters)`
+    Exponential { meters: f64 },
+    /// Multiply by a factor falling linearly from 1 at the network to 0 at `max_meters` away
+    Linear { max_meters: f64 },
+}
+
+impl SnapDistanceDecay {
+    /// Returns a weight between 0 and 1 for a point snapped this far away
+    pub fn weight(&self, distance_meters: f64) -> f64 {
+        match self {
+            SnapDistanceDecay::Exponential { meters } => (-distance_meters / meters).exp(),
+            SnapDistanceDecay::Linear { max_meters } => {
+                (1.0 - distance_meters / max_meters).max(0.0)
+            }
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub enum LtsMapping {
     SpeedLimitOnly,
     BikeOttawa,
@@ -158,3 +1221,266 @@ pub enum LtsMapping {
     /// numbers 0-4, representing the resulting LTS.
     ExternalCommand(String),
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Parses a config with some defaults, plus `extra` JSON fields (starting with a comma)
+    pub(crate) fn config_with(extra: &str) -> InputConfig {
+        serde_json::from_str(&format!(
+            r#"{{
+                "requests": {{
+                    "description": "test",
+                    "pattern": "FromEveryOriginToOneDestination",
+                    "origins_path": "origins.geojson",
+                    "destinations_path": "destinations.geojson"
+                }},
+                "cost": "Distance",
+                "uptake": "Identity",
+                "lts": "BikeOttawa",
+                "elevation_geotiff": "dem.tif"
+                {extra}
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let config = config_with(r#", "paths_relative_to_config": true"#);
+        let dem = config.elevation_geotiff.as_ref().unwrap();
+        assert_eq!(config.resolve_path("/data/york", dem), "/data/york/dem.tif");
+        assert_eq!(
+            config.resolve_path("/data/york", "/elsewhere/dem.tif"),
+            "/elsewhere/dem.tif"
+        );
+
+        // By default, paths are relative to input/
+        let config = config_with("");
+        let dem = config.elevation_geotiff.as_ref().unwrap();
+        assert_eq!(
+            config.resolve_path("/data/york", dem),
+            "/data/york/input/dem.tif"
+        );
+    }
+
+    #[test]
+    fn test_pmtiles_defaults() {
+        // Without a pmtiles section, tippecanoe guesses the zoom levels
+        let config = config_with("");
+        assert_eq!(
+            config.pmtiles.tippecanoe_args(),
+            vec![
+                "--maximum-zoom=g",
+                "--extend-zooms-if-still-dropping",
+                "--drop-fraction-as-needed",
+            ]
+        );
+
+        let config = config_with(
+            r#", "pmtiles": { "compress_tiles": false, "max_zoom": 14, "sort_key": "count" }"#,
+        );
+        assert_eq!(
+            config.pmtiles.tippecanoe_args(),
+            vec![
+                "--maximum-zoom=14",
+                "--drop-fraction-as-needed",
+                "--order-by=count",
+                "--no-tile-compression",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pmtiles_layers() {
+        let config = config_with("");
+        assert_eq!(
+            config
+                .pmtiles
+                .tippecanoe_layer_args("output.geojson", None, None),
+            vec!["-L", "rnet:output.geojson"]
+        );
+        assert_eq!(
+            config.pmtiles.tippecanoe_layer_args(
+                "output.geojson",
+                Some("origins.geojson"),
+                Some("destinations.geojson")
+            ),
+            vec![
+                "-L",
+                "rnet:output.geojson",
+                "-L",
+                "origins:origins.geojson",
+                "-L",
+                "destinations:destinations.geojson",
+                "-j",
+                r#"{"destinations":[">=","$zoom",10],"origins":[">=","$zoom",10]}"#,
+                "--smallest-maximum-zoom-guess=10",
+            ]
+        );
+
+        // Points still show up at the highest zoom
+        let config = config_with(r#", "pmtiles": { "max_zoom": 8 }"#);
+        assert_eq!(
+            config
+                .pmtiles
+                .tippecanoe_layer_args("output.geojson", Some("origins.geojson"), None)[4..],
+            vec!["-j", r#"{"origins":[">=","$zoom",8]}"#]
+        );
+    }
+
+    #[test]
+    fn test_pmtiles_compression_is_smaller() {
+        // tippecanoe isn't installed everywhere the tests run
+        if std::process::Command::new("tippecanoe")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            return;
+        }
+
+        let directory = std::env::temp_dir().join(format!(
+            "od2net_test_pmtiles_compression_{}",
+            std::process::id()
+        ));
+        fs_err::create_dir_all(&directory).unwrap();
+        let input = directory.join("output.geojson");
+        // A grid of similar streets, which gzip shrinks a lot
+        let features: Vec<String> = (0..2000)
+            .map(|i| {
+                let x = -1.1 + (i % 50) as f64 * 0.001;
+                let y = 53.9 + (i / 50) as f64 * 0.001;
+                format!(
+                    r#"{{"type": "Feature", "properties": {{"count": {}, "lts": 2}}, "geometry": {{"type": "LineString", "coordinates": [[{x}, {y}], [{}, {y}]]}}}}"#,
+                    // Fractional counts, mostly different from each other
+                    i as f64 * 0.37,
+                    x + 0.001
+                )
+            })
+            .collect();
+        fs_err::write(
+            &input,
+            format!(
+                r#"{{"type": "FeatureCollection", "features": [{}]}}"#,
+                features.join(",")
+            ),
+        )
+        .unwrap();
+
+        let tile_size = |options: &str| {
+            let output = directory.join("rnet.pmtiles");
+            let status = std::process::Command::new("tippecanoe")
+                .arg("-o")
+                .arg(&output)
+                .arg("--force")
+                .arg("--quiet")
+                .args(
+                    config_with(&format!(r#", "pmtiles": {{ {options} }}"#))
+                        .pmtiles
+                        .tippecanoe_args(),
+                )
+                .arg(&input)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            fs_err::metadata(&output).unwrap().len()
+        };
+        let compressed = tile_size("");
+        let uncompressed = tile_size(r#""compress_tiles": false"#);
+        let integer_counts = tile_size(r#""integer_counts": true"#);
+        fs_err::remove_dir_all(&directory).unwrap();
+
+        assert!(
+            compressed < uncompressed,
+            "{compressed} bytes compressed, {uncompressed} without"
+        );
+        assert!(
+            integer_counts < compressed,
+            "{integer_counts} bytes with integer counts, {compressed} without"
+        );
+    }
+
+    #[test]
+    fn test_path_difficulty() {
+        let config = config_with(r#", "path_difficulty": { "max_sac_scale": "hiking" }"#);
+        let path = |sac_scale: &str| {
+            let mut tags = Tags::new();
+            tags.insert("highway", "path");
+            tags.insert("sac_scale", sac_scale);
+            tags
+        };
+        assert!(config.path_difficulty.excludes(&path("mountain_hiking")));
+        assert!(!config.path_difficulty.excludes(&path("hiking")));
+        // Unknown values are kept
+        assert!(!config.path_difficulty.excludes(&path("strolling")));
+
+        let mut tags = path("hiking");
+        tags.insert("mtb:scale", "3+");
+        assert!(!config.path_difficulty.excludes(&tags));
+        let config = config_with(r#", "path_difficulty": { "max_mtb_scale": 2 }"#);
+        assert!(config.path_difficulty.excludes(&tags));
+
+        // Nothing is excluded by default
+        assert!(!config_with("")
+            .path_difficulty
+            .excludes(&path("difficult_alpine_hiking")));
+    }
+
+    #[test]
+    fn test_osm_download() {
+        let config = config_with(r#", "osm": { "geofabrik": "europe/portugal" }"#);
+        let osm = config.osm.unwrap();
+        assert_eq!(
+            osm.url(),
+            "https://download.geofabrik.de/europe/portugal-latest.osm.pbf"
+        );
+        assert_eq!(osm.filename(), "geofabrik_europe_portugal.osm.pbf");
+    }
+
+    #[test]
+    fn test_surface_multipliers() {
+        let config = config_with(
+            r#", "surface_multipliers": { "surface": { "gravel": 1.5 }, "smoothness": { "bad": 2.0 } }"#,
+        );
+        let multipliers = config.surface_multipliers;
+        let mut tags = Tags::new();
+        tags.insert("highway", "track");
+        assert_eq!(multipliers.multiplier(&tags), 1.0);
+        tags.insert("surface", "gravel");
+        assert_eq!(multipliers.multiplier(&tags), 1.5);
+        tags.insert("smoothness", "bad");
+        assert_eq!(multipliers.multiplier(&tags), 3.0);
+        tags.insert("surface", "asphalt");
+        assert_eq!(multipliers.multiplier(&tags), 2.0);
+    }
+
+    #[test]
+    fn test_lighting() {
+        let mut tags = Tags::new();
+        tags.insert("lit", "no");
+        // Off by default
+        assert_eq!(config_with("").lighting.multiplier(&tags, false), 1.0);
+
+        let lighting =
+            config_with(r#", "lighting": { "night": true, "unknown_multiplier": 1.5 }"#).lighting;
+        assert_eq!(lighting.multiplier(&tags, false), 2.0);
+        tags.insert("lit", "24/7");
+        assert_eq!(lighting.multiplier(&tags, false), 1.0);
+        assert_eq!(lighting.multiplier(&Tags::new(), false), 1.5);
+        // Lit areas win over tags
+        tags.insert("lit", "no");
+        assert_eq!(lighting.multiplier(&tags, true), 1.0);
+    }
+
+    #[test]
+    fn test_length_bands_must_increase() {
+        assert!(FlowBreakdown::LengthBands(vec![2000.0, 5000.0])
+            .validate()
+            .is_ok());
+        for limits in [vec![5000.0, 2000.0], vec![2000.0, 2000.0], vec![-1.0]] {
+            assert!(FlowBreakdown::LengthBands(limits).validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,558 @@
+use std::io::{BufReader, BufWriter, Read, Seek};
+
+use anyhow::Result;
+use fs_err::File;
+use geo::{HaversineDistance, Point};
+use lts::LTS;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::tags::Tag;
+
+use super::network::{Counts, Edge, Network};
+use super::timer::Timer;
+
+/// Written to cells without any edges
+pub const NODATA: f32 = -9999.0;
+
+// GeoTIFF tags, from http://docs.opengeospatial.org/is/19-008r4/19-008r4.html
+const MODEL_PIXEL_SCALE: u16 = 33550;
+const MODEL_TIEPOINT: u16 = 33922;
+const GEO_KEY_DIRECTORY: u16 = 34735;
+// Understood by GDAL and most GIS tools
+const GDAL_NODATA: u16 = 42113;
+
+/// The grid is built in memory, so refuse resolutions that'd need more than a few GB
+const MAX_CELLS: usize = 100_000_000;
+
+/// Which value of each edge to burn into the raster
+#[derive(Clone, Copy)]
+pub enum RasterMetric {
+    /// The routing cost divided by the length, averaged over both directions
+    CostPerMeter,
+    /// From 1 to 4
+    Lts,
+    /// 100 for LTS 1, down to 0 for LTS 4
+    Quietness,
+}
+
+impl RasterMetric {
+    pub fn parse(input: &str) -> Result<Self> {
+        match input {
+            "cost-per-meter" => Ok(Self::CostPerMeter),
+            "lts" => Ok(Self::Lts),
+            "quietness" => Ok(Self::Quietness),
+            _ => bail!("Unknown raster metric {input}; use cost-per-meter, lts, or quietness"),
+        }
+    }
+
+    /// None for edges that can't be used
+    fn value(self, edge: &Edge) -> Option<f64> {
+        if edge.lts == LTS::NotAllowed {
+            return None;
+        }
+        match self {
+            RasterMetric::CostPerMeter => {
+                let costs: Vec<f64> = [edge.forward_cost, edge.backward_cost]
+                    .into_iter()
+                    .flatten()
+                    .map(|cost| cost as f64)
+                    .collect();
+                if costs.is_empty() || edge.length_meters == 0.0 {
+                    return None;
+                }
+                Some(costs.iter().sum::<f64>() / (costs.len() as f64) / edge.length_meters)
+            }
+            RasterMetric::Lts => Some(edge.lts as u8 as f64),
+            RasterMetric::Quietness => Some((4.0 - edge.lts as u8 as f64) / 3.0 * 100.0),
+        }
+    }
+}
+
+/// A grid in WGS84, with the first row at the top
+pub struct Raster {
+    pub min_lon: f64,
+    pub max_lat: f64,
+    /// In degrees
+    pub cell_width: f64,
+    pub cell_height: f64,
+    pub width: usize,
+    pub height: usize,
+    /// Row by row, starting from the north-west corner. From `rasterize`, this is the
+    /// length-weighted average of every edge crossing each cell. From `rasterize_counts`, it's the
+    /// sum of every edge's count times its length in the cell.
+    pub values: Vec<Option<f64>>,
+}
+
+impl Raster {
+    fn cell(&self, lon: f64, lat: f64) -> Option<usize> {
+        let col = ((lon - self.min_lon) / self.cell_width).floor();
+        let row = ((self.max_lat - lat) / self.cell_height).floor();
+        if col < 0.0 || row < 0.0 || col >= self.width as f64 || row >= self.height as f64 {
+            return None;
+        }
+        Some((row as usize) * self.width + (col as usize))
+    }
+
+    pub fn get(&self, lon: f64, lat: f64) -> Option<f64> {
+        self.values[self.cell(lon, lat)?]
+    }
+
+    /// The center of every cell with a value, and that value
+    pub fn cell_centers(&self) -> impl Iterator<Item = ((f64, f64), f64)> + '_ {
+        self.values.iter().enumerate().filter_map(|(idx, value)| {
+            let row = (idx / self.width) as f64;
+            let col = (idx % self.width) as f64;
+            let center = (
+                self.min_lon + (col + 0.5) * self.cell_width,
+                self.max_lat - (row + 0.5) * self.cell_height,
+            );
+            value.map(|value| (center, value))
+        })
+    }
+}
+
+pub fn run(
+    network: &Network,
+    metric: RasterMetric,
+    resolution_meters: f64,
+    output_path: String,
+    timer: &mut Timer,
+) -> Result<()> {
+    timer.start("Rasterizing edges");
+    let raster = rasterize(network, metric, resolution_meters)?;
+    timer.stop();
+
+    timer.start(format!("Writing {output_path}"));
+    write_geotiff(&raster, &output_path)?;
+    timer.stop();
+    Ok(())
+}
+
+/// Burns each edge's metric into every cell it crosses, using cells roughly `resolution_meters`
+/// wide
+pub fn rasterize(
+    network: &Network,
+    metric: RasterMetric,
+    resolution_meters: f64,
+) -> Result<Raster> {
+    let edges: Vec<(&Edge, f64)> = network
+        .edges
+        .values()
+        .filter_map(|edge| metric.value(edge).map(|value| (edge, value)))
+        .collect();
+    if edges.is_empty() {
+        bail!("No edges have a value for this metric");
+    }
+    burn_edges(edges, resolution_meters, true)
+}
+
+/// Burns the count of each edge, in both directions, into every cell it crosses, summing count
+/// times meters. Uses cells roughly `resolution_meters` wide, only covering edges with counts.
+pub fn rasterize_counts(
+    network: &Network,
+    counts: &Counts,
+    resolution_meters: f64,
+) -> Result<Raster> {
+    let edges: Vec<(&Edge, f64)> = network
+        .edges
+        .iter()
+        .filter_map(|((node1, node2), edge)| {
+            let count = counts.count_per_edge.get(&(*node1, *node2)).unwrap_or(&0.0)
+                + counts.count_per_edge.get(&(*node2, *node1)).unwrap_or(&0.0);
+            (count > 0.0).then_some((edge, count))
+        })
+        .collect();
+    if edges.is_empty() {
+        bail!("No edges have any counts");
+    }
+    burn_edges(edges, resolution_meters, false)
+}
+
+/// Covers every edge with a grid. If `average`, each cell has the length-weighted average of the
+/// values of edges crossing it. Otherwise it has the sum of value times length. `edges` must not
+/// be empty. Fails if the grid would have more than `MAX_CELLS`.
+fn burn_edges(edges: Vec<(&Edge, f64)>, resolution_meters: f64, average: bool) -> Result<Raster> {
+    let mut min_lon = f64::MAX;
+    let mut min_lat = f64::MAX;
+    let mut max_lon = f64::MIN;
+    let mut max_lat = f64::MIN;
+    for (edge, _) in &edges {
+        for pt in edge.geometry() {
+            let (lon, lat) = pt.to_degrees();
+            min_lon = min_lon.min(lon);
+            min_lat = min_lat.min(lat);
+            max_lon = max_lon.max(lon);
+            max_lat = max_lat.max(lat);
+        }
+    }
+
+    // Meters per degree of latitude are about constant. Use the middle of the area for longitude.
+    let cell_height = resolution_meters / 111_320.0;
+    let cell_width = cell_height / ((min_lat + max_lat) / 2.0).to_radians().cos();
+    let width = ((max_lon - min_lon) / cell_width).floor() as usize + 1;
+    let height = ((max_lat - min_lat) / cell_height).floor() as usize + 1;
+    if width.saturating_mul(height) > MAX_CELLS {
+        bail!(
+            "A {resolution_meters}m grid over these edges would have {width}x{height} cells. Use a coarser resolution."
+        );
+    }
+    let mut raster = Raster {
+        min_lon,
+        max_lat,
+        cell_width,
+        cell_height,
+        width,
+        height,
+        values: Vec::new(),
+    };
+
+    // Sample points along each edge, at least twice per cell, weighting by the length covered
+    let mut sums = vec![(0.0, 0.0); width * height];
+    for (edge, value) in edges {
+        for pair in edge.geometry().windows(2) {
+            let (x1, y1) = pair[0].to_degrees();
+            let (x2, y2) = pair[1].to_degrees();
+            let length = Point::new(x1, y1).haversine_distance(&Point::new(x2, y2));
+            let num_samples = ((length / (resolution_meters / 2.0)).ceil() as usize).max(1);
+            for i in 0..num_samples {
+                let t = (i as f64 + 0.5) / (num_samples as f64);
+                if let Some(cell) = raster.cell(x1 + t * (x2 - x1), y1 + t * (y2 - y1)) {
+                    let weight = length / (num_samples as f64);
+                    sums[cell].0 += value * weight;
+                    sums[cell].1 += weight;
+                }
+            }
+        }
+    }
+    raster.values = sums
+        .into_iter()
+        .map(|(weighted_sum, total_weight)| {
+            (total_weight > 0.0).then(|| {
+                if average {
+                    weighted_sum / total_weight
+                } else {
+                    weighted_sum
+                }
+            })
+        })
+        .collect();
+
+    Ok(raster)
+}
+
+/// Writes a single-band float GeoTIFF in EPSG:4326
+pub fn write_geotiff(raster: &Raster, path: &str) -> Result<()> {
+    let mut encoder = TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+    let mut image =
+        encoder.new_image::<colortype::Gray32Float>(raster.width as u32, raster.height as u32)?;
+
+    let dir = image.encoder();
+    dir.write_tag(
+        Tag::Unknown(MODEL_PIXEL_SCALE),
+        &[raster.cell_width, raster.cell_height, 0.0][..],
+    )?;
+    dir.write_tag(
+        Tag::Unknown(MODEL_TIEPOINT),
+        &[0.0, 0.0, 0.0, raster.min_lon, raster.max_lat, 0.0][..],
+    )?;
+    // Version 1.1.0 with 3 keys: a geographic model, pixels representing areas, and WGS84
+    dir.write_tag(
+        Tag::Unknown(GEO_KEY_DIRECTORY),
+        &[
+            1u16, 1, 0, 3, 1024, 0, 1, 2, 1025, 0, 1, 1, 2048, 0, 1, 4326,
+        ][..],
+    )?;
+    dir.write_tag(Tag::Unknown(GDAL_NODATA), &NODATA.to_string()[..])?;
+
+    let data: Vec<f32> = raster
+        .values
+        .iter()
+        .map(|value| value.map(|x| x as f32).unwrap_or(NODATA))
+        .collect();
+    image.write_data(&data)?;
+    Ok(())
+}
+
+/// Reads the first band of a GeoTIFF in EPSG:4326, like the ones written by `write_geotiff`. Cells
+/// matching the GDAL nodata value or NaN have no value.
+pub fn read_geotiff(path: &str) -> Result<Raster> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    let (width, height) = decoder.dimensions()?;
+    let (min_lon, max_lat, cell_width, cell_height) = georeference(&mut decoder, path)?;
+    let nodata = read_nodata(&mut decoder)?;
+    let values = decoded_values(decoder.read_image()?);
+    let (width, height) = (width as usize, height as usize);
+    if values.len() % (width * height) != 0 {
+        bail!("{path} has an unexpected number of values");
+    }
+    // With more than one band, the samples of each pixel are interleaved. Just keep the first.
+    let bands = values.len() / (width * height);
+    let values = values
+        .into_iter()
+        .step_by(bands)
+        .map(|x| (!x.is_nan() && Some(x) != nodata).then_some(x))
+        .collect();
+
+    Ok(Raster {
+        min_lon,
+        max_lat,
+        cell_width,
+        cell_height,
+        width,
+        height,
+        values,
+    })
+}
+
+/// Like `read_geotiff`, but calls `f` with the center and value of every cell with a value inside
+/// `window`, instead of returning them all. Only one strip or tile is decoded at a time, and ones
+/// outside `window` are skipped, so rasters much bigger than memory, like national population
+/// grids, can be read.
+pub fn for_each_geotiff_cell<F: FnMut((f64, f64), f64)>(
+    path: &str,
+    window: Option<geo::Rect>,
+    mut f: F,
+) -> Result<()> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    let (width, height) = decoder.dimensions()?;
+    let (min_lon, max_lat, cell_width, cell_height) = georeference(&mut decoder, path)?;
+    let nodata = read_nodata(&mut decoder)?;
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    let in_window = |west: f64, south: f64, east: f64, north: f64| {
+        window.map_or(true, |window| {
+            west <= window.max().x
+                && east >= window.min().x
+                && south <= window.max().y
+                && north >= window.min().y
+        })
+    };
+
+    // Strips span the whole width. With more than one band stored separately, the first band's
+    // chunks come first.
+    let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+    let chunks_across = (width + chunk_width - 1) / chunk_width;
+    let chunks_down = (height + chunk_height - 1) / chunk_height;
+    for chunk in 0..chunks_across * chunks_down {
+        let first_col = ((chunk % chunks_across) * chunk_width) as usize;
+        let first_row = ((chunk / chunks_across) * chunk_height) as usize;
+        let (data_width, data_height) = decoder.chunk_data_dimensions(chunk);
+        let (data_width, data_height) = (data_width as usize, data_height as usize);
+        let west = min_lon + first_col as f64 * cell_width;
+        let north = max_lat - first_row as f64 * cell_height;
+        if !in_window(
+            west,
+            north - data_height as f64 * cell_height,
+            west + data_width as f64 * cell_width,
+            north,
+        ) {
+            continue;
+        }
+
+        let values = decoded_values(decoder.read_chunk(chunk)?);
+        if data_width * data_height == 0 || values.len() % (data_width * data_height) != 0 {
+            bail!("{path} has an unexpected number of values");
+        }
+        // With more than one band interleaved, just keep the first
+        let bands = values.len() / (data_width * data_height);
+        for (idx, value) in values.into_iter().step_by(bands).enumerate() {
+            if value.is_nan() || Some(value) == nodata {
+                continue;
+            }
+            let col = first_col + idx % data_width;
+            let row = first_row + idx / data_width;
+            let lon = min_lon + (col as f64 + 0.5) * cell_width;
+            let lat = max_lat - (row as f64 + 0.5) * cell_height;
+            if in_window(lon, lat, lon, lat) {
+                f((lon, lat), value);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_nodata<R: Read + Seek>(decoder: &mut Decoder<R>) -> Result<Option<f64>> {
+    Ok(match decoder.find_tag(Tag::Unknown(GDAL_NODATA))? {
+        Some(value) => value
+            .into_string()?
+            .trim_end_matches('\0')
+            .parse::<f64>()
+            .ok(),
+        None => None,
+    })
+}
+
+fn decoded_values(result: DecodingResult) -> Vec<f64> {
+    match result {
+        DecodingResult::U8(x) => x.into_iter().map(|x| x as f64).collect(),
+        DecodingResult::U16(x) => x.into_iter().map(|x| x as f64).collect(),
+        DecodingResult::U32(x) => x.into_iter().map(|x| x as f64).collect(),
+        DecodingResult::U64(x) => x.into_iter().map(|x| x as f64).collect(),
+        DecodingResult::I8(x) => x.into_iter().map(|x| x as f64).collect(),
+        DecodingResult::I16(x) => x.into_iter().map(|x| x as f64).collect(),
+        DecodingResult::I32(x) => x.into_iter().map(|x| x as f64).collect(),
+        DecodingResult::I64(x) => x.into_iter().map(|x| x as f64).collect(),
+        DecodingResult::F32(x) => x.into_iter().map(|x| x as f64).collect(),
+        DecodingResult::F64(x) => x,
+    }
+}
+
+/// Reads only the tags of a GeoTIFF in EPSG:4326, returning the bounds it covers
+pub fn read_geotiff_bounds(path: &str) -> Result<geo::Rect> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    let (width, height) = decoder.dimensions()?;
+    let (min_lon, max_lat, cell_width, cell_height) = georeference(&mut decoder, path)?;
+    Ok(geo::Rect::new(
+        geo::coord! { x: min_lon, y: max_lat - cell_height * height as f64 },
+        geo::coord! { x: min_lon + cell_width * width as f64, y: max_lat },
+    ))
+}
+
+/// Returns the west and north edges, and the width and height of a cell, in degrees
+fn georeference<R: Read + Seek>(
+    decoder: &mut Decoder<R>,
+    path: &str,
+) -> Result<(f64, f64, f64, f64)> {
+    let scale = decoder.get_tag_f64_vec(Tag::Unknown(MODEL_PIXEL_SCALE))?;
+    let tiepoint = decoder.get_tag_f64_vec(Tag::Unknown(MODEL_TIEPOINT))?;
+    if scale.len() < 2 || tiepoint.len() < 6 {
+        bail!("{path} doesn't have a valid pixel scale and tiepoint");
+    }
+    // The tiepoint maps a pixel (i, j) to a position (x, y)
+    let cell_width = scale[0];
+    let cell_height = scale[1];
+    let min_lon = tiepoint[3] - tiepoint[0] * cell_width;
+    let max_lat = tiepoint[4] + tiepoint[1] * cell_height;
+    if !(-180.0..=180.0).contains(&min_lon) || !(-90.0..=90.0).contains(&max_lat) {
+        bail!("{path} doesn't look like it's in EPSG:4326. Reproject it first, with gdalwarp -t_srs EPSG:4326");
+    }
+    Ok((min_lon, max_lat, cell_width, cell_height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::make_network;
+    use osm_reader::NodeID;
+
+    #[test]
+    fn test_known_edge_value() {
+        // A long edge heading east, and a short one heading north from its end
+        let mut network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.01, 0.0), (3, 0.01, 0.005)],
+            &[(1, 2), (2, 3)],
+        );
+        network.edges.get_mut(&(NodeID(1), NodeID(2))).unwrap().lts = LTS::LTS3;
+
+        let assert_close = |actual: Option<f64>, expected: f64| {
+            assert!(
+                (actual.unwrap() - expected).abs() < 1e-9,
+                "{actual:?} != {expected}"
+            );
+        };
+
+        let raster = rasterize(&network, RasterMetric::Lts, 50.0).unwrap();
+        assert_close(raster.get(0.005, 0.0), 3.0);
+        assert_close(raster.get(0.01, 0.0025), 1.0);
+        // Nothing near here
+        assert_eq!(raster.get(0.002, 0.004), None);
+
+        let raster = rasterize(&network, RasterMetric::Quietness, 50.0).unwrap();
+        assert_close(raster.get(0.005, 0.0), 100.0 / 3.0);
+        assert_close(raster.get(0.01, 0.0025), 100.0);
+
+        // Far too many cells
+        assert!(rasterize(&network, RasterMetric::Lts, 0.001).is_err());
+    }
+
+    #[test]
+    fn test_rasterize_counts() {
+        let network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.01, 0.0), (3, 0.01, 0.005)],
+            &[(1, 2), (2, 3)],
+        );
+        let mut counts = Counts::new();
+        counts.count_per_edge.insert((NodeID(1), NodeID(2)), 3.0);
+        counts.count_per_edge.insert((NodeID(2), NodeID(1)), 1.0);
+
+        let raster = rasterize_counts(&network, &counts, 50.0).unwrap();
+        // Only the counted edge is covered
+        assert_eq!(raster.height, 1);
+        let total: f64 = raster.values.iter().flatten().sum();
+        let length = network.edges[&(NodeID(1), NodeID(2))].length_meters;
+        assert!((total - 4.0 * length).abs() < 1e-6);
+
+        assert!(rasterize_counts(&network, &Counts::new(), 50.0).is_err());
+    }
+
+    #[test]
+    fn test_geotiff_round_trip() {
+        let raster = Raster {
+            min_lon: -0.1,
+            max_lat: 51.5,
+            cell_width: 0.01,
+            cell_height: 0.005,
+            width: 3,
+            height: 2,
+            values: vec![Some(1.0), None, Some(2.5), Some(0.0), Some(4.0), None],
+        };
+        let path = std::env::temp_dir().join("od2net_test_raster.tif");
+        let path = path.display().to_string();
+        write_geotiff(&raster, &path).unwrap();
+
+        let copy = read_geotiff(&path).unwrap();
+        assert_eq!((copy.width, copy.height), (3, 2));
+        assert!((copy.min_lon - raster.min_lon).abs() < 1e-9);
+        assert!((copy.max_lat - raster.max_lat).abs() < 1e-9);
+        assert_eq!(copy.values, raster.values);
+        assert_eq!(copy.get(-0.075, 51.4975), Some(1.0));
+        assert_eq!(copy.cell_centers().count(), 4);
+
+        let bounds = read_geotiff_bounds(&path).unwrap();
+        assert!((bounds.min().x - -0.1).abs() < 1e-9);
+        assert!((bounds.max().x - -0.07).abs() < 1e-9);
+        assert!((bounds.min().y - 51.49).abs() < 1e-9);
+        assert!((bounds.max().y - 51.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_for_each_geotiff_cell() {
+        // Big enough to be split into two strips, with each cell's value being its row
+        let (width, height) = (1000, 300);
+        let raster = Raster {
+            min_lon: 0.0,
+            max_lat: 0.3,
+            cell_width: 0.001,
+            cell_height: 0.001,
+            width,
+            height,
+            values: (0..width * height)
+                .map(|idx| Some((idx / width) as f64))
+                .collect(),
+        };
+        let path = std::env::temp_dir()
+            .join(format!(
+                "od2net_test_raster_window_{}.tif",
+                std::process::id()
+            ))
+            .display()
+            .to_string();
+        write_geotiff(&raster, &path).unwrap();
+
+        let mut count = 0;
+        for_each_geotiff_cell(&path, None, |_, _| count += 1).unwrap();
+        assert_eq!(count, width * height);
+
+        // 10 by 10 cells in the second strip
+        let window = geo::Rect::new((0.01, 0.03), (0.02, 0.04));
+        let mut cells = Vec::new();
+        for_each_geotiff_cell(&path, Some(window), |pt, value| cells.push((pt, value))).unwrap();
+        fs_err::remove_file(&path).unwrap();
+        assert_eq!(cells.len(), 100);
+        for ((_, lat), value) in cells {
+            assert!((0.03..0.04).contains(&lat));
+            assert!((260.0..270.0).contains(&value));
+        }
+    }
+}
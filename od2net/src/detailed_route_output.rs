@@ -1,16 +1,24 @@
-use std::io::BufWriter;
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
 
 use anyhow::Result;
 use fs_err::File;
-use geojson::JsonObject;
+use geojson::{Feature, FeatureWriter, JsonObject, Value};
+use osm_reader::NodeID;
 
 use super::config::Uptake;
-use super::network::Network;
+use super::network::{ElevationSource, Network};
 use super::plugins;
+use super::polyline;
 use super::requests::Request;
-use super::router::{build_ch, build_closest_intersection, PreparedCH};
+use super::router::{build_ch, build_closest_intersection};
 use super::timer::Timer;
 
+/// If `polyline_precision` is specified, route geometry is written as encoded polylines with that
+/// many decimal places, instead of GeoJSON coordinates. If `overline` is true, the routes are
+/// merged into one `overline.geojson` file instead of written separately. If `gpx` is true, each
+/// route is also written as a GPX track, with heights from `elevation` if it's specified.
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     num_requests: usize,
     ch_path: &str,
@@ -18,12 +26,17 @@ pub fn run(
     requests: Vec<Request>,
     uptake: &Uptake,
     output_directory: String,
+    polyline_precision: Option<u32>,
+    overline: bool,
+    gpx: bool,
+    mut elevation: Option<Box<dyn ElevationSource>>,
     timer: &mut Timer,
 ) -> Result<()> {
     let prepared_ch = build_ch(ch_path, network, timer)?;
     let closest_intersection = build_closest_intersection(network, &prepared_ch.node_map, timer);
 
     let mut path_calc = fast_paths::create_calculator(&prepared_ch.ch);
+    let mut overline = overline.then(Overline::default);
 
     let mut i = 1;
     for req in requests {
@@ -40,14 +53,28 @@ pub fn run(
             continue;
         }
 
-        if let Some(path) = path_calc.calc_path(&prepared_ch.ch, start, end) {
-            output_detailed_route(
-                format!("{output_directory}/route{i}.geojson"),
-                path,
-                &prepared_ch,
-                network,
-                uptake,
-            )?;
+        if let Some(path) = prepared_ch.calc_path(&mut path_calc, start, end) {
+            if gpx {
+                write_gpx(
+                    format!("{output_directory}/route{i}.gpx"),
+                    &format!("route{i}"),
+                    &path,
+                    network,
+                    elevation.as_deref_mut(),
+                )?;
+            }
+            if let Some(ref mut overline) = overline {
+                overline.add(&path, network, uptake, req.purpose.as_deref());
+            } else {
+                output_detailed_route(
+                    format!("{output_directory}/route{i}.geojson"),
+                    path,
+                    network,
+                    uptake,
+                    req.purpose.as_deref(),
+                    polyline_precision,
+                )?;
+            }
             if i == num_requests {
                 break;
             }
@@ -55,16 +82,101 @@ pub fn run(
         }
     }
 
+    if let Some(overline) = overline {
+        timer.start(format!("Writing {output_directory}/overline.geojson"));
+        overline.write(&format!("{output_directory}/overline.geojson"), network)?;
+        timer.stop();
+    }
+
     Ok(())
 }
 
+/// Merges routes into one layer, like the `overline` function in R's stplanr. Each edge used by
+/// any route appears once, with the routes crossing it summed.
+#[derive(Default)]
+struct Overline {
+    /// Keyed like `Network::edges`
+    edges: HashMap<(NodeID, NodeID), OverlineEdge>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct OverlineEdge {
+    routes: usize,
+    /// The summed uptake of routes crossing the edge in each direction
+    uptake_forwards: f64,
+    uptake_backwards: f64,
+}
+
+impl Overline {
+    fn add(&mut self, path: &[NodeID], network: &Network, uptake: &Uptake, purpose: Option<&str>) {
+        let mut edges = Vec::new();
+        let mut total_distance = 0.0;
+        for pair in path.windows(2) {
+            let (i1, i2) = (pair[0], pair[1]);
+            let (key, forwards) = if network.edges.contains_key(&(i1, i2)) {
+                ((i1, i2), true)
+            } else {
+                ((i2, i1), false)
+            };
+            total_distance += network.edges[&key].length_meters;
+            edges.push((key, forwards));
+        }
+
+        let count = plugins::uptake::calculate_uptake(uptake, total_distance, purpose);
+        for (key, forwards) in edges {
+            let edge = self.edges.entry(key).or_default();
+            edge.routes += 1;
+            if forwards {
+                edge.uptake_forwards += count;
+            } else {
+                edge.uptake_backwards += count;
+            }
+        }
+    }
+
+    /// Writes one LineString per edge, sorted by the nodes, so runs can be diffed
+    fn write(self, path: &str, network: &Network) -> Result<()> {
+        let mut edges: Vec<_> = self.edges.into_iter().collect();
+        edges.sort_by_key(|((node1, node2), _)| (node1.0.min(node2.0), node1.0.max(node2.0)));
+
+        let mut writer = FeatureWriter::from_writer(BufWriter::new(File::create(path)?));
+        for ((node1, node2), totals) in edges {
+            let mut feature =
+                network.edges[&(node1, node2)].to_geojson_for_detailed_output(node1, node2, true);
+            feature.set_property("routes", totals.routes);
+            feature.set_property("uptake", totals.uptake_forwards + totals.uptake_backwards);
+            feature.set_property("uptake_forwards", totals.uptake_forwards);
+            feature.set_property("uptake_backwards", totals.uptake_backwards);
+            writer.write_feature(&feature)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+}
+
 fn output_detailed_route(
     filename: String,
-    path: fast_paths::ShortestPath,
-    prepared_ch: &PreparedCH,
+    path: Vec<NodeID>,
     network: &Network,
     uptake: &Uptake,
+    purpose: Option<&str>,
+    polyline_precision: Option<u32>,
 ) -> Result<()> {
+    let gj = route_to_geojson(&path, network, uptake, purpose, polyline_precision);
+    let mut file = BufWriter::new(File::create(filename)?);
+    serde_json::to_writer(&mut file, &gj)?;
+    Ok(())
+}
+
+/// One LineString per edge of a route, with the route's uptake and length as foreign members. If
+/// `polyline_precision` is specified, the whole route is also included as an encoded polyline.
+pub fn route_to_geojson(
+    path: &[NodeID],
+    network: &Network,
+    uptake: &Uptake,
+    purpose: Option<&str>,
+    polyline_precision: Option<u32>,
+) -> geojson::FeatureCollection {
     // TODO Include uptake and stats about the entire route
 
     let mut features = Vec::new();
@@ -72,9 +184,8 @@ fn output_detailed_route(
     // fast_paths returns the total cost, but it's not necessarily the right unit.
     // Calculate how long this route is.
     let mut total_distance = 0.0;
-    for pair in path.get_nodes().windows(2) {
-        let i1 = prepared_ch.node_map.translate_id(pair[0]);
-        let i2 = prepared_ch.node_map.translate_id(pair[1]);
+    for pair in path.windows(2) {
+        let (i1, i2) = (pair[0], pair[1]);
         let (edge, geometry_forwards) = if let Some(edge) = network.edges.get(&(i1, i2)) {
             (edge, true)
         } else {
@@ -84,18 +195,154 @@ fn output_detailed_route(
         total_distance += edge.length_meters;
     }
 
-    let count = plugins::uptake::calculate_uptake(uptake, total_distance);
+    let count = plugins::uptake::calculate_uptake(uptake, total_distance, purpose);
     let mut foreign_members = JsonObject::new();
+    if let Some(purpose) = purpose {
+        foreign_members.insert("purpose".to_string(), purpose.into());
+    }
     foreign_members.insert("uptake".to_string(), count.into());
     foreign_members.insert("total_distance_meters".to_string(), total_distance.into());
 
-    let gj = geojson::FeatureCollection {
+    if let Some(precision) = polyline_precision {
+        let mut route_pts = Vec::new();
+        for feature in &mut features {
+            let pts = encode_geometry(feature, precision);
+            // Consecutive segments share an endpoint
+            let skip = if route_pts.is_empty() { 0 } else { 1 };
+            route_pts.extend(pts.into_iter().skip(skip));
+        }
+        foreign_members.insert(
+            "polyline".to_string(),
+            polyline::encode(&route_pts, precision).into(),
+        );
+    }
+
+    geojson::FeatureCollection {
         features,
         bbox: None,
         foreign_members: Some(foreign_members),
-    };
-    let mut file = BufWriter::new(File::create(filename)?);
-    serde_json::to_writer(&mut file, &gj)?;
+    }
+}
 
+/// Writes a route as a GPX track, which bike computers and most route planning apps can load
+fn write_gpx(
+    filename: String,
+    name: &str,
+    path: &[NodeID],
+    network: &Network,
+    mut elevation: Option<&mut dyn ElevationSource>,
+) -> Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+    writeln!(file, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        file,
+        r#"<gpx version="1.1" creator="od2net" xmlns="http://www.topografix.com/GPX/1/1">"#
+    )?;
+    writeln!(file, "  <trk>")?;
+    writeln!(file, "    <name>{name}</name>")?;
+    writeln!(file, "    <trkseg>")?;
+    for (lon, lat) in route_points(path, network) {
+        let height = elevation
+            .as_mut()
+            .and_then(|source| source.get_height_for_lon_lat(lon as f32, lat as f32));
+        match height {
+            Some(height) => writeln!(
+                file,
+                r#"      <trkpt lat="{lat}" lon="{lon}"><ele>{height}</ele></trkpt>"#
+            )?,
+            None => writeln!(file, r#"      <trkpt lat="{lat}" lon="{lon}"/>"#)?,
+        }
+    }
+    writeln!(file, "    </trkseg>")?;
+    writeln!(file, "  </trk>")?;
+    writeln!(file, "</gpx>")?;
+    file.flush()?;
     Ok(())
 }
+
+/// Every point along a route through these intersections, in order
+fn route_points(path: &[NodeID], network: &Network) -> Vec<(f64, f64)> {
+    let mut pts: Vec<(f64, f64)> = Vec::new();
+    for pair in path.windows(2) {
+        let (i1, i2) = (pair[0], pair[1]);
+        let mut edge_pts: Vec<(f64, f64)> = if let Some(edge) = network.edges.get(&(i1, i2)) {
+            edge.geometry().iter().map(|pt| pt.to_degrees()).collect()
+        } else {
+            network.edges[&(i2, i1)]
+                .geometry()
+                .iter()
+                .rev()
+                .map(|pt| pt.to_degrees())
+                .collect()
+        };
+        // Consecutive edges share an endpoint
+        if !pts.is_empty() {
+            edge_pts.remove(0);
+        }
+        pts.extend(edge_pts);
+    }
+    pts
+}
+
+/// Replaces a LineString geometry with a `polyline` property, returning the original points
+fn encode_geometry(feature: &mut Feature, precision: u32) -> Vec<(f64, f64)> {
+    let Some(geometry) = feature.geometry.take() else {
+        return Vec::new();
+    };
+    let Value::LineString(line_string) = geometry.value else {
+        return Vec::new();
+    };
+    let pts: Vec<(f64, f64)> = line_string.into_iter().map(|pt| (pt[0], pt[1])).collect();
+    feature.set_property("polyline", polyline::encode(&pts, precision));
+    pts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::make_network;
+
+    #[test]
+    fn test_overline() {
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.001, 0.001),
+            ],
+            &[(1, 2), (2, 3), (2, 4)],
+        );
+        let mut overline = Overline::default();
+        let nodes = |ids: &[i64]| ids.iter().map(|id| NodeID(*id)).collect::<Vec<_>>();
+        overline.add(&nodes(&[1, 2, 3]), &network, &Uptake::Identity, None);
+        overline.add(&nodes(&[4, 2, 1]), &network, &Uptake::Identity, None);
+
+        assert_eq!(overline.edges.len(), 3);
+        assert_eq!(
+            overline.edges[&(NodeID(1), NodeID(2))],
+            OverlineEdge {
+                routes: 2,
+                uptake_forwards: 1.0,
+                uptake_backwards: 1.0,
+            }
+        );
+        assert_eq!(
+            overline.edges[&(NodeID(2), NodeID(4))].uptake_backwards,
+            1.0
+        );
+    }
+
+    #[test]
+    fn test_route_points() {
+        let network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.001, 0.001)],
+            &[(1, 2), (3, 2)],
+        );
+        let path = vec![NodeID(1), NodeID(2), NodeID(3)];
+        assert_eq!(
+            route_points(&path, &network),
+            vec![(0.0, 0.0), (0.001, 0.0), (0.001, 0.001)]
+        );
+    }
+}
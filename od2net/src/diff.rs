@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, BufWriter, Read};
+
+use anyhow::Result;
+use fs_err::File;
+use geo::HaversineLength;
+use geojson::{Feature, FeatureReader, FeatureWriter, JsonValue};
+use serde::Serialize;
+
+use super::timer::Timer;
+
+/// Summarizes how counts changed between two runs. Edges missing from one run have a count of 0
+/// there. `count_km` sums each edge's count times its length.
+#[derive(Serialize)]
+pub struct DiffSummary {
+    pub num_edges_base: usize,
+    pub num_edges_current: usize,
+    /// Only used in the base run
+    pub num_edges_removed: usize,
+    /// Only used in the current run
+    pub num_edges_added: usize,
+    pub num_edges_increased: usize,
+    pub num_edges_decreased: usize,
+    pub total_count_km_base: f64,
+    pub total_count_km_current: f64,
+    /// Empty if the base run has no counts
+    pub percent_change_count_km: Option<f64>,
+}
+
+/// The files a run can write its edges to, checked in order
+const EDGE_OUTPUTS: [&str; 4] = [
+    "output.geojson",
+    "output.geojson.gz",
+    "output.geojson.zst",
+    "output.geojsonl",
+];
+
+/// Compares the edges from two output directories, joining them on `edge_id`. Writes
+/// `diff.geojson` with every edge used in either run, and `diff_summary.json`, to
+/// `output_directory`.
+pub fn run(
+    base_directory: &str,
+    current_directory: &str,
+    output_directory: &str,
+    timer: &mut Timer,
+) -> Result<DiffSummary> {
+    timer.start(format!("Reading edges from {base_directory}"));
+    let base = read_edges(base_directory)?;
+    timer.stop();
+    timer.start(format!("Reading edges from {current_directory}"));
+    let current = read_edges(current_directory)?;
+    timer.stop();
+
+    timer.start("Writing diff");
+    let (features, summary) = diff(base, current)?;
+    let mut writer = FeatureWriter::from_writer(BufWriter::new(File::create(format!(
+        "{output_directory}/diff.geojson"
+    ))?));
+    for feature in &features {
+        writer.write_feature(feature)?;
+    }
+    writer.finish()?;
+    serde_json::to_writer_pretty(
+        BufWriter::new(File::create(format!(
+            "{output_directory}/diff_summary.json"
+        ))?),
+        &summary,
+    )?;
+    timer.stop();
+    Ok(summary)
+}
+
+/// Reads every edge written by one run, keyed by `edge_id`
+fn read_edges(directory: &str) -> Result<BTreeMap<String, Feature>> {
+    let Some(filename) = EDGE_OUTPUTS
+        .iter()
+        .find(|filename| fs_err::metadata(format!("{directory}/{filename}")).is_ok())
+    else {
+        bail!(
+            "No edges found; {directory} needs one of {}",
+            EDGE_OUTPUTS.join(", ")
+        );
+    };
+    let path = format!("{directory}/{filename}");
+    let file = File::open(&path)?;
+    let reader: Box<dyn Read> = match *filename {
+        "output.geojson.gz" => Box::new(flate2::read::GzDecoder::new(file)),
+        #[cfg(not(target_arch = "wasm32"))]
+        "output.geojson.zst" => Box::new(zstd::Decoder::new(file)?),
+        #[cfg(target_arch = "wasm32")]
+        "output.geojson.zst" => bail!("Zstd compression isn't supported here"),
+        _ => Box::new(file),
+    };
+    let reader = BufReader::new(reader);
+
+    let mut edges = BTreeMap::new();
+    let mut add = |feature: Feature| -> Result<()> {
+        // Origins and destinations might be mixed in
+        if !matches!(
+            feature.geometry.as_ref().map(|g| &g.value),
+            Some(geojson::Value::LineString(_))
+        ) {
+            return Ok(());
+        }
+        let Some(edge_id) = feature.property("edge_id").and_then(|x| x.as_str()) else {
+            bail!("An edge in {path} has no edge_id. Is it missing from output_properties?");
+        };
+        if feature.property("count").and_then(|x| x.as_f64()).is_none() {
+            bail!("Edge {edge_id} in {path} has no count");
+        }
+        let edge_id = edge_id.to_string();
+        if edges.contains_key(&edge_id) {
+            bail!("Edge {edge_id} is in {path} more than once");
+        }
+        edges.insert(edge_id, feature);
+        Ok(())
+    };
+    if *filename == "output.geojsonl" {
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                add(serde_json::from_str(&line)?)?;
+            }
+        }
+    } else {
+        for feature in FeatureReader::from_reader(reader).features() {
+            add(feature?)?;
+        }
+    }
+    Ok(edges)
+}
+
+/// Returns one feature per edge used in either run, sorted by `edge_id`
+fn diff(
+    mut base: BTreeMap<String, Feature>,
+    mut current: BTreeMap<String, Feature>,
+) -> Result<(Vec<Feature>, DiffSummary)> {
+    let mut summary = DiffSummary {
+        num_edges_base: base.len(),
+        num_edges_current: current.len(),
+        num_edges_removed: 0,
+        num_edges_added: 0,
+        num_edges_increased: 0,
+        num_edges_decreased: 0,
+        total_count_km_base: 0.0,
+        total_count_km_current: 0.0,
+        percent_change_count_km: None,
+    };
+
+    let mut edge_ids: Vec<String> = base.keys().chain(current.keys()).cloned().collect();
+    edge_ids.sort();
+    edge_ids.dedup();
+
+    let mut features = Vec::new();
+    for edge_id in edge_ids {
+        let base_edge = base.remove(&edge_id);
+        let current_edge = current.remove(&edge_id);
+        let count = |edge: &Option<Feature>| {
+            edge.as_ref()
+                .and_then(|f| f.property("count"))
+                .and_then(|x| x.as_f64())
+                .unwrap_or(0.0)
+        };
+        let (count_base, count_current) = (count(&base_edge), count(&current_edge));
+        match (&base_edge, &current_edge) {
+            (Some(_), None) => summary.num_edges_removed += 1,
+            (None, Some(_)) => summary.num_edges_added += 1,
+            _ => {}
+        }
+        if count_current > count_base {
+            summary.num_edges_increased += 1;
+        } else if count_current < count_base {
+            summary.num_edges_decreased += 1;
+        }
+
+        // Prefer the current run's geometry and properties, in case the network changed
+        let edge = current_edge.or(base_edge).unwrap();
+        let geometry = edge.geometry.unwrap();
+        let line_string: geo::LineString = geometry.value.clone().try_into()?;
+        let length_km = line_string.haversine_length() / 1000.0;
+        summary.total_count_km_base += count_base * length_km;
+        summary.total_count_km_current += count_current * length_km;
+
+        let mut feature = Feature {
+            bbox: None,
+            geometry: Some(geometry),
+            // A hash of the way and nodes, so it matches between runs
+            id: edge.id,
+            properties: None,
+            foreign_members: None,
+        };
+        feature.set_property("edge_id", edge_id);
+        for key in ["way", "node1", "node2", "lts"] {
+            if let Some(value) = edge.properties.as_ref().and_then(|p| p.get(key)) {
+                feature.set_property(key, value.clone());
+            }
+        }
+        feature.set_property("count_base", count_base);
+        feature.set_property("count_current", count_current);
+        feature.set_property("count_delta", count_current - count_base);
+        feature.set_property("percent_change", percent_change(count_base, count_current));
+        features.push(feature);
+    }
+
+    summary.percent_change_count_km =
+        percent_change(summary.total_count_km_base, summary.total_count_km_current).as_f64();
+    Ok((features, summary))
+}
+
+/// Null when there's nothing to compare against
+fn percent_change(before: f64, after: f64) -> JsonValue {
+    if before > 0.0 {
+        JsonValue::from(100.0 * (after - before) / before)
+    } else {
+        JsonValue::Null
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edge(edge_id: &str, x: f64, count: f64) -> (String, Feature) {
+        let mut feature = Feature::from(geojson::Value::LineString(vec![
+            vec![x, 0.0],
+            vec![x + 0.001, 0.0],
+        ]));
+        feature.set_property("edge_id", edge_id);
+        feature.set_property("count", count);
+        (edge_id.to_string(), feature)
+    }
+
+    #[test]
+    fn test_diff() {
+        let base = BTreeMap::from([edge("1-2", 0.0, 10.0), edge("2-3", 0.001, 5.0)]);
+        let current = BTreeMap::from([edge("2-3", 0.001, 10.0), edge("3-4", 0.002, 2.0)]);
+        let (features, summary) = diff(base, current).unwrap();
+
+        let row = |f: &Feature| {
+            (
+                f.property("edge_id").unwrap().as_str().unwrap().to_string(),
+                f.property("count_delta").unwrap().as_f64().unwrap(),
+                f.property("percent_change").unwrap().as_f64(),
+            )
+        };
+        assert_eq!(
+            features.iter().map(row).collect::<Vec<_>>(),
+            vec![
+                ("1-2".to_string(), -10.0, Some(-100.0)),
+                ("2-3".to_string(), 5.0, Some(100.0)),
+                ("3-4".to_string(), 2.0, None),
+            ]
+        );
+
+        assert_eq!(summary.num_edges_base, 2);
+        assert_eq!(summary.num_edges_current, 2);
+        assert_eq!(summary.num_edges_removed, 1);
+        assert_eq!(summary.num_edges_added, 1);
+        assert_eq!(summary.num_edges_increased, 2);
+        assert_eq!(summary.num_edges_decreased, 1);
+        // Every edge is the same length, so 15 trips before and 12 after
+        let change = summary.percent_change_count_km.unwrap();
+        assert!((change - -20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_read_edges_seq() {
+        let directory = std::env::temp_dir().join("od2net_test_diff");
+        fs_err::create_dir_all(&directory).unwrap();
+        let directory = directory.to_str().unwrap();
+        let (_, feature) = edge("1-2", 0.0, 3.0);
+        let mut origin = Feature::from(geojson::Value::Point(vec![0.0, 0.0]));
+        origin.set_property("count", 1.0);
+        fs_err::write(
+            format!("{directory}/output.geojsonl"),
+            format!("{feature}\n{origin}\n"),
+        )
+        .unwrap();
+
+        let edges = read_edges(directory).unwrap();
+        assert_eq!(edges.keys().collect::<Vec<_>>(), vec!["1-2"]);
+
+        // Joining on edge_id only works if it's unique
+        fs_err::write(
+            format!("{directory}/output.geojsonl"),
+            format!("{feature}\n{feature}\n"),
+        )
+        .unwrap();
+        assert!(read_edges(directory).is_err());
+        fs_err::remove_dir_all(directory).unwrap();
+    }
+}
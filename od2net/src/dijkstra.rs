@@ -0,0 +1,500 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+#[cfg(not(target_arch = "wasm32"))]
+use indicatif::ParallelProgressIterator;
+use osm_reader::NodeID;
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::prelude::*;
+use rstar::RTree;
+
+use super::config::InputConfig;
+use super::network::{CountArea, Counts, Network};
+use super::node_map::NodeMap;
+use super::requests::Request;
+use super::router::{self, build_closest_intersection, IntersectionLocation, TurnGraph};
+use super::timer::Timer;
+#[cfg(not(target_arch = "wasm32"))]
+use super::utils;
+use super::zones::ZoneIndex;
+
+/// Routes directly on the network, without building a CH. Endpoints always snap to the closest
+/// intersection. This is quick to set up, so it's also used for small interactive runs on the
+/// web.
+pub struct DijkstraRouter {
+    graph: PlainGraph,
+    closest_intersection: RTree<IntersectionLocation>,
+}
+
+impl DijkstraRouter {
+    pub fn new(network: &Network, timer: &mut Timer) -> Self {
+        timer.start("Building graph for Dijkstra");
+        let graph = PlainGraph::new(network);
+        timer.stop();
+        let closest_intersection = build_closest_intersection(network, graph.node_map(), timer);
+        Self {
+            graph,
+            closest_intersection,
+        }
+    }
+
+    /// The closest intersection to a point in WGS84
+    pub fn snap(&self, x: f64, y: f64) -> NodeID {
+        let location = self.closest_intersection.nearest_neighbor(&[x, y]).unwrap();
+        self.graph.node_map().translate_id(location.data)
+    }
+
+    /// Finds the route between the intersections closest to two points. Returns `None` if both
+    /// points snap to the same intersection, or there's no route.
+    pub fn route(&self, x1: f64, y1: f64, x2: f64, y2: f64) -> Option<Vec<NodeID>> {
+        let (start, end) = (self.snap(x1, y1), self.snap(x2, y2));
+        if start == end {
+            return None;
+        }
+        self.graph.shortest_path(
+            self.graph.start(start, None),
+            end,
+            &HashMap::new(),
+            f64::INFINITY,
+        )
+    }
+
+    /// Routes every request, summing up the counts. Requests are routed in parallel, except on
+    /// wasm, where there's only one thread.
+    pub fn route_all(
+        &self,
+        network: &Network,
+        requests: Vec<Request>,
+        config: &InputConfig,
+        count_area: Option<&CountArea>,
+        zones: Option<&ZoneIndex>,
+    ) -> Counts {
+        let alternatives = config.alternatives.is_some().then_some(&self.graph);
+        let route_request = |mut counts: Counts, req: Request| {
+            let start = self
+                .closest_intersection
+                .nearest_neighbor(&[req.x1, req.y1])
+                .unwrap();
+            let end = self
+                .closest_intersection
+                .nearest_neighbor(&[req.x2, req.y2])
+                .unwrap();
+            let Some((origin_snap_meters, destination_snap_meters)) = router::check_snap_distances(
+                &req,
+                &mut counts,
+                config,
+                position(start),
+                position(end),
+            ) else {
+                return counts;
+            };
+
+            let node_map = self.graph.node_map();
+            let (start, end) = (
+                node_map.translate_id(start.data),
+                node_map.translate_id(end.data),
+            );
+            if start == end {
+                router::handle_same_endpoints(
+                    req,
+                    &mut counts,
+                    Some(start),
+                    count_area.map_or(true, |area| area.contains_node(start)),
+                    config,
+                    origin_snap_meters,
+                    destination_snap_meters,
+                );
+                return counts;
+            }
+            match self.graph.shortest_path(
+                self.graph.start(start, None),
+                end,
+                &HashMap::new(),
+                f64::INFINITY,
+            ) {
+                Some(path) => router::count_path(
+                    req,
+                    path,
+                    &mut counts,
+                    alternatives,
+                    zones,
+                    config,
+                    count_area,
+                    network,
+                    origin_snap_meters,
+                    destination_snap_meters,
+                ),
+                None => counts.errors_no_path.push(req),
+            }
+            counts
+        };
+
+        // The progress bar needs a clock, which wasm doesn't have
+        #[cfg(target_arch = "wasm32")]
+        let mut counts = requests.into_iter().fold(Counts::new(), route_request);
+        #[cfg(not(target_arch = "wasm32"))]
+        let mut counts = {
+            let progress = utils::progress_bar_for_count(requests.len());
+            let num_requests = requests.len();
+            requests
+                .into_par_iter()
+                .with_min_len(num_requests / num_cpus::get())
+                .progress_with(progress)
+                .fold(Counts::new, route_request)
+                .reduce_with(|mut acc1, acc2| {
+                    acc1.combine(acc2);
+                    acc1
+                })
+                .unwrap_or_else(Counts::new)
+        };
+        counts.uptake_scenarios = config.uptake_scenarios.keys().cloned().collect();
+        counts.set_flow_groups(config.flow_breakdown.as_ref());
+        counts
+    }
+}
+
+fn position(location: &IntersectionLocation) -> (f64, f64) {
+    let [x, y] = *location.geom();
+    (x, y)
+}
+
+/// A plain directed graph of the network. Unlike the CH, it's quick to build and edge costs can
+/// be penalized differently for every search, but searching is much slower. It uses the same
+/// `TurnGraph` as the CH, so turn restrictions and turn costs are handled the same way, and route
+/// costs match.
+pub struct PlainGraph {
+    /// Indexed by the IDs in `node_map`, with the cost of each edge leaving there
+    adjacency: Vec<Vec<(usize, usize)>>,
+    node_map: NodeMap<NodeID>,
+    arrival_copies: HashMap<(NodeID, NodeID), usize>,
+}
+
+impl PlainGraph {
+    pub fn new(network: &Network) -> Self {
+        let graph = TurnGraph::new(network);
+        let mut adjacency: Vec<Vec<(usize, usize)>> = Vec::new();
+        for (from, to, cost) in graph.edges {
+            if adjacency.len() <= from.max(to) {
+                adjacency.resize(from.max(to) + 1, Vec::new());
+            }
+            adjacency[from].push((to, cost));
+        }
+        Self {
+            adjacency,
+            node_map: graph.node_map,
+            arrival_copies: graph.arrival_copies,
+        }
+    }
+
+    pub fn node_map(&self) -> &NodeMap<NodeID> {
+        &self.node_map
+    }
+
+    /// Where to start searching from an intersection. If the route has already arrived there
+    /// from another intersection, only the turns allowed from that way are used.
+    pub fn start(&self, node: NodeID, arriving_from: Option<NodeID>) -> usize {
+        arriving_from
+            .and_then(|from| self.arrival_copies.get(&(node, from)))
+            .cloned()
+            .unwrap_or_else(|| self.node_map.get(node))
+    }
+
+    /// The unpenalized cost of each step along a route beginning at `start`, including turn
+    /// costs. The route must be possible.
+    pub fn path_costs(&self, start: usize, path: &[NodeID]) -> Vec<usize> {
+        let mut current = start;
+        path.iter()
+            .skip(1)
+            .map(|next| {
+                let (id, cost) = self
+                    .edges_from(current)
+                    .iter()
+                    .filter(|(id, _)| self.node_map.translate_id(*id) == *next)
+                    .min_by_key(|(_, cost)| *cost)
+                    .unwrap();
+                current = *id;
+                *cost
+            })
+            .collect()
+    }
+
+    /// The edges leaving an intersection, with their costs, ignoring how a route arrived there
+    pub fn neighbors(&self, node: NodeID) -> impl Iterator<Item = (NodeID, usize)> + '_ {
+        self.edges_from(self.node_map.get(node))
+            .iter()
+            .map(|(id, cost)| (self.node_map.translate_id(*id), *cost))
+    }
+
+    fn edges_from(&self, id: usize) -> &[(usize, usize)] {
+        self.adjacency.get(id).map(|x| x.as_slice()).unwrap_or(&[])
+    }
+
+    /// Searches outwards from `start`, returning the cheapest cost to every intersection reachable
+    /// within `max_cost`
+    pub fn shortest_path_tree(&self, start: NodeID, max_cost: f64) -> HashMap<NodeID, f64> {
+        let start = self.node_map.get(start);
+        let mut best_cost: HashMap<usize, f64> = HashMap::new();
+        let mut done: HashMap<NodeID, f64> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        best_cost.insert(start, 0.0);
+        queue.push(Item {
+            cost: 0.0,
+            node: start,
+        });
+
+        while let Some(Item { cost, node }) = queue.pop() {
+            if cost > best_cost[&node] {
+                continue;
+            }
+            // The first copy of an intersection reached is the cheapest
+            done.entry(self.node_map.translate_id(node)).or_insert(cost);
+            for (next, edge_cost) in self.edges_from(node) {
+                let next_cost = cost + *edge_cost as f64;
+                if next_cost <= max_cost && best_cost.get(next).map_or(true, |x| next_cost < *x) {
+                    best_cost.insert(*next, next_cost);
+                    queue.push(Item {
+                        cost: next_cost,
+                        node: *next,
+                    });
+                }
+            }
+        }
+        done
+    }
+
+    /// Dijkstra's algorithm from `start` (see `PlainGraph::start`) to any copy of `end`,
+    /// multiplying the cost of some edges (in either direction) by a penalty. Gives up on routes
+    /// with a penalized cost over `max_cost`.
+    pub fn shortest_path(
+        &self,
+        start: usize,
+        end: NodeID,
+        penalties: &HashMap<(NodeID, NodeID), f64>,
+        max_cost: f64,
+    ) -> Option<Vec<NodeID>> {
+        let mut best_cost: HashMap<usize, f64> = HashMap::new();
+        let mut prev: HashMap<usize, usize> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        best_cost.insert(start, 0.0);
+        queue.push(Item {
+            cost: 0.0,
+            node: start,
+        });
+
+        while let Some(Item { cost, node }) = queue.pop() {
+            let here = self.node_map.translate_id(node);
+            if here == end {
+                let mut path = vec![end];
+                let mut current = node;
+                while let Some(before) = prev.get(&current) {
+                    path.push(self.node_map.translate_id(*before));
+                    current = *before;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            if cost > best_cost[&node] {
+                continue;
+            }
+            for (next, edge_cost) in self.edges_from(node) {
+                let penalty = if penalties.is_empty() {
+                    1.0
+                } else {
+                    let next_node = self.node_map.translate_id(*next);
+                    penalties
+                        .get(&undirected(here, next_node))
+                        .copied()
+                        .unwrap_or(1.0)
+                };
+                let next_cost = cost + *edge_cost as f64 * penalty;
+                if next_cost <= max_cost && best_cost.get(next).map_or(true, |x| next_cost < *x) {
+                    best_cost.insert(*next, next_cost);
+                    prev.insert(*next, node);
+                    queue.push(Item {
+                        cost: next_cost,
+                        node: *next,
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+pub fn undirected(node1: NodeID, node2: NodeID) -> (NodeID, NodeID) {
+    if node1.0 < node2.0 {
+        (node1, node2)
+    } else {
+        (node2, node1)
+    }
+}
+
+/// For a min-heap ordered by cost
+struct Item {
+    cost: f64,
+    node: usize,
+}
+
+impl PartialEq for Item {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Item {}
+
+impl PartialOrd for Item {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Item {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use osm_reader::WayID;
+
+    use super::*;
+    use crate::config::tests::config_with;
+    use crate::network::tests::make_network;
+    use crate::network::TurnRestriction;
+    use crate::router::{just_build_ch, RoutingExtras};
+
+    #[test]
+    fn test_same_counts_as_ch() {
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.001, 0.0008),
+                (5, 0.002, 0.001),
+            ],
+            &[(1, 2), (2, 3), (2, 4), (4, 5), (3, 5)],
+        );
+        let requests = || {
+            [
+                (0.0, 0.0, 0.002, 0.001),
+                (0.002, 0.0, 0.001, 0.0008),
+                (0.001, 0.0008, 0.001, 0.0008),
+            ]
+            .iter()
+            .map(|(x1, y1, x2, y2)| Request::new(*x1, *y1, *x2, *y2))
+            .collect::<Vec<_>>()
+        };
+        let config = config_with(r#", "router": "Dijkstra", "same_endpoints": "ZeroDistance""#);
+        let mut timer = Timer::new();
+
+        let dijkstra = DijkstraRouter::new(&network, &mut timer).route_all(
+            &network,
+            requests(),
+            &config,
+            None,
+            None,
+        );
+
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+        let ch = router::route_all(
+            &prepared_ch,
+            &closest_intersection,
+            &RoutingExtras::default(),
+            &network,
+            requests(),
+            &config,
+            None,
+        );
+
+        assert_eq!(dijkstra.count_per_edge, ch.count_per_edge);
+        assert_eq!(dijkstra.count_per_node, ch.count_per_node);
+        assert_eq!(dijkstra.num_same_endpoints_zero_distance, 1);
+    }
+
+    #[test]
+    fn test_turns_match_ch() {
+        // Heading east along 1-2-3, with 4 north of 2. 1-5-4 is a longer detour.
+        let mut network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.001, 0.001),
+                (5, 0.0, 0.002),
+            ],
+            &[(1, 2), (2, 3), (2, 4), (1, 5), (5, 4)],
+        );
+        // No left turn from 1-2 onto 2-4, and right turns are expensive
+        network.turn_restrictions.push(TurnRestriction {
+            from: WayID(1),
+            via: NodeID(2),
+            to: WayID(3),
+            only: false,
+        });
+        network.turn_costs.right = 500;
+        let mut timer = Timer::new();
+        let router = DijkstraRouter::new(&network, &mut timer);
+        assert_eq!(
+            router.route(0.0, 0.0, 0.001, 0.001),
+            Some(vec![NodeID(1), NodeID(5), NodeID(4)])
+        );
+        // Turning right from 3-2 onto 2-4 costs more than going around via 1
+        assert_eq!(
+            router.route(0.002, 0.0, 0.001, 0.001),
+            Some(vec![NodeID(3), NodeID(2), NodeID(1), NodeID(5), NodeID(4)])
+        );
+
+        let requests = || {
+            vec![
+                Request::new(0.0, 0.0, 0.001, 0.001),
+                Request::new(0.002, 0.0, 0.001, 0.001),
+                Request::new(0.001, 0.001, 0.002, 0.0),
+            ]
+        };
+        let mut config = config_with(r#", "router": "Dijkstra""#);
+        config.collect.movements = true;
+        let dijkstra = router.route_all(&network, requests(), &config, None, None);
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+        let ch = router::route_all(
+            &prepared_ch,
+            &closest_intersection,
+            &RoutingExtras::default(),
+            &network,
+            requests(),
+            &config,
+            None,
+        );
+        assert_eq!(dijkstra.count_per_edge, ch.count_per_edge);
+        assert_eq!(dijkstra.count_per_movement, ch.count_per_movement);
+    }
+
+    #[test]
+    fn test_route() {
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.1, 0.1),
+                (5, 0.101, 0.1),
+            ],
+            &[(1, 2), (2, 3), (4, 5)],
+        );
+        let router = DijkstraRouter::new(&network, &mut Timer::new());
+        assert_eq!(router.snap(0.0021, 0.0), NodeID(3));
+        assert_eq!(
+            router.route(0.0, 0.0, 0.002, 0.0),
+            Some(vec![NodeID(1), NodeID(2), NodeID(3)])
+        );
+        assert_eq!(router.route(0.0, 0.0, 0.0001, 0.0), None);
+        // Nodes 4 and 5 aren't connected to the rest
+        assert_eq!(router.route(0.0, 0.0, 0.1, 0.1), None);
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+
+use anyhow::Result;
+use fast_paths::PathCalculator;
+use fs_err::File;
+use indicatif::{HumanCount, ParallelProgressIterator};
+use osm_reader::{NodeID, WayID};
+use rayon::prelude::*;
+use rstar::RTree;
+
+use super::config::Uptake;
+use super::network::{Edge, Network, Position};
+use super::plugins::uptake;
+use super::requests::Request;
+use super::router::{build_ch, build_closest_intersection, IntersectionLocation, PreparedCH};
+use super::timer::Timer;
+use super::utils;
+
+/// Identifies the edge to find contributing OD pairs for
+pub enum EdgeQuery {
+    /// Any edge belonging to this OSM way
+    Way(WayID),
+    /// The edge between these two OSM nodes, crossed in either direction
+    Nodes(NodeID, NodeID),
+}
+
+impl EdgeQuery {
+    /// Parses either an OSM way ID, or two OSM node IDs separated by a comma
+    pub fn parse(input: &str) -> Result<Self> {
+        if let Some((node1, node2)) = input.split_once(',') {
+            return Ok(Self::Nodes(
+                NodeID(node1.trim().parse()?),
+                NodeID(node2.trim().parse()?),
+            ));
+        }
+        Ok(Self::Way(WayID(input.trim().parse()?)))
+    }
+
+    fn matches(&self, node1: NodeID, node2: NodeID, edge: &Edge) -> bool {
+        match self {
+            EdgeQuery::Way(way) => edge.way_id == *way,
+            EdgeQuery::Nodes(a, b) => (node1 == *a && node2 == *b) || (node1 == *b && node2 == *a),
+        }
+    }
+}
+
+/// One OD pair with at least one route crossing the queried edge
+pub struct Contributor {
+    pub from: Position,
+    pub to: Position,
+    /// How many requests there were between this pair
+    pub num_requests: usize,
+    /// The summed uptake of all of those requests. This is what the pair adds to the edge's count.
+    pub count: f64,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    query: EdgeQuery,
+    top_n: usize,
+    ch_path: &str,
+    network: &Network,
+    requests: Vec<Request>,
+    uptake: &Uptake,
+    output_path: String,
+    timer: &mut Timer,
+) -> Result<()> {
+    let prepared_ch = build_ch(ch_path, network, timer)?;
+    let closest_intersection = build_closest_intersection(network, &prepared_ch.node_map, timer);
+
+    timer.start("Finding OD pairs crossing the edge");
+    let contributors = find_contributors(
+        &query,
+        network,
+        requests,
+        &prepared_ch,
+        &closest_intersection,
+        uptake,
+    );
+    timer.stop();
+
+    let total: f64 = contributors.iter().map(|c| c.count).sum();
+    println!(
+        "{} OD pairs cross this edge, with a total count of {total:.1}",
+        HumanCount(contributors.len() as u64)
+    );
+
+    timer.start(format!("Writing top {top_n} OD pairs to {output_path}"));
+    let mut file = BufWriter::new(File::create(output_path)?);
+    writeln!(file, "rank,x1,y1,x2,y2,num_requests,count,fraction")?;
+    for (idx, contributor) in contributors.iter().take(top_n).enumerate() {
+        let (x1, y1) = contributor.from.to_degrees();
+        let (x2, y2) = contributor.to.to_degrees();
+        writeln!(
+            file,
+            "{},{x1},{y1},{x2},{y2},{},{},{}",
+            idx + 1,
+            contributor.num_requests,
+            contributor.count,
+            contributor.count / total
+        )?;
+    }
+    timer.stop();
+
+    Ok(())
+}
+
+/// Routes every request and returns the OD pairs crossing the queried edge, sorted by their
+/// contribution to its count, biggest first.
+pub fn find_contributors(
+    query: &EdgeQuery,
+    network: &Network,
+    requests: Vec<Request>,
+    prepared_ch: &PreparedCH,
+    closest_intersection: &RTree<IntersectionLocation>,
+    uptake: &Uptake,
+) -> Vec<Contributor> {
+    let progress = utils::progress_bar_for_count(requests.len());
+    let num_requests = requests.len();
+
+    let per_pair = requests
+        .into_par_iter()
+        .with_min_len(num_requests / num_cpus::get())
+        .progress_with(progress)
+        .fold(
+            || (None, HashMap::new()),
+            |(mut path_calc, mut per_pair): (Option<PathCalculator>, HashMap<_, _>), req| {
+                if path_calc.is_none() {
+                    path_calc = Some(fast_paths::create_calculator(&prepared_ch.ch));
+                }
+                if let Some(count) = contribution(
+                    &req,
+                    query,
+                    path_calc.as_mut().unwrap(),
+                    closest_intersection,
+                    prepared_ch,
+                    uptake,
+                    network,
+                ) {
+                    let key = (
+                        Position::from_degrees(req.x1, req.y1),
+                        Position::from_degrees(req.x2, req.y2),
+                    );
+                    let entry = per_pair.entry(key).or_insert((0, 0.0));
+                    entry.0 += 1;
+                    entry.1 += count;
+                }
+                (path_calc, per_pair)
+            },
+        )
+        .map(|(_, per_pair)| per_pair)
+        .reduce(HashMap::new, |mut acc1, acc2| {
+            for (key, (num_requests, count)) in acc2 {
+                let entry = acc1.entry(key).or_insert((0, 0.0));
+                entry.0 += num_requests;
+                entry.1 += count;
+            }
+            acc1
+        });
+
+    let mut contributors: Vec<Contributor> = per_pair
+        .into_iter()
+        .map(|((from, to), (num_requests, count))| Contributor {
+            from,
+            to,
+            num_requests,
+            count,
+        })
+        .collect();
+    contributors.sort_by(|a, b| b.count.partial_cmp(&a.count).unwrap());
+    contributors
+}
+
+/// If this request's route crosses the queried edge, returns its uptake
+fn contribution(
+    req: &Request,
+    query: &EdgeQuery,
+    path_calc: &mut PathCalculator,
+    closest_intersection: &RTree<IntersectionLocation>,
+    prepared_ch: &PreparedCH,
+    uptake: &Uptake,
+    network: &Network,
+) -> Option<f64> {
+    let start = closest_intersection
+        .nearest_neighbor(&[req.x1, req.y1])
+        .unwrap()
+        .data;
+    let end = closest_intersection
+        .nearest_neighbor(&[req.x2, req.y2])
+        .unwrap()
+        .data;
+    if start == end {
+        return None;
+    }
+    let path = prepared_ch.calc_path(path_calc, start, end)?;
+
+    let mut crosses = false;
+    let mut total_distance = 0.0;
+    for pair in path.windows(2) {
+        let (i1, i2) = (pair[0], pair[1]);
+        let edge = network
+            .edges
+            .get(&(i1, i2))
+            .or_else(|| network.edges.get(&(i2, i1)))
+            .unwrap();
+        total_distance += edge.length_meters;
+        if query.matches(i1, i2, edge) {
+            crosses = true;
+        }
+    }
+    if !crosses {
+        return None;
+    }
+
+    let count =
+        req.weight * uptake::calculate_uptake(uptake, total_distance, req.purpose.as_deref());
+    if count == 0.0 {
+        return None;
+    }
+    Some(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config_with;
+    use crate::many_to_many::RouteMatrix;
+    use crate::network::tests::make_network;
+    use crate::network::Counts;
+    use crate::router::{handle_request, just_build_ch, RoutingExtras};
+
+    #[test]
+    fn test_contributors_sum_to_edge_count() {
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.001, 0.001),
+            ],
+            &[(1, 2), (2, 3), (2, 4)],
+        );
+        let od_pairs = [
+            (0.0, 0.0, 0.002, 0.0),
+            (0.0, 0.0, 0.002, 0.0),
+            (0.0, 0.0, 0.002, 0.0),
+            (0.001, 0.001, 0.0, 0.0),
+            (0.002, 0.0, 0.0, 0.0),
+            // Doesn't cross the 1-2 edge
+            (0.001, 0.001, 0.002, 0.0),
+        ];
+        let requests = || {
+            od_pairs
+                .iter()
+                .map(|(x1, y1, x2, y2)| Request::new(*x1, *y1, *x2, *y2))
+                .collect::<Vec<_>>()
+        };
+
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+
+        let contributors = find_contributors(
+            &EdgeQuery::parse("1,2").unwrap(),
+            &network,
+            requests(),
+            &prepared_ch,
+            &closest_intersection,
+            &Uptake::Identity,
+        );
+        assert_eq!(contributors.len(), 3);
+        assert_eq!(contributors[0].num_requests, 3);
+        assert_eq!(contributors[0].count, 3.0);
+
+        let config = config_with("");
+        let mut counts = Counts::new();
+        let mut path_calc = fast_paths::create_calculator(&prepared_ch.ch);
+        for req in requests() {
+            handle_request(
+                req,
+                &mut counts,
+                &mut path_calc,
+                &RouteMatrix::default(),
+                &closest_intersection,
+                &RoutingExtras::default(),
+                &prepared_ch,
+                &config,
+                None,
+                &network,
+            );
+        }
+        let edge_count = counts
+            .count_per_edge
+            .get(&(NodeID(1), NodeID(2)))
+            .unwrap_or(&0.0)
+            + counts
+                .count_per_edge
+                .get(&(NodeID(2), NodeID(1)))
+                .unwrap_or(&0.0);
+        let total: f64 = contributors.iter().map(|c| c.count).sum();
+        assert_eq!(total, edge_count);
+    }
+}
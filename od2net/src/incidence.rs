@@ -0,0 +1,209 @@
+use std::io::{BufWriter, Write};
+
+use anyhow::Result;
+use fast_paths::PathCalculator;
+use fs_err::File;
+use indicatif::HumanCount;
+use osm_reader::NodeID;
+use rayon::prelude::*;
+use rstar::RTree;
+
+use super::network::{edge_id, EdgeIndex, Network};
+use super::requests::Request;
+use super::router::{build_ch, build_closest_intersection, IntersectionLocation, PreparedCH};
+use super::timer::Timer;
+use super::utils;
+
+/// How many requests to route before writing their rows, bounding memory use
+const CHUNK_SIZE: usize = 10_000;
+
+/// Routes every request and writes the result as a sparse matrix. `incidence.csv` has a
+/// `request,edge,length_meters` row for every edge crossed by every route, and
+/// `incidence_edges.csv` describes each edge index.
+pub fn run(
+    ch_path: &str,
+    network: &Network,
+    requests: Vec<Request>,
+    output_directory: String,
+    timer: &mut Timer,
+) -> Result<()> {
+    let prepared_ch = build_ch(ch_path, network, timer)?;
+    let closest_intersection = build_closest_intersection(network, &prepared_ch.node_map, timer);
+    let edge_index = EdgeIndex::new(network);
+
+    timer.start("Writing the edge index");
+    let mut file = BufWriter::new(File::create(format!(
+        "{output_directory}/incidence_edges.csv"
+    ))?);
+    writeln!(file, "edge,edge_id,way,node1,node2,length_meters")?;
+    for (idx, (node1, node2)) in edge_index.edges.iter().enumerate() {
+        let edge = &network.edges[&(*node1, *node2)];
+        writeln!(
+            file,
+            "{idx},{},{},{},{},{}",
+            edge_id(edge.way_id, *node1, *node2),
+            edge.way_id.0,
+            node1.0,
+            node2.0,
+            edge.length_meters
+        )?;
+    }
+    file.flush()?;
+    timer.stop();
+
+    timer.start("Routing and writing the incidence matrix");
+    let file = BufWriter::new(File::create(format!("{output_directory}/incidence.csv"))?);
+    let num_rows = write_triplets(
+        file,
+        &requests,
+        &prepared_ch,
+        &closest_intersection,
+        network,
+        &edge_index,
+    )?;
+    timer.stop();
+    println!(
+        "Wrote {} nonzero entries for {} requests",
+        HumanCount(num_rows as u64),
+        HumanCount(requests.len() as u64)
+    );
+
+    Ok(())
+}
+
+/// Routes requests in parallel, a chunk at a time, writing a row for every edge crossed. Requests
+/// without a route have no rows. Returns the number of rows written.
+pub fn write_triplets<W: Write>(
+    mut writer: W,
+    requests: &[Request],
+    prepared_ch: &PreparedCH,
+    closest_intersection: &RTree<IntersectionLocation>,
+    network: &Network,
+    edge_index: &EdgeIndex,
+) -> Result<usize> {
+    let progress = utils::progress_bar_for_count(requests.len());
+    let mut num_rows = 0;
+    writeln!(writer, "request,edge,length_meters")?;
+    for (chunk_idx, chunk) in requests.chunks(CHUNK_SIZE).enumerate() {
+        let routes: Vec<Vec<(usize, f64)>> = chunk
+            .par_iter()
+            .map_init(
+                || fast_paths::create_calculator(&prepared_ch.ch),
+                |path_calc, req| {
+                    route_edges(
+                        req,
+                        path_calc,
+                        closest_intersection,
+                        prepared_ch,
+                        network,
+                        edge_index,
+                    )
+                },
+            )
+            .collect();
+        for (idx, route) in routes.into_iter().enumerate() {
+            let request = chunk_idx * CHUNK_SIZE + idx;
+            for (edge, length_meters) in route {
+                writeln!(writer, "{request},{edge},{length_meters}")?;
+                num_rows += 1;
+            }
+        }
+        progress.inc(chunk.len() as u64);
+    }
+    writer.flush()?;
+    Ok(num_rows)
+}
+
+/// Returns the index and length of every edge along the route, in order
+fn route_edges(
+    req: &Request,
+    path_calc: &mut PathCalculator,
+    closest_intersection: &RTree<IntersectionLocation>,
+    prepared_ch: &PreparedCH,
+    network: &Network,
+    edge_index: &EdgeIndex,
+) -> Vec<(usize, f64)> {
+    let start = closest_intersection
+        .nearest_neighbor(&[req.x1, req.y1])
+        .unwrap()
+        .data;
+    let end = closest_intersection
+        .nearest_neighbor(&[req.x2, req.y2])
+        .unwrap()
+        .data;
+    if start == end {
+        return Vec::new();
+    }
+    let Some(path) = prepared_ch.calc_path(path_calc, start, end) else {
+        return Vec::new();
+    };
+
+    let mut edges = Vec::new();
+    for pair in path.windows(2) {
+        let idx = edge_index.get(pair[0], pair[1]).unwrap();
+        let (node1, node2) = edge_index.edges[idx];
+        edges.push((idx, network.edges[&(node1, node2)].length_meters));
+    }
+    edges
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::network::tests::make_network;
+    use crate::router::just_build_ch;
+
+    #[test]
+    fn test_triplets_reconstruct_routes() {
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.001, 0.001),
+            ],
+            &[(1, 2), (2, 3), (2, 4)],
+        );
+        let requests = vec![
+            Request::new(0.0, 0.0, 0.002, 0.0),
+            // Same endpoints, so no route
+            Request::new(0.0, 0.0, 0.0, 0.0),
+            Request::new(0.001, 0.001, 0.0, 0.0),
+        ];
+
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+        let edge_index = EdgeIndex::new(&network);
+
+        let mut output = Vec::new();
+        let num_rows = write_triplets(
+            &mut output,
+            &requests,
+            &prepared_ch,
+            &closest_intersection,
+            &network,
+            &edge_index,
+        )
+        .unwrap();
+        assert_eq!(num_rows, 4);
+
+        let mut routes: Vec<BTreeSet<String>> = vec![BTreeSet::new(); requests.len()];
+        for line in String::from_utf8(output).unwrap().lines().skip(1) {
+            let cells: Vec<&str> = line.split(',').collect();
+            let request: usize = cells[0].parse().unwrap();
+            let (node1, node2) = edge_index.edges[cells[1].parse::<usize>().unwrap()];
+            let length: f64 = cells[2].parse().unwrap();
+            assert_eq!(length, network.edges[&(node1, node2)].length_meters);
+            routes[request].insert(edge_id(network.edges[&(node1, node2)].way_id, node1, node2));
+        }
+
+        let expected = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+        assert_eq!(routes[0], expected(&["1-1-2", "2-2-3"]));
+        assert!(routes[1].is_empty());
+        assert_eq!(routes[2], expected(&["1-1-2", "3-2-4"]));
+    }
+}
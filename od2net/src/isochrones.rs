@@ -0,0 +1,140 @@
+use std::io::BufWriter;
+
+use anyhow::{bail, Result};
+use fs_err::File;
+use geo::{ConvexHull, MultiPoint, Point, Polygon};
+use geojson::{Feature, FeatureWriter, Geometry};
+use indicatif::ParallelProgressIterator;
+use osm_reader::NodeID;
+use rayon::prelude::*;
+
+use super::accessibility::{snap_distinct_origins, Reachable};
+use super::dijkstra::PlainGraph;
+use super::network::{Network, Position};
+use super::requests::Request;
+use super::timer::Timer;
+use super::utils;
+
+/// For each distinct origin of the requests and each cost threshold, writes the area reachable
+/// within that cost as one polygon to `isochrones.geojson`
+pub fn run(
+    network: &Network,
+    requests: Vec<Request>,
+    mut thresholds: Vec<f64>,
+    output_directory: String,
+    timer: &mut Timer,
+) -> Result<()> {
+    if thresholds.is_empty() || thresholds.iter().any(|x| x.is_nan() || *x <= 0.0) {
+        bail!("--isochrones needs positive cost thresholds, like --isochrones=1000,2000,3000");
+    }
+    thresholds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    thresholds.dedup();
+
+    let graph = PlainGraph::new(network);
+    let origins = snap_distinct_origins(network, &requests, timer);
+
+    timer.start("Searching from every origin");
+    let progress = utils::progress_bar_for_count(origins.len());
+    let results: Vec<(Position, NodeID, Vec<Feature>)> = origins
+        .into_par_iter()
+        .progress_with(progress)
+        .map(|(pt, node)| (pt, node, isochrones(&graph, network, node, &thresholds)))
+        .collect();
+    timer.stop();
+
+    timer.start(format!("Writing {output_directory}/isochrones.geojson"));
+    let mut writer = FeatureWriter::from_writer(BufWriter::new(File::create(format!(
+        "{output_directory}/isochrones.geojson"
+    ))?));
+    for (pt, node, features) in results {
+        let (x, y) = pt.to_degrees();
+        // Draw the biggest area first, so the smaller ones are on top
+        for mut feature in features.into_iter().rev() {
+            feature.set_property("x", x);
+            feature.set_property("y", y);
+            feature.set_property("snapped_node", node.0);
+            writer.write_feature(&feature)?;
+        }
+    }
+    writer.finish()?;
+    timer.stop();
+
+    Ok(())
+}
+
+/// One polygon per threshold, in the same order, with the area reachable from `start` within that
+/// cost. The thresholds must be sorted.
+pub fn isochrones(
+    graph: &PlainGraph,
+    network: &Network,
+    start: NodeID,
+    thresholds: &[f64],
+) -> Vec<Feature> {
+    // Search once up to the biggest threshold, then filter for the smaller ones
+    let tree = graph.shortest_path_tree(start, *thresholds.last().unwrap());
+    thresholds
+        .iter()
+        .map(|threshold| {
+            let nodes = tree
+                .iter()
+                .filter(|(_, cost)| **cost <= *threshold)
+                .map(|(node, cost)| (*node, *cost))
+                .collect();
+            let reachable = Reachable::from_tree(graph, network, nodes, *threshold);
+            let mut feature = Feature::from(Geometry::from(&isochrone(network, start, &reachable)));
+            feature.set_property("threshold", *threshold);
+            feature.set_property("reachable_intersections", reachable.nodes.len());
+            feature.set_property("reachable_meters", reachable.total_meters(network));
+            feature
+        })
+        .collect()
+}
+
+/// The convex hull of every reachable edge. When nothing besides the start is reachable, this is
+/// degenerate.
+fn isochrone(network: &Network, start: NodeID, reachable: &Reachable) -> Polygon {
+    let mut points: Vec<Point> = vec![network.intersections[&start].to_degrees().into()];
+    for key in &reachable.edges {
+        points.extend(
+            network.edges[key]
+                .geometry()
+                .iter()
+                .map(|pt| Point::from(pt.to_degrees())),
+        );
+    }
+    MultiPoint::from(points).convex_hull()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::make_network;
+    use geo::{Area, Contains};
+
+    #[test]
+    fn test_isochrone() {
+        // A cross centered on node 1, with arms about 111m and 222m long
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, -0.001, 0.0),
+                (4, 0.0, 0.001),
+                (5, 0.0, -0.001),
+                (6, 0.002, 0.0),
+            ],
+            &[(1, 2), (1, 3), (1, 4), (1, 5), (2, 6)],
+        );
+        let graph = PlainGraph::new(&network);
+
+        let small = Reachable::new(&graph, &network, NodeID(1), 150.0);
+        let small = isochrone(&network, NodeID(1), &small);
+        let big = Reachable::new(&graph, &network, NodeID(1), 250.0);
+        let big = isochrone(&network, NodeID(1), &big);
+
+        assert!(small.unsigned_area() > 0.0);
+        assert!(big.unsigned_area() > small.unsigned_area());
+        assert!(!small.contains(&Point::new(0.0015, 0.0)));
+        assert!(big.contains(&Point::new(0.0015, 0.0)));
+    }
+}
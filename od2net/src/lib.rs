@@ -4,16 +4,39 @@ extern crate anyhow;
 extern crate log;
 
 // TODO Restructure
+pub mod accessibility;
+pub mod alternatives;
+pub mod api;
+pub mod batch;
+pub mod checkpoint;
 pub mod config;
+pub mod cost_raster;
 pub mod detailed_route_output;
+pub mod diff;
+pub mod dijkstra;
+pub mod edge_contributors;
+pub mod incidence;
+pub mod isochrones;
+pub mod many_to_many;
 pub mod network;
 pub mod node_map;
 pub mod od;
+pub mod od_models;
 pub mod plugins;
+pub mod polyline;
+pub mod profile_comparison;
 pub mod requests;
+pub mod route_stats;
 pub mod router;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod server;
+pub mod skims;
 pub mod timer;
 pub mod utils;
+pub mod warnings;
+pub mod zones;
+
+pub use api::{NetworkBuilder, Router, UptakeModel};
 
 use indicatif::HumanCount;
 use instant::Duration;
@@ -31,13 +54,21 @@ pub struct OutputMetadata {
     pub num_succeeded_requests: usize,
     pub num_failed_requests_same_endpoints: usize,
     pub num_failed_requests_no_path: usize,
+    pub num_failed_requests_far_snap: usize,
+    pub num_dropped_requests_same_endpoints: usize,
+    pub num_zero_distance_requests_same_endpoints: usize,
     pub num_edges_with_count: usize,
+    pub num_nodes_with_count: usize,
     pub routing_time_seconds: f32,
     pub total_meters_not_allowed: f64,
     pub total_meters_lts1: f64,
     pub total_meters_lts2: f64,
     pub total_meters_lts3: f64,
     pub total_meters_lts4: f64,
+    /// Distributions of route length, circuity, and LTS, for sanity-checking the run
+    pub route_stats: route_stats::RouteStats,
+    /// Problems from building the network and routing
+    pub warnings: warnings::Warnings,
     // These two aren't recorded in the GeoJSON or PMTiles output, because we'd have to go back and
     // update the files!
     pub total_time_seconds: Option<f32>,
@@ -51,15 +82,38 @@ impl OutputMetadata {
         num_requests: usize,
         routing_time: Duration,
     ) -> Self {
+        let mut warnings = warnings::Warnings::default();
+        warnings.add(
+            warnings::WarningCategory::RejectedSnaps,
+            "Requests had both ends snapped to the same intersection",
+            counts.errors_same_endpoints.len() + counts.num_same_endpoints_dropped,
+        );
+        warnings.add(
+            warnings::WarningCategory::NoPath,
+            "Requests had no route",
+            counts.errors_no_path.len(),
+        );
+        warnings.add(
+            warnings::WarningCategory::RejectedSnaps,
+            "Requests had an endpoint snapped farther than max_snap_meters",
+            counts.errors_far_snap.len(),
+        );
+
         Self {
             config,
             num_origins: counts.count_per_origin.len(),
             num_destinations: counts.count_per_destination.len(),
             num_requests,
-            num_succeeded_requests: num_requests - counts.num_errors(),
+            num_succeeded_requests: num_requests
+                - counts.num_errors()
+                - counts.num_same_endpoints_dropped,
             num_failed_requests_same_endpoints: counts.errors_same_endpoints.len(),
             num_failed_requests_no_path: counts.errors_no_path.len(),
+            num_failed_requests_far_snap: counts.errors_far_snap.len(),
+            num_dropped_requests_same_endpoints: counts.num_same_endpoints_dropped,
+            num_zero_distance_requests_same_endpoints: counts.num_same_endpoints_zero_distance,
             num_edges_with_count: counts.count_per_edge.len(),
+            num_nodes_with_count: counts.count_per_node.len(),
             routing_time_seconds: routing_time.as_secs_f32(),
             total_time_seconds: None,
             tippecanoe_time_seconds: None,
@@ -68,6 +122,8 @@ impl OutputMetadata {
             total_meters_lts2: counts.total_distance_by_lts[LTS::LTS2 as u8 as usize],
             total_meters_lts3: counts.total_distance_by_lts[LTS::LTS3 as u8 as usize],
             total_meters_lts4: counts.total_distance_by_lts[LTS::LTS4 as u8 as usize],
+            route_stats: route_stats::RouteStats::new(&counts.route_histograms),
+            warnings,
         }
     }
 
@@ -86,7 +142,20 @@ impl OutputMetadata {
                 "Requests (failed because no path)",
                 self.num_failed_requests_no_path,
             ),
+            (
+                "Requests (failed because snapped too far)",
+                self.num_failed_requests_far_snap,
+            ),
+            (
+                "Requests (skipped because same endpoints)",
+                self.num_dropped_requests_same_endpoints,
+            ),
+            (
+                "Requests (zero distance because same endpoints)",
+                self.num_zero_distance_requests_same_endpoints,
+            ),
             ("Edges with a count", self.num_edges_with_count),
+            ("Intersections with a count", self.num_nodes_with_count),
         ] {
             println!("- {label}: {}", HumanCount(count as u64));
         }
@@ -101,5 +170,26 @@ impl OutputMetadata {
             let km = meters / 1000.0;
             println!("- Total distance on {label}: {km:.1} km");
         }
+        if let Some(length) = self.route_stats.length_meters {
+            println!(
+                "- Route length: median {:.1} km, 90th percentile {:.1} km",
+                length.median / 1000.0,
+                length.p90 / 1000.0
+            );
+        }
+        if let Some(circuity) = self.route_stats.circuity {
+            println!(
+                "- Circuity: median {:.2}, 90th percentile {:.2}",
+                circuity.median, circuity.p90
+            );
+        }
+        for warning in &self.warnings.0 {
+            println!(
+                "- Warning ({:?}): {} ({})",
+                warning.category,
+                warning.message,
+                HumanCount(warning.count as u64)
+            );
+        }
     }
 }
@@ -1,4 +1,5 @@
-use std::io::BufWriter;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufWriter, Write};
 use std::process::Command;
 
 use anyhow::{bail, Result};
@@ -7,14 +8,35 @@ use fs_err::File;
 use indicatif::HumanCount;
 use instant::Instant;
 
+use od2net::config::OutputCompression;
+use od2net::network::CountArea;
+use od2net::NetworkBuilder;
+
+/// Checked in order in the input directory
+const OSM_INPUTS: [&str; 5] = [
+    "input.osm.pbf",
+    "input.osm.xml",
+    "input.osm",
+    "input.osm.xml.gz",
+    "input.osm.gz",
+];
+
 #[derive(Parser)]
-#[clap(about, version, author)]
+#[clap(about, version, author, subcommand_negates_reqs = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Action>,
     /// The path to a JSON file representing an InputConfig
-    config_path: String,
+    #[clap(required = true)]
+    config_path: Option<String>,
     /// Specify a random number seed, used only for some generated request patterns, like BetweenZones.
     #[clap(long, default_value_t = 42)]
     rng_seed: u64,
+    /// Save the counts so far to `output/checkpoint.bin` after routing every this many requests.
+    /// If the run is interrupted, running again with the same inputs and config resumes from the
+    /// last checkpoint.
+    #[clap(long)]
+    checkpoint_every: Option<usize>,
 
     /// Don't output a CSV file with each edge's counts.
     #[clap(long)]
@@ -22,9 +44,20 @@ struct Args {
     /// Don't output a GeoJSON file with failed requests.
     #[clap(long)]
     no_output_failed_requests: bool,
-    /// Don't output origin and destination points in the GeoJSON output, to reduce file size.
+    /// Don't output a GeoJSON file with the number of routes passing through each intersection.
+    #[clap(long)]
+    no_output_node_counts: bool,
+    /// Create `output/movements.geojson` with the number of routes making each turning movement
+    /// at each intersection.
+    #[clap(long)]
+    output_movements: bool,
+    /// Don't output a GeoJSON file with the number of successful routes starting at each origin.
     #[clap(long)]
-    no_output_od_points: bool,
+    no_output_origins: bool,
+    /// Don't output a GeoJSON file with the number of successful routes ending at each
+    /// destination.
+    #[clap(long)]
+    no_output_destinations: bool,
     /// Don't output OSM tags in the GeoJSON output, to reduce file size.
     #[clap(long)]
     no_output_osm_tags: bool,
@@ -32,10 +65,59 @@ struct Args {
     /// web app.
     #[clap(long)]
     no_output_pmtiles: bool,
+    /// Also write the tiles to `output/rnet.mbtiles`, for tile servers that need MBTiles, like
+    /// TileServer GL. Unless `--no-output-pmtiles` is set, this needs `tile-join` from
+    /// tippecanoe.
+    #[clap(long)]
+    output_mbtiles: bool,
+    /// Don't write `output/output.geojson`. Unless `--no-output-pmtiles` is also set, the edges
+    /// are piped straight into tippecanoe instead, which is faster and saves disk space.
+    #[clap(long)]
+    no_output_geojson: bool,
+
+    /// Also write the edges with counts to `output/output.fgb`, a FlatGeobuf file with a spatial
+    /// index that loads much faster than GeoJSON in QGIS.
+    #[clap(long)]
+    output_flatgeobuf: bool,
+
+    /// Also write the edges with counts to `output/output.parquet`, a GeoParquet file for loading
+    /// into DuckDB, GeoPandas, or sf.
+    #[clap(long)]
+    output_geoparquet: bool,
+
+    /// Also write `output/output.gpkg`, a GeoPackage with separate `edges`, `origins`, and
+    /// `destinations` layers.
+    #[clap(long)]
+    output_geopackage: bool,
+
+    /// Write newline-delimited GeoJSON to `output/output.geojsonl` instead of
+    /// `output/output.geojson`, with one feature per line. It can be streamed, and tippecanoe
+    /// reads it in parallel.
+    #[clap(long)]
+    output_geojson_seq: bool,
 
     /// Create an `output/metadata.json` file summarizing the run.
     #[clap(long)]
     output_metadata: bool,
+    /// Create an `output/route_summaries.csv` file with the length, circuity, and LTS of the route
+    /// for every successful request.
+    #[clap(long)]
+    output_route_summaries: bool,
+    /// Create `output/zones.geojson` and `output/zones.csv` with the trips starting, ending, and
+    /// passing through each zone, and the distance cycled there by LTS. The requests must come
+    /// from a pattern with zones.
+    #[clap(long)]
+    output_zones: bool,
+    /// Create `output/h3.geojson`, summarizing counts over H3 hexagons at this resolution, from 0
+    /// (the biggest) to 15.
+    #[clap(long)]
+    output_h3: Option<u8>,
+    /// Create `output/heatmap.tif`, a GeoTIFF with the counts of the edges in each cell.
+    #[clap(long)]
+    output_heatmap: bool,
+    /// Create `output/ways.geojson`, with counts summarized per OSM way instead of per segment.
+    #[clap(long)]
+    output_ways: bool,
 
     // TODO These two should maybe be subcommands
     /// Instead of running normally, instead calculate this many routes and write a separate
@@ -43,6 +125,71 @@ struct Args {
     /// lots of disk if you specify a large number.
     #[clap(long)]
     detailed_routes: Option<usize>,
+    /// With `--detailed-routes`, write route geometry as encoded polylines instead of GeoJSON
+    /// coordinates.
+    #[clap(long)]
+    encode_polylines: bool,
+    /// With `--detailed-routes`, merge the routes into one `output/overline.geojson` file, with
+    /// one LineString per segment, instead of writing a file for each route.
+    #[clap(long)]
+    overline: bool,
+    /// With `--detailed-routes`, also write each route as a GPX track. Heights come from the
+    /// elevation data in the config, if any.
+    #[clap(long, requires = "detailed_routes")]
+    gpx: bool,
+    /// The number of decimal places to use with `--encode-polylines`
+    #[clap(long, default_value_t = 5)]
+    polyline_precision: u32,
+
+    /// Instead of running normally, find the OD pairs whose routes cross one edge, and write the
+    /// biggest contributors to its count to `output/edge_contributors.csv`. The edge is specified
+    /// as an OSM way ID, or as two OSM node IDs separated by a comma.
+    #[clap(long)]
+    edge_contributors: Option<String>,
+    /// How many OD pairs to write with `--edge-contributors`
+    #[clap(long, default_value_t = 100)]
+    top_contributors: usize,
+
+    /// Instead of running normally, route every request with each of the cost functions in
+    /// `profiles` from the config, and write `output/profile_comparison.csv` with the route length
+    /// and LTS exposure per profile.
+    #[clap(long)]
+    compare_profiles_per_od: bool,
+    /// Instead of running normally, calculate counts with two of the `profiles` from the config,
+    /// specified as `before,after`. Writes `output/profile_delta.geojson` with the change in count
+    /// and LTS exposure for every edge.
+    #[clap(long)]
+    profile_delta: Option<String>,
+
+    /// Instead of running normally, write the edges crossed by every route as a sparse matrix.
+    /// `output/incidence.csv` has `request,edge,length_meters` rows, and
+    /// `output/incidence_edges.csv` describes each edge index.
+    #[clap(long)]
+    export_incidence: bool,
+
+    /// Instead of running normally, write `output/skims.csv` with the distance, cost, elevation
+    /// gain, and highest LTS of the route for every request.
+    #[clap(long)]
+    skims: bool,
+
+    /// Instead of running normally, find everything reachable from each distinct request origin
+    /// within this cost budget. Writes `output/accessibility.csv` with a summary per origin, and
+    /// `output/accessibility.geojson` with every reachable edge and how many origins reach it.
+    #[clap(long)]
+    accessibility_budget: Option<f64>,
+
+    /// Instead of running normally, write `output/isochrones.geojson` with the area reachable from
+    /// each distinct request origin within each of these comma-separated cost thresholds.
+    #[clap(long, value_delimiter = ',')]
+    isochrones: Vec<f64>,
+
+    /// Instead of running normally, write `output/cost_raster.tif`, a GeoTIFF with this metric
+    /// for the edges in each cell. The metric is `cost-per-meter`, `lts`, or `quietness`.
+    #[clap(long)]
+    cost_raster: Option<String>,
+    /// The approximate width of each cell with `--cost-raster` and `--output-heatmap`
+    #[clap(long, default_value_t = 50.0)]
+    raster_resolution_meters: f64,
 
     /// Instead of running normally, just write a `network.geojson` with the OSM tags, LTS, and
     /// cost for every edge in a network. No counts are calculated or included.
@@ -50,62 +197,323 @@ struct Args {
     dump_network: bool,
 }
 
+#[derive(clap::Subcommand)]
+enum Action {
+    /// Run several configs one after another, like `od2net batch */config.json`, and write a
+    /// summary of all of them. Options for every config go before `batch`. Networks and GeoTIFFs
+    /// are shared between configs where possible.
+    Batch {
+        /// The paths to JSON files representing InputConfigs
+        #[clap(required = true)]
+        config_paths: Vec<String>,
+        /// Where to write a CSV file with one row per config and scenario
+        #[clap(long, default_value = "batch_summary.csv")]
+        summary: String,
+    },
+    /// Load the network and CH once, then answer routing queries over HTTP. See
+    /// `docs/server.md` for the endpoints.
+    Serve {
+        /// The path to a JSON file representing an InputConfig
+        config_path: String,
+        /// The address and port to listen on
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        address: String,
+        /// The most requests allowed in one `POST /od` query
+        #[clap(long, default_value_t = 10_000)]
+        max_od_requests: usize,
+    },
+    /// Compare the counts from two runs, like `od2net diff base/output current/output`, joining
+    /// edges by `edge_id`. Writes `diff.geojson`, `diff_summary.json`, and `diff.pmtiles` (unless
+    /// `--no-output-pmtiles` goes before `diff`).
+    Diff {
+        /// The output directory of the run to compare against
+        base: String,
+        /// The output directory of the changed run
+        current: String,
+        /// Where to write the diff. Defaults to the current run's output directory.
+        #[clap(long)]
+        output_directory: Option<String>,
+    },
+}
+
+/// Inputs loaded while running one config that later configs in a batch can reuse
+#[derive(Default)]
+struct SharedInputs {
+    /// Where networks were saved, by `network_cache_key`
+    networks: HashMap<u32, String>,
+    geotiffs: od2net::network::GeoTiffFiles,
+}
+
+impl SharedInputs {
+    /// If an earlier config saved a network with the same cache key somewhere else, copies it to
+    /// `bin_path` and loads it, instead of building it again
+    fn reuse_network(
+        &self,
+        cache_key: u32,
+        bin_path: &str,
+    ) -> Result<Option<od2net::network::Network>> {
+        let Some(path) = self.networks.get(&cache_key) else {
+            return Ok(None);
+        };
+        if path == bin_path {
+            return Ok(None);
+        }
+        // Another config in the same directory might've overwritten it since
+        let Some(network) = od2net::network::Network::load_from_bin(path)
+            .ok()
+            .filter(|network| network.cache_key == cache_key)
+        else {
+            return Ok(None);
+        };
+        println!("Reusing the network from {path}");
+        fs_err::copy(path, bin_path)?;
+        Ok(Some(network))
+    }
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
-    let config_json = fs_err::read_to_string(&args.config_path)?;
+    let config_path = match args.command {
+        Some(Action::Batch {
+            ref config_paths,
+            ref summary,
+        }) => {
+            return run_batch(&args, config_paths, summary);
+        }
+        Some(Action::Diff {
+            ref base,
+            ref current,
+            ref output_directory,
+        }) => {
+            return run_diff(
+                &args,
+                base,
+                current,
+                output_directory.as_deref().unwrap_or(current),
+            );
+        }
+        Some(Action::Serve {
+            ref config_path, ..
+        }) => config_path,
+        None => args.config_path.as_ref().unwrap(),
+    };
+    run(&args, config_path, None, &mut SharedInputs::default())?;
+    Ok(())
+}
+
+/// Runs every config in order, even if some fail, then writes a summary
+fn run_batch(args: &Args, config_paths: &[String], summary_path: &str) -> Result<()> {
+    let mut absolute_paths = Vec::new();
+    for path in config_paths {
+        let absolute_path = std::fs::canonicalize(path)?;
+        if absolute_paths.contains(&absolute_path) {
+            bail!("{path} is in the batch more than once");
+        }
+        absolute_paths.push(absolute_path);
+    }
+    let output_names = od2net::batch::output_names(&absolute_paths);
+
+    let mut shared = SharedInputs::default();
+    let mut rows = Vec::new();
+    let mut num_failed = 0;
+    for (idx, (config_path, output_name)) in config_paths.iter().zip(output_names).enumerate() {
+        println!(
+            "Running config {} of {}: {config_path}",
+            idx + 1,
+            config_paths.len()
+        );
+        match run(args, config_path, output_name.as_deref(), &mut shared) {
+            Ok(results) => {
+                for (output_directory, output_metadata) in results {
+                    rows.push(od2net::batch::BatchSummaryRow::new(
+                        config_path,
+                        &output_directory,
+                        &output_metadata,
+                    ));
+                }
+            }
+            Err(err) => {
+                println!("{config_path} failed: {err:#}");
+                rows.push(od2net::batch::BatchSummaryRow::failed(config_path, &err));
+                num_failed += 1;
+            }
+        }
+        println!();
+    }
+
+    od2net::batch::write_summary(summary_path, &rows)?;
+    println!("Wrote a summary of the batch to {summary_path}");
+    if num_failed > 0 {
+        bail!(
+            "{num_failed} of {} configs failed; see {summary_path}",
+            config_paths.len()
+        );
+    }
+    Ok(())
+}
+
+fn run_diff(args: &Args, base: &str, current: &str, output_directory: &str) -> Result<()> {
+    let mut timer = od2net::timer::Timer::new();
+    fs_err::create_dir_all(output_directory)?;
+    let summary = od2net::diff::run(base, current, output_directory, &mut timer)?;
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+
+    if !args.no_output_pmtiles {
+        timer.start("Converting to tiles for rendering");
+        let mut cmd = Command::new("tippecanoe");
+        cmd.arg("-o")
+            .arg(format!("{output_directory}/diff.pmtiles"))
+            .arg("--force") // Overwrite existing output
+            .args(od2net::config::PmtilesOptions::default().tippecanoe_args())
+            .arg("--description")
+            .arg(serde_json::to_string(&summary)?)
+            .arg("-l")
+            .arg("diff")
+            .arg(format!("{output_directory}/diff.geojson"));
+        println!("Running: {cmd:?}");
+        if !cmd.status()?.success() {
+            bail!("tippecanoe failed");
+        }
+        timer.stop();
+    }
+    Ok(())
+}
+
+/// Runs the whole pipeline for one config, returning the output directory and metadata for every
+/// scenario routed. The outputs go in `output/`, or `output/<output_name>/` if that's set.
+fn run(
+    args: &Args,
+    config_path: &str,
+    output_name: Option<&str>,
+    shared: &mut SharedInputs,
+) -> Result<Vec<(String, od2net::OutputMetadata)>> {
+    let config_json = fs_err::read_to_string(config_path)?;
     let mut config: od2net::config::InputConfig = match serde_json::from_str(&config_json) {
         Ok(config) => config,
-        Err(err) => panic!("{} is invalid: {err}", args.config_path),
+        Err(err) => bail!("{config_path} is invalid: {err}"),
     };
     println!(
-        "Using config from {}:\n{}\n",
-        args.config_path,
+        "Using config from {config_path}:\n{}\n",
         serde_json::to_string_pretty(&config)?
     );
 
     // Assume the config file is in the directory for the area
-    let absolute_path = std::fs::canonicalize(&args.config_path).unwrap();
-    let directory = absolute_path.parent().unwrap().display();
+    let absolute_path = std::fs::canonicalize(config_path)?;
+    let directory = absolute_path.parent().unwrap().display().to_string();
+    let output = match output_name {
+        Some(name) => format!("{directory}/output/{name}"),
+        None => format!("{directory}/output"),
+    };
     fs_err::create_dir_all(format!("{directory}/intermediate"))?;
-    fs_err::create_dir_all(format!("{directory}/output"))?;
+    fs_err::create_dir_all(&output)?;
+    config.load_uptake_tables(&directory)?;
+    config.collect.route_summaries = args.output_route_summaries;
+    config.collect.movements = args.output_movements;
 
     let mut timer = od2net::timer::Timer::new();
     let pipeline_start = Instant::now();
 
+    // Problems found in this run, besides the ones from building the network or routing
+    let mut warnings = od2net::warnings::Warnings::default();
+
     timer.start("Load network");
-    let network = {
-        let bin_path = format!("{directory}/intermediate/network.bin");
-        let osm_pbf_path = format!("{directory}/input/input.osm.pbf");
-        let osm_xml_path = format!("{directory}/input/input.osm.xml");
+    let bin_path = format!("{directory}/intermediate/network.bin");
+    let cache_key = network_cache_key(&config, &directory);
+    let mut network = {
         println!("Trying to load network from {bin_path}");
         // TODO timer around something fallible is annoying
-        match od2net::network::Network::load_from_bin(&bin_path) {
+        match od2net::network::Network::load_from_bin(&bin_path)
+            .and_then(|network| {
+                if network.cache_key != cache_key {
+                    bail!("it was built from a different config or input files");
+                }
+                Ok(network)
+            })
+            .or_else(|err| shared.reuse_network(cache_key, &bin_path)?.ok_or(err))
+        {
             Ok(network) => network,
             Err(err) => {
-                // The input is usually PBF, but could be XML
-                let osm_path = if fs_err::metadata(&osm_pbf_path).is_ok() {
-                    osm_pbf_path
+                if fs_err::metadata(&bin_path).is_ok() {
+                    warnings.add(
+                        od2net::warnings::WarningCategory::StaleCache,
+                        format!("Couldn't load {bin_path} ({err}), so regenerated it"),
+                        1,
+                    );
+                }
+                let network = if let Some(columns) = config.geojson_network.clone() {
+                    let path = config.resolve_path(&directory, &columns.path);
+                    println!("That failed ({err}), so generating it from {path}");
+                    let builder = NetworkBuilder::from_config(&config);
+                    if path.ends_with(".gpkg") {
+                        builder.build_from_geopackage(&path, &columns, &mut timer)?
+                    } else {
+                        builder.build_from_geojson(&fs_err::read(path)?, &columns, &mut timer)?
+                    }
                 } else {
-                    osm_xml_path
-                };
+                    let osm_paths = if let Some(ref paths) = config.osm_inputs {
+                        if config.osm.is_some() {
+                            bail!("Only one of osm_inputs and osm can be set");
+                        }
+                        paths
+                            .iter()
+                            .map(|path| config.resolve_path(&directory, path))
+                            .collect()
+                    } else if let Some(ref osm) = config.osm {
+                        let path = format!("{directory}/input/{}", osm.filename());
+                        if fs_err::metadata(&path).is_err() {
+                            timer.start(format!("Downloading {}", osm.url()));
+                            download(&osm.url(), &path)?;
+                            timer.stop();
+                        }
+                        vec![path]
+                    } else {
+                        // The input is usually PBF, but could be XML, maybe gzipped
+                        let Some(osm_path) = OSM_INPUTS
+                            .iter()
+                            .map(|filename| format!("{directory}/input/{filename}"))
+                            .find(|path| fs_err::metadata(path).is_ok())
+                        else {
+                            bail!(
+                                "No OSM input found; put one of {} in {directory}/input/",
+                                OSM_INPUTS.join(", ")
+                            );
+                        };
+                        vec![osm_path]
+                    };
 
-                println!("That failed ({err}), so generating it from {osm_path}");
-                let geotiff_bytes = if let Some(ref filename) = config.elevation_geotiff {
-                    Some(fs_err::read(&format!("{directory}/input/{filename}"))?)
-                } else {
-                    None
+                    println!(
+                        "That failed ({err}), so generating it from {}",
+                        osm_paths.join(", ")
+                    );
+                    let mut builder = NetworkBuilder::from_config(&config);
+                    if let Some(elevation) = elevation_source(&config, &directory, shared)? {
+                        builder = builder.elevation(elevation);
+                    }
+                    if let Some(ref path) = config.clip_boundary {
+                        builder = builder.clip_boundary(od2net::od::load_area(
+                            &config.resolve_path(&directory, path),
+                        )?);
+                    }
+                    // Memory-map the inputs instead of reading them, so country-scale files don't
+                    // need to fit in RAM alongside the network being built
+                    let osm_inputs = osm_paths
+                        .iter()
+                        .map(|path| {
+                            let file = File::open(path)?;
+                            // Safety: the input files aren't expected to change while this runs
+                            unsafe { memmap2::Mmap::map(file.file()) }
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    builder.build_from_osm(
+                        &osm_inputs.iter().map(|x| &x[..]).collect::<Vec<_>>(),
+                        &mut timer,
+                    )?
                 };
 
-                let network = od2net::network::Network::make_from_osm(
-                    &fs_err::read(osm_path)?,
-                    &config.lts,
-                    &mut config.cost,
-                    &mut timer,
-                    geotiff_bytes,
-                )?;
-
+                let mut network = network;
+                network.cache_key = cache_key;
                 timer.start(format!("Saving to {bin_path}"));
-                let writer = BufWriter::new(File::create(bin_path)?);
+                let writer = BufWriter::new(File::create(&bin_path)?);
                 bincode::serialize_into(writer, &network)?;
                 timer.stop();
 
@@ -113,18 +521,56 @@ fn main() -> Result<()> {
             }
         }
     };
+    shared.networks.insert(cache_key, bin_path);
     timer.stop();
+    // These aren't saved with the network, so apply them every run
+    od2net::api::prepare_network(
+        &mut network,
+        &mut config,
+        &directory,
+        &mut shared.geotiffs,
+        &mut timer,
+    )?;
 
     if args.dump_network {
         println!("Dumping network to network.geojson");
         fs_err::write("network.geojson", &network.to_debug_geojson()?)?;
-        return Ok(());
+        return Ok(Vec::new());
+    }
+
+    if let Some(ref metric) = args.cost_raster {
+        od2net::cost_raster::run(
+            &network,
+            od2net::cost_raster::RasterMetric::parse(metric)?,
+            args.raster_resolution_meters,
+            format!("{output}/cost_raster.tif"),
+            &mut timer,
+        )?;
+        return Ok(Vec::new());
+    }
+
+    if let Some(Action::Serve {
+        ref address,
+        max_od_requests,
+        ..
+    }) = args.command
+    {
+        let prepared_ch = od2net::router::build_ch(
+            &format!("{directory}/intermediate/ch.bin"),
+            &network,
+            &mut timer,
+        )?;
+        let mut server =
+            od2net::server::Server::new(network, config, prepared_ch, max_od_requests, &mut timer)?;
+        drop(timer);
+        server.run(address)?;
+        return Ok(Vec::new());
     }
 
     timer.start("Loading or generating requests");
     let requests = od2net::od::generate_requests(
         &config.requests,
-        format!("{directory}/input"),
+        config.input_directory(&directory),
         &network,
         args.rng_seed,
         &mut timer,
@@ -134,107 +580,562 @@ fn main() -> Result<()> {
     timer.stop();
 
     if let Some(num_routes) = args.detailed_routes {
-        return od2net::detailed_route_output::run(
+        od2net::detailed_route_output::run(
             num_routes,
             &format!("{directory}/intermediate/ch.bin"),
             &network,
             requests,
             &config.uptake,
-            format!("{directory}/output/"),
+            format!("{output}/"),
+            args.encode_polylines.then_some(args.polyline_precision),
+            args.overline,
+            args.gpx,
+            if args.gpx {
+                elevation_source(&config, &directory, shared)?
+            } else {
+                None
+            },
+            &mut timer,
+        )?;
+        return Ok(Vec::new());
+    }
+
+    if args.compare_profiles_per_od {
+        od2net::profile_comparison::run(
+            &config.profiles,
+            &mut network,
+            requests,
+            format!("{directory}/intermediate"),
+            format!("{output}/profile_comparison.csv"),
+            &mut timer,
+        )?;
+        return Ok(Vec::new());
+    }
+
+    if let Some(ref profiles) = args.profile_delta {
+        let Some((before, after)) = profiles.split_once(',') else {
+            bail!("--profile-delta needs two profile names separated by a comma");
+        };
+        od2net::profile_comparison::run_edge_delta(
+            before.trim(),
+            after.trim(),
+            &config,
+            &mut network,
+            requests,
+            format!("{directory}/intermediate"),
+            format!("{output}/profile_delta.geojson"),
+            &mut timer,
+        )?;
+        return Ok(Vec::new());
+    }
+
+    if args.export_incidence {
+        od2net::incidence::run(
+            &format!("{directory}/intermediate/ch.bin"),
+            &network,
+            requests,
+            output,
+            &mut timer,
+        )?;
+        return Ok(Vec::new());
+    }
+
+    if args.skims {
+        od2net::skims::run(
+            &format!("{directory}/intermediate/ch.bin"),
+            &network,
+            requests,
+            &config,
+            output,
+            &mut timer,
+        )?;
+        return Ok(Vec::new());
+    }
+
+    if let Some(budget) = args.accessibility_budget {
+        od2net::accessibility::run(&network, requests, budget, output, &mut timer)?;
+        return Ok(Vec::new());
+    }
+
+    if !args.isochrones.is_empty() {
+        od2net::isochrones::run(
+            &network,
+            requests,
+            args.isochrones.clone(),
+            output,
+            &mut timer,
+        )?;
+        return Ok(Vec::new());
+    }
+
+    if let Some(ref edge) = args.edge_contributors {
+        od2net::edge_contributors::run(
+            od2net::edge_contributors::EdgeQuery::parse(edge)?,
+            args.top_contributors,
+            &format!("{directory}/intermediate/ch.bin"),
+            &network,
+            requests,
+            &config.uptake,
+            format!("{output}/edge_contributors.csv"),
             &mut timer,
+        )?;
+        return Ok(Vec::new());
+    }
+
+    let count_area = if let Some(ref path) = config.count_area {
+        timer.start("Finding edges in the count area");
+        let area = od2net::od::load_area(&config.resolve_path(&directory, path))?;
+        let count_area = CountArea::new(network.edges_in_area(&area));
+        println!(
+            "{} edges are in the count area",
+            HumanCount(count_area.num_edges() as u64)
         );
+        timer.stop();
+        Some(count_area)
+    } else {
+        None
+    };
+
+    let zones = if args.output_zones {
+        let Some(path) = config.requests.pattern.zones_path() else {
+            bail!("--output-zones needs a requests pattern with zones_path");
+        };
+        let path = config.resolve_path(&directory, path);
+        timer.start(format!("Matching edges to zones from {path}"));
+        let zones = od2net::zones::ZoneIndex::load(&path, &network)?;
+        timer.stop();
+        Some(zones)
+    } else {
+        None
+    };
+
+    let mut results = Vec::new();
+    if config.scenarios.is_empty() {
+        results.push(route_and_write(
+            args,
+            &format!("{directory}/intermediate/ch.bin"),
+            output,
+            &network,
+            requests,
+            config,
+            count_area.as_ref(),
+            zones.as_ref(),
+            warnings,
+            &mut timer,
+        )?);
+    } else {
+        // The network's LTS currently comes from this, and only needs recalculating when a
+        // scenario changes it
+        let mut network_lts = config.lts.clone();
+        let mut names = HashSet::new();
+        for scenario in &config.scenarios {
+            if scenario.name.is_empty() || !names.insert(&scenario.name) {
+                bail!("Scenario names must be non-empty and unique");
+            }
+        }
+        for scenario in config.scenarios.clone() {
+            timer.start(format!("Scenario {}", scenario.name));
+            let mut scenario_config = config.clone();
+            scenario_config.scenarios.clear();
+            if let Some(cost) = scenario.cost {
+                scenario_config.cost = cost;
+            }
+            if let Some(lts) = scenario.lts {
+                scenario_config.lts = lts;
+            }
+            if let Some(uptake) = scenario.uptake {
+                scenario_config.uptake = uptake;
+            }
+
+            if scenario_config.lts != network_lts {
+                if config.geojson_network.is_some() {
+                    bail!(
+                        "Scenario {} can't change lts with geojson_network",
+                        scenario.name
+                    );
+                }
+                timer.start("Recalculate LTS");
+                network.recalculate_lts(&scenario_config.lts);
+                if let Some(ref traffic_counts) = scenario_config.traffic_counts {
+                    let path = scenario_config.resolve_path(&directory, &traffic_counts.path);
+                    network.match_traffic_counts(&path, traffic_counts, &mut timer)?;
+                }
+                network_lts = scenario_config.lts.clone();
+                timer.stop();
+            }
+            timer.start("Recalculate cost");
+            network.recalculate_cost(&mut scenario_config.cost)?;
+            timer.stop();
+
+            let output_directory = format!("{output}/{}", scenario.name);
+            fs_err::create_dir_all(&output_directory)?;
+            results.push(route_and_write(
+                args,
+                &format!("{directory}/intermediate/ch_{}.bin", scenario.name),
+                output_directory,
+                &network,
+                requests.clone(),
+                scenario_config,
+                count_area.as_ref(),
+                zones.as_ref(),
+                warnings.clone(),
+                &mut timer,
+            )?);
+            timer.stop();
+        }
+    }
+
+    let total_time = Instant::now().duration_since(pipeline_start).as_secs_f32();
+    drop(timer);
+    for (output_directory, output_metadata) in &mut results {
+        output_metadata.total_time_seconds = Some(total_time);
+        println!("");
+        println!("Results in {output_directory}:");
+        output_metadata.describe();
+
+        if args.output_metadata {
+            let mut file = fs_err::File::create(format!("{output_directory}/metadata.json"))?;
+            serde_json::to_writer(&mut file, output_metadata)?;
+        }
     }
 
+    Ok(results)
+}
+
+/// Routes all requests with one config, then writes the outputs to `output_directory`
+#[allow(clippy::too_many_arguments)]
+fn route_and_write(
+    args: &Args,
+    ch_path: &str,
+    output_directory: String,
+    network: &od2net::network::Network,
+    requests: Vec<od2net::requests::Request>,
+    config: od2net::config::InputConfig,
+    count_area: Option<&CountArea>,
+    zones: Option<&od2net::zones::ZoneIndex>,
+    mut warnings: od2net::warnings::Warnings,
+    timer: &mut od2net::timer::Timer,
+) -> Result<(String, od2net::OutputMetadata)> {
+    let compression = config.output_compression;
+    let make_tiles = !args.no_output_pmtiles || args.output_mbtiles;
+    // Check before spending a long time routing
+    if compression == Some(OutputCompression::Zstd) && make_tiles && !args.no_output_geojson {
+        bail!("tippecanoe can't read zstd-compressed GeoJSON. Use gzip, or pass --no-output-geojson to pipe output into tippecanoe directly.");
+    }
+
+    let num_requests = requests.len();
     timer.start("Routing");
     let routing_start = Instant::now();
+    let checkpoints =
+        args.checkpoint_every
+            .map(|every_n_requests| od2net::checkpoint::Checkpoints {
+                path: format!("{output_directory}/checkpoint.bin"),
+                every_n_requests,
+            });
     let counts = od2net::router::run(
-        &format!("{directory}/intermediate/ch.bin"),
-        &network,
+        ch_path,
+        network,
         requests,
-        &config.uptake,
-        &mut timer,
+        &config,
+        count_area,
+        zones,
+        checkpoints.as_ref(),
+        timer,
     )?;
     println!(
         "Got counts for {} edges",
         HumanCount(counts.count_per_edge.len() as u64),
     );
     println!(
-        "{} succeeded, and {} failed",
-        HumanCount(num_requests as u64 - counts.num_errors() as u64),
+        "{} succeeded, {} failed, and {} were skipped",
+        HumanCount((num_requests - counts.num_errors() - counts.num_same_endpoints_dropped) as u64),
         HumanCount(counts.num_errors() as u64),
+        HumanCount(counts.num_same_endpoints_dropped as u64),
     );
     let routing_time = Instant::now().duration_since(routing_start);
     timer.stop();
 
     if !args.no_output_csv {
         timer.start("Writing output CSV");
-        network.write_csv(&format!("{directory}/output/counts.csv"), &counts)?;
+        network.write_csv(
+            &OutputCompression::path(compression, &format!("{output_directory}/counts.csv")),
+            &counts,
+            config.sort_output,
+            compression,
+        )?;
         timer.stop();
     }
 
     if !args.no_output_failed_requests {
         timer.start("Writing failed requests GJ");
         write_failed_requests(
-            format!("{directory}/output/failed_requests.geojson"),
+            format!("{output_directory}/failed_requests.geojson"),
+            &counts,
+        )?;
+        timer.stop();
+
+        if let Some(max_snap_meters) = config.max_snap_meters {
+            timer.start("Writing rejected snaps GJ");
+            write_far_snaps(
+                format!("{output_directory}/far_snaps.geojson"),
+                &counts,
+                max_snap_meters,
+            )?;
+            timer.stop();
+        }
+    }
+
+    if args.output_route_summaries {
+        timer.start("Writing route summaries CSV");
+        od2net::route_stats::write_csv(
+            &format!("{output_directory}/route_summaries.csv"),
+            &counts.route_summaries,
+        )?;
+        timer.stop();
+    }
+
+    if let Some(zones) = zones {
+        timer.start("Writing zone totals");
+        zones.write_outputs(&output_directory, &counts)?;
+        timer.stop();
+    }
+
+    if let Some(resolution) = args.output_h3 {
+        timer.start("Writing H3 hexagons GJ");
+        network.write_h3(
+            &format!("{output_directory}/h3.geojson"),
+            &counts,
+            resolution,
+        )?;
+        timer.stop();
+    }
+
+    if args.output_ways {
+        timer.start("Writing OSM ways GJ");
+        network.write_ways(
+            &format!("{output_directory}/ways.geojson"),
+            &counts,
+            !args.no_output_osm_tags,
+        )?;
+        timer.stop();
+    }
+
+    if args.output_heatmap {
+        timer.start("Writing heatmap GeoTIFF");
+        // Don't lose the rest of the output over this
+        match od2net::cost_raster::rasterize_counts(network, &counts, args.raster_resolution_meters)
+        {
+            Ok(raster) => od2net::cost_raster::write_geotiff(
+                &raster,
+                &format!("{output_directory}/heatmap.tif"),
+            )?,
+            Err(err) => {
+                println!("Skipping heatmap.tif: {err}");
+                warnings.add(
+                    od2net::warnings::WarningCategory::SkippedOutput,
+                    format!("Skipped heatmap.tif: {err}"),
+                    1,
+                );
+            }
+        }
+        timer.stop();
+    }
+
+    if !args.no_output_origins || !args.no_output_destinations {
+        timer.start("Writing origin and destination GJ");
+        counts.write_od_points(
+            &output_directory,
+            !args.no_output_origins,
+            !args.no_output_destinations,
+            config.sort_output,
+        )?;
+        timer.stop();
+    }
+
+    if !args.no_output_node_counts {
+        timer.start("Writing node counts GJ");
+        network.write_node_counts(&format!("{output_directory}/node_counts.geojson"), &counts)?;
+        timer.stop();
+    }
+
+    if args.output_movements {
+        timer.start("Writing movements GJ");
+        network.write_movements(&format!("{output_directory}/movements.geojson"), &counts)?;
+        timer.stop();
+    }
+
+    if args.output_flatgeobuf {
+        timer.start("Writing output FlatGeobuf");
+        network.write_flatgeobuf(
+            &format!("{output_directory}/output.fgb"),
+            &counts,
+            !args.no_output_osm_tags,
+            config.sort_output,
+        )?;
+        timer.stop();
+    }
+
+    if args.output_geoparquet {
+        timer.start("Writing output GeoParquet");
+        network.write_geoparquet(
+            &format!("{output_directory}/output.parquet"),
             &counts,
+            !args.no_output_osm_tags,
+            config.sort_output,
+        )?;
+        timer.stop();
+    }
+
+    if args.output_geopackage {
+        timer.start("Writing output GeoPackage");
+        network.write_geopackage(
+            &format!("{output_directory}/output.gpkg"),
+            &counts,
+            !args.no_output_osm_tags,
+            config.sort_output,
         )?;
         timer.stop();
     }
 
     let mut output_metadata =
         od2net::OutputMetadata::new(config, &counts, num_requests, routing_time);
-    timer.start("Writing output GJ");
-    network.write_geojson(
-        geojson::FeatureWriter::from_writer(std::io::BufWriter::new(fs_err::File::create(
-            format!("{directory}/output/output.geojson"),
-        )?)),
-        counts,
-        !args.no_output_od_points,
-        !args.no_output_osm_tags,
-        &output_metadata,
-    )?;
-    timer.stop();
+    warnings.extend(&network.warnings);
+    warnings.extend(&output_metadata.warnings);
+    output_metadata.warnings = warnings;
+    // Besides the edges, the tiles have the origins and destinations written earlier
+    let origins_path = format!("{output_directory}/origins.geojson");
+    let destinations_path = format!("{output_directory}/destinations.geojson");
+    let layer_args = |edges_path: &str| {
+        output_metadata.config.pmtiles.tippecanoe_layer_args(
+            edges_path,
+            (!args.no_output_origins).then_some(origins_path.as_str()),
+            (!args.no_output_destinations).then_some(destinations_path.as_str()),
+        )
+    };
 
-    if !args.no_output_pmtiles {
-        timer.start("Converting to pmtiles for rendering");
-        let tippecanoe_start = Instant::now();
-        let mut cmd = Command::new("tippecanoe");
-        cmd.arg(format!("{directory}/output/output.geojson"))
-            .arg("-o")
-            .arg(format!("{directory}/output/rnet.pmtiles"))
-            .arg("--force") // Overwrite existing output
-            .arg("-l")
-            .arg("rnet")
-            .arg("-zg") // Guess the zoom
-            .arg("--drop-fraction-as-needed") // TODO Drop based on low counts
-            .arg("--extend-zooms-if-still-dropping")
-            // Plumb through the config as a JSON string in the description
-            .arg("--description")
-            .arg(serde_json::to_string(&output_metadata)?);
-        println!("Running: {cmd:?}");
-        if !cmd.status()?.success() {
-            bail!("tippecanoe failed");
+    // tippecanoe picks the format from the extension. If both formats are wanted, the PMTiles are
+    // converted afterwards, instead of tiling twice.
+    let tiles_path = if !args.no_output_pmtiles {
+        Some(format!("{output_directory}/rnet.pmtiles"))
+    } else if args.output_mbtiles {
+        Some(format!("{output_directory}/rnet.mbtiles"))
+    } else {
+        None
+    };
+
+    if args.no_output_geojson {
+        if let Some(ref tiles_path) = tiles_path {
+            timer.start("Piping output into tiles for rendering");
+            let tippecanoe_start = Instant::now();
+            let mut cmd = tippecanoe_command(tiles_path, &output_metadata)?;
+            cmd.args(layer_args("/dev/stdin"));
+            println!("Running: {cmd:?}");
+            network.write_geojson_seq_to_command(
+                cmd,
+                counts,
+                !args.no_output_osm_tags,
+                output_metadata.config.sort_output,
+                output_metadata.config.output_properties.as_deref(),
+            )?;
+            output_metadata.tippecanoe_time_seconds = Some(
+                Instant::now()
+                    .duration_since(tippecanoe_start)
+                    .as_secs_f32(),
+            );
+            timer.stop();
         }
-        output_metadata.tippecanoe_time_seconds = Some(
-            Instant::now()
-                .duration_since(tippecanoe_start)
-                .as_secs_f32(),
+    } else {
+        let geojson_path = OutputCompression::path(
+            compression,
+            &if args.output_geojson_seq {
+                format!("{output_directory}/output.geojsonl")
+            } else {
+                format!("{output_directory}/output.geojson")
+            },
         );
+        timer.start("Writing output GJ");
+        let mut file = od2net::utils::create_output_file(&geojson_path, compression)?;
+        if args.output_geojson_seq {
+            network.write_geojson_seq(
+                &mut file,
+                counts,
+                // Origins and destinations are in their own files
+                false,
+                !args.no_output_osm_tags,
+                output_metadata.config.sort_output,
+                output_metadata.config.output_properties.as_deref(),
+            )?;
+        } else {
+            network.write_geojson(
+                geojson::FeatureWriter::from_writer(&mut file),
+                counts,
+                // Origins and destinations are in their own files
+                false,
+                !args.no_output_osm_tags,
+                output_metadata.config.sort_output,
+                output_metadata.config.output_properties.as_deref(),
+                &output_metadata,
+            )?;
+        }
+        file.finish()?;
         timer.stop();
-    }
 
-    output_metadata.total_time_seconds =
-        Some(Instant::now().duration_since(pipeline_start).as_secs_f32());
-    drop(timer);
-    println!("");
-    output_metadata.describe();
+        if let Some(ref tiles_path) = tiles_path {
+            timer.start("Converting to tiles for rendering");
+            let tippecanoe_start = Instant::now();
+            let mut cmd = tippecanoe_command(tiles_path, &output_metadata)?;
+            if args.output_geojson_seq && compression.is_none() {
+                // Read the input in parallel, which only works with one feature per line
+                cmd.arg("-P");
+            }
+            cmd.args(layer_args(&geojson_path));
+            println!("Running: {cmd:?}");
+            if !cmd.status()?.success() {
+                bail!("tippecanoe failed");
+            }
+            output_metadata.tippecanoe_time_seconds = Some(
+                Instant::now()
+                    .duration_since(tippecanoe_start)
+                    .as_secs_f32(),
+            );
+            timer.stop();
+        }
+    }
 
-    if args.output_metadata {
-        let mut file = fs_err::File::create("output/metadata.json")?;
-        serde_json::to_writer(&mut file, &output_metadata)?;
+    if args.output_mbtiles && !args.no_output_pmtiles {
+        timer.start("Converting pmtiles to mbtiles");
+        let mut cmd = Command::new("tile-join");
+        cmd.arg("-o")
+            .arg(format!("{output_directory}/rnet.mbtiles"))
+            .arg("--force")
+            // Don't drop anything tippecanoe kept
+            .arg("--no-tile-size-limit")
+            .arg(format!("{output_directory}/rnet.pmtiles"));
+        println!("Running: {cmd:?}");
+        if !cmd.status()?.success() {
+            bail!("tile-join failed");
+        }
+        timer.stop();
     }
 
-    Ok(())
+    Ok((output_directory, output_metadata))
+}
+
+/// Prepares tippecanoe to write PMTiles or MBTiles, depending on the extension of `tiles_path`.
+/// The input still needs to be added, or piped in.
+fn tippecanoe_command(
+    tiles_path: &str,
+    output_metadata: &od2net::OutputMetadata,
+) -> Result<Command> {
+    let mut cmd = Command::new("tippecanoe");
+    cmd.arg("-o")
+        .arg(tiles_path)
+        .arg("--force") // Overwrite existing output
+        .args(output_metadata.config.pmtiles.tippecanoe_args())
+        // Plumb through the config as a JSON string in the description
+        .arg("--description")
+        .arg(serde_json::to_string(output_metadata)?);
+    Ok(cmd)
 }
 
 fn write_failed_requests(path: String, counts: &od2net::network::Counts) -> Result<()> {
@@ -250,5 +1151,180 @@ fn write_failed_requests(path: String, counts: &od2net::network::Counts) -> Resu
         f.set_property("reason", "no path");
         writer.write_feature(&f)?;
     }
+    for snap in &counts.errors_far_snap {
+        let mut f = snap.req.as_feature();
+        f.set_property("reason", "snapped too far");
+        f.set_property("origin_snap_meters", snap.origin_snap_meters);
+        f.set_property("destination_snap_meters", snap.destination_snap_meters);
+        writer.write_feature(&f)?;
+    }
     Ok(writer.finish()?)
 }
+
+/// For requests rejected by `max_snap_meters`, writes a line from each endpoint to the
+/// intersection it snapped to
+fn write_far_snaps(
+    path: String,
+    counts: &od2net::network::Counts,
+    max_snap_meters: f64,
+) -> Result<()> {
+    let mut writer =
+        geojson::FeatureWriter::from_writer(std::io::BufWriter::new(fs_err::File::create(path)?));
+    for snap in &counts.errors_far_snap {
+        let req = &snap.req;
+        for (endpoint, from, to, meters) in [
+            (
+                "origin",
+                (req.x1, req.y1),
+                snap.snapped_origin,
+                snap.origin_snap_meters,
+            ),
+            (
+                "destination",
+                (req.x2, req.y2),
+                snap.snapped_destination,
+                snap.destination_snap_meters,
+            ),
+        ] {
+            let mut f =
+                geojson::Feature::from(geojson::Geometry::new(geojson::Value::LineString(vec![
+                    vec![from.0, from.1],
+                    vec![to.0, to.1],
+                ])));
+            f.set_property("endpoint", endpoint);
+            f.set_property("snap_meters", meters);
+            f.set_property("too_far", meters > max_snap_meters);
+            writer.write_feature(&f)?;
+        }
+    }
+    Ok(writer.finish()?)
+}
+
+/// Downloads to a temporary file first, so an interrupted download isn't mistaken for a cached one
+fn download(url: &str, path: &str) -> Result<()> {
+    let tmp_path = format!("{path}.download");
+    let mut reader = ureq::get(url).call()?.into_reader();
+    let mut file = BufWriter::new(File::create(&tmp_path)?);
+    std::io::copy(&mut reader, &mut file)?;
+    file.flush()?;
+    drop(file);
+    fs_err::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Identifies the config sections and input files that `intermediate/network.bin` is built from.
+/// Input files are identified by their size and modification time, since hashing a large OSM file
+/// would take too long.
+fn network_cache_key(config: &od2net::config::InputConfig, directory: &str) -> u32 {
+    let mut crc = flate2::Crc::new();
+    let sections = serde_json::json!({
+        "cost": config.cost,
+        "profile": config.profile,
+        "lts": config.lts,
+        "oneway": config.oneway,
+        "barriers": config.barriers,
+        "path_difficulty": config.path_difficulty,
+        "osm_inputs": config.osm_inputs,
+        "osm": config.osm,
+        "geojson_network": config.geojson_network,
+        "clip_boundary": config.clip_boundary,
+        "elevation_geotiff": config.elevation_geotiff,
+        "elevation_tiles": config.elevation_tiles,
+        "download_elevation": config.download_elevation,
+    });
+    crc.update(sections.to_string().as_bytes());
+
+    let mut paths: Vec<String> = OSM_INPUTS
+        .iter()
+        .map(|filename| format!("{directory}/input/{filename}"))
+        .collect();
+    for path in config.osm_inputs.iter().flatten() {
+        paths.push(config.resolve_path(directory, path));
+    }
+    if let Some(ref osm) = config.osm {
+        paths.push(format!("{directory}/input/{}", osm.filename()));
+    }
+    for path in [
+        config.geojson_network.as_ref().map(|columns| &columns.path),
+        config.clip_boundary.as_ref(),
+        config.elevation_geotiff.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        paths.push(config.resolve_path(directory, path));
+    }
+    // A directory of GeoTIFF tiles changes when any tile is overwritten, which doesn't touch the
+    // directory itself
+    if let Some(ref path) = config.elevation_geotiff {
+        let path = config.resolve_path(directory, path);
+        if std::path::Path::new(&path).is_dir() {
+            if let Ok(entries) = fs_err::read_dir(&path) {
+                let mut tiles: Vec<String> = entries
+                    .flatten()
+                    .map(|entry| entry.path())
+                    .filter(|tile| {
+                        tile.extension()
+                            .is_some_and(|ext| ext == "tif" || ext == "tiff")
+                    })
+                    .map(|tile| tile.display().to_string())
+                    .collect();
+                tiles.sort();
+                paths.extend(tiles);
+            }
+        }
+    }
+    for path in paths {
+        let Ok(metadata) = fs_err::metadata(&path) else {
+            continue;
+        };
+        // Configs in different directories using the same file should get the same key, so
+        // `od2net batch` can share the network
+        let path = std::fs::canonicalize(&path)
+            .map(|path| path.display().to_string())
+            .unwrap_or(path);
+        crc.update(path.as_bytes());
+        crc.update(&metadata.len().to_le_bytes());
+        if let Ok(modified) = metadata.modified() {
+            let nanos = modified
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|x| x.as_nanos())
+                .unwrap_or(0);
+            crc.update(&nanos.to_le_bytes());
+        }
+    }
+    crc.sum()
+}
+
+/// Where to look up heights, from the config. `None` if elevation isn't used.
+fn elevation_source(
+    config: &od2net::config::InputConfig,
+    directory: &str,
+    shared: &mut SharedInputs,
+) -> Result<Option<Box<dyn od2net::network::ElevationSource>>> {
+    let source: Option<Box<dyn od2net::network::ElevationSource>> =
+        match (&config.elevation_geotiff, &config.elevation_tiles) {
+            (Some(_), Some(_)) => {
+                bail!("Only one of elevation_geotiff and elevation_tiles can be set")
+            }
+            (Some(filename), None) => Some(
+                shared
+                    .geotiffs
+                    .load(&config.resolve_path(directory, filename))?,
+            ),
+            (None, Some(tiles)) => Some(Box::new(od2net::network::TileElevation::new(
+                tiles.clone(),
+                format!("{directory}/intermediate/elevation"),
+                od2net::network::HttpFetcher,
+            ))),
+            (None, None) if config.download_elevation => {
+                Some(Box::new(od2net::network::TileElevation::new(
+                    od2net::config::ElevationTiles::aws_terrain_tiles(),
+                    format!("{directory}/intermediate/elevation"),
+                    od2net::network::HttpFetcher,
+                )))
+            }
+            (None, None) => None,
+        };
+    Ok(source)
+}
@@ -0,0 +1,349 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::Arc;
+
+use anyhow::Result;
+use fast_paths::{FastGraph, INVALID_EDGE};
+use osm_reader::NodeID;
+use serde::Deserialize;
+
+use super::router::PreparedCH;
+
+/// The same CH as a `FastGraph`, but with its edges visible, so searches can be run directly on
+/// it. fast_paths keeps them private, so this is read back from the serialized `FastGraph`; the
+/// fields must stay in the same order.
+#[derive(Deserialize)]
+pub struct UpwardGraph {
+    num_nodes: usize,
+    ranks: Vec<usize>,
+    /// Grouped by the rank of `base_node`, always going up to a higher rank
+    edges_fwd: Vec<Edge>,
+    first_edge_ids_fwd: Vec<usize>,
+    /// Grouped by the rank of `base_node`, reversed, so `adj_node` has a higher rank and the
+    /// original edge goes from `adj_node` to `base_node`
+    edges_bwd: Vec<Edge>,
+    first_edge_ids_bwd: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct Edge {
+    base_node: usize,
+    adj_node: usize,
+    weight: usize,
+    /// For shortcuts, the two edges skipped over. `INVALID_EDGE` otherwise.
+    replaced_in_edge: usize,
+    replaced_out_edge: usize,
+}
+
+impl Edge {
+    fn is_shortcut(&self) -> bool {
+        self.replaced_in_edge != INVALID_EDGE
+    }
+}
+
+/// How a search reached a node
+#[derive(Clone, Copy)]
+struct Reached {
+    cost: usize,
+    parent: usize,
+    /// In `edges_fwd` or `edges_bwd`, depending on the search. `INVALID_EDGE` at the start.
+    edge: usize,
+}
+
+impl UpwardGraph {
+    pub fn new(ch: &FastGraph) -> Result<Self> {
+        let graph: Self = bincode::deserialize(&bincode::serialize(ch)?)?;
+        if graph.num_nodes != ch.get_num_nodes() || graph.ranks.len() != graph.num_nodes {
+            bail!("Couldn't read the edges of the CH");
+        }
+        Ok(graph)
+    }
+
+    /// Finds the cost to reach every node with a higher rank than the start, only using edges
+    /// going up. Searching backwards from `starts` finds the cost to reach them instead.
+    fn upward_search(&self, starts: &[usize], forwards: bool) -> HashMap<usize, Reached> {
+        let (edges, first_edge_ids) = if forwards {
+            (&self.edges_fwd, &self.first_edge_ids_fwd)
+        } else {
+            (&self.edges_bwd, &self.first_edge_ids_bwd)
+        };
+        let mut reached: HashMap<usize, Reached> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        for start in starts {
+            reached.insert(
+                *start,
+                Reached {
+                    cost: 0,
+                    parent: *start,
+                    edge: INVALID_EDGE,
+                },
+            );
+            queue.push(Reverse((0, *start)));
+        }
+
+        while let Some(Reverse((cost, node))) = queue.pop() {
+            if cost > reached[&node].cost {
+                continue;
+            }
+            let rank = self.ranks[node];
+            for edge_id in first_edge_ids[rank]..first_edge_ids[rank + 1] {
+                let edge = &edges[edge_id];
+                let next_cost = cost.saturating_add(edge.weight);
+                if reached
+                    .get(&edge.adj_node)
+                    .map_or(true, |r| next_cost < r.cost)
+                {
+                    reached.insert(
+                        edge.adj_node,
+                        Reached {
+                            cost: next_cost,
+                            parent: node,
+                            edge: edge_id,
+                        },
+                    );
+                    queue.push(Reverse((next_cost, edge.adj_node)));
+                }
+            }
+        }
+        reached
+    }
+
+    /// Expands the shortcuts on the way from the start of `forwards` up to `meeting`, then down
+    /// to the start of `backwards`, into the full path.
+    fn unpack_path(
+        &self,
+        forwards: &HashMap<usize, Reached>,
+        backwards: &HashMap<usize, Reached>,
+        meeting: usize,
+    ) -> Vec<usize> {
+        let mut path = Vec::new();
+        let mut node = meeting;
+        while forwards[&node].edge != INVALID_EDGE {
+            self.unpack_fwd(&mut path, forwards[&node].edge, true);
+            node = forwards[&node].parent;
+        }
+        path.reverse();
+        node = meeting;
+        while backwards[&node].edge != INVALID_EDGE {
+            self.unpack_bwd(&mut path, backwards[&node].edge, false);
+            node = backwards[&node].parent;
+        }
+        path.push(node);
+        path
+    }
+
+    // These two match fast_paths' PathCalculator. Each original edge adds the node it leaves from
+    // when `reverse`, or arrives at otherwise.
+    fn unpack_fwd(&self, path: &mut Vec<usize>, edge_id: usize, reverse: bool) {
+        let edge = &self.edges_fwd[edge_id];
+        if !edge.is_shortcut() {
+            path.push(edge.base_node);
+        } else if reverse {
+            self.unpack_fwd(path, edge.replaced_out_edge, reverse);
+            self.unpack_bwd(path, edge.replaced_in_edge, reverse);
+        } else {
+            self.unpack_bwd(path, edge.replaced_in_edge, reverse);
+            self.unpack_fwd(path, edge.replaced_out_edge, reverse);
+        }
+    }
+
+    fn unpack_bwd(&self, path: &mut Vec<usize>, edge_id: usize, reverse: bool) {
+        let edge = &self.edges_bwd[edge_id];
+        if !edge.is_shortcut() {
+            path.push(edge.adj_node);
+        } else if reverse {
+            self.unpack_fwd(path, edge.replaced_out_edge, reverse);
+            self.unpack_bwd(path, edge.replaced_in_edge, reverse);
+        } else {
+            self.unpack_bwd(path, edge.replaced_in_edge, reverse);
+            self.unpack_fwd(path, edge.replaced_out_edge, reverse);
+        }
+    }
+}
+
+/// Routes between many pairs of intersections, keyed by their IDs in the CH, found together with
+/// a bucket-based many-to-many CH search. Each distinct destination is searched backwards once,
+/// leaving its cost in a bucket at every node reached. Each distinct origin is searched forwards
+/// once, and the cheapest route to every destination meets at one of the nodes reached, so
+/// scanning their buckets finds all of them. With zone-based patterns, a batch of requests has
+/// far fewer distinct endpoints than pairs, so this does much less work than searching per pair.
+/// Routes are shared, so looking one up doesn't copy it.
+#[derive(Default)]
+pub struct RouteMatrix {
+    paths: HashMap<(usize, usize), Option<Arc<[NodeID]>>>,
+}
+
+impl RouteMatrix {
+    pub fn new(graph: &UpwardGraph, prepared_ch: &PreparedCH, pairs: &[(usize, usize)]) -> Self {
+        let mut targets = Vec::new();
+        let mut target_index: HashMap<usize, usize> = HashMap::new();
+        let mut targets_per_source: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (source, target) in pairs {
+            let idx = *target_index.entry(*target).or_insert_with(|| {
+                targets.push(*target);
+                targets.len() - 1
+            });
+            let wanted = targets_per_source.entry(*source).or_default();
+            if !wanted.contains(&idx) {
+                wanted.push(idx);
+            }
+        }
+
+        // Routes ending at an intersection with turn restrictions can reach any copy of it
+        let backwards: Vec<HashMap<usize, Reached>> = targets
+            .iter()
+            .map(|target| match prepared_ch.turn_copies.get(target) {
+                Some(copies) => graph.upward_search(copies, false),
+                None => graph.upward_search(&[*target], false),
+            })
+            .collect();
+        let mut buckets: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+        for (idx, reached) in backwards.iter().enumerate() {
+            for (node, r) in reached {
+                buckets.entry(*node).or_default().push((idx, r.cost));
+            }
+        }
+
+        let mut paths = HashMap::new();
+        for (source, wanted) in targets_per_source {
+            let forwards = graph.upward_search(&[source], true);
+            // The cheapest (cost, meeting node) per target. Ties go to the lowest node, so the
+            // route doesn't depend on the order buckets are scanned.
+            let mut best: HashMap<usize, (usize, usize)> = HashMap::new();
+            for (node, r) in &forwards {
+                let Some(bucket) = buckets.get(node) else {
+                    continue;
+                };
+                for (idx, cost) in bucket {
+                    let candidate = (r.cost.saturating_add(*cost), *node);
+                    if best.get(idx).map_or(true, |b| candidate < *b) {
+                        best.insert(*idx, candidate);
+                    }
+                }
+            }
+
+            for idx in wanted {
+                let path = best.get(&idx).map(|(_, meeting)| {
+                    graph
+                        .unpack_path(&forwards, &backwards[idx], *meeting)
+                        .into_iter()
+                        .map(|id| prepared_ch.node_map.translate_id(id))
+                        .collect()
+                });
+                paths.insert((source, targets[idx]), path);
+            }
+        }
+        Self { paths }
+    }
+
+    /// None if this pair wasn't included. Some(None) if there's no route between them.
+    pub fn get(&self, start: usize, end: usize) -> Option<Option<Arc<[NodeID]>>> {
+        self.paths.get(&(start, end)).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use osm_reader::WayID;
+
+    use super::*;
+    use crate::network::tests::make_network;
+    use crate::network::TurnRestriction;
+    use crate::router::{just_build_cch, just_build_ch};
+    use crate::timer::Timer;
+
+    #[test]
+    fn test_matches_point_to_point() {
+        // An irregular grid, so no two routes cost the same
+        let mut network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.0011, 0.0001),
+                (3, 0.0023, -0.0002),
+                (4, 0.0001, 0.0012),
+                (5, 0.0013, 0.0009),
+                (6, 0.0021, 0.0014),
+                (7, -0.0002, 0.0025),
+                (8, 0.0012, 0.0021),
+                (9, 0.0024, 0.0027),
+            ],
+            &[
+                (1, 2),
+                (2, 3),
+                (4, 5),
+                (5, 6),
+                (7, 8),
+                (8, 9),
+                (1, 4),
+                (4, 7),
+                (2, 5),
+                (5, 8),
+                (3, 6),
+                (6, 9),
+            ],
+        );
+        // No turning from 2-5 onto 5-6
+        network.turn_restrictions.push(TurnRestriction {
+            from: WayID(9),
+            via: NodeID(5),
+            to: WayID(4),
+            only: false,
+        });
+
+        let mut timer = Timer::new();
+        for prepared_ch in [
+            just_build_ch(&network, &mut timer),
+            just_build_cch(&network, &mut timer),
+        ] {
+            let ids: Vec<usize> = (1..=9)
+                .map(|id| prepared_ch.node_map.get(NodeID(id)))
+                .collect();
+            let mut pairs = Vec::new();
+            for start in &ids {
+                for end in &ids {
+                    if start != end {
+                        pairs.push((*start, *end));
+                    }
+                }
+            }
+
+            let graph = UpwardGraph::new(&prepared_ch.ch).unwrap();
+            let matrix = RouteMatrix::new(&graph, &prepared_ch, &pairs);
+            let mut path_calc = fast_paths::create_calculator(&prepared_ch.ch);
+            for (start, end) in pairs {
+                let expected = prepared_ch.calc_path(&mut path_calc, start, end).unwrap();
+                let path = matrix.get(start, end).unwrap().unwrap();
+                assert_eq!(path.to_vec(), expected);
+            }
+            // The restricted turn is avoided
+            let path = matrix.get(ids[1], ids[5]).unwrap().unwrap();
+            assert_ne!(&path[..3], &[NodeID(2), NodeID(5), NodeID(6)]);
+            assert!(matrix.get(ids[0], ids[0]).is_none());
+        }
+    }
+
+    #[test]
+    fn test_no_route() {
+        // Two separate pieces
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.0, 0.001),
+                (4, 0.001, 0.001),
+            ],
+            &[(1, 2), (3, 4)],
+        );
+        let prepared_ch = just_build_ch(&network, &mut Timer::new());
+        let ids: Vec<usize> = (1..=4)
+            .map(|id| prepared_ch.node_map.get(NodeID(id)))
+            .collect();
+        let graph = UpwardGraph::new(&prepared_ch.ch).unwrap();
+        let matrix = RouteMatrix::new(&graph, &prepared_ch, &[(ids[0], ids[1]), (ids[0], ids[2])]);
+        assert_eq!(
+            matrix.get(ids[0], ids[1]).unwrap().unwrap().to_vec(),
+            vec![NodeID(1), NodeID(2)]
+        );
+        assert_eq!(matrix.get(ids[0], ids[2]), Some(None));
+    }
+}
@@ -1,5 +1,28 @@
+use serde::{Deserialize, Serialize};
+
 use lts::Tags;
 
+use super::Position;
+
+/// An amenity from OSM, kept with the network so requests can be generated to them
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Amenity {
+    pub position: Position,
+    /// The value of the `amenity` tag, like `school`, or `shop` for shops without one
+    pub kind: String,
+}
+
+impl Amenity {
+    /// Only call this for objects passing `is_amenity`
+    pub fn new(position: Position, tags: &Tags) -> Self {
+        let kind = tags.get("amenity").map(|x| x.as_str()).unwrap_or("shop");
+        Self {
+            position,
+            kind: kind.to_string(),
+        }
+    }
+}
+
 /// Determines if this OSM object should count as some kind of useful commercial amenity. Many
 /// categories are excluded.
 pub fn is_amenity(tags: &Tags) -> bool {
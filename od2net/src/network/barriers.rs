@@ -0,0 +1,90 @@
+use lts::Tags;
+
+use super::Edge;
+use crate::config::Barriers;
+
+impl Barriers {
+    /// Adds the penalty for every barrier along the edge, or makes the edge impassable
+    pub(super) fn apply(&self, edge: &mut Edge) {
+        let mut penalty = 0;
+        for tags in &edge.barriers {
+            if self.is_blocking(tags) {
+                edge.forward_cost = None;
+                edge.backward_cost = None;
+                return;
+            }
+            penalty += tags
+                .get("barrier")
+                .and_then(|barrier| self.penalties.get(barrier))
+                .cloned()
+                .unwrap_or(self.default_penalty);
+        }
+        edge.forward_cost = edge.forward_cost.map(|cost| cost + penalty);
+        edge.backward_cost = edge.backward_cost.map(|cost| cost + penalty);
+    }
+
+    fn is_blocking(&self, tags: &Tags) -> bool {
+        if tags.is_any(
+            "bicycle",
+            vec!["yes", "designated", "permissive", "dismount"],
+        ) {
+            return false;
+        }
+        tags.is("bicycle", "no")
+            || tags.is_any("access", vec!["no", "private"])
+            || tags.is("locked", "yes")
+            || tags
+                .get("barrier")
+                .is_some_and(|barrier| self.blocked.contains(barrier))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::{make_network, tags};
+
+    #[test]
+    fn test_barriers() {
+        let mut network = make_network(&[(1, 0.0, 0.0), (2, 0.0, 0.001)], &[(1, 2)]);
+        let edge = network.edges.values_mut().next().unwrap();
+        let cost = edge.forward_cost.unwrap();
+        let mut barriers = Barriers {
+            penalties: [("gate".to_string(), 30)].into_iter().collect(),
+            default_penalty: 5,
+            blocked: vec!["full-height_turnstile".to_string()],
+        };
+
+        edge.barriers = vec![
+            tags(&[("barrier", "gate")]),
+            tags(&[("barrier", "bollard")]),
+        ];
+        barriers.apply(edge);
+        assert_eq!(edge.forward_cost, Some(cost + 35));
+        assert_eq!(edge.backward_cost, Some(cost + 35));
+
+        for tags in [
+            tags(&[("barrier", "gate"), ("locked", "yes")]),
+            tags(&[("barrier", "gate"), ("access", "private")]),
+            tags(&[("barrier", "full-height_turnstile")]),
+        ] {
+            edge.forward_cost = Some(cost);
+            edge.backward_cost = Some(cost);
+            edge.barriers = vec![tags];
+            barriers.apply(edge);
+            assert_eq!(edge.forward_cost, None);
+            assert_eq!(edge.backward_cost, None);
+        }
+
+        // Explicit bicycle access wins
+        edge.forward_cost = Some(cost);
+        edge.barriers = vec![tags(&[
+            ("barrier", "gate"),
+            ("access", "private"),
+            ("bicycle", "yes"),
+        ])];
+        barriers.penalties.clear();
+        barriers.apply(edge);
+        assert_eq!(edge.forward_cost, Some(cost + 5));
+    }
+}
@@ -0,0 +1,137 @@
+use lts::{Tags, LTS};
+
+use super::{calculate_slope_factor, Edge};
+
+/// Paths narrower than this, in meters, can't fit a cargo bike or tricycle at all
+const MIN_WIDTH: f64 = 1.2;
+/// Paths narrower than this, in meters, are awkward to ride and pass others on
+const COMFORTABLE_WIDTH: f64 = 2.0;
+const NARROW_MULTIPLIER: f64 = 1.5;
+/// Added to the cost for every barrier that can be passed, on top of `barriers` penalties
+const BARRIER_PENALTY: usize = 50;
+/// A loaded cargo bike or tricycle cruises at about this speed on flat ground, slower than a
+/// regular bike
+const DESIGN_SPEED_KMH: f64 = 12.0;
+/// Riders brake to keep the load under control, so descents don't go much faster than this
+const MAX_DESCENT_KMH: f64 = 15.0;
+
+/// Cargo bikes can't use steps. Otherwise it's the same as cycling.
+pub fn cargo_bike_lts(tags: &Tags, cycling_lts: LTS) -> LTS {
+    if tags.is("highway", "steps") {
+        LTS::NotAllowed
+    } else {
+        cycling_lts
+    }
+}
+
+/// Penalizes narrow ways and every barrier along the edge, or makes the edge impassable if it's
+/// too narrow or has a barrier designed to stop wide cycles
+pub(super) fn apply(edge: &mut Edge) {
+    let width = get_width(&edge.tags);
+    if width.is_some_and(|width| width < MIN_WIDTH) || edge.barriers.iter().any(is_blocking) {
+        edge.forward_cost = None;
+        edge.backward_cost = None;
+        return;
+    }
+
+    let multiplier = if width.is_some_and(|width| width < COMFORTABLE_WIDTH) {
+        NARROW_MULTIPLIER
+    } else {
+        1.0
+    };
+    let penalty = BARRIER_PENALTY * edge.barriers.len();
+    let adjust = |cost: usize| (cost as f64 * multiplier).round() as usize + penalty;
+    edge.forward_cost = edge.forward_cost.map(adjust);
+    edge.backward_cost = edge.backward_cost.map(adjust);
+}
+
+fn is_blocking(tags: &Tags) -> bool {
+    if tags.is_any(
+        "barrier",
+        vec![
+            "cycle_barrier",
+            "stile",
+            "kissing_gate",
+            "turnstile",
+            "full-height_turnstile",
+            "motorcycle_barrier",
+        ],
+    ) {
+        return true;
+    }
+    if tags.is("barrier", "kerb") && !tags.is_any("kerb", vec!["flush", "lowered", "no"]) {
+        return true;
+    }
+    get_width(tags).is_some_and(|width| width < MIN_WIDTH)
+}
+
+/// Handles values like `1.5` and `1.5 m`, ignoring other units
+fn get_width(tags: &Tags) -> Option<f64> {
+    ["maxwidth:physical", "maxwidth", "width", "est_width"]
+        .into_iter()
+        .find_map(|key| tags.get(key))
+        .and_then(|value| value.trim_end_matches('m').trim().parse().ok())
+}
+
+/// Like `calculate_slope_factor`, for a heavy bike ridden more slowly. Climbs are half as hard
+/// again, and descents can't go faster than `MAX_DESCENT_KMH`.
+pub fn calculate_cargo_bike_slope_factor(slope: f64, length: f64) -> f64 {
+    let factor = calculate_slope_factor(slope, length);
+    if slope > 0.0 {
+        1.0 + (factor - 1.0) * 1.5
+    } else {
+        factor.max(DESIGN_SPEED_KMH / MAX_DESCENT_KMH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::{make_network, tags};
+
+    #[test]
+    fn test_cargo_bike() {
+        let mut network = make_network(&[(1, 0.0, 0.0), (2, 0.0, 0.001)], &[(1, 2)]);
+        let edge = network.edges.values_mut().next().unwrap();
+        let cost = edge.forward_cost.unwrap();
+        let reset = |edge: &mut Edge| {
+            edge.forward_cost = Some(cost);
+            edge.backward_cost = Some(cost);
+        };
+
+        edge.barriers = vec![tags(&[("barrier", "bollard")])];
+        apply(edge);
+        assert_eq!(edge.forward_cost, Some(cost + BARRIER_PENALTY));
+
+        reset(edge);
+        edge.tags.insert("width", "1.5 m");
+        apply(edge);
+        let narrow = (cost as f64 * NARROW_MULTIPLIER).round() as usize + BARRIER_PENALTY;
+        assert_eq!(edge.backward_cost, Some(narrow));
+
+        for barrier in [
+            tags(&[("barrier", "cycle_barrier")]),
+            tags(&[("barrier", "kerb")]),
+            tags(&[("barrier", "bollard"), ("maxwidth", "1.0")]),
+        ] {
+            reset(edge);
+            edge.barriers = vec![barrier];
+            apply(edge);
+            assert_eq!(edge.forward_cost, None);
+        }
+
+        reset(edge);
+        edge.barriers = vec![tags(&[("barrier", "kerb"), ("kerb", "lowered")])];
+        edge.tags.insert("width", "1");
+        apply(edge);
+        assert_eq!(edge.forward_cost, None);
+
+        assert_eq!(
+            cargo_bike_lts(&tags(&[("highway", "steps")]), LTS::LTS1),
+            LTS::NotAllowed
+        );
+        assert!(calculate_cargo_bike_slope_factor(5.0, 100.0) > calculate_slope_factor(5.0, 100.0));
+        // A steep descent is only 15km/h instead of 12km/h
+        assert_eq!(calculate_cargo_bike_slope_factor(-10.0, 100.0), 0.8);
+    }
+}
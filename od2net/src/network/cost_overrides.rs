@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use fs_err::File;
+use osm_reader::WayID;
+use serde::Deserialize;
+
+use super::Edge;
+
+/// Adjusts the cost of every edge belonging to one OSM way, after the cost function runs
+#[derive(Clone, Debug, PartialEq)]
+pub struct CostOverride {
+    /// Replaces the cost in both directions
+    pub cost: Option<usize>,
+    /// Multiplies the cost in both directions, after applying `cost`
+    pub multiplier: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct Row {
+    way_id: i64,
+    cost: Option<usize>,
+    multiplier: Option<f64>,
+}
+
+/// Reads a CSV file with a `way_id` column and optional `cost` and `multiplier` columns
+pub fn load_cost_overrides(path: &str) -> Result<HashMap<WayID, CostOverride>> {
+    let mut overrides = HashMap::new();
+    for rec in csv::Reader::from_reader(File::open(path)?).deserialize() {
+        let row: Row = rec?;
+        if let Some(multiplier) = row.multiplier {
+            if multiplier < 0.0 {
+                bail!("Way {} has a negative multiplier in {path}", row.way_id);
+            }
+        }
+        overrides.insert(
+            WayID(row.way_id),
+            CostOverride {
+                cost: row.cost,
+                multiplier: row.multiplier,
+            },
+        );
+    }
+    Ok(overrides)
+}
+
+impl CostOverride {
+    /// Only changes directions that are already allowed
+    pub(super) fn apply(&self, edge: &mut Edge) {
+        let adjust = |cost: Option<usize>| {
+            let cost = self.cost.or(cost)?;
+            Some(match self.multiplier {
+                Some(multiplier) => (cost as f64 * multiplier).round() as usize,
+                None => cost,
+            })
+        };
+        if edge.forward_cost.is_some() {
+            edge.forward_cost = adjust(edge.forward_cost);
+        }
+        if edge.backward_cost.is_some() {
+            edge.backward_cost = adjust(edge.backward_cost);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CostFunction;
+    use crate::network::tests::make_network;
+    use crate::network::Network;
+    use osm_reader::NodeID;
+
+    #[test]
+    fn test_cost_overrides() {
+        let path = std::env::temp_dir().join("od2net_test_cost_overrides.csv");
+        fs_err::write(&path, "way_id,cost,multiplier\n1,,2.5\n2,500,\n3,100,0.5\n").unwrap();
+        let overrides = load_cost_overrides(path.to_str().unwrap()).unwrap();
+        fs_err::remove_file(&path).unwrap();
+
+        let mut network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.0, 0.001),
+                (3, 0.0, 0.002),
+                (4, 0.0, 0.003),
+            ],
+            &[(1, 2), (2, 3), (3, 4)],
+        );
+        let cost = |network: &Network, node1, node2| {
+            let edge = &network.edges[&(NodeID(node1), NodeID(node2))];
+            (edge.forward_cost, edge.backward_cost)
+        };
+        let original = cost(&network, 1, 2).0.unwrap();
+        // Way 3 is one-way
+        network
+            .edges
+            .get_mut(&(NodeID(3), NodeID(4)))
+            .unwrap()
+            .tags
+            .insert("oneway", "yes");
+        network.oneway.respect = true;
+
+        network.cost_overrides = overrides;
+        network
+            .recalculate_cost(&mut CostFunction::Distance)
+            .unwrap();
+        let multiplied = Some((original as f64 * 2.5).round() as usize);
+        assert_eq!(cost(&network, 1, 2), (multiplied, multiplied));
+        assert_eq!(cost(&network, 2, 3), (Some(500), Some(500)));
+        assert_eq!(cost(&network, 3, 4), (Some(50), None));
+    }
+}
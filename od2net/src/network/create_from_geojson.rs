@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use geo::prelude::HaversineLength;
+use geojson::{Feature, FeatureReader, JsonValue};
+use indicatif::HumanCount;
+use osm_reader::{NodeID, WayID};
+
+use super::create_from_osm::{split_edges, Way};
+use super::{Network, Position};
+use crate::config::{CostFunction, GeojsonNetwork};
+use crate::timer::Timer;
+use lts::{Tags, LTS};
+
+/// What each feature says about the edges split from it
+struct FeatureAttributes {
+    lts: LTS,
+    length_meters: f64,
+    /// None if the config doesn't have cost properties
+    cost: Option<(Option<f64>, Option<f64>)>,
+}
+
+impl Network {
+    /// Builds a network from road centerlines that aren't from OSM. Every feature property is
+    /// kept as a tag, but the LTS and (optionally) cost come from the properties named in
+    /// `columns`.
+    pub fn make_from_geojson(
+        input_bytes: &[u8],
+        columns: &GeojsonNetwork,
+        cost: &mut CostFunction,
+        exposure_layers: &[String],
+        timer: &mut Timer,
+    ) -> Result<Network> {
+        timer.start("Make Network from GeoJSON");
+        let network = Network::make_from_features(
+            FeatureReader::from_reader(input_bytes)
+                .features()
+                .map(|feature| feature.map_err(anyhow::Error::from)),
+            columns,
+            cost,
+            exposure_layers,
+            timer,
+        );
+        timer.stop();
+        network
+    }
+
+    /// Like `make_from_geojson`, for features from any source
+    pub(super) fn make_from_features(
+        features: impl Iterator<Item = Result<Feature>>,
+        columns: &GeojsonNetwork,
+        cost: &mut CostFunction,
+        exposure_layers: &[String],
+        timer: &mut Timer,
+    ) -> Result<Network> {
+        timer.start("Read features");
+        // There are no node IDs, so make them up, using the same ID for the same point
+        let mut node_ids: HashMap<Position, NodeID> = HashMap::new();
+        let mut nodes = HashMap::new();
+        let mut ways = HashMap::new();
+        let mut attributes = HashMap::new();
+
+        for (idx, feature) in features.enumerate() {
+            let feature = feature?;
+            let Some(ref geometry) = feature.geometry else {
+                bail!("Feature {idx} has no geometry");
+            };
+            let lines = match geo::Geometry::<f64>::try_from(geometry.clone())? {
+                geo::Geometry::LineString(line) => vec![line],
+                geo::Geometry::MultiLineString(lines) => lines.0,
+                _ => bail!("Feature {idx} isn't a LineString or MultiLineString"),
+            };
+
+            let lts = parse_lts(&feature, &columns.lts_property)
+                .ok_or_else(|| anyhow!("Feature {idx} doesn't have an LTS from 0 to 4"))?;
+            let feature_cost = if let Some(ref forward) = columns.forward_cost_property {
+                let backward = columns.backward_cost_property.as_ref().unwrap_or(forward);
+                Some((
+                    parse_cost(&feature, forward),
+                    parse_cost(&feature, backward),
+                ))
+            } else {
+                None
+            };
+            let mut tags = Tags::new();
+            for (key, value) in feature.properties_iter() {
+                match value {
+                    JsonValue::Null => {}
+                    JsonValue::String(value) => tags.insert(key, value),
+                    _ => tags.insert(key, value.to_string()),
+                }
+            }
+
+            for line in lines {
+                if line.0.len() < 2 {
+                    continue;
+                }
+                let way_id = WayID(ways.len() as i64 + 1);
+                let mut way_nodes = Vec::new();
+                for pt in &line.0 {
+                    let pos = Position::from_degrees(pt.x, pt.y);
+                    let next_id = NodeID(node_ids.len() as i64 + 1);
+                    let id = *node_ids.entry(pos).or_insert(next_id);
+                    nodes.insert(id, pos);
+                    way_nodes.push(id);
+                }
+                attributes.insert(
+                    way_id,
+                    FeatureAttributes {
+                        lts,
+                        length_meters: line.haversine_length(),
+                        cost: feature_cost,
+                    },
+                );
+                ways.insert(
+                    way_id,
+                    Way {
+                        tags: tags.clone(),
+                        nodes: way_nodes,
+                    },
+                );
+            }
+        }
+        timer.stop();
+
+        timer.start("Split into edges");
+        let mut network = split_edges(nodes, ways, &HashMap::new());
+        timer.stop();
+        println!(
+            "  Split into {} edges",
+            HumanCount(network.edges.len() as u64),
+        );
+
+        for edge in network.edges.values_mut() {
+            edge.lts = attributes[&edge.way_id].lts;
+        }
+
+        timer.start("Calculate cost for all edges");
+        network.exposure_layers = exposure_layers.to_vec();
+        network.recalculate_cost(cost)?;
+        // Split the cost of each feature between its edges, by length
+        for edge in network.edges.values_mut() {
+            let feature = &attributes[&edge.way_id];
+            let Some((forward, backward)) = feature.cost else {
+                continue;
+            };
+            let scale = |cost: Option<f64>| {
+                if edge.lts == LTS::NotAllowed || feature.length_meters == 0.0 {
+                    return None;
+                }
+                cost.map(|cost| {
+                    (cost * edge.length_meters / feature.length_meters).round() as usize
+                })
+            };
+            edge.forward_cost = scale(forward);
+            edge.backward_cost = scale(backward);
+        }
+        timer.stop();
+
+        Ok(network)
+    }
+}
+
+fn parse_lts(feature: &Feature, key: &str) -> Option<LTS> {
+    let value = match feature.property(key)? {
+        JsonValue::Number(x) => x.as_u64()?,
+        JsonValue::String(x) => x.parse().ok()?,
+        _ => return None,
+    };
+    match value {
+        0 => Some(LTS::NotAllowed),
+        1 => Some(LTS::LTS1),
+        2 => Some(LTS::LTS2),
+        3 => Some(LTS::LTS3),
+        4 => Some(LTS::LTS4),
+        _ => None,
+    }
+}
+
+/// Missing, null, or negative costs mean the road can't be used in that direction
+fn parse_cost(feature: &Feature, key: &str) -> Option<f64> {
+    let cost = match feature.property(key)? {
+        JsonValue::Number(x) => x.as_f64()?,
+        JsonValue::String(x) => x.parse().ok()?,
+        _ => return None,
+    };
+    (cost >= 0.0).then_some(cost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_from_geojson() {
+        // A road heading east, with a one-way road meeting it halfway along
+        let input = r#"{
+  "type": "FeatureCollection",
+  "features": [
+    {
+      "type": "Feature",
+      "geometry": { "type": "LineString", "coordinates": [[0.0, 0.0], [0.001, 0.0], [0.002, 0.0]] },
+      "properties": { "name": "Main Street", "stress": 3, "cost": 100 }
+    },
+    {
+      "type": "Feature",
+      "geometry": { "type": "LineString", "coordinates": [[0.001, 0.001], [0.001, 0.0]] },
+      "properties": { "name": "Side Street", "stress": "1", "cost": 30, "cost_back": null }
+    }
+  ]
+}"#;
+        let columns = GeojsonNetwork {
+            path: "roads.geojson".to_string(),
+            layer: None,
+            lts_property: "stress".to_string(),
+            forward_cost_property: Some("cost".to_string()),
+            backward_cost_property: Some("cost_back".to_string()),
+        };
+        let network = Network::make_from_geojson(
+            input.as_bytes(),
+            &columns,
+            &mut CostFunction::Distance,
+            &[],
+            &mut Timer::new(),
+        )
+        .unwrap();
+
+        // Main Street is split where Side Street meets it
+        assert_eq!(network.edges.len(), 3);
+        assert_eq!(network.intersections.len(), 4);
+
+        let main_street: Vec<_> = network
+            .edges
+            .values()
+            .filter(|e| e.tags.is("name", "Main Street"))
+            .collect();
+        assert_eq!(main_street.len(), 2);
+        for edge in main_street {
+            assert_eq!(edge.lts, LTS::LTS3);
+            assert_eq!(edge.forward_cost, Some(50));
+            // No backward_cost_property on this feature
+            assert_eq!(edge.backward_cost, None);
+        }
+
+        let side_street = network
+            .edges
+            .values()
+            .find(|e| e.tags.is("name", "Side Street"))
+            .unwrap();
+        assert_eq!(side_street.lts, LTS::LTS1);
+        assert_eq!(side_street.forward_cost, Some(30));
+        assert_eq!(side_street.backward_cost, None);
+        assert_eq!(side_street.tags.get("stress").unwrap(), "1");
+    }
+}
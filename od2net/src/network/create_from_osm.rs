@@ -1,40 +1,77 @@
-use std::{collections::HashMap, io::Cursor};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
 
 use anyhow::Result;
-use elevation::GeoTiffElevation;
+use flate2::read::GzDecoder;
 use geo::prelude::HaversineLength;
-use geo::{LineString, Polygon};
+use geo::{LineString, MultiPolygon, Polygon};
 use indicatif::HumanCount;
 use osm_reader::{Element, NodeID, WayID};
 use rstar::primitives::{GeomWithData, Line};
 use rstar::RTree;
 
-use super::amenities::is_amenity;
+use super::amenities::{is_amenity, Amenity};
+use super::cargo_bike;
 use super::greenspace;
-use super::{Edge, Network, Position};
-use crate::config::{CostFunction, LtsMapping};
+use super::walking;
+use super::{Edge, ElevationSource, Network, Position, TurnRestriction};
+use crate::config::{
+    Barriers, CostFunction, Lighting, LtsMapping, Oneway, PathDifficulty, Profile,
+    SurfaceMultipliers, TurnCosts,
+};
 use crate::timer::Timer;
+use crate::warnings::{WarningCategory, Warnings};
 use crate::{plugins, utils};
 use lts::{Tags, LTS};
 
 impl Network {
+    /// Builds one network from one or more OSM inputs, each PBF or XML. Elements shared between
+    /// inputs, like roads along the seam of two neighbouring extracts, are only used once.
+    #[allow(clippy::too_many_arguments)]
     pub fn make_from_osm(
-        input_bytes: &[u8],
+        inputs: &[&[u8]],
         lts: &LtsMapping,
+        profile: Profile,
+        path_difficulty: &PathDifficulty,
+        clip_boundary: Option<&MultiPolygon<f64>>,
         cost: &mut CostFunction,
+        exposure_layers: &[String],
+        oneway: &Oneway,
+        barriers: &Barriers,
         timer: &mut Timer,
-        geotiff_bytes: Option<Vec<u8>>,
+        elevation: Option<Box<dyn ElevationSource>>,
     ) -> Result<Network> {
         timer.start("Make Network from xml or pbf");
+        let inputs = inputs
+            .iter()
+            .map(|input_bytes| decompress_if_gzipped(input_bytes))
+            .collect::<Result<Vec<_>>>()?;
         timer.start("Scrape OSM data");
-        let (nodes, ways, amenity_positions, greenspace_polygons) = scrape_elements(input_bytes)?;
+        let ScrapedElements {
+            nodes,
+            ways,
+            amenities,
+            greenspace_polygons,
+            turn_restrictions,
+            traffic_signals,
+            barrier_nodes,
+            num_dropped,
+            num_too_difficult,
+        } = scrape_elements(&inputs, path_difficulty)?;
         timer.stop();
         println!(
             "  Got {} nodes, {} ways, and {} amenities",
             HumanCount(nodes.len() as u64),
             HumanCount(ways.len() as u64),
-            HumanCount(amenity_positions.len() as u64)
+            HumanCount(amenities.len() as u64)
         );
+        if num_too_difficult > 0 {
+            println!(
+                "  Excluded {} paths that're too difficult",
+                HumanCount(num_too_difficult as u64)
+            );
+        }
 
         if false {
             let mut writer = geojson::FeatureWriter::from_writer(std::io::BufWriter::new(
@@ -46,49 +83,65 @@ impl Network {
         }
 
         timer.start("Split into edges");
-        let mut network = split_edges(nodes, ways);
+        let mut network = split_edges(nodes, ways, &barrier_nodes);
+        network.profile = profile;
+        // Pedestrians don't follow turn restrictions
+        if profile != Profile::Walking {
+            network.turn_restrictions = turn_restrictions;
+        }
+        network.traffic_signals = traffic_signals;
         timer.stop();
+        network.warnings.add(
+            WarningCategory::DroppedWays,
+            "Ways were missing nodes, probably because they cross the edge of the input",
+            num_dropped,
+        );
+        network.warnings.add(
+            WarningCategory::DroppedWays,
+            "Paths were too difficult, according to path_difficulty",
+            num_too_difficult,
+        );
         println!(
             "  Split into {} edges",
             HumanCount(network.edges.len() as u64),
         );
 
+        if let Some(boundary) = clip_boundary {
+            timer.start("Clip to boundary");
+            network.clip(boundary);
+            timer.stop();
+            println!(
+                "  Kept {} edges touching the boundary",
+                HumanCount(network.edges.len() as u64),
+            );
+        }
+
         // TODO Might be more useful to double-count and just see how many things are within a 50m
         // buffer
         let closest_edge = build_closest_edge(&network, timer);
         timer.start("Match amenities to closest edge");
-        let progress = utils::progress_bar_for_count(amenity_positions.len());
-        for amenity in amenity_positions {
+        let progress = utils::progress_bar_for_count(amenities.len());
+        for amenity in &amenities {
             progress.inc(1);
-            if let Some(edge) = closest_edge.nearest_neighbor(&amenity.to_degrees_array()) {
+            if let Some(edge) = closest_edge.nearest_neighbor(&amenity.position.to_degrees_array())
+            {
                 network.edges.get_mut(&edge.data).unwrap().nearby_amenities += 1;
             }
         }
+        network.amenities = amenities;
         timer.stop();
 
         timer.start("Calculate LTS for all edges");
-        let progress = utils::progress_bar_for_count(network.edges.len());
-        // LTS calculations can have high overhead in one case, so calculate them in batches
-        let all_keys: Vec<(NodeID, NodeID)> = network.edges.keys().cloned().collect();
-        for key_batch in all_keys.chunks(1000) {
-            let tags_batch: Vec<&Tags> =
-                key_batch.iter().map(|e| &network.edges[&e].tags).collect();
-            let lts_batch = plugins::lts::calculate_lts_batch(lts, tags_batch);
-            for (key, lts) in key_batch.into_iter().zip(lts_batch) {
-                progress.inc(1);
-                network.edges.get_mut(&key).unwrap().lts = lts;
-            }
-        }
+        network.recalculate_lts(lts);
         timer.stop();
 
-        if let Some(bytes) = geotiff_bytes {
+        if let Some(mut elevation) = elevation {
             timer.start("Calculate elevation for all edges");
-            let mut geotiff = GeoTiffElevation::new(Cursor::new(bytes));
             let mut succeeded = 0;
             let progress = utils::progress_bar_for_count(network.edges.len());
             for (_, edge) in &mut network.edges {
                 progress.inc(1);
-                if edge.apply_elevation(&mut geotiff) {
+                if edge.apply_elevation(elevation.as_mut(), profile) {
                     succeeded += 1;
                 }
             }
@@ -98,9 +151,17 @@ impl Network {
                 HumanCount(succeeded as u64),
                 HumanCount(network.edges.len() as u64)
             );
+            network.warnings.add(
+                WarningCategory::MissingElevation,
+                "Edges had no elevation data",
+                network.edges.len() - succeeded,
+            );
         }
 
         timer.start("Calculate cost for all edges");
+        network.oneway = oneway.clone();
+        network.barriers = barriers.clone();
+        network.exposure_layers = exposure_layers.to_vec();
         network.recalculate_cost(cost)?;
         timer.stop();
 
@@ -108,22 +169,71 @@ impl Network {
         Ok(network)
     }
 
+    /// Removes edges entirely outside the boundary. Edges crossing it are kept whole.
+    fn clip(&mut self, boundary: &MultiPolygon<f64>) {
+        let keep = self.edges_in_area(boundary);
+        self.edges.retain(|key, _| keep.contains(key));
+        let nodes: HashSet<NodeID> = keep.into_iter().flat_map(|(n1, n2)| [n1, n2]).collect();
+        self.intersections.retain(|node, _| nodes.contains(node));
+    }
+
+    /// Sets every edge's LTS from its tags, adjusted for the network's profile. This doesn't
+    /// include LTS raised by `traffic_counts`.
+    pub fn recalculate_lts(&mut self, lts: &LtsMapping) {
+        let progress = utils::progress_bar_for_count(self.edges.len());
+        // LTS calculations can have high overhead in one case, so calculate them in batches
+        let all_keys: Vec<(NodeID, NodeID)> = self.edges.keys().cloned().collect();
+        for key_batch in all_keys.chunks(1000) {
+            let tags_batch: Vec<&Tags> = key_batch.iter().map(|e| &self.edges[&e].tags).collect();
+            let lts_batch = plugins::lts::calculate_lts_batch(lts, tags_batch);
+            for (key, lts) in key_batch.into_iter().zip(lts_batch) {
+                progress.inc(1);
+                let edge = self.edges.get_mut(&key).unwrap();
+                edge.lts = match self.profile {
+                    Profile::Cycling => lts,
+                    Profile::CargoBike => cargo_bike::cargo_bike_lts(&edge.tags, lts),
+                    Profile::Walking => walking::walking_lts(&edge.tags, lts),
+                };
+            }
+        }
+    }
+
     pub fn recalculate_cost(&mut self, cost: &mut CostFunction) -> Result<()> {
-        cost.normalize()?;
+        cost.normalize(&self.exposure_layers)?;
 
+        let mut calculator = plugins::cost::CostCalculator::new(cost)?;
         let progress = utils::progress_bar_for_count(self.edges.len());
         let all_keys: Vec<(NodeID, NodeID)> = self.edges.keys().cloned().collect();
         for key_batch in all_keys.chunks(1000) {
             let input_batch: Vec<&Edge> = key_batch.iter().map(|e| &self.edges[&e]).collect();
-            let output_batch = plugins::cost::calculate_batch(cost, input_batch);
-            for (key, cost) in key_batch.into_iter().zip(output_batch) {
+            let output_batch = calculator.calculate_batch(input_batch)?;
+            for (key, (forward_cost, backward_cost)) in key_batch.into_iter().zip(output_batch) {
                 progress.inc(1);
 
-                if let Some((forward_cost, backward_cost)) = cost {
-                    let edge = self.edges.get_mut(&key).unwrap();
-                    edge.forward_cost = Some(forward_cost);
-                    edge.backward_cost = Some(backward_cost);
-                };
+                // Reset the cost if it's not allowed anymore, in case this was previously
+                // calculated with a different cost function
+                let edge = self.edges.get_mut(&key).unwrap();
+                edge.forward_cost = forward_cost;
+                edge.backward_cost = backward_cost;
+                if !self.surface_multipliers.is_empty() || self.lighting.night {
+                    let multiplier = self.surface_multipliers.multiplier(&edge.tags)
+                        * self.lighting.multiplier(&edge.tags, edge.lit_override);
+                    let scale = |cost: usize| (cost as f64 * multiplier).round() as usize;
+                    edge.forward_cost = edge.forward_cost.map(scale);
+                    edge.backward_cost = edge.backward_cost.map(scale);
+                }
+                // Pedestrians can walk both ways on one-way streets, and past barriers meant for
+                // bikes and cars
+                if self.profile != Profile::Walking {
+                    self.oneway.apply(edge);
+                    self.barriers.apply(edge);
+                }
+                if self.profile == Profile::CargoBike {
+                    cargo_bike::apply(edge);
+                }
+                if let Some(cost_override) = self.cost_overrides.get(&edge.way_id) {
+                    cost_override.apply(edge);
+                }
             }
         }
 
@@ -131,73 +241,161 @@ impl Network {
     }
 }
 
-struct Way {
-    tags: Tags,
-    nodes: Vec<NodeID>,
+pub(super) struct Way {
+    pub tags: Tags,
+    pub nodes: Vec<NodeID>,
+}
+
+/// osm_reader detects XML or PBF, but not compression. Gzipped input (like `.osm.gz`) is
+/// recognized by its magic bytes and decompressed first.
+fn decompress_if_gzipped(input_bytes: &[u8]) -> Result<Cow<'_, [u8]>> {
+    if !input_bytes.starts_with(&[0x1f, 0x8b]) {
+        return Ok(Cow::Borrowed(input_bytes));
+    }
+    let mut decompressed = Vec::new();
+    GzDecoder::new(input_bytes).read_to_end(&mut decompressed)?;
+    Ok(Cow::Owned(decompressed))
+}
+
+struct ScrapedElements {
+    nodes: HashMap<NodeID, Position>,
+    ways: HashMap<WayID, Way>,
+    amenities: Vec<Amenity>,
+    greenspace_polygons: Vec<Polygon>,
+    turn_restrictions: Vec<TurnRestriction>,
+    traffic_signals: HashSet<NodeID>,
+    /// Tags of `barrier` nodes used by ways
+    barrier_nodes: HashMap<NodeID, Tags>,
+    /// Ways missing nodes
+    num_dropped: usize,
+    num_too_difficult: usize,
 }
 
 fn scrape_elements(
-    input_bytes: &[u8],
-) -> Result<(
-    HashMap<NodeID, Position>,
-    HashMap<WayID, Way>,
-    Vec<Position>,
-    Vec<Polygon>,
-)> {
-    // Scrape every node ID -> position
+    inputs: &[Cow<[u8]>],
+    path_difficulty: &PathDifficulty,
+) -> Result<ScrapedElements> {
+    // Most nodes in a big input are for buildings and other things that aren't used. Storing only
+    // the needed ones takes much less memory than storing all of them, at the cost of parsing
+    // twice. The first pass finds the nodes that ways need.
+    let mut needed_nodes = HashSet::new();
+    for input_bytes in inputs {
+        osm_reader::parse(input_bytes, |elem| {
+            if let Element::Way { node_ids, tags, .. } = elem {
+                let tags = Tags::from(tags);
+                if tags.has("highway") || greenspace::is_greenspace(&tags) {
+                    needed_nodes.extend(node_ids);
+                } else if is_amenity(&tags) {
+                    needed_nodes.extend(node_ids.first());
+                }
+            }
+        })?;
+    }
+
+    // Scrape every needed node ID -> position
     let mut nodes = HashMap::new();
     // Scrape every routable road. Just tags and node lists to start.
     let mut ways = HashMap::new();
-    let mut amenity_positions = Vec::new();
+    let mut amenities = Vec::new();
     let mut greenspace_polygons = Vec::new();
+    let mut turn_restrictions = Vec::new();
+    let mut traffic_signals = HashSet::new();
+    let mut barrier_nodes = HashMap::new();
+    // Amenity nodes and ways already seen in an earlier input
+    let mut seen_amenity_nodes = HashSet::new();
+    let mut seen_ways = HashSet::new();
+    let mut num_too_difficult = 0;
 
-    osm_reader::parse(input_bytes, |elem| match elem {
-        Element::Node { id, lon, lat, tags } => {
-            let pos = Position::from_degrees(lon, lat);
-            nodes.insert(id, pos);
-
-            let tags = Tags::from(tags);
-            if is_amenity(&tags) {
-                amenity_positions.push(pos);
-            }
-        }
-        Element::Way { id, node_ids, tags } => {
-            let tags = Tags::from(tags);
-            if is_amenity(&tags) {
-                // TODO Calculate a centroid instead
-                amenity_positions.push(nodes[&node_ids[0]]);
-            }
+    for input_bytes in inputs {
+        osm_reader::parse(input_bytes, |elem| match elem {
+            Element::Node { id, lon, lat, tags } => {
+                let pos = Position::from_degrees(lon, lat);
+                if needed_nodes.contains(&id) {
+                    nodes.insert(id, pos);
+                }
 
-            if let Some(polygon) = greenspace::get_polygon(&tags, &nodes, &node_ids) {
-                greenspace_polygons.push(polygon);
+                let tags = Tags::from(tags);
+                if tags.is("highway", "traffic_signals") {
+                    traffic_signals.insert(id);
+                }
+                if tags.has("barrier") && needed_nodes.contains(&id) {
+                    barrier_nodes.insert(id, tags.clone());
+                }
+                if is_amenity(&tags) && seen_amenity_nodes.insert(id) {
+                    amenities.push(Amenity::new(pos, &tags));
+                }
             }
+            Element::Way { id, node_ids, tags } => {
+                if !seen_ways.insert(id) {
+                    return;
+                }
+                let tags = Tags::from(tags);
+                if is_amenity(&tags) {
+                    // TODO Calculate a centroid instead. Skip ways whose first node is outside
+                    // the extract.
+                    if let Some(pos) = node_ids.first().and_then(|id| nodes.get(id)) {
+                        amenities.push(Amenity::new(*pos, &tags));
+                    }
+                }
 
-            // If a way crosses the boundary and all nodes aren't present, filter it out up-front
-            let all_nodes = node_ids.iter().all(|n| nodes.contains_key(n));
+                if let Some(polygon) = greenspace::get_polygon(&tags, &nodes, &node_ids) {
+                    greenspace_polygons.push(polygon);
+                }
 
-            // Include everything here, and let LTS::NotAllowed later filter some out
-            if tags.has("highway") && all_nodes {
-                ways.insert(
-                    id,
-                    Way {
-                        tags,
-                        nodes: node_ids,
-                    },
-                );
+                // Include everything here, and let LTS::NotAllowed later filter some out. The
+                // exception is paths too difficult to cycle on, which no cost function should use.
+                if tags.has("highway") {
+                    if path_difficulty.excludes(&tags) {
+                        num_too_difficult += 1;
+                    } else {
+                        ways.insert(
+                            id,
+                            Way {
+                                tags,
+                                nodes: node_ids,
+                            },
+                        );
+                    }
+                }
             }
-        }
-        Element::Relation { .. } => {
-            // TODO Handle for amenities. What about when they're large, or might be
-            // double-tagged?
-            // https://www.openstreetmap.org/relation/14875126
-        }
-        Element::Bounds { .. } => {}
-    })?;
+            Element::Relation { tags, members, .. } => {
+                if let Some(restriction) =
+                    TurnRestriction::from_relation(&Tags::from(tags), &members)
+                {
+                    turn_restrictions.push(restriction);
+                }
+                // TODO Handle for amenities. What about when they're large, or might be
+                // double-tagged?
+                // https://www.openstreetmap.org/relation/14875126
+            }
+            Element::Bounds { .. } => {}
+        })?;
+    }
 
-    Ok((nodes, ways, amenity_positions, greenspace_polygons))
+    // If a way crosses the boundary and all nodes aren't present, filter it out. This happens
+    // after reading every input, since another input might have the missing nodes.
+    let num_ways = ways.len();
+    ways.retain(|_, way| way.nodes.iter().all(|n| nodes.contains_key(n)));
+    let num_dropped = num_ways - ways.len();
+
+    Ok(ScrapedElements {
+        nodes,
+        ways,
+        amenities,
+        greenspace_polygons,
+        turn_restrictions,
+        traffic_signals,
+        barrier_nodes,
+        num_dropped,
+        num_too_difficult,
+    })
 }
 
-fn split_edges(nodes: HashMap<NodeID, Position>, ways: HashMap<WayID, Way>) -> Network {
+pub(super) fn split_edges(
+    nodes: HashMap<NodeID, Position>,
+    ways: HashMap<WayID, Way>,
+    barrier_nodes: &HashMap<NodeID, Tags>,
+) -> Network {
     // Count how many ways reference each node
     let mut node_counter: HashMap<NodeID, usize> = HashMap::new();
     for way in ways.values() {
@@ -212,6 +410,7 @@ fn split_edges(nodes: HashMap<NodeID, Position>, ways: HashMap<WayID, Way>) -> N
     for (way_id, way) in ways {
         let mut node1 = way.nodes[0];
         let mut pts = Vec::new();
+        let mut barriers = Vec::new();
 
         let num_nodes = way.nodes.len();
         for (idx, node) in way.nodes.into_iter().enumerate() {
@@ -236,14 +435,24 @@ fn split_edges(nodes: HashMap<NodeID, Position>, ways: HashMap<WayID, Way>) -> N
                         backward_cost: None,
                         slope: None,
                         slope_factor: None,
+                        ebike_slope_factor: None,
+                        elevation_gain: None,
                         lts: LTS::NotAllowed,
                         nearby_amenities: 0,
+                        barriers: std::mem::take(&mut barriers),
+                        aadt: None,
+                        exposure: BTreeMap::new(),
+                        lit_override: false,
                     },
                 );
 
                 // Start the next edge
                 node1 = node;
                 pts.push(nodes[&node]);
+            } else if let Some(tags) = barrier_nodes.get(&node) {
+                if !is_endpoint {
+                    barriers.push(tags.clone());
+                }
             }
         }
     }
@@ -251,6 +460,19 @@ fn split_edges(nodes: HashMap<NodeID, Position>, ways: HashMap<WayID, Way>) -> N
     Network {
         edges,
         intersections,
+        turn_restrictions: Vec::new(),
+        traffic_signals: HashSet::new(),
+        amenities: Vec::new(),
+        turn_costs: TurnCosts::default(),
+        profile: Profile::Cycling,
+        oneway: Oneway::default(),
+        barriers: Barriers::default(),
+        surface_multipliers: SurfaceMultipliers::default(),
+        lighting: Lighting::default(),
+        exposure_layers: Vec::new(),
+        cost_overrides: HashMap::new(),
+        warnings: Warnings::default(),
+        cache_key: 0,
     }
 }
 
@@ -262,10 +484,10 @@ fn calculate_length_meters(pts: &[Position]) -> f64 {
 
 // Split every Edge into individual line segments, and identify by the OSM node ID pair.
 // TODO WGS84 caveat, and no linestring primitive?
-type EdgeLocation = GeomWithData<Line<[f64; 2]>, (NodeID, NodeID)>;
+pub type EdgeLocation = GeomWithData<Line<[f64; 2]>, (NodeID, NodeID)>;
 
-fn build_closest_edge(network: &Network, timer: &mut Timer) -> RTree<EdgeLocation> {
-    timer.start("Building RTree for matching amenities to edges");
+pub(super) fn build_closest_edge(network: &Network, timer: &mut Timer) -> RTree<EdgeLocation> {
+    timer.start("Building RTree for matching things to edges");
     let mut lines = Vec::new();
     for (id, edge) in &network.edges {
         for pair in edge.geometry.windows(2) {
@@ -279,3 +501,293 @@ fn build_closest_edge(network: &Network, timer: &mut Timer) -> RTree<EdgeLocatio
     timer.stop();
     rtree
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::*;
+    use crate::config::tests::config_with;
+
+    const OSM_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+  <node id="1" lat="0.0" lon="0.0"/>
+  <node id="2" lat="0.0" lon="0.001"/>
+  <node id="3" lat="0.001" lon="0.001"/>
+  <way id="1">
+    <nd ref="1"/>
+    <nd ref="2"/>
+    <nd ref="3"/>
+    <tag k="highway" v="residential"/>
+  </way>
+</osm>"#;
+
+    fn edges(input_bytes: &[u8]) -> Vec<(NodeID, NodeID)> {
+        let mut config = config_with("");
+        let network = Network::make_from_osm(
+            &[input_bytes],
+            &config.lts,
+            config.profile,
+            &config.path_difficulty,
+            None,
+            &mut config.cost,
+            &[],
+            &config.oneway,
+            &config.barriers,
+            &mut Timer::new(),
+            None,
+        )
+        .unwrap();
+        let mut edges: Vec<_> = network.edges.into_keys().collect();
+        edges.sort();
+        edges
+    }
+
+    #[test]
+    fn test_xml_and_gzipped_xml() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(OSM_XML.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let expected = vec![(NodeID(1), NodeID(3))];
+        assert_eq!(edges(OSM_XML.as_bytes()), expected);
+        assert_eq!(edges(&gzipped), expected);
+    }
+
+    #[test]
+    fn test_route_around_difficult_path() {
+        // A direct mountain path from 1 to 2, and a longer road detour through 3
+        let osm_xml = r#"<osm version="0.6">
+  <node id="1" lat="0.0" lon="0.0"/>
+  <node id="2" lat="0.0" lon="0.002"/>
+  <node id="3" lat="0.001" lon="0.001"/>
+  <way id="1">
+    <nd ref="1"/>
+    <nd ref="2"/>
+    <tag k="highway" v="path"/>
+    <tag k="sac_scale" v="mountain_hiking"/>
+  </way>
+  <way id="2">
+    <nd ref="1"/>
+    <nd ref="3"/>
+    <tag k="highway" v="residential"/>
+  </way>
+  <way id="3">
+    <nd ref="3"/>
+    <nd ref="2"/>
+    <tag k="highway" v="residential"/>
+  </way>
+</osm>"#;
+        let route = |max_sac_scale: &str| {
+            let mut config = config_with(&format!(
+                r#", "path_difficulty": {{ "max_sac_scale": "{max_sac_scale}" }}"#
+            ));
+            let mut timer = Timer::new();
+            let network = Network::make_from_osm(
+                &[osm_xml.as_bytes()],
+                &config.lts,
+                config.profile,
+                &config.path_difficulty,
+                None,
+                &mut config.cost,
+                &[],
+                &config.oneway,
+                &config.barriers,
+                &mut timer,
+                None,
+            )
+            .unwrap();
+            crate::dijkstra::DijkstraRouter::new(&network, &mut timer)
+                .route(0.0, 0.0, 0.002, 0.0)
+                .unwrap()
+        };
+
+        assert_eq!(route("hiking"), vec![NodeID(1), NodeID(3), NodeID(2)]);
+        assert_eq!(route("mountain_hiking"), vec![NodeID(1), NodeID(2)]);
+    }
+
+    #[test]
+    fn test_merge_overlapping_inputs() {
+        // Way 1 crosses the seam. The first input is missing node 3, and both have node 2 and
+        // the shop.
+        let west = r#"<osm version="0.6">
+  <node id="1" lat="0.0" lon="0.0"/>
+  <node id="2" lat="0.0" lon="0.001"/>
+  <node id="5" lat="0.0" lon="0.001">
+    <tag k="shop" v="bakery"/>
+  </node>
+  <way id="1">
+    <nd ref="1"/>
+    <nd ref="2"/>
+    <nd ref="3"/>
+    <tag k="highway" v="residential"/>
+  </way>
+</osm>"#;
+        let east = r#"<osm version="0.6">
+  <node id="2" lat="0.0" lon="0.001"/>
+  <node id="3" lat="0.0" lon="0.002"/>
+  <node id="4" lat="0.0" lon="0.003"/>
+  <node id="5" lat="0.0" lon="0.001">
+    <tag k="shop" v="bakery"/>
+  </node>
+  <way id="1">
+    <nd ref="1"/>
+    <nd ref="2"/>
+    <nd ref="3"/>
+    <tag k="highway" v="residential"/>
+  </way>
+  <way id="2">
+    <nd ref="3"/>
+    <nd ref="4"/>
+    <tag k="highway" v="residential"/>
+  </way>
+</osm>"#;
+
+        let inputs = [
+            Cow::Borrowed(west.as_bytes()),
+            Cow::Borrowed(east.as_bytes()),
+        ];
+        let scraped = scrape_elements(&inputs, &PathDifficulty::default()).unwrap();
+        assert_eq!(scraped.ways.len(), 2);
+        assert_eq!(scraped.amenities.len(), 1);
+        assert_eq!(scraped.num_dropped, 0);
+
+        // A shop whose outline is outside the extract is skipped
+        let clipped = r#"<osm version="0.6">
+  <way id="3">
+    <nd ref="98"/>
+    <nd ref="99"/>
+    <tag k="shop" v="bakery"/>
+  </way>
+</osm>"#;
+        let scraped = scrape_elements(
+            &[Cow::Borrowed(clipped.as_bytes())],
+            &PathDifficulty::default(),
+        )
+        .unwrap();
+        assert!(scraped.amenities.is_empty());
+
+        let mut config = config_with("");
+        let network = Network::make_from_osm(
+            &[west.as_bytes(), east.as_bytes()],
+            &config.lts,
+            config.profile,
+            &config.path_difficulty,
+            None,
+            &mut config.cost,
+            &[],
+            &config.oneway,
+            &config.barriers,
+            &mut Timer::new(),
+            None,
+        )
+        .unwrap();
+        let mut edges: Vec<_> = network.edges.into_keys().collect();
+        edges.sort();
+        assert_eq!(edges, vec![(NodeID(1), NodeID(3)), (NodeID(3), NodeID(4))]);
+    }
+
+    #[test]
+    fn test_clip() {
+        let mut network = crate::network::tests::make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.003, 0.0),
+            ],
+            &[(1, 2), (2, 3), (3, 4)],
+        );
+        let boundary = MultiPolygon(vec![Polygon::new(
+            LineString::from(vec![
+                (-0.001, -0.001),
+                (0.0015, -0.001),
+                (0.0015, 0.001),
+                (-0.001, 0.001),
+                (-0.001, -0.001),
+            ]),
+            Vec::new(),
+        )]);
+        network.clip(&boundary);
+
+        let mut edges: Vec<_> = network.edges.keys().cloned().collect();
+        edges.sort();
+        // The edge crossing the boundary is kept
+        assert_eq!(edges, vec![(NodeID(1), NodeID(2)), (NodeID(2), NodeID(3))]);
+        let mut intersections: Vec<_> = network.intersections.keys().cloned().collect();
+        intersections.sort();
+        assert_eq!(intersections, vec![NodeID(1), NodeID(2), NodeID(3)]);
+    }
+
+    #[test]
+    fn test_only_needed_nodes_kept() {
+        // Nodes 3 to 6 are a building, and node 7 is a shop
+        let input = r#"<osm version="0.6">
+  <node id="1" lat="0.0" lon="0.0"/>
+  <node id="2" lat="0.0" lon="0.001"/>
+  <node id="3" lat="0.001" lon="0.0"/>
+  <node id="4" lat="0.001" lon="0.001"/>
+  <node id="5" lat="0.002" lon="0.001"/>
+  <node id="6" lat="0.002" lon="0.0"/>
+  <node id="7" lat="0.0" lon="0.0005">
+    <tag k="shop" v="bakery"/>
+  </node>
+  <way id="1">
+    <nd ref="1"/>
+    <nd ref="2"/>
+    <tag k="highway" v="residential"/>
+  </way>
+  <way id="2">
+    <nd ref="3"/>
+    <nd ref="4"/>
+    <nd ref="5"/>
+    <nd ref="6"/>
+    <nd ref="3"/>
+    <tag k="building" v="yes"/>
+  </way>
+</osm>"#;
+        let scraped = scrape_elements(
+            &[Cow::Borrowed(input.as_bytes())],
+            &PathDifficulty::default(),
+        )
+        .unwrap();
+        let mut node_ids: Vec<_> = scraped.nodes.into_keys().collect();
+        node_ids.sort();
+        assert_eq!(node_ids, vec![NodeID(1), NodeID(2)]);
+        assert_eq!(scraped.ways.len(), 1);
+        assert_eq!(scraped.amenities.len(), 1);
+        assert_eq!(scraped.amenities[0].position.to_degrees(), (0.0005, 0.0));
+    }
+
+    #[test]
+    fn test_barrier_in_middle_of_edge() {
+        let input = r#"<osm version="0.6">
+  <node id="1" lat="0.0" lon="0.0"/>
+  <node id="2" lat="0.0" lon="0.001">
+    <tag k="barrier" v="gate"/>
+  </node>
+  <node id="3" lat="0.0" lon="0.002">
+    <tag k="barrier" v="bollard"/>
+  </node>
+  <way id="1">
+    <nd ref="1"/>
+    <nd ref="2"/>
+    <nd ref="3"/>
+    <tag k="highway" v="residential"/>
+  </way>
+</osm>"#;
+        let scraped = scrape_elements(
+            &[Cow::Borrowed(input.as_bytes())],
+            &PathDifficulty::default(),
+        )
+        .unwrap();
+        let network = split_edges(scraped.nodes, scraped.ways, &scraped.barrier_nodes);
+        let edge = &network.edges[&(NodeID(1), NodeID(3))];
+        // The bollard at the end of the way isn't in the middle of the edge
+        assert_eq!(edge.barriers.len(), 1);
+        assert!(edge.barriers[0].is("barrier", "gate"));
+    }
+}
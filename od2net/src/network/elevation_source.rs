@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+use std::io::{BufReader, Cursor, Read, Seek};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use elevation::GeoTiffElevation;
+use fs_err::File;
+use geo::Rect;
+use rstar::primitives::{GeomWithData, Rectangle};
+use rstar::RTree;
+
+use crate::config::{ElevationTiles, TileEncoding};
+
+/// Something that can look up the height of a point, in meters
+pub trait ElevationSource {
+    /// Returns None if there's no data covering this point
+    fn get_height_for_lon_lat(&mut self, lon: f32, lat: f32) -> Option<f32>;
+}
+
+impl<R: Read + Seek + Send> ElevationSource for GeoTiffElevation<R> {
+    fn get_height_for_lon_lat(&mut self, lon: f32, lat: f32) -> Option<f32> {
+        GeoTiffElevation::get_height_for_lon_lat(self, lon, lat)
+    }
+}
+
+/// Looks up heights from several sources, like neighbouring GeoTIFF tiles. Only the sources whose
+/// bounds cover a point are tried, in order.
+pub struct MultiElevation {
+    sources: Vec<Box<dyn ElevationSource>>,
+    /// The bounds of each source, in WGS84
+    index: RTree<GeomWithData<Rectangle<[f64; 2]>, usize>>,
+}
+
+impl MultiElevation {
+    pub fn new(sources: Vec<(Box<dyn ElevationSource>, Rect)>) -> Self {
+        let mut boxes = Vec::new();
+        let sources = sources
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (source, bounds))| {
+                let rect = Rectangle::from_corners(
+                    [bounds.min().x, bounds.min().y],
+                    [bounds.max().x, bounds.max().y],
+                );
+                boxes.push(GeomWithData::new(rect, idx));
+                source
+            })
+            .collect();
+        Self {
+            sources,
+            index: RTree::bulk_load(boxes),
+        }
+    }
+}
+
+impl ElevationSource for MultiElevation {
+    fn get_height_for_lon_lat(&mut self, lon: f32, lat: f32) -> Option<f32> {
+        let mut candidates: Vec<usize> = self
+            .index
+            .locate_all_at_point(&[lon as f64, lat as f64])
+            .map(|obj| obj.data)
+            .collect();
+        candidates.sort();
+        candidates
+            .into_iter()
+            .find_map(|idx| self.sources[idx].get_height_for_lon_lat(lon, lat))
+    }
+}
+
+/// Reads GeoTIFFs for elevation or exposure layers, keeping single files in memory so they're only
+/// read once when several configs or layers share them
+#[derive(Default)]
+pub struct GeoTiffFiles {
+    /// The contents of each file, by canonical path
+    files: HashMap<String, Arc<[u8]>>,
+}
+
+impl GeoTiffFiles {
+    /// `path` is one GeoTIFF, or a directory of `.tif` tiles. Tiles are read lazily, since a
+    /// directory of them might not fit in memory.
+    pub fn load(&mut self, path: &str) -> Result<Box<dyn ElevationSource>> {
+        if !std::path::Path::new(path).is_dir() {
+            let bytes = self.read(path)?;
+            return Ok(Box::new(GeoTiffElevation::new(Cursor::new(bytes))));
+        }
+
+        let mut paths = Vec::new();
+        for entry in fs_err::read_dir(path)? {
+            let entry_path = entry?.path();
+            if entry_path
+                .extension()
+                .is_some_and(|ext| ext == "tif" || ext == "tiff")
+            {
+                paths.push(entry_path);
+            }
+        }
+        if paths.is_empty() {
+            bail!("No .tif files in {path}");
+        }
+        paths.sort();
+        println!("Using {} GeoTIFFs from {path}", paths.len());
+
+        let mut sources: Vec<(Box<dyn ElevationSource>, Rect)> = Vec::new();
+        for path in paths {
+            let bounds = crate::cost_raster::read_geotiff_bounds(&path.display().to_string())?;
+            let reader = BufReader::new(File::open(path)?);
+            sources.push((Box::new(GeoTiffElevation::new(reader)), bounds));
+        }
+        Ok(Box::new(MultiElevation::new(sources)))
+    }
+
+    fn read(&mut self, path: &str) -> Result<Arc<[u8]>> {
+        let path = std::fs::canonicalize(path)?.display().to_string();
+        if let Some(bytes) = self.files.get(&path) {
+            return Ok(bytes.clone());
+        }
+        let bytes: Arc<[u8]> = fs_err::read(&path)?.into();
+        self.files.insert(path, bytes.clone());
+        Ok(bytes)
+    }
+}
+
+/// Downloads one tile. Separate from `TileElevation` so tests don't need the network.
+pub trait TileFetcher {
+    fn fetch(&mut self, url: &str) -> Result<Vec<u8>>;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub struct HttpFetcher;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl TileFetcher for HttpFetcher {
+    fn fetch(&mut self, url: &str) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ureq::get(url)
+            .call()?
+            .into_reader()
+            .read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// Looks up heights from raster tiles, fetching each tile the first time it's needed. Tiles are
+/// cached on disk, so later runs don't download them again.
+pub struct TileElevation<F: TileFetcher> {
+    config: ElevationTiles,
+    cache_directory: String,
+    fetcher: F,
+    /// None means the tile couldn't be fetched or decoded
+    tiles: HashMap<(u32, u32), Option<Tile>>,
+}
+
+struct Tile {
+    width: usize,
+    height: usize,
+    heights: Vec<f32>,
+}
+
+impl<F: TileFetcher> TileElevation<F> {
+    pub fn new(config: ElevationTiles, cache_directory: String, fetcher: F) -> Self {
+        Self {
+            config,
+            cache_directory,
+            fetcher,
+            tiles: HashMap::new(),
+        }
+    }
+
+    fn get_tile(&mut self, x: u32, y: u32) -> Option<&Tile> {
+        if !self.tiles.contains_key(&(x, y)) {
+            let tile = match self.load_tile(x, y) {
+                Ok(tile) => Some(tile),
+                Err(err) => {
+                    println!(
+                        "Couldn't get elevation tile {}/{x}/{y}, treating it as missing: {err}",
+                        self.config.zoom
+                    );
+                    None
+                }
+            };
+            self.tiles.insert((x, y), tile);
+        }
+        self.tiles[&(x, y)].as_ref()
+    }
+
+    fn load_tile(&mut self, x: u32, y: u32) -> Result<Tile> {
+        let z = self.config.zoom;
+        let cache_path = format!("{}/{z}/{x}/{y}.png", self.cache_directory);
+        let bytes = match fs_err::read(&cache_path) {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                let url = self
+                    .config
+                    .url_template
+                    .replace("{z}", &z.to_string())
+                    .replace("{x}", &x.to_string())
+                    .replace("{y}", &y.to_string());
+                let bytes = self.fetcher.fetch(&url)?;
+                fs_err::create_dir_all(format!("{}/{z}/{x}", self.cache_directory))?;
+                fs_err::write(&cache_path, &bytes)?;
+                bytes
+            }
+        };
+        decode_tile(&bytes, self.config.encoding)
+    }
+}
+
+impl<F: TileFetcher> ElevationSource for TileElevation<F> {
+    fn get_height_for_lon_lat(&mut self, lon: f32, lat: f32) -> Option<f32> {
+        // Web Mercator tile coordinates, with the fractional part locating the pixel
+        let n = 2.0_f64.powi(self.config.zoom as i32);
+        let lat_radians = (lat as f64).to_radians();
+        let x = (lon as f64 + 180.0) / 360.0 * n;
+        let y = (1.0 - lat_radians.tan().asinh() / std::f64::consts::PI) / 2.0 * n;
+        if !(0.0..n).contains(&x) || !(0.0..n).contains(&y) {
+            return None;
+        }
+
+        let tile = self.get_tile(x as u32, y as u32)?;
+        let col = ((x.fract() * tile.width as f64) as usize).min(tile.width - 1);
+        let row = ((y.fract() * tile.height as f64) as usize).min(tile.height - 1);
+        Some(tile.heights[row * tile.width + col])
+    }
+}
+
+fn decode_tile(bytes: &[u8], encoding: TileEncoding) -> Result<Tile> {
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let samples = info.color_type.samples();
+    if samples < 3 {
+        bail!("Tile isn't RGB, it's {:?}", info.color_type);
+    }
+
+    let width = info.width as usize;
+    let height = info.height as usize;
+    let mut heights = Vec::with_capacity(width * height);
+    for row in 0..height {
+        let line = &buffer[row * info.line_size..];
+        for col in 0..width {
+            let pixel = &line[col * samples..];
+            let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+            heights.push(match encoding {
+                TileEncoding::Terrarium => (r * 256.0 + g + b / 256.0) - 32768.0,
+                TileEncoding::TerrainRgb => -10000.0 + (r * 256.0 * 256.0 + g * 256.0 + b) * 0.1,
+            });
+        }
+    }
+    Ok(Tile {
+        width,
+        height,
+        heights,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves the same tile for every URL, remembering what was requested
+    struct MockFetcher {
+        tile: Vec<u8>,
+        requested: Vec<String>,
+    }
+
+    impl TileFetcher for MockFetcher {
+        fn fetch(&mut self, url: &str) -> Result<Vec<u8>> {
+            self.requested.push(url.to_string());
+            Ok(self.tile.clone())
+        }
+    }
+
+    struct FailingFetcher;
+
+    impl TileFetcher for FailingFetcher {
+        fn fetch(&mut self, _: &str) -> Result<Vec<u8>> {
+            bail!("no network")
+        }
+    }
+
+    /// A 2x2 Terrarium tile where every pixel is 100m high
+    fn encode_tile() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, 2, 2);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[128, 100, 0].repeat(4)).unwrap();
+        writer.finish().unwrap();
+        bytes
+    }
+
+    fn tile_config() -> ElevationTiles {
+        ElevationTiles {
+            url_template: "https://example.com/{z}/{x}/{y}.png".to_string(),
+            encoding: TileEncoding::Terrarium,
+            zoom: 12,
+        }
+    }
+
+    #[test]
+    fn test_tiles_fetched_once() {
+        let cache_directory = std::env::temp_dir()
+            .join(format!("od2net_elevation_test_{}", std::process::id()))
+            .display()
+            .to_string();
+        let _ = std::fs::remove_dir_all(&cache_directory);
+
+        let mut elevation = TileElevation::new(
+            tile_config(),
+            cache_directory.clone(),
+            MockFetcher {
+                tile: encode_tile(),
+                requested: Vec::new(),
+            },
+        );
+        assert_eq!(elevation.get_height_for_lon_lat(-0.1, 51.5), Some(100.0));
+        assert_eq!(
+            elevation.get_height_for_lon_lat(-0.1001, 51.5001),
+            Some(100.0)
+        );
+        assert_eq!(
+            elevation.fetcher.requested,
+            vec!["https://example.com/12/2046/1362.png".to_string()]
+        );
+
+        // A new run uses the tile cached on disk
+        let mut elevation =
+            TileElevation::new(tile_config(), cache_directory.clone(), FailingFetcher);
+        assert_eq!(elevation.get_height_for_lon_lat(-0.1, 51.5), Some(100.0));
+        // But anywhere else has no coverage
+        assert_eq!(elevation.get_height_for_lon_lat(10.0, 10.0), None);
+
+        std::fs::remove_dir_all(&cache_directory).unwrap();
+    }
+
+    #[test]
+    fn test_multi_elevation() {
+        /// Covers one side of the prime meridian
+        struct HalfWorld {
+            east: bool,
+            lookups: std::rc::Rc<std::cell::Cell<usize>>,
+        }
+        impl ElevationSource for HalfWorld {
+            fn get_height_for_lon_lat(&mut self, lon: f32, _: f32) -> Option<f32> {
+                self.lookups.set(self.lookups.get() + 1);
+                ((lon >= 0.0) == self.east).then_some(if self.east { 20.0 } else { 10.0 })
+            }
+        }
+
+        let lookups = std::rc::Rc::new(std::cell::Cell::new(0));
+        let rect = |x1: f64, x2: f64| Rect::new((x1, -90.0), (x2, 90.0));
+        let mut elevation = MultiElevation::new(vec![
+            (
+                Box::new(HalfWorld {
+                    east: false,
+                    lookups: lookups.clone(),
+                }),
+                rect(-180.0, 0.0),
+            ),
+            (
+                Box::new(HalfWorld {
+                    east: true,
+                    lookups: lookups.clone(),
+                }),
+                rect(0.0, 180.0),
+            ),
+        ]);
+        // Only the tile covering each point is tried
+        assert_eq!(elevation.get_height_for_lon_lat(-1.0, 50.0), Some(10.0));
+        assert_eq!(elevation.get_height_for_lon_lat(1.0, 50.0), Some(20.0));
+        assert_eq!(lookups.get(), 2);
+        // On the seam, both are tried in order
+        assert_eq!(elevation.get_height_for_lon_lat(0.0, 50.0), Some(20.0));
+        assert_eq!(lookups.get(), 4);
+    }
+}
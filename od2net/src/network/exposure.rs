@@ -0,0 +1,144 @@
+use std::io::BufReader;
+
+use anyhow::Result;
+use fs_err::File;
+use geo::{BoundingRect, Contains, Point, Polygon};
+use geojson::FeatureReader;
+use rstar::primitives::{GeomWithData, Rectangle};
+use rstar::RTree;
+
+use super::{ElevationSource, Network};
+
+/// Something with a value at each point, like air pollution or noise
+pub trait SampleSource {
+    /// Returns None if there's no data covering this point
+    fn sample(&mut self, lon: f64, lat: f64) -> Option<f64>;
+}
+
+/// Any raster works, not just elevation
+impl SampleSource for Box<dyn ElevationSource> {
+    fn sample(&mut self, lon: f64, lat: f64) -> Option<f64> {
+        self.get_height_for_lon_lat(lon as f32, lat as f32)
+            .map(|x| x as f64)
+    }
+}
+
+/// Polygons with a value each, like noise contours. Where polygons overlap, the highest value
+/// wins.
+pub struct PolygonValues {
+    polygons: Vec<(Polygon<f64>, f64)>,
+    rtree: RTree<GeomWithData<Rectangle<[f64; 2]>, usize>>,
+}
+
+/// Reads a GeoJSON file with Polygons or MultiPolygons, using a numeric property as each one's
+/// value
+pub fn load_polygon_values(path: &str, property: &str) -> Result<PolygonValues> {
+    let reader = FeatureReader::from_reader(BufReader::new(File::open(path)?));
+    let mut polygons = Vec::new();
+    for feature in reader.features() {
+        let feature = feature?;
+        let Some(value) = feature.property(property).and_then(|x| x.as_f64()) else {
+            bail!("A feature in {path} is missing a numeric {property}");
+        };
+        let Some(gj_geom) = feature.geometry else {
+            continue;
+        };
+        let geo_geometry: geo::Geometry<f64> = gj_geom.try_into()?;
+        match geo_geometry {
+            geo::Geometry::MultiPolygon(mp) => {
+                polygons.extend(mp.into_iter().map(|p| (p, value)));
+            }
+            geo::Geometry::Polygon(p) => polygons.push((p, value)),
+            _ => bail!("A feature in {path} has geometry other than a Polygon or MultiPolygon"),
+        }
+    }
+
+    let rtree = RTree::bulk_load(
+        polygons
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, (polygon, _))| {
+                let bounds = polygon.bounding_rect()?;
+                Some(GeomWithData::new(
+                    Rectangle::from_corners(bounds.min().into(), bounds.max().into()),
+                    idx,
+                ))
+            })
+            .collect(),
+    );
+    Ok(PolygonValues { polygons, rtree })
+}
+
+impl SampleSource for PolygonValues {
+    fn sample(&mut self, lon: f64, lat: f64) -> Option<f64> {
+        let pt = Point::new(lon, lat);
+        self.rtree
+            .locate_all_at_point(&[pt.x(), pt.y()])
+            .map(|obj| &self.polygons[obj.data])
+            .filter(|(polygon, _)| polygon.contains(&pt))
+            .map(|(_, value)| *value)
+            .reduce(f64::max)
+    }
+}
+
+impl Network {
+    /// Sets `exposure[name]` on every edge to the average value of the layer along it. Returns
+    /// how many edges got a value.
+    pub fn set_exposure<S: SampleSource + ?Sized>(&mut self, name: &str, layer: &mut S) -> usize {
+        let mut count = 0;
+        for edge in self.edges.values_mut() {
+            let values: Vec<f64> = edge
+                .sample_points()
+                .into_iter()
+                .filter_map(|(_, (lon, lat))| layer.sample(lon, lat))
+                .collect();
+            if values.is_empty() {
+                edge.exposure.remove(name);
+            } else {
+                let mean = values.iter().sum::<f64>() / values.len() as f64;
+                edge.exposure.insert(name.to_string(), mean);
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use osm_reader::NodeID;
+
+    use super::*;
+    use crate::network::tests::make_network;
+
+    #[test]
+    fn test_polygon_exposure() {
+        let path = std::env::temp_dir().join("od2net_test_noise.geojson");
+        // A quiet band covering everything, with a loud one over the first half of the road
+        fs_err::write(
+            &path,
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {"db": 50}, "geometry": {"type": "Polygon", "coordinates": [[[-1, -1], [1, -1], [1, 1], [-1, 1], [-1, -1]]]}},
+                {"type": "Feature", "properties": {"db": 70}, "geometry": {"type": "Polygon", "coordinates": [[[-0.0001, -0.0001], [0.00099, -0.0001], [0.00099, 0.0001], [-0.0001, 0.0001], [-0.0001, -0.0001]]]}}
+            ]}"#,
+        )
+        .unwrap();
+        let mut noise = load_polygon_values(path.to_str().unwrap(), "db").unwrap();
+        fs_err::remove_file(&path).unwrap();
+
+        let mut network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.002, 0.0),
+                (3, 5.0, 5.0),
+                (4, 5.0, 5.001),
+            ],
+            &[(1, 2), (3, 4)],
+        );
+        assert_eq!(network.set_exposure("noise", &mut noise), 1);
+        let value = network.edges[&(NodeID(1), NodeID(2))].exposure["noise"];
+        assert!(value > 55.0 && value < 65.0);
+        // Outside every polygon
+        assert!(network.edges[&(NodeID(3), NodeID(4))].exposure.is_empty());
+    }
+}
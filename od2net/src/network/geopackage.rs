@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use geo::{Coord, LineString, MultiLineString};
+use geojson::Feature;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, OpenFlags, Transaction};
+
+use super::output::{
+    linestring_wkb, point_wkb, points_in_order, EdgeSchema, PropertyType, PropertyValue,
+};
+use super::{Counts, Network, Position};
+use crate::config::{CostFunction, GeojsonNetwork};
+use crate::timer::Timer;
+
+/// Everything in the output is in WGS84
+const SRS_ID: i32 = 4326;
+
+const WGS84_DEFINITION: &str = r#"GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563,AUTHORITY["EPSG","7030"]],AUTHORITY["EPSG","6326"]],PRIMEM["Greenwich",0,AUTHORITY["EPSG","8901"]],UNIT["degree",0.0174532925199433,AUTHORITY["EPSG","9122"]],AUTHORITY["EPSG","4326"]]"#;
+
+impl Network {
+    /// Writes a GeoPackage with three layers: `edges` with the same properties as the GeoJSON
+    /// output (OSM tags are a JSON string), and `origins` and `destinations` with their counts.
+    /// Overwrites any existing file.
+    pub fn write_geopackage(
+        &self,
+        path: &str,
+        counts: &Counts,
+        output_osm_tags: bool,
+        sort_output: bool,
+    ) -> Result<()> {
+        if std::path::Path::new(path).exists() {
+            fs_err::remove_file(path)?;
+        }
+        let mut conn = Connection::open(path)?;
+        // 'GPKG' in ASCII, and version 1.3
+        conn.execute_batch(
+            "PRAGMA application_id = 1196444487;
+             PRAGMA user_version = 10300;",
+        )?;
+        let tx = conn.transaction()?;
+        create_metadata_tables(&tx)?;
+
+        let schema = EdgeSchema::new(self, counts, output_osm_tags);
+        create_layer(&tx, "edges", "LINESTRING", &schema.columns)?;
+        {
+            let mut insert = tx.prepare(&insert_sql("edges", &schema.columns))?;
+            for (node1, node2, edge, edge_counts) in self.counted_edges(counts, sort_output) {
+                let mut row = vec![Value::Blob(geometry_blob(linestring_wkb(&edge.geometry)))];
+                row.extend(
+                    schema
+                        .values(edge, node1, node2, &edge_counts)?
+                        .into_iter()
+                        .map(sql_value),
+                );
+                insert.execute(params_from_iter(row))?;
+            }
+        }
+
+        let count_column = vec![("count".to_string(), PropertyType::Double)];
+        for (layer, points) in [
+            ("origins", &counts.count_per_origin),
+            ("destinations", &counts.count_per_destination),
+        ] {
+            create_layer(&tx, layer, "POINT", &count_column)?;
+            write_points(&tx, layer, points, sort_output)?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Like `make_from_geojson`, but reads road centerlines from one layer of a GeoPackage. The
+    /// layer must be in WGS84.
+    pub fn make_from_geopackage(
+        path: &str,
+        columns: &GeojsonNetwork,
+        cost: &mut CostFunction,
+        exposure_layers: &[String],
+        timer: &mut Timer,
+    ) -> Result<Network> {
+        timer.start(format!("Make Network from {path}"));
+        let network = read_features(path, columns.layer.as_deref()).and_then(|features| {
+            Network::make_from_features(
+                features.into_iter().map(Ok),
+                columns,
+                cost,
+                exposure_layers,
+                timer,
+            )
+        });
+        timer.stop();
+        network
+    }
+}
+
+/// Reads every row of a layer as a feature. Columns besides the geometry become properties.
+fn read_features(path: &str, layer: Option<&str>) -> Result<Vec<Feature>> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut layers: Vec<(String, String, i64)> = conn
+        .prepare("SELECT table_name, column_name, srs_id FROM gpkg_geometry_columns")?
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let (table, geometry_column, srs_id) = match layer {
+        Some(layer) => {
+            let Some(idx) = layers.iter().position(|(table, _, _)| table == layer) else {
+                bail!("{path} has no layer called {layer}");
+            };
+            layers.swap_remove(idx)
+        }
+        None => {
+            if layers.len() != 1 {
+                bail!(
+                    "{path} has {} layers, so set geojson_network's layer to one of: {}",
+                    layers.len(),
+                    layers
+                        .iter()
+                        .map(|(table, _, _)| table.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+            layers.pop().unwrap()
+        }
+    };
+    if srs_id != SRS_ID as i64 {
+        bail!("{path} layer {table} isn't in WGS84. Reproject it first, with ogr2ogr -t_srs EPSG:4326");
+    }
+
+    let mut select = conn.prepare(&format!("SELECT * FROM \"{table}\""))?;
+    let names: Vec<String> = select
+        .column_names()
+        .into_iter()
+        .map(|name| name.to_string())
+        .collect();
+    let mut rows = select.query(())?;
+    let mut features = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut feature = Feature {
+            bbox: None,
+            geometry: None,
+            id: None,
+            properties: None,
+            foreign_members: None,
+        };
+        for (idx, name) in names.iter().enumerate() {
+            let value: Value = row.get(idx)?;
+            match value {
+                Value::Blob(blob) if *name == geometry_column => {
+                    let geometry = parse_geometry_blob(&blob).map_err(|err| {
+                        anyhow!("Bad geometry in {table} row {}: {err}", features.len())
+                    })?;
+                    feature.geometry = Some(geojson::Geometry::new((&geometry).into()));
+                }
+                Value::Integer(x) => feature.set_property(name, x),
+                Value::Real(x) => feature.set_property(name, x),
+                Value::Text(x) => feature.set_property(name, x),
+                Value::Null | Value::Blob(_) => {}
+            }
+        }
+        features.push(feature);
+    }
+    Ok(features)
+}
+
+/// Parses the GeoPackage binary header and the WKB LineString or MultiLineString after it.
+/// Z and M values are ignored.
+fn parse_geometry_blob(blob: &[u8]) -> Result<geo::Geometry> {
+    if blob.len() < 8 || &blob[0..2] != b"GP" {
+        bail!("not a GeoPackage geometry");
+    }
+    let flags = blob[3];
+    if flags & 0b1_0000 != 0 {
+        bail!("empty geometry");
+    }
+    let envelope_length = match (flags >> 1) & 0b111 {
+        0 => 0,
+        1 => 32,
+        2 | 3 => 48,
+        4 => 64,
+        x => bail!("unknown envelope type {x}"),
+    };
+    let Some(wkb) = blob.get(8 + envelope_length..) else {
+        bail!("truncated header");
+    };
+    let mut reader = WkbReader {
+        wkb,
+        position: 0,
+        little_endian: true,
+    };
+    let (geometry_type, dimensions) = reader.header()?;
+    match geometry_type {
+        2 => Ok(reader.line_string(dimensions)?.into()),
+        5 => {
+            let mut lines = Vec::new();
+            for _ in 0..reader.u32()? {
+                let (geometry_type, dimensions) = reader.header()?;
+                if geometry_type != 2 {
+                    bail!("a MultiLineString has WKB type {geometry_type} inside");
+                }
+                lines.push(reader.line_string(dimensions)?);
+            }
+            Ok(MultiLineString::new(lines).into())
+        }
+        _ => bail!("WKB type {geometry_type} isn't a LineString or MultiLineString"),
+    }
+}
+
+struct WkbReader<'a> {
+    wkb: &'a [u8],
+    position: usize,
+    /// From the most recent header
+    little_endian: bool,
+}
+
+impl WkbReader<'_> {
+    /// Every geometry, even inside a multi-geometry, starts with its own byte order
+    fn header(&mut self) -> Result<(u32, usize)> {
+        self.little_endian = match self.take::<1>()? {
+            [0] => false,
+            [1] => true,
+            [x] => bail!("unknown WKB byte order {x}"),
+        };
+        let code = self.u32()?;
+        // ISO WKB adds 1000 for Z, 2000 for M, and 3000 for both
+        let dimensions = match code / 1000 {
+            0 => 2,
+            1 | 2 => 3,
+            3 => 4,
+            _ => bail!("unknown WKB type {code}"),
+        };
+        Ok((code % 1000, dimensions))
+    }
+
+    fn line_string(&mut self, dimensions: usize) -> Result<LineString> {
+        let mut pts = Vec::new();
+        for _ in 0..self.u32()? {
+            let x = self.f64()?;
+            let y = self.f64()?;
+            for _ in 2..dimensions {
+                self.f64()?;
+            }
+            pts.push(Coord { x, y });
+        }
+        Ok(LineString::new(pts))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take()?;
+        Ok(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        let bytes = self.take()?;
+        Ok(if self.little_endian {
+            f64::from_le_bytes(bytes)
+        } else {
+            f64::from_be_bytes(bytes)
+        })
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let Some(bytes) = self.wkb.get(self.position..self.position + N) else {
+            bail!("truncated WKB");
+        };
+        self.position += N;
+        Ok(bytes.try_into().unwrap())
+    }
+}
+
+fn create_metadata_tables(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE gpkg_spatial_ref_sys (
+            srs_name TEXT NOT NULL,
+            srs_id INTEGER PRIMARY KEY,
+            organization TEXT NOT NULL,
+            organization_coordsys_id INTEGER NOT NULL,
+            definition TEXT NOT NULL,
+            description TEXT
+        );
+        CREATE TABLE gpkg_contents (
+            table_name TEXT NOT NULL PRIMARY KEY,
+            data_type TEXT NOT NULL,
+            identifier TEXT UNIQUE,
+            description TEXT DEFAULT '',
+            last_change DATETIME NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ','now')),
+            min_x DOUBLE,
+            min_y DOUBLE,
+            max_x DOUBLE,
+            max_y DOUBLE,
+            srs_id INTEGER,
+            CONSTRAINT fk_gc_r_srs_id FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys(srs_id)
+        );
+        CREATE TABLE gpkg_geometry_columns (
+            table_name TEXT NOT NULL,
+            column_name TEXT NOT NULL,
+            geometry_type_name TEXT NOT NULL,
+            srs_id INTEGER NOT NULL,
+            z TINYINT NOT NULL,
+            m TINYINT NOT NULL,
+            CONSTRAINT pk_geom_cols PRIMARY KEY (table_name, column_name),
+            CONSTRAINT fk_gc_tn FOREIGN KEY (table_name) REFERENCES gpkg_contents(table_name),
+            CONSTRAINT fk_gc_srs FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys (srs_id)
+        );
+        INSERT INTO gpkg_spatial_ref_sys VALUES
+            ('Undefined cartesian SRS', -1, 'NONE', -1, 'undefined', NULL),
+            ('Undefined geographic SRS', 0, 'NONE', 0, 'undefined', NULL);",
+    )?;
+    tx.execute(
+        "INSERT INTO gpkg_spatial_ref_sys VALUES ('WGS 84 geodetic', ?1, 'EPSG', ?1, ?2, NULL)",
+        (SRS_ID, WGS84_DEFINITION),
+    )?;
+    Ok(())
+}
+
+/// Creates an empty feature table and registers it
+fn create_layer(
+    tx: &Transaction,
+    layer: &str,
+    geometry_type: &str,
+    columns: &[(String, PropertyType)],
+) -> Result<()> {
+    let mut sql = format!(
+        "CREATE TABLE {layer} (fid INTEGER PRIMARY KEY AUTOINCREMENT, geom {geometry_type}"
+    );
+    for (name, property_type) in columns {
+        let sql_type = match property_type {
+            PropertyType::String | PropertyType::Json => "TEXT",
+            PropertyType::Long | PropertyType::ULong => "INTEGER",
+            PropertyType::UByte => "TINYINT",
+            PropertyType::Double => "DOUBLE",
+        };
+        sql.push_str(&format!(", \"{name}\" {sql_type}"));
+    }
+    sql.push(')');
+    tx.execute(&sql, ())?;
+
+    tx.execute(
+        "INSERT INTO gpkg_contents (table_name, data_type, identifier, srs_id) VALUES (?1, 'features', ?1, ?2)",
+        (layer, SRS_ID),
+    )?;
+    tx.execute(
+        "INSERT INTO gpkg_geometry_columns VALUES (?1, 'geom', ?2, ?3, 0, 0)",
+        (layer, geometry_type, SRS_ID),
+    )?;
+    Ok(())
+}
+
+fn insert_sql(layer: &str, columns: &[(String, PropertyType)]) -> String {
+    let names: Vec<String> = columns
+        .iter()
+        .map(|(name, _)| format!("\"{name}\""))
+        .collect();
+    let placeholders = vec!["?"; columns.len() + 1].join(", ");
+    format!(
+        "INSERT INTO {layer} (geom, {}) VALUES ({placeholders})",
+        names.join(", ")
+    )
+}
+
+fn write_points(
+    tx: &Transaction,
+    layer: &str,
+    points: &HashMap<Position, f64>,
+    sort_output: bool,
+) -> Result<()> {
+    let mut insert = tx.prepare(&format!(
+        "INSERT INTO {layer} (geom, count) VALUES (?1, ?2)"
+    ))?;
+    for (pt, count) in points_in_order(points.iter().map(|(pt, count)| (*pt, *count)), sort_output)
+    {
+        insert.execute((geometry_blob(point_wkb(pt)), count))?;
+    }
+    Ok(())
+}
+
+/// Wraps WKB in the GeoPackage binary header, with no envelope
+fn geometry_blob(wkb: Vec<u8>) -> Vec<u8> {
+    // The magic "GP", version 0, and flags for little-endian with no envelope
+    let mut blob = vec![b'G', b'P', 0, 1];
+    blob.extend(SRS_ID.to_le_bytes());
+    blob.extend(wkb);
+    blob
+}
+
+fn sql_value(value: Option<PropertyValue>) -> Value {
+    match value {
+        Some(PropertyValue::String(x) | PropertyValue::Json(x)) => Value::Text(x),
+        Some(PropertyValue::Long(x)) => Value::Integer(x),
+        Some(PropertyValue::ULong(x)) => Value::Integer(x as i64),
+        Some(PropertyValue::UByte(x)) => Value::Integer(x as i64),
+        Some(PropertyValue::Double(x)) => Value::Real(x),
+        None => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::make_network;
+    use osm_reader::NodeID;
+
+    #[test]
+    fn test_write_geopackage() {
+        let network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (2, 3)],
+        );
+        let mut counts = Counts::new();
+        counts.count_per_edge.insert((NodeID(1), NodeID(2)), 3.0);
+        counts
+            .count_per_origin
+            .insert(Position::from_degrees(0.0, 0.0), 3.0);
+
+        let path = std::env::temp_dir().join("od2net_test_output.gpkg");
+        let path = path.to_str().unwrap();
+        network.write_geopackage(path, &counts, true, true).unwrap();
+        // Writing again replaces the file
+        network.write_geopackage(path, &counts, true, true).unwrap();
+
+        let conn = Connection::open(path).unwrap();
+        let (edge_id, lts, count): (String, i64, f64) = conn
+            .query_row("SELECT edge_id, lts, count FROM edges", (), |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(edge_id, "1-1-2");
+        assert_eq!(lts, 1);
+        assert_eq!(count, 3.0);
+
+        let num_origins: i64 = conn
+            .query_row("SELECT COUNT(*) FROM origins", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(num_origins, 1);
+        let num_destinations: i64 = conn
+            .query_row("SELECT COUNT(*) FROM destinations", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(num_destinations, 0);
+
+        let geometry: Vec<u8> = conn
+            .query_row("SELECT geom FROM edges", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(&geometry[0..4], b"GP\x00\x01");
+        drop(conn);
+        fs_err::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_make_from_geopackage() {
+        // Write a network's edges, then read them back as road centerlines
+        let network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.001, 0.001)],
+            &[(1, 2), (2, 3)],
+        );
+        let mut counts = Counts::new();
+        counts.count_per_edge.insert((NodeID(1), NodeID(2)), 1.0);
+        counts.count_per_edge.insert((NodeID(2), NodeID(3)), 1.0);
+        let path =
+            std::env::temp_dir().join(format!("od2net_test_roads_{}.gpkg", std::process::id()));
+        let path = path.to_str().unwrap();
+        network
+            .write_geopackage(path, &counts, false, true)
+            .unwrap();
+
+        let mut columns = GeojsonNetwork {
+            path: path.to_string(),
+            layer: None,
+            lts_property: "lts".to_string(),
+            forward_cost_property: None,
+            backward_cost_property: None,
+        };
+        let read = |columns: &GeojsonNetwork| {
+            Network::make_from_geopackage(
+                path,
+                columns,
+                &mut CostFunction::Distance,
+                &[],
+                &mut Timer::new(),
+            )
+        };
+        // There are also origins and destinations layers
+        assert!(read(&columns).is_err());
+        columns.layer = Some("edges".to_string());
+        let copy = read(&columns).unwrap();
+        fs_err::remove_file(path).unwrap();
+
+        assert_eq!(copy.edges.len(), 2);
+        assert_eq!(copy.intersections.len(), 3);
+        let mut lengths: Vec<f64> = copy.edges.values().map(|e| e.length_meters).collect();
+        lengths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let mut expected: Vec<f64> = network.edges.values().map(|e| e.length_meters).collect();
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for (actual, expected) in lengths.into_iter().zip(expected) {
+            assert!((actual - expected).abs() < 0.01);
+        }
+        for edge in copy.edges.values() {
+            assert_eq!(edge.lts, lts::LTS::LTS1);
+            assert!(edge.tags.has("edge_id"));
+        }
+    }
+
+    #[test]
+    fn test_parse_geometry_blob() {
+        // Big-endian, with an XY envelope and a Z coordinate
+        let mut blob = vec![b'G', b'P', 0, 0b0000_0010];
+        blob.extend(SRS_ID.to_be_bytes());
+        blob.extend([0; 32]);
+        blob.push(0);
+        blob.extend(1002u32.to_be_bytes());
+        blob.extend(2u32.to_be_bytes());
+        for x in [1.0f64, 2.0, 10.0, 3.0, 4.0, 10.0] {
+            blob.extend(x.to_be_bytes());
+        }
+        let geometry = parse_geometry_blob(&blob).unwrap();
+        assert_eq!(
+            geometry,
+            geo::Geometry::LineString(LineString::from(vec![(1.0, 2.0), (3.0, 4.0)]))
+        );
+
+        assert!(parse_geometry_blob(&blob[0..20]).is_err());
+        assert!(
+            parse_geometry_blob(&geometry_blob(point_wkb(Position::from_degrees(1.0, 2.0))))
+                .is_err()
+        );
+    }
+}
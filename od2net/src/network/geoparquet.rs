@@ -0,0 +1,215 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use arrow::array::{
+    ArrayRef, BinaryBuilder, Float64Builder, Int64Builder, StringBuilder, UInt64Builder,
+    UInt8Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use fs_err::File;
+use parquet::arrow::ArrowWriter;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+
+use super::output::{linestring_wkb, EdgeSchema, PropertyType, PropertyValue};
+use super::{Counts, Network};
+
+/// How many rows to buffer before writing a row group, bounding memory use
+const BATCH_SIZE: usize = 65_536;
+
+impl Network {
+    /// Writes the edges with counts as GeoParquet, with WKB geometry. This has the same
+    /// properties as the GeoJSON output, except OSM tags are a JSON string, and origin and
+    /// destination points aren't included.
+    pub fn write_geoparquet(
+        &self,
+        path: &str,
+        counts: &Counts,
+        output_osm_tags: bool,
+        sort_output: bool,
+    ) -> Result<()> {
+        let edge_schema = EdgeSchema::new(self, counts, output_osm_tags);
+        let mut fields: Vec<Field> = edge_schema
+            .columns
+            .iter()
+            .map(|(name, property_type)| Field::new(name, data_type(*property_type), true))
+            .collect();
+        fields.push(Field::new("geometry", DataType::Binary, false));
+        let schema = Arc::new(Schema::new(fields));
+
+        // No CRS means OGC:CRS84, which is WGS84 in longitude, latitude order
+        let geo_metadata = serde_json::json!({
+            "version": "1.0.0",
+            "primary_column": "geometry",
+            "columns": {
+                "geometry": {
+                    "encoding": "WKB",
+                    "geometry_types": ["LineString"],
+                },
+            },
+        });
+        let properties = WriterProperties::builder()
+            .set_key_value_metadata(Some(vec![KeyValue::new(
+                "geo".to_string(),
+                geo_metadata.to_string(),
+            )]))
+            .build();
+        let mut writer =
+            ArrowWriter::try_new(File::create(path)?, schema.clone(), Some(properties))?;
+
+        for chunk in self.counted_edges(counts, sort_output).chunks(BATCH_SIZE) {
+            let mut columns: Vec<ColumnBuilder> = edge_schema
+                .columns
+                .iter()
+                .map(|(_, property_type)| ColumnBuilder::new(*property_type))
+                .collect();
+            let mut geometry = BinaryBuilder::new();
+            for (node1, node2, edge, edge_counts) in chunk {
+                let values = edge_schema.values(edge, *node1, *node2, edge_counts)?;
+                for (column, value) in columns.iter_mut().zip(values) {
+                    column.append(value);
+                }
+                geometry.append_value(linestring_wkb(&edge.geometry));
+            }
+
+            let mut arrays: Vec<ArrayRef> = columns.into_iter().map(|c| c.finish()).collect();
+            arrays.push(Arc::new(geometry.finish()));
+            writer.write(&RecordBatch::try_new(schema.clone(), arrays)?)?;
+        }
+        writer.close()?;
+        Ok(())
+    }
+}
+
+fn data_type(property_type: PropertyType) -> DataType {
+    match property_type {
+        PropertyType::String | PropertyType::Json => DataType::Utf8,
+        PropertyType::Long => DataType::Int64,
+        PropertyType::ULong => DataType::UInt64,
+        PropertyType::UByte => DataType::UInt8,
+        PropertyType::Double => DataType::Float64,
+    }
+}
+
+/// Accumulates one column of a batch
+enum ColumnBuilder {
+    String(StringBuilder),
+    Long(Int64Builder),
+    ULong(UInt64Builder),
+    UByte(UInt8Builder),
+    Double(Float64Builder),
+}
+
+impl ColumnBuilder {
+    fn new(property_type: PropertyType) -> Self {
+        match property_type {
+            PropertyType::String | PropertyType::Json => Self::String(StringBuilder::new()),
+            PropertyType::Long => Self::Long(Int64Builder::new()),
+            PropertyType::ULong => Self::ULong(UInt64Builder::new()),
+            PropertyType::UByte => Self::UByte(UInt8Builder::new()),
+            PropertyType::Double => Self::Double(Float64Builder::new()),
+        }
+    }
+
+    /// The value must match the type of the column
+    fn append(&mut self, value: Option<PropertyValue>) {
+        match (self, value) {
+            (Self::String(b), Some(PropertyValue::String(x) | PropertyValue::Json(x))) => {
+                b.append_value(x)
+            }
+            (Self::Long(b), Some(PropertyValue::Long(x))) => b.append_value(x),
+            (Self::ULong(b), Some(PropertyValue::ULong(x))) => b.append_value(x),
+            (Self::UByte(b), Some(PropertyValue::UByte(x))) => b.append_value(x),
+            (Self::Double(b), Some(PropertyValue::Double(x))) => b.append_value(x),
+            (Self::String(b), None) => b.append_null(),
+            (Self::Long(b), None) => b.append_null(),
+            (Self::ULong(b), None) => b.append_null(),
+            (Self::UByte(b), None) => b.append_null(),
+            (Self::Double(b), None) => b.append_null(),
+            _ => unreachable!("value doesn't match the column type"),
+        }
+    }
+
+    fn finish(self) -> ArrayRef {
+        match self {
+            Self::String(mut b) => Arc::new(b.finish()),
+            Self::Long(mut b) => Arc::new(b.finish()),
+            Self::ULong(mut b) => Arc::new(b.finish()),
+            Self::UByte(mut b) => Arc::new(b.finish()),
+            Self::Double(mut b) => Arc::new(b.finish()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::make_network;
+    use arrow::array::{Array, BinaryArray, Float64Array, StringArray, UInt8Array};
+    use osm_reader::NodeID;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    #[test]
+    fn test_geoparquet_round_trip() {
+        let network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (2, 3)],
+        );
+        let mut counts = Counts::new();
+        counts.count_per_edge.insert((NodeID(1), NodeID(2)), 3.0);
+        counts.count_per_edge.insert((NodeID(3), NodeID(2)), 2.0);
+
+        let path = std::env::temp_dir().join("od2net_test_output.parquet");
+        let path = path.to_str().unwrap();
+        network.write_geoparquet(path, &counts, true, true).unwrap();
+
+        let builder =
+            ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(path).unwrap()).unwrap();
+        let geo = builder
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap()
+            .iter()
+            .find(|kv| kv.key == "geo")
+            .and_then(|kv| kv.value.clone())
+            .unwrap();
+        let geo: serde_json::Value = serde_json::from_str(&geo).unwrap();
+        assert_eq!(geo["primary_column"], "geometry");
+        assert_eq!(geo["columns"]["geometry"]["encoding"], "WKB");
+
+        let batches: Vec<RecordBatch> = builder.build().unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 2);
+        let column = |name: &str| batch.column_by_name(name).unwrap().clone();
+
+        let edge_ids = column("edge_id");
+        let edge_ids = edge_ids.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(edge_ids.value(0), "1-1-2");
+        assert_eq!(edge_ids.value(1), "2-2-3");
+        let count = column("count");
+        let count = count.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!((count.value(0), count.value(1)), (3.0, 2.0));
+        let backwards = column("count_backwards");
+        let backwards = backwards.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(backwards.value(1), 2.0);
+        let lts = column("lts");
+        assert_eq!(
+            lts.as_any().downcast_ref::<UInt8Array>().unwrap().value(0),
+            1
+        );
+        // Missing values are null
+        assert!(column("aadt").is_null(0));
+        assert!(column("osm_tags").is_valid(0));
+
+        let geometry = column("geometry");
+        let geometry = geometry.as_any().downcast_ref::<BinaryArray>().unwrap();
+        assert_eq!(
+            geometry.value(0),
+            linestring_wkb(&network.edges[&(NodeID(1), NodeID(2))].geometry)
+        );
+        fs_err::remove_file(path).unwrap();
+    }
+}
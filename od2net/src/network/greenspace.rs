@@ -26,7 +26,7 @@ pub fn get_polygon(
 }
 
 // TODO From A/B Street. Relax these greatly?
-fn is_greenspace(tags: &Tags) -> bool {
+pub fn is_greenspace(tags: &Tags) -> bool {
     if tags.is_any("leisure", vec!["garden", "park", "golf_course"]) {
         return true;
     }
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::io::BufWriter;
+
+use anyhow::{bail, Result};
+use fs_err::File;
+use geo::{HaversineDistance, Point, Polygon};
+use geojson::{Feature, FeatureWriter, Geometry};
+use h3o::{CellIndex, LatLng, Resolution};
+use lts::LTS;
+
+use super::{Counts, Network};
+
+/// Totals for one hexagon
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct CellTotals {
+    /// The length of routeable edges in the cell
+    length_meters: f64,
+    /// The sum of each edge's count times its length in the cell
+    count_meters: f64,
+}
+
+impl Network {
+    /// Writes one GeoJSON Polygon per H3 cell at some resolution (0 to 15) containing part of the
+    /// routeable network. Each edge is split between the cells it passes through by length.
+    pub fn write_h3(&self, path: &str, counts: &Counts, resolution: u8) -> Result<()> {
+        let Ok(resolution) = Resolution::try_from(resolution) else {
+            bail!("The H3 resolution must be from 0 to 15, not {resolution}");
+        };
+        let mut writer = FeatureWriter::from_writer(BufWriter::new(File::create(path)?));
+        for (cell, totals) in self.aggregate_h3(counts, resolution)? {
+            let boundary: Polygon = Polygon::new(
+                cell.boundary()
+                    .iter()
+                    .map(|pt| (pt.lng(), pt.lat()))
+                    .collect(),
+                Vec::new(),
+            );
+            let mut feature = Feature::from(Geometry::from(&boundary));
+            feature.set_property("h3", cell.to_string());
+            feature.set_property("length_meters", totals.length_meters);
+            feature.set_property("count_meters", totals.count_meters);
+            // The length-weighted average count
+            feature.set_property("mean_count", totals.count_meters / totals.length_meters);
+            writer.write_feature(&feature)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Sorted by cell, so the output is deterministic
+    fn aggregate_h3(
+        &self,
+        counts: &Counts,
+        resolution: Resolution,
+    ) -> Result<BTreeMap<CellIndex, CellTotals>> {
+        // Split long lines into pieces shorter than a hexagon's edge, so each piece can be
+        // assigned to the cell containing its midpoint
+        let max_piece_meters = resolution.edge_length_m() / 4.0;
+
+        let mut cells: BTreeMap<CellIndex, CellTotals> = BTreeMap::new();
+        for ((node1, node2), edge) in &self.edges {
+            if edge.lts == LTS::NotAllowed {
+                continue;
+            }
+            let count = counts.count_per_edge.get(&(*node1, *node2)).unwrap_or(&0.0)
+                + counts.count_per_edge.get(&(*node2, *node1)).unwrap_or(&0.0);
+
+            for pair in edge.geometry.windows(2) {
+                let (x1, y1) = pair[0].to_degrees();
+                let (x2, y2) = pair[1].to_degrees();
+                let meters = Point::new(x1, y1).haversine_distance(&Point::new(x2, y2));
+                let num_pieces = (meters / max_piece_meters).ceil().max(1.0);
+                for i in 0..num_pieces as usize {
+                    let fraction = (i as f64 + 0.5) / num_pieces;
+                    let cell = LatLng::new(y1 + fraction * (y2 - y1), x1 + fraction * (x2 - x1))?
+                        .to_cell(resolution);
+                    let totals = cells.entry(cell).or_default();
+                    totals.length_meters += meters / num_pieces;
+                    totals.count_meters += count * meters / num_pieces;
+                }
+            }
+        }
+        Ok(cells)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::make_network;
+    use osm_reader::NodeID;
+
+    #[test]
+    fn test_aggregate_h3() {
+        let network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.01, 0.0), (3, 0.02, 0.0)],
+            &[(1, 2), (3, 2)],
+        );
+        let mut counts = Counts::new();
+        counts.count_per_edge.insert((NodeID(1), NodeID(2)), 3.0);
+        counts.count_per_edge.insert((NodeID(2), NodeID(3)), 1.0);
+
+        let cells = network
+            .aggregate_h3(&counts, Resolution::try_from(9).unwrap())
+            .unwrap();
+        // Each edge is about 1.1km long, and cells at this resolution are about 350m across
+        assert!(cells.len() > 2);
+
+        let length: f64 = cells.values().map(|c| c.length_meters).sum();
+        let count_meters: f64 = cells.values().map(|c| c.count_meters).sum();
+        let edge1 = network.edges[&(NodeID(1), NodeID(2))].length_meters;
+        let edge2 = network.edges[&(NodeID(3), NodeID(2))].length_meters;
+        assert!((length - (edge1 + edge2)).abs() < 1e-6);
+        // The second edge is crossed backwards, but still counts
+        assert!((count_meters - (3.0 * edge1 + edge2)).abs() < 1e-6);
+
+        assert!(network.write_h3("unused", &counts, 16).is_err());
+    }
+}
@@ -1,39 +1,137 @@
 mod amenities;
+mod barriers;
+mod cargo_bike;
+mod cost_overrides;
+mod create_from_geojson;
 mod create_from_osm;
+mod elevation_source;
+mod exposure;
+#[cfg(not(target_arch = "wasm32"))]
+mod geopackage;
+#[cfg(not(target_arch = "wasm32"))]
+mod geoparquet;
 mod greenspace;
+#[cfg(not(target_arch = "wasm32"))]
+mod h3;
+mod oneway;
 mod output;
+mod traffic_counts;
+mod turn_restrictions;
+mod walking;
+mod ways;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::BufReader;
-use std::io::{Read, Seek};
 
 use anyhow::Result;
-use elevation::GeoTiffElevation;
 use fs_err::File;
+use geo::{HaversineDistance, Intersects, LineString, MultiPolygon, Point};
 use osm_reader::{NodeID, WayID};
 use serde::{Deserialize, Serialize};
 
 use lts::{Tags, LTS};
 
+use super::config::{
+    length_band, length_band_names, Barriers, FlowBreakdown, Lighting, Oneway, Profile,
+    SurfaceMultipliers, TurnCosts,
+};
 use super::requests::Request;
+use super::route_stats::{RouteHistograms, RouteSummary};
+use super::warnings::Warnings;
+pub use amenities::Amenity;
+pub use cost_overrides::{load_cost_overrides, CostOverride};
+pub use create_from_osm::EdgeLocation;
+#[cfg(not(target_arch = "wasm32"))]
+pub use elevation_source::HttpFetcher;
+pub use elevation_source::{
+    ElevationSource, GeoTiffFiles, MultiElevation, TileElevation, TileFetcher,
+};
+pub use exposure::{load_polygon_values, PolygonValues, SampleSource};
+pub use turn_restrictions::{TurnRestriction, TurnRestrictions};
 
 #[derive(Serialize, Deserialize)]
 pub struct Network {
     // TODO Doesn't handle multiple edges between the same node pair
     pub edges: HashMap<(NodeID, NodeID), Edge>,
     pub intersections: HashMap<NodeID, Position>,
+    /// Honored by the router
+    pub turn_restrictions: Vec<TurnRestriction>,
+    pub traffic_signals: HashSet<NodeID>,
+    /// Every amenity from OSM, used by `ODPattern::ToNearestAmenities`. Empty for networks built
+    /// from GeoJSON.
+    pub amenities: Vec<Amenity>,
+    /// From the config, so this isn't saved with the network. Used when building the CH.
+    #[serde(skip)]
+    pub turn_costs: TurnCosts,
+    /// Used when calculating edge costs, so it's saved with the network, unlike `turn_costs`
+    pub profile: Profile,
+    pub oneway: Oneway,
+    pub barriers: Barriers,
+    /// From the config, so this isn't saved with the network. Applied right after the cost
+    /// function.
+    #[serde(skip)]
+    pub surface_multipliers: SurfaceMultipliers,
+    /// From the config, so this isn't saved with the network. Applied right after the cost
+    /// function.
+    #[serde(skip)]
+    pub lighting: Lighting,
+    /// From the config, so this isn't saved with the network. Applied after the cost function.
+    #[serde(skip)]
+    pub cost_overrides: HashMap<WayID, CostOverride>,
+    /// The names of `exposure_layers` from the config, so cost expressions using them can be
+    /// checked. Not saved with the network.
+    #[serde(skip)]
+    pub exposure_layers: Vec<String>,
+    /// Problems found while building the network. These are saved with it, so runs loading the
+    /// network from a file still report them.
+    pub warnings: Warnings,
+    /// Identifies the config and input files this was built from, so a stale `network.bin` can be
+    /// detected. 0 when the caller doesn't track this.
+    pub cache_key: u32,
 }
 
 // TODO Rename this. We don't represent counts, but instead summed uptake. If every single route we
 // considered would actually happen, then this would be equivalent to counts.
+#[derive(Serialize, Deserialize)]
 pub struct Counts {
     // TODO Don't use f64 -- we'll end up rounding somewhere anyway, so pick a precision upfront.
     pub count_per_edge: HashMap<(NodeID, NodeID), f64>,
+    /// The names of `uptake_scenarios` from the config, in order
+    pub uptake_scenarios: Vec<String>,
+    /// For each edge in `count_per_edge`, the count with each of the `uptake_scenarios`. Empty
+    /// when there are none.
+    pub uptake_scenario_count_per_edge: HashMap<(NodeID, NodeID), Vec<f64>>,
+    /// The names of the `flow_breakdown` groups from the config. While routing, groups are added
+    /// as routes use them; `set_flow_groups` puts them in order afterwards. Purposes are only
+    /// listed if some route has them.
+    pub flow_groups: Vec<String>,
+    /// For each edge in `count_per_edge`, the count from each of the `flow_groups` crossing it, by
+    /// index. Groups added after the edge was last crossed may be missing from the end. Empty
+    /// without a breakdown.
+    pub flow_group_count_per_edge: HashMap<(NodeID, NodeID), Vec<f64>>,
+    /// How many routes pass through each intersection, including the start and end
+    pub count_per_node: HashMap<NodeID, f64>,
+    /// How many routes make each turning movement, keyed by the intersection before, at, and
+    /// after the movement. Only kept with `Collect::movements`.
+    pub count_per_movement: HashMap<(NodeID, NodeID, NodeID), f64>,
+    /// How many routes cross each zone without starting or ending there, keyed by the zone's
+    /// index in a `ZoneIndex`. Only filled out when routing with zones.
+    pub count_through_zone: HashMap<usize, f64>,
+    /// The count times the meters used along every edge in each zone, keyed like
+    /// `count_through_zone` and indexed by LTS as u8. Only filled out when routing with zones.
+    pub meters_by_lts_in_zone: HashMap<usize, [f64; 5]>,
 
     /// These requests failed because the start and end snapped to the same intersection
     pub errors_same_endpoints: Vec<Request>,
+    /// How many requests with the start and end snapped to the same intersection were skipped
+    pub num_same_endpoints_dropped: usize,
+    /// How many requests with the start and end snapped to the same intersection were counted as
+    /// zero-distance trips
+    pub num_same_endpoints_zero_distance: usize,
     /// These requests failed because there's no path
     pub errors_no_path: Vec<Request>,
+    /// These requests failed because an endpoint snapped farther than `max_snap_meters` away
+    pub errors_far_snap: Vec<FarSnap>,
 
     // Count how many times a point is used successfully as an origin or destination
     pub count_per_origin: HashMap<Position, f64>,
@@ -41,19 +139,39 @@ pub struct Counts {
 
     // In meters. Indexed by LTS as u8
     pub total_distance_by_lts: [f64; 5],
+
+    /// Describes the route for every successful request, in the order of the requests. Only kept
+    /// with `Collect::route_summaries`.
+    pub route_summaries: Vec<RouteSummary>,
+    /// Distributions over every route summary, always kept
+    pub route_histograms: RouteHistograms,
 }
 
 impl Counts {
     pub fn new() -> Self {
         Self {
             count_per_edge: HashMap::new(),
+            uptake_scenarios: Vec::new(),
+            uptake_scenario_count_per_edge: HashMap::new(),
+            flow_groups: Vec::new(),
+            flow_group_count_per_edge: HashMap::new(),
+            count_per_node: HashMap::new(),
+            count_per_movement: HashMap::new(),
+            count_through_zone: HashMap::new(),
+            meters_by_lts_in_zone: HashMap::new(),
             errors_same_endpoints: Vec::new(),
+            num_same_endpoints_dropped: 0,
+            num_same_endpoints_zero_distance: 0,
             errors_no_path: Vec::new(),
+            errors_far_snap: Vec::new(),
 
             count_per_origin: HashMap::new(),
             count_per_destination: HashMap::new(),
 
             total_distance_by_lts: [0.0; 5],
+
+            route_summaries: Vec::new(),
+            route_histograms: RouteHistograms::default(),
         }
     }
 
@@ -62,10 +180,55 @@ impl Counts {
         self.errors_same_endpoints
             .extend(other.errors_same_endpoints);
         self.errors_no_path.extend(other.errors_no_path);
+        self.errors_far_snap.extend(other.errors_far_snap);
+        self.route_summaries.extend(other.route_summaries);
+        self.route_histograms.combine(other.route_histograms);
+        self.num_same_endpoints_dropped += other.num_same_endpoints_dropped;
+        self.num_same_endpoints_zero_distance += other.num_same_endpoints_zero_distance;
 
         for (key, count) in other.count_per_edge {
             *self.count_per_edge.entry(key).or_insert(0.0) += count;
         }
+        for (key, counts) in other.uptake_scenario_count_per_edge {
+            let entry = self
+                .uptake_scenario_count_per_edge
+                .entry(key)
+                .or_insert_with(|| vec![0.0; counts.len()]);
+            for (total, count) in entry.iter_mut().zip(counts) {
+                *total += count;
+            }
+        }
+        // The two sides may have seen groups in a different order
+        let group_indices: Vec<usize> = other
+            .flow_groups
+            .iter()
+            .map(|name| self.add_flow_group(name))
+            .collect();
+        for (key, groups) in other.flow_group_count_per_edge {
+            let entry = self.flow_group_count_per_edge.entry(key).or_default();
+            for (idx, count) in groups.into_iter().enumerate() {
+                let group = group_indices[idx];
+                if entry.len() <= group {
+                    entry.resize(group + 1, 0.0);
+                }
+                entry[group] += count;
+            }
+        }
+        for (key, count) in other.count_per_node {
+            *self.count_per_node.entry(key).or_insert(0.0) += count;
+        }
+        for (key, count) in other.count_per_movement {
+            *self.count_per_movement.entry(key).or_insert(0.0) += count;
+        }
+        for (key, count) in other.count_through_zone {
+            *self.count_through_zone.entry(key).or_insert(0.0) += count;
+        }
+        for (key, meters) in other.meters_by_lts_in_zone {
+            let total = self.meters_by_lts_in_zone.entry(key).or_insert([0.0; 5]);
+            for (total, meters) in total.iter_mut().zip(meters) {
+                *total += meters;
+            }
+        }
         for (key, count) in other.count_per_origin {
             *self.count_per_origin.entry(key).or_insert(0.0) += count;
         }
@@ -77,16 +240,198 @@ impl Counts {
         }
     }
 
+    /// Returns the name and count of each of the `uptake_scenarios` for one edge
+    pub fn scenario_counts(&self, node1: NodeID, node2: NodeID) -> Vec<(&String, f64)> {
+        let totals = self.uptake_scenario_count_per_edge.get(&(node1, node2));
+        self.uptake_scenarios
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| (name, totals.map(|x| x[idx]).unwrap_or(0.0)))
+            .collect()
+    }
+
+    /// Returns the index in `flow_groups` of the group for a route of this length and purpose,
+    /// adding the group if it's new
+    pub fn flow_group_index(
+        &mut self,
+        breakdown: &FlowBreakdown,
+        route_meters: f64,
+        purpose: Option<&str>,
+    ) -> usize {
+        match breakdown {
+            FlowBreakdown::LengthBands(limits) => {
+                if self.flow_groups.is_empty() {
+                    self.flow_groups = length_band_names(limits);
+                }
+                length_band(limits, route_meters)
+            }
+            FlowBreakdown::Purpose => self.add_flow_group(purpose.unwrap_or("unknown")),
+        }
+    }
+
+    fn add_flow_group(&mut self, name: &str) -> usize {
+        match self.flow_groups.iter().position(|x| x == name) {
+            Some(idx) => idx,
+            None => {
+                self.flow_groups.push(name.to_string());
+                self.flow_groups.len() - 1
+            }
+        }
+    }
+
+    /// Puts `flow_groups` in order once routing is done: length bands from shortest to longest,
+    /// and purposes by name
+    pub fn set_flow_groups(&mut self, breakdown: Option<&FlowBreakdown>) {
+        let order = match breakdown {
+            None => Vec::new(),
+            Some(FlowBreakdown::LengthBands(limits)) => length_band_names(limits),
+            Some(FlowBreakdown::Purpose) => {
+                let mut names = self.flow_groups.clone();
+                names.sort();
+                names
+            }
+        };
+        // Where each group in order is now
+        let old_indices: Vec<Option<usize>> = order
+            .iter()
+            .map(|name| self.flow_groups.iter().position(|x| x == name))
+            .collect();
+        for groups in self.flow_group_count_per_edge.values_mut() {
+            *groups = old_indices
+                .iter()
+                .map(|idx| idx.and_then(|idx| groups.get(idx)).copied().unwrap_or(0.0))
+                .collect();
+        }
+        self.flow_groups = order;
+    }
+
+    /// Returns the name and count of each of the `flow_groups` for one edge
+    pub fn flow_group_counts(&self, node1: NodeID, node2: NodeID) -> Vec<(&String, f64)> {
+        let groups = self.flow_group_count_per_edge.get(&(node1, node2));
+        self.flow_groups
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| {
+                (
+                    name,
+                    groups.and_then(|x| x.get(idx)).copied().unwrap_or(0.0),
+                )
+            })
+            .collect()
+    }
+
     pub fn num_errors(&self) -> usize {
-        self.errors_same_endpoints.len() + self.errors_no_path.len()
+        self.errors_same_endpoints.len() + self.errors_no_path.len() + self.errors_far_snap.len()
     }
 }
 
+/// A request with an endpoint snapped too far away to trust
+#[derive(Serialize, Deserialize)]
+pub struct FarSnap {
+    pub req: Request,
+    /// Where the origin and destination snapped to, in WGS84
+    pub snapped_origin: (f64, f64),
+    pub snapped_destination: (f64, f64),
+    pub origin_snap_meters: f64,
+    pub destination_snap_meters: f64,
+}
+
 impl Network {
     pub fn load_from_bin(path: &str) -> Result<Network> {
         let network = bincode::deserialize_from(BufReader::new(File::open(path)?))?;
         Ok(network)
     }
+
+    /// Returns every edge whose geometry at least partly overlaps the area
+    pub fn edges_in_area(&self, area: &MultiPolygon<f64>) -> HashSet<(NodeID, NodeID)> {
+        self.edges
+            .iter()
+            .filter(|(_, edge)| {
+                let line: LineString<f64> =
+                    edge.geometry.iter().map(|pt| pt.to_degrees()).collect();
+                line.intersects(area)
+            })
+            .map(|(key, _)| *key)
+            .collect()
+    }
+}
+
+/// Limits counting to part of the network. Routes still travel through the rest of it.
+pub struct CountArea {
+    edges: HashSet<(NodeID, NodeID)>,
+    /// Every intersection at the end of an edge in the area
+    nodes: HashSet<NodeID>,
+}
+
+impl CountArea {
+    pub fn new(edges: HashSet<(NodeID, NodeID)>) -> Self {
+        let nodes = edges.iter().flat_map(|(i1, i2)| [*i1, *i2]).collect();
+        Self { edges, nodes }
+    }
+
+    pub fn num_edges(&self) -> usize {
+        self.edges.len()
+    }
+
+    /// In either direction
+    pub fn contains_edge(&self, i1: NodeID, i2: NodeID) -> bool {
+        self.edges.contains(&(i1, i2)) || self.edges.contains(&(i2, i1))
+    }
+
+    pub fn contains_node(&self, id: NodeID) -> bool {
+        self.nodes.contains(&id)
+    }
+}
+
+/// Identifies an edge by its OSM way and the OSM nodes at its ends, in the order of the edge's
+/// geometry (its key in `Network::edges`). This is the same across runs over the same network and
+/// doesn't depend on which direction a route crossed the edge. Two edges between the same
+/// intersections always get different IDs.
+pub fn edge_id(way: WayID, node1: NodeID, node2: NodeID) -> String {
+    format!("{}-{}-{}", way.0, node1.0, node2.0)
+}
+
+/// A numeric version of `edge_id`, for the Feature `id`. It's a hash of the same parts, so it
+/// doesn't change when other edges are added or removed. It fits in a JavaScript number, and is
+/// always at least 2^52, so it can't clash with points numbered from 1 in the same file.
+pub fn numeric_edge_id(way: WayID, node1: NodeID, node2: NodeID) -> u64 {
+    // FNV-1a, since std's hashers may change between Rust versions
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in [way.0, node1.0, node2.0] {
+        for byte in part.to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    (hash & ((1 << 52) - 1)) | (1 << 52)
+}
+
+/// Assigns every edge in the network an index, sorted by way and then nodes, like `edge_id`. This
+/// is the same across runs over the same network.
+pub struct EdgeIndex {
+    pub edges: Vec<(NodeID, NodeID)>,
+    lookup: HashMap<(NodeID, NodeID), usize>,
+}
+
+impl EdgeIndex {
+    pub fn new(network: &Network) -> Self {
+        let mut edges: Vec<(NodeID, NodeID)> = network.edges.keys().cloned().collect();
+        edges.sort_by_key(|key| (network.edges[key].way_id.0, key.0 .0, key.1 .0));
+        let lookup = edges
+            .iter()
+            .enumerate()
+            .map(|(idx, key)| (*key, idx))
+            .collect();
+        Self { edges, lookup }
+    }
+
+    /// Finds an edge crossed in either direction
+    pub fn get(&self, node1: NodeID, node2: NodeID) -> Option<usize> {
+        self.lookup
+            .get(&(node1, node2))
+            .or_else(|| self.lookup.get(&(node2, node1)))
+            .cloned()
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -127,7 +472,7 @@ fn trim_f64(x: f64) -> f64 {
     (x * 10e6).round() / 10e6
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Edge {
     pub way_id: WayID,
     pub tags: Tags,
@@ -136,6 +481,10 @@ pub struct Edge {
     pub slope: Option<f64>,
     // A factor to multiply cost by in the (forwards, backwards) direction
     pub slope_factor: Option<(f64, f64)>,
+    /// Like slope_factor, for an e-bike. Cost functions wrapped in `EBike` use this instead.
+    pub ebike_slope_factor: Option<(f64, f64)>,
+    /// Total meters climbed and descended in the forwards direction
+    pub elevation_gain: Option<(f64, f64)>,
     // Storing the derived field is negligible for file size
     pub length_meters: f64,
     // LTS is often incorporated in cost, but is also used for visualization. It's useful to
@@ -147,37 +496,134 @@ pub struct Edge {
     // TODO Maybe generalize as a cost and a bunch of properties per edge -- like proximity
     // modifiers for greenspace, lighting, commercial areas
     pub nearby_amenities: usize,
+    /// Tags of `barrier` nodes in the middle of this edge
+    pub barriers: Vec<Tags>,
+    /// Annual average daily motor traffic, from `traffic_counts`
+    pub aadt: Option<f64>,
+    /// The average value along this edge of each `exposure_layers` entry covering it
+    pub exposure: BTreeMap<String, f64>,
+    /// Inside one of `lighting.lit_areas`, so lit no matter what the `lit` tag says
+    pub lit_override: bool,
 }
 
 impl Edge {
-    /// Sets `slope` and `slope_factor` if true. If false, failed to get data.
-    pub fn apply_elevation<R: Read + Seek + Send>(
+    pub fn geometry(&self) -> &[Position] {
+        &self.geometry
+    }
+
+    /// Sets `slope`, `slope_factor`, `ebike_slope_factor` (only when cycling), and
+    /// `elevation_gain` if true. If false, failed to get data.
+    pub fn apply_elevation<E: ElevationSource + ?Sized>(
         &mut self,
-        geotiff: &mut GeoTiffElevation<R>,
+        elevation: &mut E,
+        mode: Profile,
     ) -> bool {
-        let Some(slope) = self.get_slope(geotiff) else {
+        let profile = self.get_profile(elevation);
+        if profile.len() < 2 {
             return false;
+        }
+        let (start, end) = (profile[0], *profile.last().unwrap());
+        let length = end.0 - start.0;
+        if length <= 0.0 {
+            return false;
+        }
+        self.slope = Some((end.1 - start.1) / length * 100.0);
+
+        let mut gain = 0.0;
+        let mut loss = 0.0;
+        for pair in profile.windows(2) {
+            let rise = pair[1].1 - pair[0].1;
+            if rise > 0.0 {
+                gain += rise;
+            } else {
+                loss -= rise;
+            }
+        }
+        self.elevation_gain = Some((gain, loss));
+
+        // Group the profile into stretches going up or down, so a dip in the middle of a long
+        // edge counts, as (length, slope) pairs
+        let mut runs = Vec::new();
+        let mut run_start = start;
+        for idx in 1..profile.len() {
+            let pt = profile[idx];
+            let rising = pt.1 >= profile[idx - 1].1;
+            let last = idx == profile.len() - 1;
+            if last || rising != (profile[idx + 1].1 >= pt.1) {
+                let run_length = pt.0 - run_start.0;
+                if run_length > 0.0 {
+                    runs.push((run_length, (pt.1 - run_start.1) / run_length * 100.0));
+                }
+                run_start = pt;
+            }
+        }
+        // Weight each stretch's factor by its length
+        let weighted = |slope_factor: &dyn Fn(f64, f64) -> f64| {
+            let mut forward = 0.0;
+            let mut backward = 0.0;
+            for (run_length, run_slope) in &runs {
+                forward += run_length * slope_factor(*run_slope, *run_length);
+                backward += run_length * slope_factor(-run_slope, *run_length);
+            }
+            (forward / length, backward / length)
         };
-        self.slope = Some(slope);
-        self.slope_factor = Some((
-            calculate_slope_factor(slope, self.length_meters),
-            calculate_slope_factor(-slope, self.length_meters),
-        ));
+        match mode {
+            Profile::Cycling => {
+                self.slope_factor = Some(weighted(&calculate_slope_factor));
+                self.ebike_slope_factor =
+                    Some(weighted(&|slope, _| calculate_ebike_slope_factor(slope)));
+            }
+            Profile::CargoBike => {
+                self.slope_factor = Some(weighted(&cargo_bike::calculate_cargo_bike_slope_factor));
+            }
+            Profile::Walking => {
+                self.slope_factor = Some(weighted(&|slope, _| {
+                    walking::calculate_walking_slope_factor(slope)
+                }));
+            }
+        }
         true
     }
 
-    fn get_slope<R: Read + Seek + Send>(&self, geotiff: &mut GeoTiffElevation<R>) -> Option<f64> {
-        let (lon1, lat1) = self.geometry[0].to_degrees();
-        let (lon2, lat2) = self.geometry.last().unwrap().to_degrees();
-
-        let height1 = geotiff.get_height_for_lon_lat(lon1 as f32, lat1 as f32)?;
-        let height2 = geotiff.get_height_for_lon_lat(lon2 as f32, lat2 as f32)?;
+    /// Samples height along the edge, at least every `SAMPLE_METERS`. Returns (distance from the
+    /// start, height) pairs, skipping points without data.
+    fn get_profile<E: ElevationSource + ?Sized>(&self, elevation: &mut E) -> Vec<(f64, f64)> {
+        self.sample_points()
+            .into_iter()
+            .filter_map(|(dist, (lon, lat))| {
+                let height = elevation.get_height_for_lon_lat(lon as f32, lat as f32)?;
+                Some((dist, height as f64))
+            })
+            .collect()
+    }
 
-        let slope = (height2 - height1) / (self.length_meters as f32) * 100.0;
-        Some(slope.into())
+    /// Points along the edge, at least every `SAMPLE_METERS`, as (distance from the start, (lon,
+    /// lat)) pairs
+    fn sample_points(&self) -> Vec<(f64, (f64, f64))> {
+        let mut points = vec![(0.0, self.geometry[0].to_degrees())];
+        let mut dist = 0.0;
+        for pair in self.geometry.windows(2) {
+            let (lon1, lat1) = pair[0].to_degrees();
+            let (lon2, lat2) = pair[1].to_degrees();
+            let segment = Point::new(lon1, lat1).haversine_distance(&Point::new(lon2, lat2));
+            let steps = (segment / SAMPLE_METERS).ceil().max(1.0) as usize;
+            for step in 1..=steps {
+                let pct = step as f64 / steps as f64;
+                points.push((
+                    dist + pct * segment,
+                    (lon1 + pct * (lon2 - lon1), lat1 + pct * (lat2 - lat1)),
+                ));
+            }
+            dist += segment;
+        }
+        points
     }
 }
 
+/// How far apart to look up heights and other raster values along an edge. Most elevation data
+/// has a coarser resolution.
+const SAMPLE_METERS: f64 = 20.0;
+
 /// This returns a factor to multiply cost by, to adjust the speed of a cyclist. See
 /// <https://github.com/U-Shift/Declives-RedeViaria/blob/main/SpeedSlopeFactor/SpeedSlopeFactor.md#speed-slope-factor-1>.
 fn calculate_slope_factor(slope: f64, length: f64) -> f64 {
@@ -208,10 +654,104 @@ fn calculate_slope_factor(slope: f64, length: f64) -> f64 {
     slope_factor
 }
 
+/// An e-bike cruises at about this speed on flat ground
+const EBIKE_FLAT_KMH: f64 = 20.0;
+/// Descents can't speed up an e-bike much past where the motor stops assisting, at 25km/h
+const EBIKE_MAX_DESCENT_KMH: f64 = 30.0;
+
+/// Like `calculate_slope_factor`, for a pedelec. The motor makes climbs much easier, but since it
+/// already goes faster on the flat, descents don't help as much either.
+fn calculate_ebike_slope_factor(slope: f64) -> f64 {
+    if slope < 0.0 {
+        calculate_slope_factor(slope, 0.0).max(EBIKE_FLAT_KMH / EBIKE_MAX_DESCENT_KMH)
+    } else if slope <= 20.0 {
+        // The same curve as the bike's gentlest one, but flattened
+        1.0 + slope * slope / 14.0 / 14.0
+    } else {
+        5.0
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use geo::prelude::HaversineLength;
+    use geo::LineString;
+
     use super::*;
 
+    /// Builds tags from (key, value) pairs
+    pub(crate) fn tags(kv: &[(&str, &str)]) -> Tags {
+        let mut tags = Tags::new();
+        for (k, v) in kv {
+            tags.insert(*k, *v);
+        }
+        tags
+    }
+
+    /// Builds a small network for tests. Nodes are (ID, lon, lat), and every edge is a straight
+    /// line between two of them, with LTS 1 and a cost equal to its length.
+    pub(crate) fn make_network(nodes: &[(i64, f64, f64)], edges: &[(i64, i64)]) -> Network {
+        let mut intersections = HashMap::new();
+        for (id, lon, lat) in nodes {
+            intersections.insert(NodeID(*id), Position::from_degrees(*lon, *lat));
+        }
+
+        let mut network = Network {
+            edges: HashMap::new(),
+            intersections,
+            turn_restrictions: Vec::new(),
+            traffic_signals: HashSet::new(),
+            amenities: Vec::new(),
+            turn_costs: TurnCosts::default(),
+            profile: Profile::Cycling,
+            oneway: Oneway::default(),
+            barriers: Barriers::default(),
+            surface_multipliers: SurfaceMultipliers::default(),
+            lighting: Lighting::default(),
+            exposure_layers: Vec::new(),
+            cost_overrides: HashMap::new(),
+            warnings: Warnings::default(),
+            cache_key: 0,
+        };
+        for (idx, (node1, node2)) in edges.iter().enumerate() {
+            let geometry = vec![
+                network.intersections[&NodeID(*node1)],
+                network.intersections[&NodeID(*node2)],
+            ];
+            let length_meters = LineString::<f64>::from(
+                geometry
+                    .iter()
+                    .map(|pt| pt.to_degrees())
+                    .collect::<Vec<_>>(),
+            )
+            .haversine_length();
+            let mut tags = Tags::new();
+            tags.insert("highway", "residential");
+            network.edges.insert(
+                (NodeID(*node1), NodeID(*node2)),
+                Edge {
+                    way_id: WayID(idx as i64 + 1),
+                    tags,
+                    geometry,
+                    slope: None,
+                    slope_factor: None,
+                    ebike_slope_factor: None,
+                    elevation_gain: None,
+                    length_meters,
+                    lts: LTS::LTS1,
+                    forward_cost: Some(length_meters.round() as usize),
+                    backward_cost: Some(length_meters.round() as usize),
+                    nearby_amenities: 0,
+                    barriers: Vec::new(),
+                    aadt: None,
+                    exposure: BTreeMap::new(),
+                    lit_override: false,
+                },
+            );
+        }
+        network
+    }
+
     #[test]
     fn speed_slope_test() {
         let speed_flat = 15.0;
@@ -229,4 +769,126 @@ mod tests {
         let delta = slope_speed - 37.17009;
         assert!(delta < 0.00001);
     }
+
+    #[test]
+    fn ebike_slope_test() {
+        for slope in [2.0, 5.0, 10.0, 15.0] {
+            assert!(calculate_ebike_slope_factor(slope) > 1.0);
+            assert!(calculate_ebike_slope_factor(slope) < calculate_slope_factor(slope, 100.0));
+        }
+        // Descending can't go faster than 30km/h
+        assert_eq!(calculate_ebike_slope_factor(-10.0), 20.0 / 30.0);
+        // Gentle descents are the same as a bike
+        assert_eq!(
+            calculate_ebike_slope_factor(-2.0),
+            calculate_slope_factor(-2.0, 100.0)
+        );
+    }
+
+    #[test]
+    fn test_stable_edge_ids() {
+        let path = |run: usize| {
+            std::env::temp_dir()
+                .join(format!("od2net_counts_{}_{run}.csv", std::process::id()))
+                .display()
+                .to_string()
+        };
+
+        for run in 0..2 {
+            let network = make_network(
+                &[
+                    (1, 0.0, 0.0),
+                    (2, 0.001, 0.0),
+                    (3, 0.002, 0.0),
+                    (4, 0.001, 0.001),
+                ],
+                &[(1, 2), (3, 2), (2, 4)],
+            );
+            let mut timer = crate::timer::Timer::new();
+            let prepared_ch = crate::router::just_build_ch(&network, &mut timer);
+            let closest_intersection = crate::router::build_closest_intersection(
+                &network,
+                &prepared_ch.node_map,
+                &mut timer,
+            );
+            let requests = vec![
+                Request::new(0.0, 0.0, 0.002, 0.0),
+                Request::new(0.002, 0.0, 0.001, 0.001),
+            ];
+            let counts = crate::router::route_all(
+                &prepared_ch,
+                &closest_intersection,
+                &crate::router::RoutingExtras::default(),
+                &network,
+                requests,
+                &crate::config::tests::config_with(""),
+                None,
+            );
+            network.write_csv(&path(run), &counts, true, None).unwrap();
+        }
+
+        let first = fs_err::read_to_string(path(0)).unwrap();
+        let second = fs_err::read_to_string(path(1)).unwrap();
+        assert_eq!(first, second);
+        // The edge from 3 to 2 is crossed in both directions, but has one ID
+        let lines: Vec<&str> = first.lines().filter(|l| l.starts_with("2-3-2,")).collect();
+        assert_eq!(lines, vec!["2-3-2,2,3,2,2,1,1"]);
+
+        for run in 0..2 {
+            fs_err::remove_file(path(run)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_combine_flow_groups() {
+        let breakdown = FlowBreakdown::Purpose;
+        let edge = (NodeID(1), NodeID(2));
+        let add = |counts: &mut Counts, purpose: &str, count: f64| {
+            let idx = counts.flow_group_index(&breakdown, 0.0, Some(purpose));
+            let totals = counts.flow_group_count_per_edge.entry(edge).or_default();
+            totals.resize(totals.len().max(idx + 1), 0.0);
+            totals[idx] += count;
+        };
+
+        // Each side sees the groups in a different order
+        let mut counts1 = Counts::new();
+        add(&mut counts1, "work", 1.0);
+        add(&mut counts1, "school", 2.0);
+        let mut counts2 = Counts::new();
+        add(&mut counts2, "school", 3.0);
+        add(&mut counts2, "shopping", 4.0);
+        counts1.combine(counts2);
+        counts1.set_flow_groups(Some(&breakdown));
+
+        assert_eq!(counts1.flow_groups, vec!["school", "shopping", "work"]);
+        let groups: Vec<f64> = counts1
+            .flow_group_counts(edge.0, edge.1)
+            .into_iter()
+            .map(|(_, count)| count)
+            .collect();
+        assert_eq!(groups, vec![5.0, 4.0, 1.0]);
+    }
+
+    #[test]
+    fn test_elevation_profile() {
+        // A valley, 10m deep in the middle of the edge
+        struct Valley;
+        impl ElevationSource for Valley {
+            fn get_height_for_lon_lat(&mut self, lon: f32, _: f32) -> Option<f32> {
+                Some(10.0 * (lon - 0.001).abs() / 0.001)
+            }
+        }
+
+        let mut network = make_network(&[(1, 0.0, 0.0), (2, 0.002, 0.0)], &[(1, 2)]);
+        let edge = network.edges.values_mut().next().unwrap();
+        assert!(edge.apply_elevation(&mut Valley, Profile::Cycling));
+        assert!(edge.slope.unwrap().abs() < 0.1);
+        let (gain, loss) = edge.elevation_gain.unwrap();
+        assert!((gain - 10.0).abs() < 0.1);
+        assert!((loss - 10.0).abs() < 0.1);
+        // Comparing only the endpoints would say this is flat
+        let (forward, backward) = edge.slope_factor.unwrap();
+        assert!(forward > 1.0);
+        assert!((forward - backward).abs() < 0.01);
+    }
 }
@@ -0,0 +1,130 @@
+use lts::{Tags, LTS};
+
+use super::Edge;
+use crate::config::Oneway;
+
+#[derive(Debug, PartialEq)]
+enum Direction {
+    Both,
+    Forwards,
+    Backwards,
+}
+
+/// Which ways a bike may travel along an OSM way, before considering contraflow config
+fn direction(tags: &Tags) -> Direction {
+    match tags.get("oneway:bicycle").map(|x| x.as_str()) {
+        Some("no") => return Direction::Both,
+        Some("yes" | "true" | "1") => return Direction::Forwards,
+        Some("-1") => return Direction::Backwards,
+        _ => {}
+    }
+    let contraflow = [
+        "opposite",
+        "opposite_lane",
+        "opposite_track",
+        "opposite_share_busway",
+    ];
+    for key in [
+        "cycleway",
+        "cycleway:both",
+        "cycleway:left",
+        "cycleway:right",
+    ] {
+        if tags.is_any(key, contraflow.to_vec()) {
+            return Direction::Both;
+        }
+    }
+    match tags.get("oneway").map(|x| x.as_str()) {
+        Some("yes" | "true" | "1") => Direction::Forwards,
+        Some("-1") => Direction::Backwards,
+        Some("no") => Direction::Both,
+        _ if tags.is("junction", "roundabout") => Direction::Forwards,
+        _ => Direction::Both,
+    }
+}
+
+impl Oneway {
+    /// Removes or penalizes the cost of riding against the flow of a one-way edge
+    pub(super) fn apply(&self, edge: &mut Edge) {
+        if !self.respect {
+            return;
+        }
+        let contraflow_allowed = self
+            .contraflow_max_lts
+            .is_some_and(|max| edge.lts != LTS::NotAllowed && edge.lts <= max);
+        let against_flow = |cost: Option<usize>| {
+            if contraflow_allowed {
+                cost.map(|c| (c as f64 * self.contraflow_penalty).round() as usize)
+            } else {
+                None
+            }
+        };
+        match direction(&edge.tags) {
+            Direction::Both => {}
+            Direction::Forwards => edge.backward_cost = against_flow(edge.backward_cost),
+            Direction::Backwards => edge.forward_cost = against_flow(edge.forward_cost),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::tags;
+
+    #[test]
+    fn test_direction() {
+        assert_eq!(direction(&tags(&[])), Direction::Both);
+        assert_eq!(direction(&tags(&[("oneway", "yes")])), Direction::Forwards);
+        assert_eq!(direction(&tags(&[("oneway", "-1")])), Direction::Backwards);
+        assert_eq!(
+            direction(&tags(&[("junction", "roundabout")])),
+            Direction::Forwards
+        );
+        assert_eq!(
+            direction(&tags(&[("oneway", "yes"), ("oneway:bicycle", "no")])),
+            Direction::Both
+        );
+        assert_eq!(
+            direction(&tags(&[
+                ("oneway", "yes"),
+                ("cycleway:left", "opposite_lane")
+            ])),
+            Direction::Both
+        );
+        assert_eq!(
+            direction(&tags(&[("oneway:bicycle", "yes")])),
+            Direction::Forwards
+        );
+    }
+
+    #[test]
+    fn test_contraflow() {
+        let mut network =
+            crate::network::tests::make_network(&[(1, 0.0, 0.0), (2, 0.0, 0.001)], &[(1, 2)]);
+        let edge = network.edges.values_mut().next().unwrap();
+        edge.tags.insert("oneway", "yes");
+        let cost = edge.forward_cost.unwrap();
+
+        // Ignored by default
+        Oneway::default().apply(edge);
+        assert_eq!(edge.backward_cost, Some(cost));
+
+        let mut oneway = Oneway {
+            respect: true,
+            contraflow_max_lts: Some(LTS::LTS1),
+            contraflow_penalty: 1.5,
+        };
+        oneway.apply(edge);
+        assert_eq!(edge.forward_cost, Some(cost));
+        assert_eq!(
+            edge.backward_cost,
+            Some((cost as f64 * 1.5).round() as usize)
+        );
+
+        edge.backward_cost = Some(cost);
+        oneway.contraflow_max_lts = None;
+        oneway.apply(edge);
+        assert_eq!(edge.backward_cost, None);
+    }
+}
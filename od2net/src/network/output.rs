@@ -1,12 +1,19 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io::{BufWriter, Write};
+use std::process::{Command, Stdio};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+#[cfg(not(target_arch = "wasm32"))]
+use flatgeobuf::{ColumnType, FgbCrs, FgbWriter, FgbWriterOptions, GeometryType};
 use fs_err::File;
 use geojson::{feature::Id, Feature, FeatureWriter, Geometry, JsonObject, JsonValue, Value};
+#[cfg(not(target_arch = "wasm32"))]
+use geozero::{ColumnValue, PropertyProcessor};
 use indicatif::HumanCount;
 use osm_reader::NodeID;
 
-use super::{Counts, Edge, Network};
+use super::{edge_id, numeric_edge_id, Counts, Edge, Network, Position};
+use crate::config::OutputCompression;
 use crate::OutputMetadata;
 
 impl Edge {
@@ -14,12 +21,20 @@ impl Edge {
         &self,
         node1: NodeID,
         node2: NodeID,
-        count: f64,
-        id: usize,
+        edge_counts: &DirectionalCounts,
+        counts: &Counts,
         output_osm_tags: bool,
     ) -> Feature {
-        let mut feature = self.to_base_geojson(id, node1, node2, output_osm_tags);
-        feature.set_property("count", count);
+        let mut feature = self.to_base_geojson(node1, node2, output_osm_tags);
+        feature.set_property("count", edge_counts.total());
+        feature.set_property("count_forwards", edge_counts.forwards);
+        feature.set_property("count_backwards", edge_counts.backwards);
+        for (name, count) in counts.uptake_scenarios.iter().zip(&edge_counts.scenarios) {
+            feature.set_property(format!("count_{name}"), *count);
+        }
+        for (name, count) in counts.flow_groups.iter().zip(&edge_counts.groups) {
+            feature.set_property(format!("flow_{name}"), *count);
+        }
         feature
     }
 
@@ -29,9 +44,11 @@ impl Edge {
         node2: NodeID,
         geometry_forwards: bool,
     ) -> Feature {
-        let mut feature = self.to_base_geojson(0, node1, node2, true);
+        let mut feature = self.to_base_geojson(node1, node2, true);
         feature.id = None;
         if !geometry_forwards {
+            // The ID uses the direction of the geometry
+            feature.set_property("edge_id", edge_id(self.way_id, node2, node1));
             if let Some(ref mut geometry) = feature.geometry {
                 if let Value::LineString(ref mut pts) = geometry.value {
                     pts.reverse();
@@ -41,20 +58,16 @@ impl Edge {
         feature
     }
 
-    fn to_base_geojson(
-        &self,
-        id: usize,
-        node1: NodeID,
-        node2: NodeID,
-        output_osm_tags: bool,
-    ) -> Feature {
+    fn to_base_geojson(&self, node1: NodeID, node2: NodeID, output_osm_tags: bool) -> Feature {
         let geometry = Geometry::new(Value::LineString(
             self.geometry.iter().map(|pt| pt.to_degrees_vec()).collect(),
         ));
         let mut feature = Feature {
             bbox: None,
             geometry: Some(geometry),
-            id: Some(Id::Number(id.into())),
+            id: Some(Id::Number(
+                numeric_edge_id(self.way_id, node1, node2).into(),
+            )),
             properties: None,
             foreign_members: None,
         };
@@ -66,6 +79,7 @@ impl Edge {
             }
             feature.set_property("osm_tags", tags);
         }
+        feature.set_property("edge_id", edge_id(self.way_id, node1, node2));
         feature.set_property("way", self.way_id.0);
         feature.set_property("node1", node1.0);
         feature.set_property("node2", node2.0);
@@ -79,8 +93,18 @@ impl Edge {
         if let Some(slope) = self.slope {
             feature.set_property("slope", slope);
         };
+        if let Some((gain, loss)) = self.elevation_gain {
+            feature.set_property("elevation_gain", gain);
+            feature.set_property("elevation_loss", loss);
+        }
         feature.set_property("lts", serde_json::to_value(self.lts).unwrap());
         feature.set_property("nearby_amenities", self.nearby_amenities);
+        if let Some(aadt) = self.aadt {
+            feature.set_property("aadt", aadt);
+        }
+        for (name, value) in &self.exposure {
+            feature.set_property(format!("exposure_{name}"), *value);
+        }
         feature
     }
 }
@@ -92,48 +116,174 @@ impl Network {
         counts: Counts,
         output_od_points: bool,
         output_osm_tags: bool,
+        sort_output: bool,
+        output_properties: Option<&[String]>,
         output_metadata: &OutputMetadata,
     ) -> Result<()> {
         // Write one feature at a time to avoid memory problems
         writer.write_foreign_member("metadata", output_metadata)?;
-
-        let mut skipped = 0;
-        let mut id_counter = 0;
-        for ((node1, node2), count) in counts.count_per_edge {
-            // TODO Track forwards and backwards counts separately, and optionally merge later?
-            if let Some(edge) = self
-                .edges
-                .get(&(node1, node2))
-                .or_else(|| self.edges.get(&(node2, node1)))
-            {
-                id_counter += 1;
-                let feature = edge.to_geojson(node1, node2, count, id_counter, output_osm_tags);
+        self.output_features(
+            counts,
+            output_od_points,
+            output_osm_tags,
+            sort_output,
+            output_properties,
+            |feature| {
                 writer.write_feature(&feature)?;
-            } else {
-                // TODO We don't handle routes starting or ending in the middle of an edge yet
-                //println!("No edge from https://www.openstreetmap.org/node/{node1} to https://www.openstreetmap.org/node/{node2} or vice versa");
-                skipped += 1;
+                Ok(())
+            },
+        )?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Like `write_geojson`, but writes newline-delimited GeoJSON, with one feature per line and
+    /// no FeatureCollection around them. This can be streamed by tippecanoe, jq, or ogr2ogr. There's
+    /// nowhere to put the metadata.
+    pub fn write_geojson_seq<W: std::io::Write>(
+        &self,
+        mut writer: W,
+        counts: Counts,
+        output_od_points: bool,
+        output_osm_tags: bool,
+        sort_output: bool,
+        output_properties: Option<&[String]>,
+    ) -> Result<()> {
+        self.output_features(
+            counts,
+            output_od_points,
+            output_osm_tags,
+            sort_output,
+            output_properties,
+            |feature| {
+                serde_json::to_writer(&mut writer, &feature)?;
+                writeln!(writer)?;
+                Ok(())
+            },
+        )?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Pipes the edges from the main output into a command reading newline-delimited GeoJSON from
+    /// STDIN, like tippecanoe. This avoids writing a huge GeoJSON file just to read it back.
+    pub fn write_geojson_seq_to_command(
+        &self,
+        mut cmd: Command,
+        counts: Counts,
+        output_osm_tags: bool,
+        sort_output: bool,
+        output_properties: Option<&[String]>,
+    ) -> Result<()> {
+        let mut child = cmd.stdin(Stdio::piped()).spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let result = self.write_geojson_seq(
+            BufWriter::new(stdin),
+            counts,
+            false,
+            output_osm_tags,
+            sort_output,
+            output_properties,
+        );
+        // If the command failed early, writing fails too, but the command's failure is more useful
+        if !child.wait()?.success() {
+            bail!("{cmd:?} failed");
+        }
+        result
+    }
+
+    /// Every property a segment can have in the main output, though some are only set on some
+    /// segments
+    fn edge_property_names(&self, counts: &Counts) -> BTreeSet<String> {
+        let mut names: BTreeSet<String> = [
+            "osm_tags",
+            "edge_id",
+            "way",
+            "node1",
+            "node2",
+            "length",
+            "forward_cost",
+            "backward_cost",
+            "slope",
+            "elevation_gain",
+            "elevation_loss",
+            "lts",
+            "nearby_amenities",
+            "aadt",
+            "count",
+            "count_forwards",
+            "count_backwards",
+        ]
+        .into_iter()
+        .map(|name| name.to_string())
+        .collect();
+        for name in &self.exposure_layers {
+            names.insert(format!("exposure_{name}"));
+        }
+        for name in &counts.uptake_scenarios {
+            names.insert(format!("count_{name}"));
+        }
+        for name in &counts.flow_groups {
+            names.insert(format!("flow_{name}"));
+        }
+        names
+    }
+
+    /// Calls `output` with every feature in the main output, one at a time to avoid memory
+    /// problems. If `output_properties` is specified, edges only have those properties.
+    fn output_features<F: FnMut(Feature) -> Result<()>>(
+        &self,
+        counts: Counts,
+        output_od_points: bool,
+        output_osm_tags: bool,
+        sort_output: bool,
+        output_properties: Option<&[String]>,
+        mut output: F,
+    ) -> Result<()> {
+        let keep: Option<HashSet<&str>> =
+            output_properties.map(|names| names.iter().map(|name| name.as_str()).collect());
+        if let Some(ref keep) = keep {
+            let known = self.edge_property_names(&counts);
+            let mut unknown: Vec<&str> = keep
+                .iter()
+                .filter(|name| !known.contains(**name))
+                .cloned()
+                .collect();
+            if !unknown.is_empty() {
+                unknown.sort();
+                bail!(
+                    "output_properties has {}, which segments don't have. The options are {}",
+                    unknown.join(", "),
+                    known.into_iter().collect::<Vec<_>>().join(", ")
+                );
             }
         }
-        println!(
-            "Skipped {} edges (started/ended mid-edge)",
-            HumanCount(skipped)
-        );
+        // Don't bother building tags that'll be removed
+        let output_osm_tags =
+            output_osm_tags && keep.as_ref().map_or(true, |keep| keep.contains("osm_tags"));
 
+        for (node1, node2, edge, edge_counts) in self.counted_edges(&counts, sort_output) {
+            let mut feature = edge.to_geojson(node1, node2, &edge_counts, &counts, output_osm_tags);
+            if let (Some(keep), Some(properties)) = (&keep, &mut feature.properties) {
+                properties.retain(|key, _| keep.contains(key.as_str()));
+            }
+            output(feature)?;
+        }
         if output_od_points {
             // Also write origin/destination points with the number of routes to the same file. It
             // hugely bloats the size, but keeping them together is useful right now.
+            let mut id_counter = 0;
 
             for (key, counter) in [
                 ("origin_count", counts.count_per_origin),
                 ("destination_count", counts.count_per_destination),
             ] {
-                for (pt, count) in counter {
+                for (pt, count) in points_in_order(counter, sort_output) {
                     id_counter += 1;
                     let geometry = Geometry::new(Value::Point(pt.to_degrees_vec()));
                     let mut properties = JsonObject::new();
                     properties.insert(key.to_string(), JsonValue::from(count));
-                    writer.write_feature(&Feature {
+                    output(Feature {
                         bbox: None,
                         geometry: Some(geometry),
                         id: Some(Id::Number(id_counter.into())),
@@ -144,33 +294,239 @@ impl Network {
             }
         }
 
-        writer.finish()?;
-
         Ok(())
     }
 
-    pub fn write_csv(&self, path: &str, counts: &Counts) -> Result<()> {
+    pub fn write_csv(
+        &self,
+        path: &str,
+        counts: &Counts,
+        sort_output: bool,
+        compression: Option<OutputCompression>,
+    ) -> Result<()> {
+        let mut file = crate::utils::create_output_file(path, compression)?;
+        write!(
+            file,
+            "edge_id,way,node1,node2,count,count_forwards,count_backwards"
+        )?;
+        for name in &counts.uptake_scenarios {
+            write!(file, ",count_{name}")?;
+        }
+        for name in &counts.flow_groups {
+            write!(file, ",flow_{name}")?;
+        }
+        writeln!(file)?;
+
+        for (node1, node2, edge, edge_counts) in self.counted_edges(counts, sort_output) {
+            let way = edge.way_id.0;
+            let id = edge_id(edge.way_id, node1, node2);
+            write!(
+                file,
+                "{id},{way},{},{},{},{},{}",
+                node1.0,
+                node2.0,
+                edge_counts.total(),
+                edge_counts.forwards,
+                edge_counts.backwards
+            )?;
+            for count in edge_counts.scenarios.iter().chain(&edge_counts.groups) {
+                write!(file, ",{count}")?;
+            }
+            writeln!(file)?;
+        }
+        file.finish()
+    }
+
+    /// Writes the edges with counts as FlatGeobuf, with a spatial index. This has the same
+    /// properties as the GeoJSON output, except OSM tags are a JSON string, and origin and
+    /// destination points aren't included.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn write_flatgeobuf(
+        &self,
+        path: &str,
+        counts: &Counts,
+        output_osm_tags: bool,
+        sort_output: bool,
+    ) -> Result<()> {
+        let schema = EdgeSchema::new(self, counts, output_osm_tags);
+        let mut fgb = FgbWriter::create_with_options(
+            "rnet",
+            GeometryType::LineString,
+            FgbWriterOptions {
+                write_index: true,
+                crs: FgbCrs {
+                    code: 4326,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )?;
+        for (name, property_type) in &schema.columns {
+            let column_type = match property_type {
+                PropertyType::String => ColumnType::String,
+                PropertyType::Json => ColumnType::Json,
+                PropertyType::Long => ColumnType::Long,
+                PropertyType::ULong => ColumnType::ULong,
+                PropertyType::UByte => ColumnType::UByte,
+                PropertyType::Double => ColumnType::Double,
+            };
+            fgb.add_column(name, column_type, |_, column| {
+                column.nullable = true;
+            });
+        }
+
+        for (node1, node2, edge, edge_counts) in self.counted_edges(counts, sort_output) {
+            let values = schema.values(edge, node1, node2, &edge_counts)?;
+            let line: geo::LineString = edge.geometry.iter().map(|pt| pt.to_degrees()).collect();
+            // The callback can't return an error, so remember the first one
+            let mut result = Ok(());
+            fgb.add_feature_geom(geo::Geometry::LineString(line), |feature| {
+                for (idx, value) in values.iter().enumerate() {
+                    let name = &schema.columns[idx].0;
+                    let value = match value {
+                        Some(PropertyValue::String(x)) => ColumnValue::String(x),
+                        Some(PropertyValue::Json(x)) => ColumnValue::Json(x),
+                        Some(PropertyValue::Long(x)) => ColumnValue::Long(*x),
+                        Some(PropertyValue::ULong(x)) => ColumnValue::ULong(*x),
+                        Some(PropertyValue::UByte(x)) => ColumnValue::UByte(*x),
+                        Some(PropertyValue::Double(x)) => ColumnValue::Double(*x),
+                        None => continue,
+                    };
+                    if let Err(err) = feature.property(idx, name, &value) {
+                        result = Err(err);
+                        return;
+                    }
+                }
+            })?;
+            result?;
+        }
+
         let mut file = BufWriter::new(File::create(path)?);
-        writeln!(file, "way,node1,node2,count")?;
+        fgb.write(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
 
+    /// Returns every edge with a count, in the same order as the GeoJSON output. Counts crossing
+    /// an edge in either direction are merged, and the nodes are in the same order as in `edges`.
+    /// Counts on node pairs that aren't edges in the network are skipped.
+    pub(super) fn counted_edges(
+        &self,
+        counts: &Counts,
+        sort_output: bool,
+    ) -> Vec<(NodeID, NodeID, &Edge, DirectionalCounts)> {
+        let mut merged: HashMap<(NodeID, NodeID), DirectionalCounts> = HashMap::new();
         let mut skipped = 0;
         for ((node1, node2), count) in &counts.count_per_edge {
-            if let Some(edge) = self
-                .edges
-                .get(&(*node1, *node2))
-                .or_else(|| self.edges.get(&(*node2, *node1)))
-            {
-                let way = edge.way_id.0;
-                writeln!(file, "{way},{},{},{count}", node1.0, node2.0)?;
+            let (key, forwards) = if self.edges.contains_key(&(*node1, *node2)) {
+                ((*node1, *node2), true)
+            } else if self.edges.contains_key(&(*node2, *node1)) {
+                ((*node2, *node1), false)
             } else {
+                // TODO We don't handle routes starting or ending in the middle of an edge yet
                 skipped += 1;
+                continue;
+            };
+            let entry = merged.entry(key).or_insert_with(|| DirectionalCounts {
+                forwards: 0.0,
+                backwards: 0.0,
+                scenarios: vec![0.0; counts.uptake_scenarios.len()],
+                groups: vec![0.0; counts.flow_groups.len()],
+            });
+            if forwards {
+                entry.forwards += count;
+            } else {
+                entry.backwards += count;
+            }
+            for (total, (_, count)) in entry
+                .scenarios
+                .iter_mut()
+                .zip(counts.scenario_counts(*node1, *node2))
+            {
+                *total += count;
+            }
+            for (total, (_, count)) in entry
+                .groups
+                .iter_mut()
+                .zip(counts.flow_group_counts(*node1, *node2))
+            {
+                *total += count;
             }
         }
-
         println!(
             "Skipped {} edges (started/ended mid-edge)",
             HumanCount(skipped)
         );
+
+        let mut edges: Vec<_> = merged
+            .into_iter()
+            .map(|((node1, node2), edge_counts)| {
+                (node1, node2, &self.edges[&(node1, node2)], edge_counts)
+            })
+            .collect();
+        if sort_output {
+            // The same order as edge_id
+            edges.sort_by_key(|(node1, node2, edge, _)| (edge.way_id.0, node1.0, node2.0));
+        }
+        edges
+    }
+
+    /// Write a GeoJSON file with a point for every intersection that at least one route passes
+    /// through
+    pub fn write_node_counts(&self, path: &str, counts: &Counts) -> Result<()> {
+        let mut writer = FeatureWriter::from_writer(BufWriter::new(File::create(path)?));
+        for (node, count) in &counts.count_per_node {
+            let Some(pt) = self.intersections.get(node) else {
+                continue;
+            };
+            let mut properties = JsonObject::new();
+            properties.insert("node".to_string(), JsonValue::from(node.0));
+            properties.insert("count".to_string(), JsonValue::from(*count));
+            writer.write_feature(&Feature {
+                bbox: None,
+                geometry: Some(Geometry::new(Value::Point(pt.to_degrees_vec()))),
+                // The OSM node ID is stable
+                id: Some(Id::Number(node.0.into())),
+                properties: Some(properties),
+                foreign_members: None,
+            })?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Write a GeoJSON file with a point for every turning movement made at an intersection,
+    /// ordered by intersection, then biggest first
+    pub fn write_movements(&self, path: &str, counts: &Counts) -> Result<()> {
+        let mut movements: Vec<_> = counts.count_per_movement.iter().collect();
+        movements.sort_by(
+            |((from1, via1, to1), count1), ((from2, via2, to2), count2)| {
+                via1.0
+                    .cmp(&via2.0)
+                    .then_with(|| count2.total_cmp(count1))
+                    .then_with(|| (from1.0, to1.0).cmp(&(from2.0, to2.0)))
+            },
+        );
+
+        let mut writer = FeatureWriter::from_writer(BufWriter::new(File::create(path)?));
+        for ((from, via, to), count) in movements {
+            let Some(pt) = self.intersections.get(via) else {
+                continue;
+            };
+            let mut properties = JsonObject::new();
+            properties.insert("node".to_string(), JsonValue::from(via.0));
+            properties.insert("from".to_string(), JsonValue::from(from.0));
+            properties.insert("to".to_string(), JsonValue::from(to.0));
+            properties.insert("count".to_string(), JsonValue::from(*count));
+            writer.write_feature(&Feature {
+                bbox: None,
+                geometry: Some(Geometry::new(Value::Point(pt.to_degrees_vec()))),
+                id: None,
+                properties: Some(properties),
+                foreign_members: None,
+            })?;
+        }
+        writer.finish()?;
         Ok(())
     }
 
@@ -179,13 +535,504 @@ impl Network {
         let mut gj_bytes = Vec::new();
         {
             let mut writer = FeatureWriter::from_writer(BufWriter::new(&mut gj_bytes));
-            let mut id_counter = 0;
             for ((node1, node2), edge) in &self.edges {
-                id_counter += 1;
-                writer.write_feature(&edge.to_base_geojson(id_counter, *node1, *node2, true))?;
+                writer.write_feature(&edge.to_base_geojson(*node1, *node2, true))?;
             }
             writer.finish()?;
         }
         Ok(String::from_utf8(gj_bytes)?)
     }
 }
+
+impl Counts {
+    /// Writes `origins.geojson` and `destinations.geojson` to a directory, if enabled. Each has a
+    /// Point for every place where at least one successful route starts or ends, with its `count`.
+    pub fn write_od_points(
+        &self,
+        output_directory: &str,
+        origins: bool,
+        destinations: bool,
+        sort_output: bool,
+    ) -> Result<()> {
+        for (enabled, name, points) in [
+            (origins, "origins", &self.count_per_origin),
+            (destinations, "destinations", &self.count_per_destination),
+        ] {
+            if !enabled {
+                continue;
+            }
+            let mut writer = FeatureWriter::from_writer(BufWriter::new(File::create(format!(
+                "{output_directory}/{name}.geojson"
+            ))?));
+            for (idx, (pt, count)) in
+                points_in_order(points.iter().map(|(pt, count)| (*pt, *count)), sort_output)
+                    .into_iter()
+                    .enumerate()
+            {
+                let mut properties = JsonObject::new();
+                properties.insert("count".to_string(), JsonValue::from(count));
+                writer.write_feature(&Feature {
+                    bbox: None,
+                    geometry: Some(Geometry::new(Value::Point(pt.to_degrees_vec()))),
+                    id: Some(Id::Number((idx + 1).into())),
+                    properties: Some(properties),
+                    foreign_members: None,
+                })?;
+            }
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// The counts crossing one edge
+pub(super) struct DirectionalCounts {
+    /// From the edge's `node1` to `node2`, in the direction of its geometry
+    pub forwards: f64,
+    pub backwards: f64,
+    /// With each of the `uptake_scenarios`, summing both directions
+    pub scenarios: Vec<f64>,
+    /// From each of the `flow_groups`, summing both directions
+    pub groups: Vec<f64>,
+}
+
+impl DirectionalCounts {
+    pub fn total(&self) -> f64 {
+        self.forwards + self.backwards
+    }
+}
+
+/// The type of one property, for output formats with a fixed schema
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(super) enum PropertyType {
+    String,
+    /// A string with JSON
+    Json,
+    Long,
+    ULong,
+    UByte,
+    Double,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(super) enum PropertyValue {
+    String(String),
+    Json(String),
+    Long(i64),
+    ULong(u64),
+    UByte(u8),
+    Double(f64),
+}
+
+/// The properties written for each edge by output formats with a fixed schema. These match the
+/// GeoJSON output.
+#[cfg(not(target_arch = "wasm32"))]
+pub(super) struct EdgeSchema<'a> {
+    pub columns: Vec<(String, PropertyType)>,
+    exposure_names: BTreeSet<&'a String>,
+    output_osm_tags: bool,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<'a> EdgeSchema<'a> {
+    pub fn new(network: &'a Network, counts: &Counts, output_osm_tags: bool) -> Self {
+        let exposure_names: BTreeSet<&String> = network
+            .edges
+            .values()
+            .flat_map(|edge| edge.exposure.keys())
+            .collect();
+
+        let mut columns: Vec<(String, PropertyType)> = Vec::new();
+        if output_osm_tags {
+            columns.push(("osm_tags".to_string(), PropertyType::Json));
+        }
+        for (name, property_type) in [
+            ("edge_id", PropertyType::String),
+            ("way", PropertyType::Long),
+            ("node1", PropertyType::Long),
+            ("node2", PropertyType::Long),
+            ("length", PropertyType::Double),
+            ("forward_cost", PropertyType::ULong),
+            ("backward_cost", PropertyType::ULong),
+            ("slope", PropertyType::Double),
+            ("elevation_gain", PropertyType::Double),
+            ("elevation_loss", PropertyType::Double),
+            ("lts", PropertyType::UByte),
+            ("nearby_amenities", PropertyType::ULong),
+            ("aadt", PropertyType::Double),
+        ] {
+            columns.push((name.to_string(), property_type));
+        }
+        for name in &exposure_names {
+            columns.push((format!("exposure_{name}"), PropertyType::Double));
+        }
+        for name in ["count", "count_forwards", "count_backwards"] {
+            columns.push((name.to_string(), PropertyType::Double));
+        }
+        for name in &counts.uptake_scenarios {
+            columns.push((format!("count_{name}"), PropertyType::Double));
+        }
+        for name in &counts.flow_groups {
+            columns.push((format!("flow_{name}"), PropertyType::Double));
+        }
+
+        Self {
+            columns,
+            exposure_names,
+            output_osm_tags,
+        }
+    }
+
+    /// Returns the value of every column for one edge, in order. Missing values are `None`.
+    pub fn values(
+        &self,
+        edge: &Edge,
+        node1: NodeID,
+        node2: NodeID,
+        edge_counts: &DirectionalCounts,
+    ) -> Result<Vec<Option<PropertyValue>>> {
+        let mut values = Vec::with_capacity(self.columns.len());
+        if self.output_osm_tags {
+            values.push(Some(PropertyValue::Json(serde_json::to_string(
+                edge.tags.inner(),
+            )?)));
+        }
+        values.extend([
+            Some(PropertyValue::String(edge_id(edge.way_id, node1, node2))),
+            Some(PropertyValue::Long(edge.way_id.0)),
+            Some(PropertyValue::Long(node1.0)),
+            Some(PropertyValue::Long(node2.0)),
+            Some(PropertyValue::Double(edge.length_meters)),
+            edge.forward_cost.map(|x| PropertyValue::ULong(x as u64)),
+            edge.backward_cost.map(|x| PropertyValue::ULong(x as u64)),
+            edge.slope.map(PropertyValue::Double),
+            edge.elevation_gain
+                .map(|(gain, _)| PropertyValue::Double(gain)),
+            edge.elevation_gain
+                .map(|(_, loss)| PropertyValue::Double(loss)),
+            Some(PropertyValue::UByte(edge.lts as u8)),
+            Some(PropertyValue::ULong(edge.nearby_amenities as u64)),
+            edge.aadt.map(PropertyValue::Double),
+        ]);
+        for name in &self.exposure_names {
+            values.push(edge.exposure.get(*name).map(|x| PropertyValue::Double(*x)));
+        }
+        for count in [
+            edge_counts.total(),
+            edge_counts.forwards,
+            edge_counts.backwards,
+        ] {
+            values.push(Some(PropertyValue::Double(count)));
+        }
+        for count in edge_counts.scenarios.iter().chain(&edge_counts.groups) {
+            values.push(Some(PropertyValue::Double(*count)));
+        }
+        Ok(values)
+    }
+}
+
+/// If `sorted`, orders points by longitude, then latitude
+pub(super) fn points_in_order(
+    counts: impl IntoIterator<Item = (Position, f64)>,
+    sorted: bool,
+) -> Vec<(Position, f64)> {
+    let mut points: Vec<_> = counts.into_iter().collect();
+    if sorted {
+        points.sort_by_key(|(pt, _)| (pt.lon, pt.lat));
+    }
+    points
+}
+
+/// Encodes a LineString as little-endian WKB
+#[cfg(not(target_arch = "wasm32"))]
+pub(super) fn linestring_wkb(pts: &[Position]) -> Vec<u8> {
+    let mut wkb = Vec::with_capacity(9 + 16 * pts.len());
+    wkb.push(1);
+    wkb.extend(2u32.to_le_bytes());
+    wkb.extend((pts.len() as u32).to_le_bytes());
+    for pt in pts {
+        let (x, y) = pt.to_degrees();
+        wkb.extend(x.to_le_bytes());
+        wkb.extend(y.to_le_bytes());
+    }
+    wkb
+}
+
+/// Encodes a Point as little-endian WKB
+#[cfg(not(target_arch = "wasm32"))]
+pub(super) fn point_wkb(pt: Position) -> Vec<u8> {
+    let mut wkb = Vec::with_capacity(21);
+    wkb.push(1);
+    wkb.extend(1u32.to_le_bytes());
+    let (x, y) = pt.to_degrees();
+    wkb.extend(x.to_le_bytes());
+    wkb.extend(y.to_le_bytes());
+    wkb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wkb() {
+        let wkb = linestring_wkb(&[
+            Position::from_degrees(1.0, 2.0),
+            Position::from_degrees(3.0, 4.0),
+        ]);
+        assert_eq!(wkb.len(), 9 + 32);
+        assert_eq!(&wkb[0..9], &[1, 2, 0, 0, 0, 2, 0, 0, 0]);
+        let x1 = f64::from_le_bytes(wkb[9..17].try_into().unwrap());
+        let y2 = f64::from_le_bytes(wkb[33..41].try_into().unwrap());
+        assert!((x1 - 1.0).abs() < 1e-6);
+        assert!((y2 - 4.0).abs() < 1e-6);
+
+        let wkb = point_wkb(Position::from_degrees(1.0, 2.0));
+        assert_eq!(wkb.len(), 21);
+        assert_eq!(&wkb[0..5], &[1, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_directional_counts() {
+        let network =
+            crate::network::tests::make_network(&[(1, 0.0, 0.0), (2, 0.001, 0.0)], &[(1, 2)]);
+        let mut counts = Counts::new();
+        counts.uptake_scenarios = vec!["high".to_string()];
+        counts.count_per_edge.insert((NodeID(1), NodeID(2)), 1.0);
+        counts.count_per_edge.insert((NodeID(2), NodeID(1)), 3.0);
+        counts
+            .uptake_scenario_count_per_edge
+            .insert((NodeID(1), NodeID(2)), vec![2.0]);
+        counts
+            .uptake_scenario_count_per_edge
+            .insert((NodeID(2), NodeID(1)), vec![6.0]);
+
+        let edges = network.counted_edges(&counts, true);
+        assert_eq!(edges.len(), 1);
+        let (node1, node2, _, edge_counts) = &edges[0];
+        assert_eq!((*node1, *node2), (NodeID(1), NodeID(2)));
+        assert_eq!(edge_counts.forwards, 1.0);
+        assert_eq!(edge_counts.backwards, 3.0);
+        assert_eq!(edge_counts.total(), 4.0);
+        assert_eq!(edge_counts.scenarios, vec![8.0]);
+    }
+
+    #[test]
+    fn test_geojson_seq() {
+        let network = crate::network::tests::make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (2, 3)],
+        );
+        let mut counts = Counts::new();
+        counts.count_per_edge.insert((NodeID(1), NodeID(2)), 1.0);
+        counts.count_per_edge.insert((NodeID(3), NodeID(2)), 2.0);
+        counts
+            .count_per_origin
+            .insert(Position::from_degrees(0.0, 0.0), 3.0);
+
+        let mut output = Vec::new();
+        network
+            .write_geojson_seq(&mut output, counts, true, false, true, None)
+            .unwrap();
+        let lines: Vec<Feature> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                line.parse::<geojson::GeoJson>()
+                    .unwrap()
+                    .try_into()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].property("edge_id").unwrap(), "1-1-2");
+        // Crossed against the direction of the edge
+        assert_eq!(lines[1].property("edge_id").unwrap(), "2-2-3");
+        assert_eq!(lines[1].property("count").unwrap(), 2.0);
+        assert_eq!(lines[1].property("count_forwards").unwrap(), 0.0);
+        assert_eq!(lines[1].property("count_backwards").unwrap(), 2.0);
+        assert_eq!(lines[2].property("origin_count").unwrap(), 3.0);
+    }
+
+    #[test]
+    fn test_write_od_points() {
+        let mut counts = Counts::new();
+        counts
+            .count_per_origin
+            .insert(Position::from_degrees(0.001, 0.0), 2.0);
+        counts
+            .count_per_origin
+            .insert(Position::from_degrees(0.0, 0.0), 1.0);
+        counts
+            .count_per_destination
+            .insert(Position::from_degrees(0.002, 0.0), 3.0);
+
+        let dir = std::env::temp_dir().join("od2net_test_od_points");
+        fs_err::create_dir_all(&dir).unwrap();
+        let _ = fs_err::remove_file(dir.join("destinations.geojson"));
+        counts
+            .write_od_points(dir.to_str().unwrap(), true, false, true)
+            .unwrap();
+
+        let gj: geojson::FeatureCollection = fs_err::read_to_string(dir.join("origins.geojson"))
+            .unwrap()
+            .parse::<geojson::GeoJson>()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let values: Vec<f64> = gj
+            .features
+            .iter()
+            .map(|f| f.property("count").unwrap().as_f64().unwrap())
+            .collect();
+        // Sorted by position
+        assert_eq!(values, vec![1.0, 2.0]);
+        assert!(!dir.join("destinations.geojson").exists());
+        fs_err::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_geojson_seq_to_command() {
+        let network = crate::network::tests::make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (2, 3)],
+        );
+        let mut counts = Counts::new();
+        counts.count_per_edge.insert((NodeID(1), NodeID(2)), 1.0);
+        counts.count_per_edge.insert((NodeID(2), NodeID(3)), 2.0);
+
+        let path = std::env::temp_dir().join("od2net_test_piped.geojsonl");
+        let mut cmd = Command::new("cat");
+        cmd.stdout(std::fs::File::create(&path).unwrap());
+        network
+            .write_geojson_seq_to_command(cmd, counts, false, true, None)
+            .unwrap();
+        let output = fs_err::read_to_string(&path).unwrap();
+        assert_eq!(output.lines().count(), 2);
+        fs_err::remove_file(path).unwrap();
+
+        assert!(network
+            .write_geojson_seq_to_command(Command::new("false"), Counts::new(), false, true, None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_compressed_csv() {
+        use std::io::Read;
+
+        let network =
+            crate::network::tests::make_network(&[(1, 0.0, 0.0), (2, 0.001, 0.0)], &[(1, 2)]);
+        let mut counts = Counts::new();
+        counts.count_per_edge.insert((NodeID(1), NodeID(2)), 1.0);
+
+        for compression in [OutputCompression::Gzip, OutputCompression::Zstd] {
+            let path = OutputCompression::path(
+                Some(compression),
+                std::env::temp_dir()
+                    .join("od2net_test_counts.csv")
+                    .to_str()
+                    .unwrap(),
+            );
+            network
+                .write_csv(&path, &counts, true, Some(compression))
+                .unwrap();
+
+            let file = fs_err::File::open(&path).unwrap();
+            let mut csv = String::new();
+            match compression {
+                OutputCompression::Gzip => {
+                    flate2::read::GzDecoder::new(file)
+                        .read_to_string(&mut csv)
+                        .unwrap();
+                }
+                OutputCompression::Zstd => {
+                    zstd::Decoder::new(file)
+                        .unwrap()
+                        .read_to_string(&mut csv)
+                        .unwrap();
+                }
+            }
+            assert_eq!(csv.lines().count(), 2);
+            assert!(csv.lines().nth(1).unwrap().starts_with("1-2,"));
+            fs_err::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_stable_feature_ids() {
+        let network = crate::network::tests::make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (2, 3)],
+        );
+        // Only the second edge has a count
+        let mut counts = Counts::new();
+        counts.count_per_edge.insert((NodeID(2), NodeID(3)), 1.0);
+        counts
+            .count_per_origin
+            .insert(Position::from_degrees(0.0, 0.0), 1.0);
+
+        let mut output = Vec::new();
+        network
+            .write_geojson_seq(&mut output, counts, true, false, false, None)
+            .unwrap();
+        let ids: Vec<Option<Id>> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let feature: Feature = line
+                    .parse::<geojson::GeoJson>()
+                    .unwrap()
+                    .try_into()
+                    .unwrap();
+                feature.id
+            })
+            .collect();
+        // The edge's ID only depends on its way and nodes, and is pinned so it can't change
+        // between releases
+        assert_eq!(
+            ids,
+            vec![
+                Some(Id::Number(4553933956911462_u64.into())),
+                Some(Id::Number(1.into()))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_output_properties() {
+        let network =
+            crate::network::tests::make_network(&[(1, 0.0, 0.0), (2, 0.001, 0.0)], &[(1, 2)]);
+        let mut counts = Counts::new();
+        counts.count_per_edge.insert((NodeID(1), NodeID(2)), 1.0);
+
+        let mut output = Vec::new();
+        let properties = vec!["count".to_string(), "lts".to_string()];
+        network
+            .write_geojson_seq(&mut output, counts, false, true, false, Some(&properties))
+            .unwrap();
+        let feature: Feature = String::from_utf8(output)
+            .unwrap()
+            .trim()
+            .parse::<geojson::GeoJson>()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let mut keys: Vec<&String> = feature.properties.as_ref().unwrap().keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["count", "lts"]);
+
+        let properties = vec!["count".to_string(), "cuont".to_string()];
+        let err = network
+            .write_geojson_seq(
+                Vec::<u8>::new(),
+                Counts::new(),
+                false,
+                true,
+                false,
+                Some(&properties),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("cuont"));
+    }
+}
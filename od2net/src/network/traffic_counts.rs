@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::io::BufReader;
+
+use anyhow::Result;
+use fs_err::File;
+use geo::{
+    Closest, ClosestPoint, EuclideanDistance, HaversineDistance, HaversineLength, LineString, Point,
+};
+use geojson::FeatureReader;
+use osm_reader::{NodeID, WayID};
+use serde::Deserialize;
+
+use lts::LTS;
+
+use super::create_from_osm::build_closest_edge;
+use super::Network;
+use crate::config::TrafficCounts;
+use crate::timer::Timer;
+
+/// Points along a counted road are looked up this far apart
+const SAMPLE_METERS: f64 = 20.0;
+/// A counted road only matches edges pointing within this many degrees of it
+const MAX_BEARING_DIFFERENCE: f64 = 30.0;
+/// Along a meridian; a degree of longitude is this times the cosine of the latitude
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+#[derive(Deserialize)]
+struct Row {
+    way_id: i64,
+    aadt: f64,
+}
+
+impl Network {
+    /// Sets `aadt` on the edges matching each traffic count, and raises their LTS according to
+    /// `lts_thresholds`. When several counts match one edge, the highest is used. Returns how
+    /// many edges matched.
+    pub fn match_traffic_counts(
+        &mut self,
+        path: &str,
+        config: &TrafficCounts,
+        timer: &mut Timer,
+    ) -> Result<usize> {
+        let mut aadt_per_edge: HashMap<(NodeID, NodeID), f64> = HashMap::new();
+        if path.ends_with(".csv") {
+            let mut aadt_per_way: HashMap<WayID, f64> = HashMap::new();
+            for rec in csv::Reader::from_reader(File::open(path)?).deserialize() {
+                let row: Row = rec?;
+                let aadt = aadt_per_way.entry(WayID(row.way_id)).or_insert(0.0);
+                *aadt = aadt.max(row.aadt);
+            }
+            for (key, edge) in &self.edges {
+                if let Some(aadt) = aadt_per_way.get(&edge.way_id) {
+                    aadt_per_edge.insert(*key, *aadt);
+                }
+            }
+        } else {
+            let closest_edge = build_closest_edge(self, timer);
+            timer.start(format!("Matching traffic counts from {path}"));
+            let reader = FeatureReader::from_reader(BufReader::new(File::open(path)?));
+            for feature in reader.features() {
+                let feature = feature?;
+                let Some(aadt) = feature.property(&config.property).and_then(|x| x.as_f64()) else {
+                    bail!(
+                        "A feature in {path} is missing a numeric {}",
+                        config.property
+                    );
+                };
+                let Some(gj_geom) = feature.geometry else {
+                    continue;
+                };
+                let geo_geometry: geo::Geometry<f64> = gj_geom.try_into()?;
+                let lines = match geo_geometry {
+                    geo::Geometry::Point(pt) => vec![LineString::from(vec![pt.0])],
+                    geo::Geometry::LineString(line) => vec![line],
+                    geo::Geometry::MultiLineString(mls) => mls.0,
+                    _ => bail!("A feature in {path} has geometry other than a Point or LineString"),
+                };
+
+                for (pt, count_bearing) in lines.iter().flat_map(sample_points) {
+                    // A degree of longitude is shortest, so once candidates are this far away in
+                    // degrees, none of the rest can be close enough
+                    let max_degrees = config.max_distance_meters
+                        / (METERS_PER_DEGREE * pt.y().to_radians().cos());
+                    for segment in closest_edge.nearest_neighbor_iter(&[pt.x(), pt.y()]) {
+                        let line = geo::Line::new(segment.geom().from, segment.geom().to);
+                        let snapped = match line.closest_point(&pt) {
+                            Closest::Intersection(snapped) | Closest::SinglePoint(snapped) => {
+                                snapped
+                            }
+                            Closest::Indeterminate => continue,
+                        };
+                        if snapped.euclidean_distance(&pt) > max_degrees {
+                            break;
+                        }
+                        if snapped.haversine_distance(&pt) > config.max_distance_meters {
+                            continue;
+                        }
+                        // Counts along a road mustn't spill onto cross streets near the ends of
+                        // each segment. Points have no direction to check.
+                        if let Some(count_bearing) = count_bearing {
+                            if bearing_difference(count_bearing, bearing(line))
+                                > MAX_BEARING_DIFFERENCE
+                            {
+                                continue;
+                            }
+                        }
+                        let edge_aadt = aadt_per_edge.entry(segment.data).or_insert(0.0);
+                        *edge_aadt = edge_aadt.max(aadt);
+                        break;
+                    }
+                }
+            }
+            timer.stop();
+        }
+
+        for edge in self.edges.values_mut() {
+            edge.aadt = None;
+        }
+        for (key, aadt) in &aadt_per_edge {
+            let edge = self.edges.get_mut(key).unwrap();
+            edge.aadt = Some(*aadt);
+            if edge.lts == LTS::NotAllowed {
+                continue;
+            }
+            if let Some(thresholds) = config.lts_thresholds {
+                for (threshold, lts) in
+                    thresholds
+                        .into_iter()
+                        .zip([LTS::LTS2, LTS::LTS3, LTS::LTS4])
+                {
+                    if *aadt >= threshold && edge.lts < lts {
+                        edge.lts = lts;
+                    }
+                }
+            }
+        }
+        Ok(aadt_per_edge.len())
+    }
+}
+
+/// The points of a line, plus more in between so they're at most `SAMPLE_METERS` apart, with the
+/// bearing of the line at each one. A single point has no bearing.
+fn sample_points(line: &LineString<f64>) -> Vec<(Point<f64>, Option<f64>)> {
+    let mut points: Vec<(Point<f64>, Option<f64>)> = line
+        .points()
+        .take(1)
+        .map(|pt| (pt, line.lines().next().map(bearing)))
+        .collect();
+    for segment in line.lines() {
+        let length = LineString::from(vec![segment.start, segment.end]).haversine_length();
+        let steps = (length / SAMPLE_METERS).ceil().max(1.0) as usize;
+        for step in 1..=steps {
+            let pct = step as f64 / steps as f64;
+            points.push((
+                Point::from(segment.start + segment.delta() * pct),
+                Some(bearing(segment)),
+            ));
+        }
+    }
+    points
+}
+
+/// Degrees counterclockwise from east, correcting for longitude shrinking away from the equator
+fn bearing(line: geo::Line<f64>) -> f64 {
+    let dx = line.dx() * line.start.y.to_radians().cos();
+    line.dy().atan2(dx).to_degrees()
+}
+
+/// Between 0 and 90 degrees, ignoring which way the lines point
+fn bearing_difference(b1: f64, b2: f64) -> f64 {
+    let diff = (b1 - b2).abs() % 180.0;
+    diff.min(180.0 - diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config_with;
+    use crate::network::tests::make_network;
+
+    #[test]
+    fn test_match_traffic_counts() {
+        // A main road from 1 to 3, and a side street from 2 to 4
+        let mut network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.001, 0.001),
+            ],
+            &[(1, 2), (2, 3), (2, 4)],
+        );
+        let path = std::env::temp_dir().join("od2net_test_traffic_counts.geojson");
+        fs_err::write(
+            &path,
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {"aadt": 12000}, "geometry": {"type": "LineString", "coordinates": [[0.0, 0.00005], [0.00099, 0.00005]]}},
+                {"type": "Feature", "properties": {"aadt": 3000}, "geometry": {"type": "Point", "coordinates": [0.00105, 0.0008]}},
+                {"type": "Feature", "properties": {"aadt": 50000}, "geometry": {"type": "Point", "coordinates": [0.01, 0.01]}}
+            ]}"#,
+        )
+        .unwrap();
+        let config = config_with(
+            r#", "traffic_counts": { "path": "counts.geojson", "lts_thresholds": [1000, 5000, 10000] }"#,
+        );
+        let matched = network
+            .match_traffic_counts(
+                path.to_str().unwrap(),
+                config.traffic_counts.as_ref().unwrap(),
+                &mut Timer::new(),
+            )
+            .unwrap();
+        fs_err::remove_file(&path).unwrap();
+
+        assert_eq!(matched, 2);
+        let edge = |n1, n2| &network.edges[&(NodeID(n1), NodeID(n2))];
+        assert_eq!(edge(1, 2).aadt, Some(12000.0));
+        assert_eq!(edge(1, 2).lts, LTS::LTS4);
+        // The end of the main road's count is closer to the side street, but points the wrong way
+        assert_eq!(edge(2, 4).aadt, Some(3000.0));
+        assert_eq!(edge(2, 4).lts, LTS::LTS2);
+        // The far away count doesn't match anything
+        assert_eq!(edge(2, 3).aadt, None);
+        assert_eq!(edge(2, 3).lts, LTS::LTS1);
+    }
+}
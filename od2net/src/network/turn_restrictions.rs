@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use osm_reader::{NodeID, OsmID, WayID};
+use serde::{Deserialize, Serialize};
+
+use lts::Tags;
+
+/// A `type=restriction` relation with a via node. Restrictions with via ways aren't handled.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TurnRestriction {
+    pub from: WayID,
+    pub via: NodeID,
+    pub to: WayID,
+    /// `only_*` restrictions allow only this turn. Otherwise, this turn isn't allowed.
+    pub only: bool,
+}
+
+impl TurnRestriction {
+    /// Returns None if the relation isn't a turn restriction applying to cyclists, or has a via
+    /// way
+    pub fn from_relation(tags: &Tags, members: &[(String, OsmID)]) -> Option<Self> {
+        if !tags.is("type", "restriction") {
+            return None;
+        }
+        if tags
+            .get("except")
+            .is_some_and(|except| except.split(';').any(|x| x == "bicycle"))
+        {
+            return None;
+        }
+        let restriction = tags
+            .get("restriction:bicycle")
+            .or_else(|| tags.get("restriction"))?;
+        let only = if restriction.starts_with("only_") {
+            true
+        } else if restriction.starts_with("no_") {
+            false
+        } else {
+            return None;
+        };
+
+        let mut from = None;
+        let mut via = None;
+        let mut to = None;
+        for (role, member) in members {
+            match (role.as_str(), member) {
+                ("from", OsmID::Way(way)) => from = Some(*way),
+                ("via", OsmID::Node(node)) => via = Some(*node),
+                ("to", OsmID::Way(way)) => to = Some(*way),
+                _ => {}
+            }
+        }
+        Some(Self {
+            from: from?,
+            via: via?,
+            to: to?,
+            only,
+        })
+    }
+}
+
+/// Turn restrictions grouped by their via node
+pub struct TurnRestrictions<'a> {
+    per_node: HashMap<NodeID, Vec<&'a TurnRestriction>>,
+}
+
+impl<'a> TurnRestrictions<'a> {
+    pub fn new(restrictions: &'a [TurnRestriction]) -> Self {
+        let mut per_node: HashMap<NodeID, Vec<&TurnRestriction>> = HashMap::new();
+        for restriction in restrictions {
+            per_node
+                .entry(restriction.via)
+                .or_default()
+                .push(restriction);
+        }
+        Self { per_node }
+    }
+
+    pub fn is_restricted(&self, node: NodeID) -> bool {
+        self.per_node.contains_key(&node)
+    }
+
+    /// Can a route arriving at `via` on way `from` leave on way `to`?
+    pub fn allows(&self, from: WayID, via: NodeID, to: WayID) -> bool {
+        let Some(restrictions) = self.per_node.get(&via) else {
+            return true;
+        };
+        restrictions.iter().filter(|r| r.from == from).all(|r| {
+            if r.only {
+                r.to == to
+            } else {
+                r.to != to
+            }
+        })
+    }
+}
@@ -0,0 +1,113 @@
+use lts::{Tags, LTS};
+
+/// Walking LTS, given the cycling LTS as a measure of motor traffic. Paths away from traffic and
+/// roads with sidewalks are LTS 1; other roads keep their cycling LTS.
+pub fn walking_lts(tags: &Tags, cycling_lts: LTS) -> LTS {
+    if !is_walking_allowed(tags) {
+        return LTS::NotAllowed;
+    }
+    if tags.is_any(
+        "highway",
+        vec![
+            "footway",
+            "path",
+            "pedestrian",
+            "steps",
+            "living_street",
+            "track",
+            "bridleway",
+            "cycleway",
+        ],
+    ) {
+        return LTS::LTS1;
+    }
+    if tags.is_any("sidewalk", vec!["both", "left", "right", "yes", "separate"])
+        || tags.is_any("sidewalk:both", vec!["yes", "separate"])
+        || tags.is_any("sidewalk:left", vec!["yes", "separate"])
+        || tags.is_any("sidewalk:right", vec!["yes", "separate"])
+    {
+        return LTS::LTS1;
+    }
+    // Roads cyclists can't use, like some trunk roads, can still be walked along
+    if cycling_lts == LTS::NotAllowed {
+        LTS::LTS4
+    } else {
+        cycling_lts
+    }
+}
+
+fn is_walking_allowed(tags: &Tags) -> bool {
+    if tags.is_any("foot", vec!["yes", "designated", "permissive"]) {
+        return true;
+    }
+    if tags.is_any("foot", vec!["no", "private", "use_sidepath"]) {
+        return false;
+    }
+    if tags.is_any("access", vec!["no", "private"]) {
+        return false;
+    }
+    !tags.is_any(
+        "highway",
+        vec![
+            "motorway",
+            "motorway_link",
+            "proposed",
+            "construction",
+            "raceway",
+            "bus_guideway",
+        ],
+    )
+}
+
+/// Tobler's hiking function, as a factor to multiply cost by. Walking is fastest going slightly
+/// downhill, at a 5% grade.
+pub fn calculate_walking_slope_factor(slope: f64) -> f64 {
+    (3.5 * ((slope / 100.0 + 0.05).abs() - 0.05)).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::tags;
+
+    #[test]
+    fn test_walking_lts() {
+        let lts = |kv: &[(&str, &str)], cycling_lts| walking_lts(&tags(kv), cycling_lts);
+
+        // Sidewalks can't be cycled on, but can be walked on
+        assert_eq!(
+            lts(
+                &[("highway", "footway"), ("footway", "sidewalk")],
+                LTS::NotAllowed
+            ),
+            LTS::LTS1
+        );
+        assert_eq!(
+            lts(
+                &[("highway", "footway"), ("footway", "crossing")],
+                LTS::LTS1
+            ),
+            LTS::LTS1
+        );
+        assert_eq!(
+            lts(&[("highway", "primary"), ("sidewalk", "both")], LTS::LTS4),
+            LTS::LTS1
+        );
+        assert_eq!(lts(&[("highway", "primary")], LTS::LTS4), LTS::LTS4);
+        assert_eq!(
+            lts(&[("highway", "primary"), ("foot", "no")], LTS::LTS4),
+            LTS::NotAllowed
+        );
+        assert_eq!(
+            lts(&[("highway", "motorway")], LTS::NotAllowed),
+            LTS::NotAllowed
+        );
+    }
+
+    #[test]
+    fn test_walking_slope_factor() {
+        assert_eq!(calculate_walking_slope_factor(0.0), 1.0);
+        assert!(calculate_walking_slope_factor(-5.0) < 1.0);
+        assert!(calculate_walking_slope_factor(10.0) > calculate_walking_slope_factor(-10.0));
+    }
+}
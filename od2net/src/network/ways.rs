@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::io::BufWriter;
+
+use anyhow::Result;
+use fs_err::File;
+use geojson::{feature::Id, Feature, FeatureWriter, Geometry, JsonObject, JsonValue, Value};
+use lts::LTS;
+
+use super::{Counts, Edge, Network};
+
+/// Totals for one OSM way
+struct WayTotals<'a> {
+    edges: Vec<&'a Edge>,
+    length_meters: f64,
+    /// The sum of each edge's count times its length
+    count_meters: f64,
+    max_count: f64,
+    /// The highest LTS of any edge, since a way's edges can differ
+    lts: LTS,
+}
+
+impl Network {
+    /// Writes one MultiLineString per OSM way with any counts, covering all of its edges. This is
+    /// much smaller than the per-edge output, and matches how people usually refer to streets.
+    pub fn write_ways(&self, path: &str, counts: &Counts, output_osm_tags: bool) -> Result<()> {
+        let mut writer = FeatureWriter::from_writer(BufWriter::new(File::create(path)?));
+        for (way, totals) in self.aggregate_ways(counts) {
+            let geometry = Geometry::new(Value::MultiLineString(
+                totals
+                    .edges
+                    .iter()
+                    .map(|edge| edge.geometry.iter().map(|pt| pt.to_degrees_vec()).collect())
+                    .collect(),
+            ));
+            let mut feature = Feature {
+                bbox: None,
+                geometry: Some(geometry),
+                id: Some(Id::Number(way.into())),
+                properties: None,
+                foreign_members: None,
+            };
+            if output_osm_tags {
+                // Edges of the same way can have different tags, like from barriers split out of
+                // it, so only keep the ones they all share
+                let mut tags = JsonObject::new();
+                for (key, value) in totals.edges[0].tags.inner() {
+                    if totals
+                        .edges
+                        .iter()
+                        .all(|edge| edge.tags.inner().get(key) == Some(value))
+                    {
+                        tags.insert(key.to_string(), JsonValue::from(value.to_string()));
+                    }
+                }
+                feature.set_property("osm_tags", tags);
+            }
+            feature.set_property("way", way);
+            feature.set_property("length", totals.length_meters);
+            // The length-weighted average count
+            feature.set_property("count", totals.count_meters / totals.length_meters);
+            feature.set_property("max_count", totals.max_count);
+            feature.set_property("lts", serde_json::to_value(totals.lts).unwrap());
+            writer.write_feature(&feature)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Only includes ways with at least one counted edge, sorted by way ID
+    fn aggregate_ways(&self, counts: &Counts) -> BTreeMap<i64, WayTotals> {
+        let mut ways: BTreeMap<i64, WayTotals> = BTreeMap::new();
+        for ((node1, node2), edge) in &self.edges {
+            if edge.lts == LTS::NotAllowed {
+                continue;
+            }
+            let count = counts.count_per_edge.get(&(*node1, *node2)).unwrap_or(&0.0)
+                + counts.count_per_edge.get(&(*node2, *node1)).unwrap_or(&0.0);
+            let totals = ways.entry(edge.way_id.0).or_insert_with(|| WayTotals {
+                edges: Vec::new(),
+                length_meters: 0.0,
+                count_meters: 0.0,
+                max_count: 0.0,
+                lts: edge.lts,
+            });
+            totals.edges.push(edge);
+            totals.length_meters += edge.length_meters;
+            totals.count_meters += count * edge.length_meters;
+            totals.max_count = totals.max_count.max(count);
+            if edge.lts > totals.lts {
+                totals.lts = edge.lts;
+            }
+        }
+        ways.retain(|_, totals| totals.max_count > 0.0);
+        // Order the parts of each way deterministically
+        for totals in ways.values_mut() {
+            totals.edges.sort_by(|a, b| {
+                a.geometry[0]
+                    .to_degrees()
+                    .partial_cmp(&b.geometry[0].to_degrees())
+                    .unwrap()
+            });
+        }
+        ways
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::make_network;
+    use osm_reader::{NodeID, WayID};
+
+    #[test]
+    fn test_aggregate_ways() {
+        let mut network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.003, 0.0),
+                (4, 0.0, 0.001),
+            ],
+            &[(1, 2), (2, 3), (1, 4)],
+        );
+        // The first two edges are part of one way
+        for key in [(NodeID(1), NodeID(2)), (NodeID(2), NodeID(3))] {
+            network.edges.get_mut(&key).unwrap().way_id = WayID(100);
+        }
+        network.edges.get_mut(&(NodeID(2), NodeID(3))).unwrap().lts = LTS::LTS3;
+        network
+            .edges
+            .get_mut(&(NodeID(1), NodeID(4)))
+            .unwrap()
+            .way_id = WayID(200);
+
+        let mut counts = Counts::new();
+        counts.count_per_edge.insert((NodeID(1), NodeID(2)), 3.0);
+        counts.count_per_edge.insert((NodeID(3), NodeID(2)), 1.0);
+
+        let ways = network.aggregate_ways(&counts);
+        // The way without counts is left out
+        assert_eq!(ways.keys().collect::<Vec<_>>(), vec![&100]);
+        let way = &ways[&100];
+        assert_eq!(way.edges.len(), 2);
+        assert_eq!(way.max_count, 3.0);
+        // The worst part of the way
+        assert_eq!(way.lts, LTS::LTS3);
+        // The second edge is twice as long, so the average is (3 + 2 * 1) / 3
+        let count = way.count_meters / way.length_meters;
+        assert!((count - 5.0 / 3.0).abs() < 1e-6);
+    }
+}
@@ -34,6 +34,13 @@ impl<T: Copy + Ord + Debug + Serialize> NodeMap<T> {
         id
     }
 
+    /// Adds another NodeId that translates to an existing node. `get` still returns the first.
+    pub fn add_copy(&mut self, node: T) -> NodeId {
+        let id = self.id_to_node.len();
+        self.id_to_node.push(node);
+        id
+    }
+
     pub fn get(&self, node: T) -> NodeId {
         if let Some(id) = self.node_to_id.get(&node) {
             *id
@@ -64,7 +71,8 @@ pub fn deserialize_nodemap<
     let id_to_node = inner.id_to_node;
     let mut node_to_id = BTreeMap::new();
     for (id, node) in id_to_node.iter().enumerate() {
-        node_to_id.insert(*node, id);
+        // Copies come after the original
+        node_to_id.entry(*node).or_insert(id);
     }
 
     Ok(NodeMap {
@@ -1,19 +1,25 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::BufReader;
 
 use anyhow::Result;
 use fs_err::File;
-use geo::{BoundingRect, Centroid, Contains, MultiPolygon};
+use geo::{
+    BoundingRect, Centroid, ChamberlainDuquetteArea, Contains, HaversineDistance, MultiPolygon,
+    Point,
+};
 use geojson::{FeatureReader, Value};
 use indicatif::HumanCount;
 use nanorand::{Rng, WyRand};
+use rstar::primitives::GeomWithData;
 use rstar::{RTree, AABB};
 use serde::Deserialize;
 
-use super::config::{ODPattern, Requests};
+use super::config::{Intrazonal, ODPattern, OdCsvFormat, Requests};
 use super::network::Network;
+use super::od_models::{self, ZonePopulation, ZoneTotals};
 use super::requests::Request;
 use super::timer::Timer;
+use super::utils::resolve_path;
 
 pub fn generate_requests(
     config: &Requests,
@@ -22,19 +28,33 @@ pub fn generate_requests(
     rng_seed: u64,
     timer: &mut Timer,
 ) -> Result<Vec<Request>> {
-    let origins = if config.origins_path.is_empty() {
-        Vec::new()
+    let weight_property = config.point_weight_property.as_deref();
+    let (origins, origin_weights) = if let Some(ref raster) = config.origin_raster {
+        let path = resolve_path(&input_directory, &raster.path);
+        timer.start(format!("Loading origins from {path}"));
+        let origins =
+            load_raster_points(&path, network_bounds(network), raster.num_samples, rng_seed)?;
+        timer.stop();
+        origins
+    } else if config.origins_path.is_empty() {
+        (Vec::new(), Vec::new())
     } else {
         timer.start("Loading origins");
-        let origins = load_points(format!("{input_directory}/{}", config.origins_path))?;
+        let origins = load_points(
+            resolve_path(&input_directory, &config.origins_path),
+            weight_property,
+        )?;
         timer.stop();
         origins
     };
-    let destinations = if config.destinations_path.is_empty() {
-        Vec::new()
+    let (destinations, destination_weights) = if config.destinations_path.is_empty() {
+        (Vec::new(), Vec::new())
     } else {
         timer.start("Loading destinations");
-        let destinations = load_points(format!("{input_directory}/{}", config.destinations_path))?;
+        let destinations = load_points(
+            resolve_path(&input_directory, &config.destinations_path),
+            weight_property,
+        )?;
         timer.stop();
         destinations
     };
@@ -52,12 +72,12 @@ pub fn generate_requests(
                 HumanCount(origins.len() as u64),
             ));
             for pt in origins {
-                requests.push(Request {
-                    x1: pt.0,
-                    y1: pt.1,
-                    x2: destinations[0].0,
-                    y2: destinations[0].1,
-                });
+                requests.push(Request::new(
+                    pt.0,
+                    pt.1,
+                    destinations[0].0,
+                    destinations[0].1,
+                ));
             }
             timer.stop();
         }
@@ -71,33 +91,77 @@ pub fn generate_requests(
             ));
             for pt in origins {
                 let goto = closest.nearest_neighbor(&pt).unwrap();
-                requests.push(Request {
-                    x1: pt.0,
-                    y1: pt.1,
-                    x2: goto.0,
-                    y2: goto.1,
-                });
+                requests.push(Request::new(pt.0, pt.1, goto.0, goto.1));
+            }
+            timer.stop();
+        }
+        ODPattern::ToNearestAmenities { amenity, n } => {
+            let amenities: Vec<(f64, f64)> = network
+                .amenities
+                .iter()
+                .filter(|x| x.kind == *amenity)
+                .map(|x| x.position.to_degrees())
+                .collect();
+            if amenities.is_empty() {
+                bail!("The network doesn't have any amenity={amenity}");
+            }
+            println!(
+                "Found {} amenity={amenity}",
+                HumanCount(amenities.len() as u64)
+            );
+            timer.start("Prep rtree for amenities");
+            // A degree of longitude shrinks away from the equator. Scale longitudes by the
+            // average, so distances in the RTree are proportional to meters.
+            let mean_lat = amenities.iter().map(|pt| pt.1).sum::<f64>() / amenities.len() as f64;
+            let lon_scale = mean_lat.to_radians().cos();
+            let closest = RTree::bulk_load(
+                amenities
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, pt)| GeomWithData::new([pt.0 * lon_scale, pt.1], idx))
+                    .collect(),
+            );
+            timer.stop();
+            timer.start(format!(
+                "ToNearestAmenities for {} origins",
+                HumanCount(origins.len() as u64),
+            ));
+            for pt in origins {
+                for goto in closest
+                    .nearest_neighbor_iter(&[pt.0 * lon_scale, pt.1])
+                    .take(*n)
+                {
+                    let goto = amenities[goto.data];
+                    requests.push(Request::new(pt.0, pt.1, goto.0, goto.1));
+                }
             }
             timer.stop();
         }
         ODPattern::BetweenZones {
             zones_path,
             csv_path,
+            csv_format,
             origin_zone_centroid_fallback,
             destination_zone_centroid_fallback,
         } => {
-            let zones_path = format!("{input_directory}/{zones_path}");
-            let csv_path = format!("{input_directory}/{csv_path}");
+            let zones_path = resolve_path(&input_directory, zones_path);
+            let csv_path = resolve_path(&input_directory, csv_path);
 
             timer.start(format!("Loading zones from {zones_path}"));
             let zones = load_zones(&zones_path)?;
             timer.stop();
             timer.start("Matching points to zones");
-            let origins_per_zone =
-                points_per_polygon("origin", origins, &zones, *origin_zone_centroid_fallback)?;
-            let destinations_per_zone = points_per_polygon(
+            let (origins_per_zone, origin_centroids) = points_per_polygon(
+                "origin",
+                origins,
+                &origin_weights,
+                &zones,
+                *origin_zone_centroid_fallback,
+            )?;
+            let (destinations_per_zone, destination_centroids) = points_per_polygon(
                 "destination",
                 destinations,
+                &destination_weights,
                 &zones,
                 *destination_zone_centroid_fallback,
             )?;
@@ -105,30 +169,146 @@ pub fn generate_requests(
 
             timer.start(format!("Generating requests from {csv_path}"));
             let mut rng = WyRand::new_seed(rng_seed);
-
-            for rec in csv::Reader::from_reader(File::open(csv_path)?).deserialize() {
-                let row: BetweenZonesRow = rec?;
-                for _ in 0..row.count {
-                    let from = match origins_per_zone.get(&row.from) {
-                        Some(points) => points[rng.generate_range(0..points.len())],
-                        None => {
-                            bail!("Unknown zone {}", row.from);
-                        }
-                    };
-                    let to = match destinations_per_zone.get(&row.to) {
-                        Some(points) => points[rng.generate_range(0..points.len())],
-                        None => {
-                            bail!("Unknown zone {}", row.to);
-                        }
-                    };
-                    requests.push(Request {
-                        x1: from.0,
-                        y1: from.1,
-                        x2: to.0,
-                        y2: to.1,
-                    });
-                }
+            let mut rows = match csv_format {
+                OdCsvFormat::Long => csv::Reader::from_reader(File::open(csv_path)?)
+                    .deserialize()
+                    .collect::<Result<Vec<BetweenZonesRow>, _>>()?,
+                OdCsvFormat::Wide => read_wide_csv(&csv_path)?,
+            };
+            if let Some(ref path) = config.ipf_margins_path {
+                let path = resolve_path(&input_directory, path);
+                fit_to_margins(&mut rows, &path)?;
             }
+            sample_between_zones(
+                rows,
+                &zones,
+                &origins_per_zone,
+                &origin_centroids,
+                &destinations_per_zone,
+                &destination_centroids,
+                config,
+                &mut rng,
+                &mut requests,
+            )?;
+            timer.stop();
+        }
+        ODPattern::Gravity {
+            zones_path,
+            totals_csv_path,
+            deterrence,
+            origin_zone_centroid_fallback,
+            destination_zone_centroid_fallback,
+        } => {
+            let zones_path = resolve_path(&input_directory, zones_path);
+            let totals_csv_path = resolve_path(&input_directory, totals_csv_path);
+
+            timer.start(format!("Loading zones from {zones_path}"));
+            let zones = load_zones(&zones_path)?;
+            timer.stop();
+            timer.start("Matching points to zones");
+            let (origins_per_zone, origin_centroids) = points_per_polygon(
+                "origin",
+                origins,
+                &origin_weights,
+                &zones,
+                *origin_zone_centroid_fallback,
+            )?;
+            let (destinations_per_zone, destination_centroids) = points_per_polygon(
+                "destination",
+                destinations,
+                &destination_weights,
+                &zones,
+                *destination_zone_centroid_fallback,
+            )?;
+            timer.stop();
+
+            timer.start(format!("Running a gravity model with {totals_csv_path}"));
+            let totals = load_zone_totals(&totals_csv_path, &zones)?;
+            let rows: Vec<BetweenZonesRow> = od_models::gravity(&totals, *deterrence)
+                .into_iter()
+                .map(|(from, to, count)| BetweenZonesRow {
+                    from: totals[from].name.clone(),
+                    to: totals[to].name.clone(),
+                    count,
+                    purpose: None,
+                })
+                .collect();
+            println!(
+                "Gravity model produced {} trips",
+                HumanCount(rows.iter().map(|row| row.count as u64).sum::<u64>())
+            );
+            let mut rng = WyRand::new_seed(rng_seed);
+            sample_between_zones(
+                rows,
+                &zones,
+                &origins_per_zone,
+                &origin_centroids,
+                &destinations_per_zone,
+                &destination_centroids,
+                config,
+                &mut rng,
+                &mut requests,
+            )?;
+            timer.stop();
+        }
+        ODPattern::Radiation {
+            zones_path,
+            populations_csv_path,
+            origin_zone_centroid_fallback,
+            destination_zone_centroid_fallback,
+        } => {
+            let zones_path = resolve_path(&input_directory, zones_path);
+            let populations_csv_path = resolve_path(&input_directory, populations_csv_path);
+
+            timer.start(format!("Loading zones from {zones_path}"));
+            let zones = load_zones(&zones_path)?;
+            timer.stop();
+            timer.start("Matching points to zones");
+            let (origins_per_zone, origin_centroids) = points_per_polygon(
+                "origin",
+                origins,
+                &origin_weights,
+                &zones,
+                *origin_zone_centroid_fallback,
+            )?;
+            let (destinations_per_zone, destination_centroids) = points_per_polygon(
+                "destination",
+                destinations,
+                &destination_weights,
+                &zones,
+                *destination_zone_centroid_fallback,
+            )?;
+            timer.stop();
+
+            timer.start(format!(
+                "Running a radiation model with {populations_csv_path}"
+            ));
+            let populations = load_zone_populations(&populations_csv_path, &zones)?;
+            let mut rng = WyRand::new_seed(rng_seed);
+            let rows: Vec<BetweenZonesRow> = od_models::radiation(&populations, &mut rng)
+                .into_iter()
+                .map(|(from, to, count)| BetweenZonesRow {
+                    from: populations[from].name.clone(),
+                    to: populations[to].name.clone(),
+                    count,
+                    purpose: None,
+                })
+                .collect();
+            println!(
+                "Radiation model produced {} trips",
+                HumanCount(rows.iter().map(|row| row.count as u64).sum::<u64>())
+            );
+            sample_between_zones(
+                rows,
+                &zones,
+                &origins_per_zone,
+                &origin_centroids,
+                &destinations_per_zone,
+                &destination_centroids,
+                config,
+                &mut rng,
+                &mut requests,
+            )?;
             timer.stop();
         }
         // TODO Maybe refactor these -- allow zones to be empty, O and D can have named points
@@ -138,9 +318,9 @@ pub fn generate_requests(
             destinations_path,
             origin_zone_centroid_fallback,
         } => {
-            let zones_path = format!("{input_directory}/{zones_path}");
-            let csv_path = format!("{input_directory}/{csv_path}");
-            let destinations_path = format!("{input_directory}/{destinations_path}");
+            let zones_path = resolve_path(&input_directory, zones_path);
+            let csv_path = resolve_path(&input_directory, csv_path);
+            let destinations_path = resolve_path(&input_directory, destinations_path);
 
             timer.start(format!(
                 "Loading zones from {zones_path} and named destinations from {destinations_path}"
@@ -149,8 +329,13 @@ pub fn generate_requests(
             let destinations = load_named_points(&destinations_path)?;
             timer.stop();
             timer.start("Matching points to zones");
-            let origins_per_zone =
-                points_per_polygon("origin", origins, &zones, *origin_zone_centroid_fallback)?;
+            let (origins_per_zone, origin_centroids) = points_per_polygon(
+                "origin",
+                origins,
+                &origin_weights,
+                &zones,
+                *origin_zone_centroid_fallback,
+            )?;
             timer.stop();
 
             timer.start(format!("Generating requests from {csv_path}"));
@@ -158,13 +343,20 @@ pub fn generate_requests(
 
             for rec in csv::Reader::from_reader(File::open(csv_path)?).deserialize() {
                 let row: BetweenZonesRow = rec?;
-                for _ in 0..row.count {
+                let (num_pairs, weight) = split_flow(row.count, config.max_per_od);
+                for _ in 0..num_pairs {
                     let from = match origins_per_zone.get(&row.from) {
-                        Some(points) => points[rng.generate_range(0..points.len())],
+                        Some(points) => points.sample(&mut rng),
                         None => {
                             bail!("Unknown zone {}", row.from);
                         }
                     };
+                    let from = jitter_centroid(
+                        from,
+                        origin_centroids.contains(&row.from),
+                        config.centroid_jitter_meters,
+                        &mut rng,
+                    );
                     let to = match destinations.get(&row.to) {
                         Some(pt) => *pt,
                         None => {
@@ -176,6 +368,8 @@ pub fn generate_requests(
                         y1: from.1,
                         x2: to.0,
                         y2: to.1,
+                        purpose: row.purpose.clone(),
+                        weight,
                     });
                 }
             }
@@ -183,7 +377,12 @@ pub fn generate_requests(
         }
         ODPattern::LineStrings(path) => {
             timer.start(format!("Loading LineString requests from {path}"));
-            requests = Request::load_from_geojson(format!("{input_directory}/{path}"))?;
+            requests = Request::load_from_geojson(resolve_path(&input_directory, path))?;
+            timer.stop();
+        }
+        ODPattern::Csv(path) => {
+            timer.start(format!("Loading requests from {path}"));
+            requests = Request::load_from_csv(&resolve_path(&input_directory, path))?;
             timer.stop();
         }
         ODPattern::AllPairsIntersections => {
@@ -191,29 +390,153 @@ pub fn generate_requests(
                 let (x1, y1) = from.to_degrees();
                 for to in network.intersections.values() {
                     let (x2, y2) = to.to_degrees();
-                    requests.push(Request { x1, y1, x2, y2 });
+                    requests.push(Request::new(x1, y1, x2, y2));
                 }
             }
         }
     }
 
+    if config.min_od_distance_meters.is_some() || config.max_od_distance_meters.is_some() {
+        timer.start("Filtering requests by straight-line distance");
+        let removed = filter_by_distance(
+            &mut requests,
+            config.min_od_distance_meters,
+            config.max_od_distance_meters,
+        );
+        println!(
+            "Removed {} requests outside the straight-line distance limits",
+            HumanCount(removed as u64)
+        );
+        timer.stop();
+    }
+
+    timer.start("Merging identical requests");
+    let merged = deduplicate(&mut requests);
+    println!("Merged {} identical requests", HumanCount(merged as u64));
+    timer.stop();
+
     Ok(requests)
 }
 
+/// Merges requests with the same origin, destination, and purpose into the first one, summing
+/// their weights, so each unique pair is only routed once. Returns how many were merged.
+fn deduplicate(requests: &mut Vec<Request>) -> usize {
+    let before = requests.len();
+    let mut first_index: HashMap<([u64; 4], Option<String>), usize> = HashMap::new();
+    let mut unique: Vec<Request> = Vec::new();
+    for req in requests.drain(..) {
+        let key = (
+            [req.x1, req.y1, req.x2, req.y2].map(f64::to_bits),
+            req.purpose.clone(),
+        );
+        if let Some(idx) = first_index.get(&key) {
+            unique[*idx].weight += req.weight;
+        } else {
+            first_index.insert(key, unique.len());
+            unique.push(req);
+        }
+    }
+    *requests = unique;
+    before - requests.len()
+}
+
+/// Removes requests with a straight-line distance outside of the limits, returning how many were
+/// removed.
+fn filter_by_distance(requests: &mut Vec<Request>, min: Option<f64>, max: Option<f64>) -> usize {
+    let before = requests.len();
+    requests.retain(|req| {
+        let distance = req.crow_flies_distance_meters();
+        min.map(|min| distance >= min).unwrap_or(true)
+            && max.map(|max| distance <= max).unwrap_or(true)
+    });
+    before - requests.len()
+}
+
 // TODO Use geo?
-fn load_points(path: String) -> Result<Vec<(f64, f64)>> {
+/// If `weight_property` is specified, also returns the weight of each point from that numeric
+/// property. Otherwise the weights are empty.
+fn load_points(path: String, weight_property: Option<&str>) -> Result<(Vec<(f64, f64)>, Vec<f64>)> {
     println!("Loading points from {path}");
-    let reader = FeatureReader::from_reader(BufReader::new(File::open(path)?));
+    let reader = FeatureReader::from_reader(BufReader::new(File::open(&path)?));
     let mut points = Vec::new();
+    let mut weights = Vec::new();
     for feature in reader.features() {
         let feature = feature?;
-        if let Some(geometry) = feature.geometry {
-            if let Value::Point(pt) = geometry.value {
+        if let Some(ref geometry) = feature.geometry {
+            if let Value::Point(ref pt) = geometry.value {
                 points.push((pt[0], pt[1]));
+                if let Some(key) = weight_property {
+                    // Points without the property count normally
+                    let weight = feature
+                        .property(key)
+                        .map(|x| x.as_f64())
+                        .unwrap_or(Some(1.0));
+                    match weight {
+                        Some(weight) if weight >= 0.0 => weights.push(weight),
+                        _ => bail!("A point in {path} has an invalid {key}: {:?}", weight),
+                    }
+                }
             }
         }
     }
-    Ok(points)
+    Ok((points, weights))
+}
+
+/// Returns the center of every raster cell inside `window` with a positive value, weighted by that
+/// value. If `num_samples` is set, instead returns that many unweighted points, each picked in
+/// proportion to the cell values.
+fn load_raster_points(
+    path: &str,
+    window: Option<geo::Rect>,
+    num_samples: Option<usize>,
+    rng_seed: u64,
+) -> Result<(Vec<(f64, f64)>, Vec<f64>)> {
+    let mut points = Vec::new();
+    let mut weights = Vec::new();
+    crate::cost_raster::for_each_geotiff_cell(path, window, |pt, value| {
+        if value > 0.0 {
+            points.push(pt);
+            weights.push(value);
+        }
+    })?;
+    if points.is_empty() {
+        bail!("{path} doesn't have any cells with a positive value covering the network");
+    }
+    let Some(num_samples) = num_samples else {
+        return Ok((points, weights));
+    };
+
+    let mut total = 0.0;
+    let cumulative_weights = weights
+        .into_iter()
+        .map(|weight| {
+            total += weight;
+            total
+        })
+        .collect();
+    let cells = ZonePoints {
+        points,
+        cumulative_weights: Some(cumulative_weights),
+    };
+    let mut rng = WyRand::new_seed(rng_seed);
+    let points = (0..num_samples).map(|_| cells.sample(&mut rng)).collect();
+    Ok((points, Vec::new()))
+}
+
+/// The bounding box of every intersection, or None for an empty network
+fn network_bounds(network: &Network) -> Option<geo::Rect> {
+    let mut points = network
+        .intersections
+        .values()
+        .map(|pos| geo::Coord::from(pos.to_degrees()));
+    let first = points.next()?;
+    let (min, max) = points.fold((first, first), |(min, max), pt| {
+        (
+            geo::coord! { x: min.x.min(pt.x), y: min.y.min(pt.y) },
+            geo::coord! { x: max.x.max(pt.x), y: max.y.max(pt.y) },
+        )
+    });
+    Some(geo::Rect::new(min, max))
 }
 
 // TODO Refactor?
@@ -242,31 +565,78 @@ fn load_named_points(path: &str) -> Result<HashMap<String, (f64, f64)>> {
     Ok(result)
 }
 
+/// The points inside one zone
+struct ZonePoints {
+    points: Vec<(f64, f64)>,
+    /// If the points are weighted, the running total of their weights
+    cumulative_weights: Option<Vec<f64>>,
+}
+
+impl ZonePoints {
+    /// Picks a random point, in proportion to its weight if there are any
+    fn sample(&self, rng: &mut WyRand) -> (f64, f64) {
+        match self.cumulative_weights {
+            Some(ref cumulative) => {
+                let x = rng.generate::<f64>() * cumulative.last().unwrap();
+                let idx = cumulative.partition_point(|weight| *weight <= x);
+                self.points[idx.min(self.points.len() - 1)]
+            }
+            None => self.points[rng.generate_range(0..self.points.len())],
+        }
+    }
+}
+
+/// Zone name to the points inside it
+type PointsPerZone = HashMap<String, ZonePoints>;
+
+/// Also returns the zones using their centroid, because no points are inside. If `weights` isn't
+/// empty, it has the weight of each point. Zones whose points all have 0 weight are treated as
+/// empty.
 fn points_per_polygon(
     name: &str,
     points: Vec<(f64, f64)>,
+    weights: &[f64],
     polygons: &HashMap<String, MultiPolygon<f64>>,
     use_centroids_for_empty_zones: bool,
-) -> Result<HashMap<String, Vec<(f64, f64)>>> {
-    let tree = RTree::bulk_load(points);
+) -> Result<(PointsPerZone, HashSet<String>)> {
+    let tree = RTree::bulk_load(
+        points
+            .into_iter()
+            .enumerate()
+            .map(|(idx, pt)| GeomWithData::new(pt, idx))
+            .collect(),
+    );
 
     let mut empty = Vec::new();
     let mut output = HashMap::new();
     for (key, polygon) in polygons {
         let mut pts_inside = Vec::new();
+        let mut cumulative_weights = Vec::new();
+        let mut total_weight = 0.0;
         let bounds = polygon.bounding_rect().unwrap();
         let min = bounds.min();
         let max = bounds.max();
         let envelope: AABB<(f64, f64)> = AABB::from_corners((min.x, min.y), (max.x, max.y));
-        for pt in tree.locate_in_envelope(&envelope) {
+        for obj in tree.locate_in_envelope(&envelope) {
+            let pt = obj.geom();
             if polygon.contains(&geo::Point::new(pt.0, pt.1)) {
                 pts_inside.push(*pt);
+                if !weights.is_empty() {
+                    total_weight += weights[obj.data];
+                    cumulative_weights.push(total_weight);
+                }
             }
         }
-        if pts_inside.is_empty() {
+        if pts_inside.is_empty() || (!weights.is_empty() && total_weight == 0.0) {
             empty.push(key);
         }
-        output.insert(key.clone(), pts_inside);
+        output.insert(
+            key.clone(),
+            ZonePoints {
+                points: pts_inside,
+                cumulative_weights: (!weights.is_empty()).then_some(cumulative_weights),
+            },
+        );
     }
 
     if !empty.is_empty() && !use_centroids_for_empty_zones {
@@ -276,15 +646,348 @@ fn points_per_polygon(
         "{} zones have no matching {name} points. Using centroid instead.",
         HumanCount(empty.len() as u64)
     );
+    let mut centroids = HashSet::new();
     for key in empty {
         if let Some(centroid) = polygons[key].centroid() {
-            output.insert(key.clone(), vec![centroid.into()]);
+            output.insert(
+                key.clone(),
+                ZonePoints {
+                    points: vec![centroid.into()],
+                    cumulative_weights: None,
+                },
+            );
+            centroids.insert(key.clone());
         } else {
             bail!("{key} had no matching {name} points, and couldn't calculate its centroid");
         }
     }
 
-    Ok(output)
+    Ok((output, centroids))
+}
+
+/// Creates `count` requests for every row, each between random points in the `from` and `to`
+/// zones
+#[allow(clippy::too_many_arguments)]
+fn sample_between_zones(
+    rows: Vec<BetweenZonesRow>,
+    zones: &HashMap<String, MultiPolygon<f64>>,
+    origins_per_zone: &PointsPerZone,
+    origin_centroids: &HashSet<String>,
+    destinations_per_zone: &PointsPerZone,
+    destination_centroids: &HashSet<String>,
+    config: &Requests,
+    rng: &mut WyRand,
+    requests: &mut Vec<Request>,
+) -> Result<()> {
+    // With a maximum distance, skip rows between zones too far apart for any pair of points to be
+    // close enough, without sampling them. This saves lots of work for national OD data.
+    let extents: HashMap<&String, ((f64, f64), f64)> = if config.max_od_distance_meters.is_some() {
+        zones
+            .iter()
+            .filter_map(|(name, zone)| Some((name, zone_extent(zone)?)))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let jitter = config.centroid_jitter_meters.unwrap_or(0.0);
+    let mut skipped_far = 0;
+
+    for row in rows {
+        if let (Some(max), Some((center1, radius1)), Some((center2, radius2))) = (
+            config.max_od_distance_meters,
+            extents.get(&row.from),
+            extents.get(&row.to),
+        ) {
+            let distance = Point::from(*center1).haversine_distance(&Point::from(*center2));
+            if distance - radius1 - radius2 - 2.0 * jitter > max {
+                skipped_far += row.count;
+                continue;
+            }
+        }
+
+        let intrazonal = if row.from == row.to {
+            config.intrazonal
+        } else {
+            Intrazonal::SampleIndependently
+        };
+        if intrazonal == Intrazonal::Skip {
+            continue;
+        }
+        let average_distance = if intrazonal == Intrazonal::AverageDistance {
+            let Some(zone) = zones.get(&row.from) else {
+                bail!("Unknown zone {}", row.from);
+            };
+            Some(average_intrazonal_distance(zone))
+        } else {
+            None
+        };
+
+        let (num_pairs, weight) = split_flow(row.count, config.max_per_od);
+        for _ in 0..num_pairs {
+            let from = match origins_per_zone.get(&row.from) {
+                Some(points) => points.sample(rng),
+                None => {
+                    bail!("Unknown zone {}", row.from);
+                }
+            };
+            let from = jitter_centroid(
+                from,
+                origin_centroids.contains(&row.from),
+                config.centroid_jitter_meters,
+                rng,
+            );
+            let to = if let Some(distance) = average_distance {
+                let angle = 2.0 * std::f64::consts::PI * rng.generate::<f64>();
+                move_point(from, distance, angle)
+            } else {
+                let Some(points) = destinations_per_zone.get(&row.to) else {
+                    bail!("Unknown zone {}", row.to);
+                };
+                // With DistinctPoints, retry a few times. If every point in the zone is the same,
+                // skip the trip.
+                let attempts = if intrazonal == Intrazonal::DistinctPoints {
+                    100
+                } else {
+                    1
+                };
+                let mut to = None;
+                for _ in 0..attempts {
+                    let pt = jitter_centroid(
+                        points.sample(rng),
+                        destination_centroids.contains(&row.to),
+                        config.centroid_jitter_meters,
+                        rng,
+                    );
+                    if intrazonal != Intrazonal::DistinctPoints || pt != from {
+                        to = Some(pt);
+                        break;
+                    }
+                }
+                let Some(to) = to else {
+                    continue;
+                };
+                to
+            };
+            requests.push(Request {
+                x1: from.0,
+                y1: from.1,
+                x2: to.0,
+                y2: to.1,
+                purpose: row.purpose.clone(),
+                weight,
+            });
+        }
+    }
+    if skipped_far > 0 {
+        println!(
+            "Skipped {} trips between zones farther apart than max_od_distance_meters",
+            HumanCount(skipped_far as u64)
+        );
+    }
+    Ok(())
+}
+
+/// Returns the centroid of a zone, and a distance in meters that every point inside the zone is
+/// within
+fn zone_extent(zone: &MultiPolygon<f64>) -> Option<((f64, f64), f64)> {
+    let centroid = zone.centroid()?;
+    let bounds = zone.bounding_rect()?;
+    let (min, max) = (bounds.min(), bounds.max());
+    let radius = [
+        (min.x, min.y),
+        (min.x, max.y),
+        (max.x, min.y),
+        (max.x, max.y),
+    ]
+    .into_iter()
+    .map(|corner| centroid.haversine_distance(&Point::from(corner)))
+    .fold(0.0, f64::max);
+    Some((centroid.into(), radius))
+}
+
+/// Estimates the average straight-line distance between two random points in a zone, by
+/// treating it as a circle with the same area
+fn average_intrazonal_distance(zone: &MultiPolygon<f64>) -> f64 {
+    let radius = (zone.chamberlain_duquette_unsigned_area() / std::f64::consts::PI).sqrt();
+    128.0 * radius / (45.0 * std::f64::consts::PI)
+}
+
+/// Returns how many OD pairs to sample for a flow, and the weight of each, so that no pair
+/// represents more than `max_per_od` trips
+fn split_flow(count: usize, max_per_od: Option<usize>) -> (usize, f64) {
+    match max_per_od {
+        Some(max) if count > 0 => {
+            let num_pairs = count.div_ceil(max.max(1));
+            (num_pairs, count as f64 / num_pairs as f64)
+        }
+        _ => (count, 1.0),
+    }
+}
+
+/// Reads a zone-by-zone matrix, with origins as rows and destinations as columns. Cells with 0
+/// trips are skipped.
+fn read_wide_csv(path: &str) -> Result<Vec<BetweenZonesRow>> {
+    let mut reader = csv::Reader::from_reader(File::open(path)?);
+    let destinations: Vec<String> = reader
+        .headers()?
+        .iter()
+        .skip(1)
+        .map(|x| x.to_string())
+        .collect();
+    let mut rows = Vec::new();
+    for rec in reader.records() {
+        let rec = rec?;
+        let Some(from) = rec.get(0) else {
+            continue;
+        };
+        for (to, cell) in destinations.iter().zip(rec.iter().skip(1)) {
+            let cell = cell.trim();
+            if cell.is_empty() {
+                continue;
+            }
+            let Ok(count) = cell.parse::<usize>() else {
+                bail!("{path} has a non-integer count {cell} from {from} to {to}");
+            };
+            if count > 0 {
+                rows.push(BetweenZonesRow {
+                    from: from.to_string(),
+                    to: to.clone(),
+                    count,
+                    purpose: None,
+                });
+            }
+        }
+    }
+    Ok(rows)
+}
+
+/// Adjusts the counts with IPF, to match the number of trips starting and ending in each zone from
+/// a CSV file
+fn fit_to_margins(rows: &mut Vec<BetweenZonesRow>, path: &str) -> Result<()> {
+    let mut origins = HashMap::new();
+    let mut destinations = HashMap::new();
+    for rec in csv::Reader::from_reader(File::open(path)?).deserialize() {
+        let row: ZoneTotalsRow = rec?;
+        origins.insert(row.name.clone(), row.origins);
+        destinations.insert(row.name, row.destinations);
+    }
+
+    let before: usize = rows.iter().map(|row| row.count).sum();
+    let adjusted = od_models::ipf(
+        &rows
+            .iter()
+            .map(|row| (row.from.as_str(), row.to.as_str(), row.count as f64))
+            .collect::<Vec<_>>(),
+        &origins,
+        &destinations,
+    );
+
+    // Round to whole trips, keeping the total from each origin zone
+    let mut per_origin: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (idx, row) in rows.iter().enumerate() {
+        per_origin.entry(row.from.as_str()).or_default().push(idx);
+    }
+    let mut counts = vec![0; rows.len()];
+    for indices in per_origin.into_values() {
+        let weights: Vec<f64> = indices.iter().map(|idx| adjusted[*idx]).collect();
+        let total = weights.iter().sum::<f64>().round() as usize;
+        for (idx, count) in indices
+            .into_iter()
+            .zip(od_models::split_total(total, &weights))
+        {
+            counts[idx] = count;
+        }
+    }
+    for (row, count) in rows.iter_mut().zip(counts) {
+        row.count = count;
+    }
+    println!(
+        "Fitting to the margins in {path} changed the number of trips from {} to {}",
+        HumanCount(before as u64),
+        HumanCount(rows.iter().map(|row| row.count as u64).sum::<u64>())
+    );
+    Ok(())
+}
+
+/// Reads a CSV file with the number of trips starting and ending in each zone
+fn load_zone_totals(
+    path: &str,
+    zones: &HashMap<String, MultiPolygon<f64>>,
+) -> Result<Vec<ZoneTotals>> {
+    let mut totals = Vec::new();
+    for rec in csv::Reader::from_reader(File::open(path)?).deserialize() {
+        let row: ZoneTotalsRow = rec?;
+        let Some(zone) = zones.get(&row.name) else {
+            bail!("Unknown zone {} in {path}", row.name);
+        };
+        let Some(centroid) = zone.centroid() else {
+            bail!("Couldn't calculate the centroid of {}", row.name);
+        };
+        totals.push(ZoneTotals {
+            name: row.name,
+            centroid: centroid.into(),
+            origins: row.origins,
+            destinations: row.destinations,
+        });
+    }
+    Ok(totals)
+}
+
+/// Reads a CSV file with the population of each zone and the number of trips starting there
+fn load_zone_populations(
+    path: &str,
+    zones: &HashMap<String, MultiPolygon<f64>>,
+) -> Result<Vec<ZonePopulation>> {
+    let mut populations = Vec::new();
+    for rec in csv::Reader::from_reader(File::open(path)?).deserialize() {
+        let row: ZonePopulationRow = rec?;
+        let Some(zone) = zones.get(&row.name) else {
+            bail!("Unknown zone {} in {path}", row.name);
+        };
+        let Some(centroid) = zone.centroid() else {
+            bail!("Couldn't calculate the centroid of {}", row.name);
+        };
+        populations.push(ZonePopulation {
+            name: row.name,
+            centroid: centroid.into(),
+            population: row.population,
+            origins: row.origins,
+        });
+    }
+    Ok(populations)
+}
+
+/// If the point is a zone centroid and jittering is enabled, moves it by a random offset
+fn jitter_centroid(
+    pt: (f64, f64),
+    is_centroid: bool,
+    radius_meters: Option<f64>,
+    rng: &mut WyRand,
+) -> (f64, f64) {
+    match radius_meters {
+        Some(radius_meters) if is_centroid => jitter(pt, radius_meters, rng),
+        _ => pt,
+    }
+}
+
+/// Moves a point in a random direction, up to `radius_meters` away. Points are spread evenly over
+/// the circle.
+fn jitter(pt: (f64, f64), radius_meters: f64, rng: &mut WyRand) -> (f64, f64) {
+    let distance = radius_meters * rng.generate::<f64>().sqrt();
+    let angle = 2.0 * std::f64::consts::PI * rng.generate::<f64>();
+    move_point(pt, distance, angle)
+}
+
+/// Moves a point `distance` meters in the direction of `angle`, in radians counter-clockwise from
+/// east
+fn move_point(pt: (f64, f64), distance: f64, angle: f64) -> (f64, f64) {
+    // Approximate meters per degree, which is fine over short distances
+    let meters_per_degree_lat = 111_320.0;
+    let meters_per_degree_lon = meters_per_degree_lat * pt.1.to_radians().cos();
+    (
+        pt.0 + distance * angle.cos() / meters_per_degree_lon,
+        pt.1 + distance * angle.sin() / meters_per_degree_lat,
+    )
 }
 
 // TODO Can we use this?
@@ -295,9 +998,33 @@ struct Zone {
     name: String,
 }*/
 
+/// Combines every Polygon and MultiPolygon in a GeoJSON file into one area
+pub fn load_area(geojson_path: &str) -> Result<MultiPolygon<f64>> {
+    let reader = FeatureReader::from_reader(BufReader::new(File::open(geojson_path)?));
+
+    let mut polygons = Vec::new();
+    for feature in reader.features() {
+        let Some(gj_geom) = feature?.geometry else {
+            continue;
+        };
+        let geo_geometry: geo::Geometry<f64> = gj_geom.try_into()?;
+        match geo_geometry {
+            geo::Geometry::MultiPolygon(mp) => polygons.extend(mp),
+            geo::Geometry::Polygon(p) => polygons.push(p),
+            _ => bail!(
+                "A feature in {geojson_path} has geometry other than a Polygon or MultiPolygon"
+            ),
+        }
+    }
+    if polygons.is_empty() {
+        bail!("{geojson_path} doesn't contain any polygons");
+    }
+    Ok(MultiPolygon(polygons))
+}
+
 /// Extract multipolygon zones from a GeoJSON file, using the "name" property as the key in the
 /// resulting map.
-fn load_zones(geojson_path: &str) -> Result<HashMap<String, MultiPolygon<f64>>> {
+pub(crate) fn load_zones(geojson_path: &str) -> Result<HashMap<String, MultiPolygon<f64>>> {
     let reader = FeatureReader::from_reader(BufReader::new(File::open(geojson_path)?));
 
     let mut zones: HashMap<String, MultiPolygon<f64>> = HashMap::new();
@@ -334,4 +1061,275 @@ struct BetweenZonesRow {
     from: String,
     to: String,
     count: usize,
+    /// An optional column with the trip purpose, like `commute`
+    #[serde(default)]
+    purpose: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ZoneTotalsRow {
+    name: String,
+    origins: f64,
+    destinations: f64,
+}
+
+#[derive(Deserialize)]
+struct ZonePopulationRow {
+    name: String,
+    population: f64,
+    origins: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_by_distance() {
+        // Roughly 111m per 0.001 degrees of latitude
+        let mut requests: Vec<Request> = [0.0001, 0.001, 0.01, 0.1]
+            .into_iter()
+            .map(|dy| Request::new(0.0, 0.0, 0.0, dy))
+            .collect();
+        let removed = filter_by_distance(&mut requests, Some(100.0), Some(5000.0));
+        assert_eq!(removed, 2);
+        assert_eq!(requests.len(), 2);
+        for req in &requests {
+            let distance = req.crow_flies_distance_meters();
+            assert!((100.0..=5000.0).contains(&distance));
+        }
+    }
+
+    #[test]
+    fn test_to_nearest_amenities() {
+        let path = std::env::temp_dir().join("od2net_test_amenity_origins.geojson");
+        fs_err::write(
+            &path,
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {}, "geometry": {"type": "Point", "coordinates": [0.0, 0.0]}}
+            ]}"#,
+        )
+        .unwrap();
+        let mut config = crate::config::tests::config_with("").requests;
+        config.pattern = ODPattern::ToNearestAmenities {
+            amenity: "school".to_string(),
+            n: 2,
+        };
+        config.origins_path = path.display().to_string();
+        config.destinations_path = String::new();
+
+        let mut network =
+            crate::network::tests::make_network(&[(1, 0.0, 0.0), (2, 0.01, 0.0)], &[(1, 2)]);
+        for (lon, kind) in [
+            (0.001, "cafe"),
+            (0.004, "school"),
+            (0.002, "school"),
+            (0.003, "school"),
+        ] {
+            network.amenities.push(crate::network::Amenity {
+                position: crate::network::Position::from_degrees(lon, 0.0),
+                kind: kind.to_string(),
+            });
+        }
+
+        let requests =
+            generate_requests(&config, String::new(), &network, 42, &mut Timer::new()).unwrap();
+        let destinations: Vec<i64> = requests
+            .iter()
+            .map(|req| (req.x2 * 1000.0).round() as i64)
+            .collect();
+        assert_eq!(destinations, vec![2, 3]);
+
+        // At 60 degrees north, a degree of longitude is half as long as one of latitude, so the
+        // school to the east is closer, even though it's further away in degrees
+        fs_err::write(
+            &path,
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {}, "geometry": {"type": "Point", "coordinates": [0.0, 60.0]}}
+            ]}"#,
+        )
+        .unwrap();
+        config.pattern = ODPattern::ToNearestAmenities {
+            amenity: "school".to_string(),
+            n: 1,
+        };
+        network.amenities.clear();
+        for (lon, lat) in [(0.0, 60.001), (0.0015, 60.0)] {
+            network.amenities.push(crate::network::Amenity {
+                position: crate::network::Position::from_degrees(lon, lat),
+                kind: "school".to_string(),
+            });
+        }
+        let requests =
+            generate_requests(&config, String::new(), &network, 42, &mut Timer::new()).unwrap();
+        fs_err::remove_file(&path).unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!((requests[0].x2 - 0.0015).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_deduplicate() {
+        let req = |x2: f64, purpose: Option<&str>, weight| Request {
+            x1: 0.0,
+            y1: 0.0,
+            x2,
+            y2: 0.0,
+            purpose: purpose.map(|x| x.to_string()),
+            weight,
+        };
+        let mut requests = vec![
+            req(1.0, None, 1.0),
+            req(2.0, None, 1.0),
+            req(1.0, None, 2.5),
+            req(1.0, Some("school"), 1.0),
+            req(2.0, None, 1.0),
+        ];
+        assert_eq!(deduplicate(&mut requests), 2);
+        let summary: Vec<(f64, Option<&str>, f64)> = requests
+            .iter()
+            .map(|req| (req.x2, req.purpose.as_deref(), req.weight))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1.0, None, 3.5),
+                (2.0, None, 2.0),
+                (1.0, Some("school"), 1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_flow() {
+        assert_eq!(split_flow(200, None), (200, 1.0));
+        assert_eq!(split_flow(200, Some(5)), (40, 5.0));
+        assert_eq!(split_flow(7, Some(5)), (2, 3.5));
+        assert_eq!(split_flow(3, Some(5)), (1, 3.0));
+        assert_eq!(split_flow(0, Some(5)), (0, 1.0));
+    }
+
+    #[test]
+    fn test_zone_extent() {
+        let zone: MultiPolygon<f64> = geo::Rect::new((0.0, 0.0), (0.01, 0.01)).to_polygon().into();
+        let (center, radius) = zone_extent(&zone).unwrap();
+        assert!((center.0 - 0.005).abs() < 1e-9 && (center.1 - 0.005).abs() < 1e-9);
+        // Half the diagonal of a roughly 1.1km square
+        assert!((780.0..800.0).contains(&radius), "{radius}");
+    }
+
+    #[test]
+    fn test_average_intrazonal_distance() {
+        // Roughly a 1km square at the equator. The mean distance in a circle with the same area
+        // is about 511m.
+        let zone: MultiPolygon<f64> = geo::Rect::new((0.0, 0.0), (0.009, 0.009))
+            .to_polygon()
+            .into();
+        let distance = average_intrazonal_distance(&zone);
+        assert!((490.0..530.0).contains(&distance), "{distance}");
+    }
+
+    #[test]
+    fn test_read_wide_csv() {
+        let path = std::env::temp_dir().join("od2net_test_wide_od.csv");
+        fs_err::write(&path, "origin,A,B\nA,0,3\nB,2,\n").unwrap();
+        let rows = read_wide_csv(&path.display().to_string()).unwrap();
+        let rows: Vec<(&str, &str, usize)> = rows
+            .iter()
+            .map(|row| (row.from.as_str(), row.to.as_str(), row.count))
+            .collect();
+        assert_eq!(rows, vec![("A", "B", 3), ("B", "A", 2)]);
+    }
+
+    #[test]
+    fn test_weighted_points() {
+        let path = std::env::temp_dir().join(format!(
+            "od2net_test_weighted_points_{}.geojson",
+            std::process::id()
+        ));
+        let point = |x: f64, properties: &str| {
+            format!(
+                r#"{{"type": "Feature", "properties": {{{properties}}}, "geometry": {{"type": "Point", "coordinates": [{x}, {x}]}}}}"#
+            )
+        };
+        let write = |features: Vec<String>| {
+            fs_err::write(
+                &path,
+                format!(
+                    r#"{{"type": "FeatureCollection", "features": [{}]}}"#,
+                    features.join(", ")
+                ),
+            )
+            .unwrap();
+        };
+        let load = || load_points(path.display().to_string(), Some("dwellings"));
+
+        // Three points in zone A, with one missing the property, and one point with no weight in B
+        write(vec![
+            point(0.001, r#""dwellings": 8"#),
+            point(0.002, r#""dwellings": 1"#),
+            point(0.003, ""),
+            point(0.025, r#""dwellings": 0"#),
+        ]);
+        let (points, weights) = load().unwrap();
+        assert_eq!(weights, vec![8.0, 1.0, 1.0, 0.0]);
+
+        let zones: HashMap<String, MultiPolygon<f64>> = [("A", 0.0), ("B", 0.02)]
+            .into_iter()
+            .map(|(name, min)| {
+                let zone = geo::Rect::new((min, min), (min + 0.01, min + 0.01)).to_polygon();
+                (name.to_string(), zone.into())
+            })
+            .collect();
+        let (per_zone, centroids) =
+            points_per_polygon("origin", points, &weights, &zones, true).unwrap();
+        // B only has a point with no weight, so its centroid is used
+        assert_eq!(centroids, HashSet::from(["B".to_string()]));
+
+        let mut rng = WyRand::new_seed(42);
+        let heaviest = (0..1000)
+            .filter(|_| per_zone["A"].sample(&mut rng) == (0.001, 0.001))
+            .count();
+        assert!((700..900).contains(&heaviest), "{heaviest}");
+
+        write(vec![point(0.001, r#""dwellings": -1"#)]);
+        assert!(load().is_err());
+        fs_err::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_jitter() {
+        let centroid = (-0.1, 51.5);
+        let jittered = |seed| {
+            let mut rng = WyRand::new_seed(seed);
+            (0..100)
+                .map(|_| jitter_centroid(centroid, true, Some(200.0), &mut rng))
+                .collect::<Vec<_>>()
+        };
+
+        let points = jittered(42);
+        for pt in &points {
+            let distance =
+                Request::new(centroid.0, centroid.1, pt.0, pt.1).crow_flies_distance_meters();
+            assert!(distance <= 201.0, "{distance} is too far");
+        }
+        // Spread out, not all on top of each other or stuck near the center
+        let far = points
+            .iter()
+            .filter(|pt| {
+                Request::new(centroid.0, centroid.1, pt.0, pt.1).crow_flies_distance_meters()
+                    > 100.0
+            })
+            .count();
+        assert!(far > 50);
+
+        assert_eq!(points, jittered(42));
+        assert_ne!(points, jittered(43));
+
+        // Points that aren't centroids stay put
+        let mut rng = WyRand::new_seed(42);
+        assert_eq!(
+            jitter_centroid(centroid, false, Some(200.0), &mut rng),
+            centroid
+        );
+    }
 }
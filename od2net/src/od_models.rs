@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+
+use geo::{HaversineDistance, Point};
+use nanorand::{Rng, WyRand};
+
+use super::config::Deterrence;
+
+/// The number of trips starting and ending in one zone
+pub struct ZoneTotals {
+    pub name: String,
+    pub centroid: (f64, f64),
+    pub origins: f64,
+    pub destinations: f64,
+}
+
+/// A production-constrained gravity model. Every zone's origins are split between the other
+/// zones, in proportion to their destinations multiplied by the deterrence of the distance
+/// between centroids. Trips within a zone aren't created. Returns `(from, to, count)`, indexing
+/// into `zones`.
+pub fn gravity(zones: &[ZoneTotals], deterrence: Deterrence) -> Vec<(usize, usize, usize)> {
+    let mut flows = Vec::new();
+    for (from, origin) in zones.iter().enumerate() {
+        let weights: Vec<f64> = zones
+            .iter()
+            .enumerate()
+            .map(|(to, destination)| {
+                if from == to {
+                    0.0
+                } else {
+                    destination.destinations
+                        * deterrence.weight(centroid_distance(origin, destination))
+                }
+            })
+            .collect();
+        let counts = split_total(origin.origins.round() as usize, &weights);
+        for (to, count) in counts.into_iter().enumerate() {
+            if count > 0 {
+                flows.push((from, to, count));
+            }
+        }
+    }
+    flows
+}
+
+/// The population of one zone, and the number of trips starting there
+pub struct ZonePopulation {
+    pub name: String,
+    pub centroid: (f64, f64),
+    pub population: f64,
+    pub origins: f64,
+}
+
+/// The radiation model from Simini et al. (2012). Every zone's origins are split between the
+/// other zones, in proportion to `m * n / ((m + s) * (m + n + s))`, where `m` and `n` are the
+/// origin and destination zone populations, and `s` is the population of the zones closer to the
+/// origin than the destination (the intervening opportunities). Each trip's destination is
+/// sampled randomly. Trips within a zone aren't created. Returns `(from, to, count)`, indexing
+/// into `zones`.
+///
+/// Sorting the other zones by distance from each origin finds every `s` with a running sum, so
+/// this takes O(Z² log Z) time for Z zones.
+pub fn radiation(zones: &[ZonePopulation], rng: &mut WyRand) -> Vec<(usize, usize, usize)> {
+    let mut flows = Vec::new();
+    for (from, origin) in zones.iter().enumerate() {
+        let distances: Vec<f64> = zones
+            .iter()
+            .map(|zone| distance(origin.centroid, zone.centroid))
+            .collect();
+        let m = origin.population;
+
+        let mut by_distance: Vec<usize> = (0..zones.len()).filter(|idx| *idx != from).collect();
+        by_distance.sort_by(|a, b| distances[*a].total_cmp(&distances[*b]));
+        let mut weights = vec![0.0; zones.len()];
+        // The population of zones strictly closer than the current ones
+        let mut s = 0.0;
+        let mut start = 0;
+        while start < by_distance.len() {
+            // Zones at the same distance don't intervene for each other
+            let mut end = start;
+            while end < by_distance.len()
+                && distances[by_distance[end]] == distances[by_distance[start]]
+            {
+                end += 1;
+            }
+            for to in &by_distance[start..end] {
+                let n = zones[*to].population;
+                let denominator = (m + s) * (m + n + s);
+                if denominator > 0.0 {
+                    weights[*to] = m * n / denominator;
+                }
+            }
+            s += by_distance[start..end]
+                .iter()
+                .map(|idx| zones[*idx].population)
+                .sum::<f64>();
+            start = end;
+        }
+
+        // Cumulative weights, for sampling
+        let mut cumulative = Vec::new();
+        let mut total_weight = 0.0;
+        for weight in weights {
+            total_weight += weight;
+            cumulative.push(total_weight);
+        }
+        if total_weight <= 0.0 {
+            continue;
+        }
+
+        let mut counts = vec![0; zones.len()];
+        for _ in 0..origin.origins.round() as usize {
+            let x = rng.generate::<f64>() * total_weight;
+            let to = cumulative
+                .partition_point(|weight| *weight <= x)
+                .min(zones.len() - 1);
+            counts[to] += 1;
+        }
+        for (to, count) in counts.into_iter().enumerate() {
+            if count > 0 {
+                flows.push((from, to, count));
+            }
+        }
+    }
+    flows
+}
+
+/// Iterative proportional fitting. Scales `(from, to, count)` flows until the total leaving each
+/// zone matches `origins` and the total arriving matches `destinations`. Zones missing from the
+/// margins aren't constrained. Returns the adjusted count for each flow, which won't be whole
+/// numbers.
+pub fn ipf(
+    flows: &[(&str, &str, f64)],
+    origins: &HashMap<String, f64>,
+    destinations: &HashMap<String, f64>,
+) -> Vec<f64> {
+    let mut counts: Vec<f64> = flows.iter().map(|(_, _, count)| *count).collect();
+    for _ in 0..100 {
+        let mut max_error: f64 = 0.0;
+        for (use_origin, margins) in [(true, origins), (false, destinations)] {
+            let zone = |idx: usize| {
+                if use_origin {
+                    flows[idx].0
+                } else {
+                    flows[idx].1
+                }
+            };
+            let mut totals: HashMap<&str, f64> = HashMap::new();
+            for (idx, count) in counts.iter().enumerate() {
+                *totals.entry(zone(idx)).or_insert(0.0) += count;
+            }
+            for (idx, count) in counts.iter_mut().enumerate() {
+                if let Some(target) = margins.get(zone(idx)) {
+                    let total = totals[zone(idx)];
+                    if total > 0.0 {
+                        max_error = max_error.max((total - target).abs() / target.max(1.0));
+                        *count *= target / total;
+                    }
+                }
+            }
+        }
+        if max_error < 1e-6 {
+            break;
+        }
+    }
+    counts
+}
+
+fn centroid_distance(zone1: &ZoneTotals, zone2: &ZoneTotals) -> f64 {
+    distance(zone1.centroid, zone2.centroid)
+}
+
+fn distance(pt1: (f64, f64), pt2: (f64, f64)) -> f64 {
+    Point::from(pt1).haversine_distance(&Point::from(pt2))
+}
+
+/// Splits `total` into whole numbers, proportional to `weights`. The parts with the largest
+/// remainders are rounded up, so the result always sums to `total`, unless every weight is 0.
+pub fn split_total(total: usize, weights: &[f64]) -> Vec<usize> {
+    let sum: f64 = weights.iter().sum();
+    if sum <= 0.0 {
+        return vec![0; weights.len()];
+    }
+    let exact: Vec<f64> = weights
+        .iter()
+        .map(|weight| total as f64 * weight / sum)
+        .collect();
+    let mut result: Vec<usize> = exact.iter().map(|x| x.floor() as usize).collect();
+    let mut remaining = total - result.iter().sum::<usize>();
+    let mut by_remainder: Vec<usize> = (0..weights.len()).collect();
+    by_remainder.sort_by(|a, b| {
+        let remainder = |idx: usize| exact[idx] - exact[idx].floor();
+        remainder(*b).partial_cmp(&remainder(*a)).unwrap()
+    });
+    for idx in by_remainder {
+        if remaining == 0 {
+            break;
+        }
+        result[idx] += 1;
+        remaining -= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(name: &str, x: f64, origins: f64, destinations: f64) -> ZoneTotals {
+        ZoneTotals {
+            name: name.to_string(),
+            centroid: (x, 0.0),
+            origins,
+            destinations,
+        }
+    }
+
+    #[test]
+    fn test_split_total() {
+        assert_eq!(split_total(10, &[1.0, 1.0, 1.0]), vec![4, 3, 3]);
+        assert_eq!(split_total(7, &[0.0, 2.0, 5.0]), vec![0, 2, 5]);
+        assert_eq!(split_total(5, &[0.0, 0.0]), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_radiation() {
+        // B and C are equally big, but C is further from A, with B intervening
+        let zone = |name: &str, x, population, origins| ZonePopulation {
+            name: name.to_string(),
+            centroid: (x, 0.0),
+            population,
+            origins,
+        };
+        let zones = vec![
+            zone("A", 0.0, 1000.0, 1000.0),
+            zone("B", 0.01, 1000.0, 0.0),
+            zone("C", 0.02, 1000.0, 0.0),
+        ];
+        let flows = |seed| radiation(&zones, &mut WyRand::new_seed(seed));
+
+        let result = flows(42);
+        let count_to = |to| {
+            result
+                .iter()
+                .find(|(_, x, _)| *x == to)
+                .map(|(_, _, count)| *count)
+                .unwrap_or(0)
+        };
+        assert_eq!(count_to(1) + count_to(2), 1000);
+        // The expected split is 1/2 to B and 1/6 to C, so 3/4 and 1/4 after normalizing
+        assert!((700..800).contains(&count_to(1)));
+        assert!(result.iter().all(|(from, to, _)| from != to));
+
+        assert_eq!(result, flows(42));
+        assert_ne!(result, flows(43));
+
+        // Zones the same distance away don't intervene for each other, so B and C split A's trips
+        let zones = vec![
+            zone("A", 0.0, 1000.0, 1000.0),
+            zone("B", 0.01, 1000.0, 0.0),
+            zone("C", -0.01, 1000.0, 0.0),
+        ];
+        let result = radiation(&zones, &mut WyRand::new_seed(42));
+        assert!(result
+            .iter()
+            .all(|(_, _, count)| (450..550).contains(count)));
+    }
+
+    #[test]
+    fn test_ipf() {
+        let flows = vec![
+            ("A", "A", 10.0),
+            ("A", "B", 10.0),
+            ("B", "A", 10.0),
+            ("B", "B", 10.0),
+        ];
+        let margins = |a: f64, b: f64| {
+            [("A".to_string(), a), ("B".to_string(), b)]
+                .into_iter()
+                .collect::<HashMap<_, _>>()
+        };
+        let counts = ipf(&flows, &margins(30.0, 10.0), &margins(20.0, 20.0));
+        let total = |filter: &dyn Fn(&str, &str) -> bool| -> f64 {
+            flows
+                .iter()
+                .zip(&counts)
+                .filter(|((from, to, _), _)| filter(from, to))
+                .map(|(_, count)| count)
+                .sum()
+        };
+        assert!((total(&|from, _| from == "A") - 30.0).abs() < 1e-3);
+        assert!((total(&|from, _| from == "B") - 10.0).abs() < 1e-3);
+        assert!((total(&|_, to| to == "A") - 20.0).abs() < 1e-3);
+        assert!((total(&|_, to| to == "B") - 20.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_gravity() {
+        // B is close to A, C is much further away, and both attract the same number of trips
+        let zones = vec![
+            zone("A", 0.0, 100.0, 0.0),
+            zone("B", 0.01, 0.0, 50.0),
+            zone("C", 0.1, 0.0, 50.0),
+        ];
+        for deterrence in [
+            Deterrence::Power { exponent: 2.0 },
+            Deterrence::Exponential { beta: 0.5 },
+        ] {
+            let flows = gravity(&zones, deterrence);
+            let total: usize = flows.iter().map(|(_, _, count)| count).sum();
+            assert_eq!(total, 100);
+            let count_to = |to| {
+                flows
+                    .iter()
+                    .find(|(_, x, _)| *x == to)
+                    .map(|(_, _, count)| *count)
+                    .unwrap_or(0)
+            };
+            assert!(count_to(1) > count_to(2));
+            // No trips within a zone
+            assert!(flows.iter().all(|(from, to, _)| from != to));
+        }
+    }
+}
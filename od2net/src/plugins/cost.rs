@@ -1,23 +1,82 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Write;
 use std::process::{Command, Stdio};
 
 use anyhow::Result;
 use serde::Serialize;
 
+use super::expression::Expr;
 use crate::config::{CostFunction, GeneralizedCostFunction};
 use crate::network::Edge;
 use lts::LTS;
 
-pub fn calculate_batch(
+/// Calculates costs for batches of edges. Anything expensive to set up, like a WebAssembly
+/// plugin, is only prepared once.
+pub struct CostCalculator<'a> {
+    cost: &'a CostFunction,
+    #[cfg(not(target_arch = "wasm32"))]
+    wasm_plugin: Option<WasmPlugin>,
+}
+
+impl<'a> CostCalculator<'a> {
+    pub fn new(cost: &'a CostFunction) -> Result<Self> {
+        let mut inner = cost;
+        while let CostFunction::EBike(wrapped) = inner {
+            inner = wrapped;
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        let wasm_plugin = match inner {
+            CostFunction::WasmPlugin(path) => Some(WasmPlugin::load(path)?),
+            _ => None,
+        };
+        #[cfg(target_arch = "wasm32")]
+        if let CostFunction::WasmPlugin(_) = inner {
+            bail!("WebAssembly cost plugins aren't supported here");
+        }
+        Ok(Self {
+            cost,
+            #[cfg(not(target_arch = "wasm32"))]
+            wasm_plugin,
+        })
+    }
+
+    /// Returns the (forward, backward) cost of each edge. None means the edge can't be used in
+    /// that direction.
+    pub fn calculate_batch(
+        &mut self,
+        input_batch: Vec<&Edge>,
+    ) -> Result<Vec<(Option<usize>, Option<usize>)>> {
+        let mut cost = self.cost;
+        let mut ebike = false;
+        while let CostFunction::EBike(inner) = cost {
+            cost = inner;
+            ebike = true;
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(ref mut plugin) = self.wasm_plugin {
+            return plugin.calculate_batch(input_batch, ebike);
+        }
+        Ok(calculate(cost, input_batch, ebike)?
+            .into_iter()
+            .map(|cost| (cost.map(|(f, _)| f), cost.map(|(_, b)| b)))
+            .collect())
+    }
+}
+
+/// If `ebike`, every cost function sees `ebike_slope_factor` as the slope factor
+fn calculate(
     cost: &CostFunction,
     input_batch: Vec<&Edge>,
-) -> Vec<Option<(usize, usize)>> {
-    match cost {
-        CostFunction::Distance => input_batch.into_iter().map(distance).collect(),
+    ebike: bool,
+) -> Result<Vec<Option<(usize, usize)>>> {
+    Ok(match cost {
+        CostFunction::Distance => input_batch
+            .into_iter()
+            .map(|e| distance(e, ebike))
+            .collect(),
         CostFunction::OsmHighwayType(ref weights) => input_batch
             .into_iter()
-            .map(|e| osm_highway_type(e, weights))
+            .map(|e| osm_highway_type(e, weights, ebike))
             .collect(),
         CostFunction::ByLTS {
             lts1,
@@ -26,24 +85,47 @@ pub fn calculate_batch(
             lts4,
         } => input_batch
             .into_iter()
-            .map(|e| by_lts(e, *lts1, *lts2, *lts3, *lts4))
+            .map(|e| by_lts(e, *lts1, *lts2, *lts3, *lts4, ebike))
             .collect(),
         CostFunction::Generalized(ref params) => input_batch
             .into_iter()
-            .map(|e| generalized(e, params))
+            .map(|e| generalized(e, params, ebike))
             .collect(),
-        CostFunction::ExternalCommand(command) => external_command(command, input_batch).unwrap(),
+        CostFunction::ExternalCommand(command) => external_command(command, input_batch, ebike)?,
+        // CostCalculator handles these
+        CostFunction::WasmPlugin(_) => unreachable!(),
+        CostFunction::Expression { expression, tables } => {
+            // normalize has already checked this
+            let expr = Expr::parse(expression).unwrap();
+            input_batch
+                .into_iter()
+                .map(|e| expression_cost(e, &expr, tables, ebike))
+                .collect()
+        }
+        CostFunction::EBike(inner) => calculate(inner, input_batch, true)?,
+    })
+}
+
+fn slope_factor(edge: &Edge, ebike: bool) -> Option<(f64, f64)> {
+    if ebike {
+        edge.ebike_slope_factor
+    } else {
+        edge.slope_factor
     }
 }
 
-fn distance(edge: &Edge) -> Option<(usize, usize)> {
+fn distance(edge: &Edge, ebike: bool) -> Option<(usize, usize)> {
     // Note this accounts for slope_factor
-    by_lts(edge, 1.0, 1.0, 1.0, 1.0)
+    by_lts(edge, 1.0, 1.0, 1.0, 1.0, ebike)
 }
 
-fn osm_highway_type(edge: &Edge, weights: &HashMap<String, f64>) -> Option<(usize, usize)> {
+fn osm_highway_type(
+    edge: &Edge,
+    weights: &HashMap<String, f64>,
+    ebike: bool,
+) -> Option<(usize, usize)> {
     let raw_weight = weights.get(edge.tags.get("highway").unwrap())?;
-    let slope_factor = edge.slope_factor.unwrap_or((1., 1.));
+    let slope_factor = slope_factor(edge, ebike).unwrap_or((1., 1.));
 
     Some((
         (raw_weight * slope_factor.0 * edge.length_meters).round() as usize,
@@ -51,7 +133,14 @@ fn osm_highway_type(edge: &Edge, weights: &HashMap<String, f64>) -> Option<(usiz
     ))
 }
 
-fn by_lts(edge: &Edge, lts1: f64, lts2: f64, lts3: f64, lts4: f64) -> Option<(usize, usize)> {
+fn by_lts(
+    edge: &Edge,
+    lts1: f64,
+    lts2: f64,
+    lts3: f64,
+    lts4: f64,
+    ebike: bool,
+) -> Option<(usize, usize)> {
     let raw_weight = match edge.lts {
         LTS::NotAllowed => {
             return None;
@@ -62,7 +151,7 @@ fn by_lts(edge: &Edge, lts1: f64, lts2: f64, lts3: f64, lts4: f64) -> Option<(us
         LTS::LTS4 => lts4,
     };
 
-    let slope_factor = edge.slope_factor.unwrap_or((1., 1.));
+    let slope_factor = slope_factor(edge, ebike).unwrap_or((1., 1.));
 
     Some((
         (raw_weight * slope_factor.0 * edge.length_meters).round() as usize,
@@ -70,7 +159,11 @@ fn by_lts(edge: &Edge, lts1: f64, lts2: f64, lts3: f64, lts4: f64) -> Option<(us
     ))
 }
 
-fn generalized(edge: &Edge, params: &GeneralizedCostFunction) -> Option<(usize, usize)> {
+fn generalized(
+    edge: &Edge,
+    params: &GeneralizedCostFunction,
+    ebike: bool,
+) -> Option<(usize, usize)> {
     let lts_weight = match edge.lts {
         LTS::NotAllowed => {
             return None;
@@ -92,7 +185,7 @@ fn generalized(edge: &Edge, params: &GeneralizedCostFunction) -> Option<(usize,
 
     // TODO Does it make sense to apply this along with the LTS? Does this approach need a way to
     // look at cyclist speed?
-    let slope_factor = edge.slope_factor.unwrap_or((1., 1.));
+    let slope_factor = slope_factor(edge, ebike).unwrap_or((1., 1.));
 
     // Use the tradeoffs to get a final penalty
     let forward_penalty = (params.tradeoff_lts * lts_weight * slope_factor.0)
@@ -111,7 +204,61 @@ fn generalized(edge: &Edge, params: &GeneralizedCostFunction) -> Option<(usize,
     Some(penalty)
 }
 
-fn external_command(command: &str, input_batch: Vec<&Edge>) -> Result<Vec<Option<(usize, usize)>>> {
+/// The variables a cost expression can use, besides `exposure_<name>` for each exposure layer
+pub const EXPRESSION_VARIABLES: &[&str] = &[
+    "length_meters",
+    "lts",
+    "nearby_amenities",
+    "slope",
+    "slope_factor",
+    "aadt",
+];
+
+fn expression_cost(
+    edge: &Edge,
+    expr: &Expr,
+    tables: &BTreeMap<String, Vec<f64>>,
+    ebike: bool,
+) -> Option<(usize, usize)> {
+    if edge.lts == LTS::NotAllowed {
+        return None;
+    }
+    let slope_factor = slope_factor(edge, ebike).unwrap_or((1., 1.));
+    let slope = edge.slope.unwrap_or(0.0);
+
+    // slope and slope_factor depend on the direction
+    let cost = |forwards: bool| {
+        let variable = |name: &str| match name {
+            "length_meters" => edge.length_meters,
+            "lts" => edge.lts as u8 as f64,
+            "nearby_amenities" => edge.nearby_amenities as f64,
+            "slope" if forwards => slope,
+            "slope" => -slope,
+            "slope_factor" if forwards => slope_factor.0,
+            "slope_factor" => slope_factor.1,
+            "aadt" => edge.aadt.unwrap_or(0.0),
+            _ => name
+                .strip_prefix("exposure_")
+                .and_then(|layer| edge.exposure.get(layer))
+                .cloned()
+                .unwrap_or(0.0),
+        };
+        let cost = expr.eval(&variable, tables);
+        // Also rules out NaN
+        if cost > 0.0 && cost.is_finite() {
+            Some(cost.round() as usize)
+        } else {
+            None
+        }
+    };
+    Some((cost(true)?, cost(false)?))
+}
+
+fn external_command(
+    command: &str,
+    input_batch: Vec<&Edge>,
+    ebike: bool,
+) -> Result<Vec<Option<(usize, usize)>>> {
     let args: Vec<&str> = command.split(" ").collect();
 
     let mut cmd = Command::new(args[0])
@@ -122,14 +269,7 @@ fn external_command(command: &str, input_batch: Vec<&Edge>) -> Result<Vec<Option
     if let Some(mut stdin) = cmd.stdin.take() {
         let input: Vec<EdgeInput> = input_batch
             .iter()
-            .map(|edge| EdgeInput {
-                osm_tags: edge.tags.inner(),
-                lts: edge.lts,
-                nearby_amenities: edge.nearby_amenities,
-                length_meters: edge.length_meters,
-                slope: edge.slope,
-                slope_factor: edge.slope_factor,
-            })
+            .map(|edge| EdgeInput::new(edge, ebike))
             .collect();
         write!(stdin, "{}", serde_json::to_string(&input)?)?;
     }
@@ -139,6 +279,98 @@ fn external_command(command: &str, input_batch: Vec<&Edge>) -> Result<Vec<Option
     Ok(output_batch)
 }
 
+/// A compiled WebAssembly cost plugin. The module must export `memory`, `alloc(len: i32) -> i32`
+/// returning a pointer to `len` free bytes, and `cost(ptr: i32, len: i32, backwards: i32) -> i64`.
+/// It may export `dealloc(ptr: i32, len: i32)` to free what `alloc` returned. The input is one
+/// `EdgeInput` as JSON. A negative cost means the edge can't be used in that direction.
+#[cfg(not(target_arch = "wasm32"))]
+struct WasmPlugin {
+    store: wasmi::Store<()>,
+    memory: wasmi::Memory,
+    alloc: wasmi::TypedFunc<i32, i32>,
+    dealloc: Option<wasmi::TypedFunc<(i32, i32), ()>>,
+    cost: wasmi::TypedFunc<(i32, i32, i32), i64>,
+    /// A buffer from `alloc`, reused for every edge that fits, and its length
+    buffer: Option<(i32, i32)>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WasmPlugin {
+    fn load(path: &str) -> Result<Self> {
+        let engine = wasmi::Engine::default();
+        let module = wasmi::Module::new(&engine, &fs_err::read(path)?[..])?;
+        let mut store = wasmi::Store::new(&engine, ());
+        let instance = wasmi::Linker::<()>::new(&engine)
+            .instantiate(&mut store, &module)?
+            .start(&mut store)?;
+        let Some(memory) = instance.get_memory(&store, "memory") else {
+            bail!("{path} doesn't export memory");
+        };
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc")?;
+        let dealloc = instance
+            .get_typed_func::<(i32, i32), ()>(&store, "dealloc")
+            .ok();
+        let cost = instance.get_typed_func::<(i32, i32, i32), i64>(&store, "cost")?;
+        Ok(Self {
+            store,
+            memory,
+            alloc,
+            dealloc,
+            cost,
+            buffer: None,
+        })
+    }
+
+    fn calculate_batch(
+        &mut self,
+        input_batch: Vec<&Edge>,
+        ebike: bool,
+    ) -> Result<Vec<(Option<usize>, Option<usize>)>> {
+        let mut output_batch = Vec::new();
+        for edge in input_batch {
+            let input = serde_json::to_vec(&EdgeInput::new(edge, ebike))?;
+            let len = input.len() as i32;
+            let ptr = self.buffer_for(len)?;
+            self.memory.write(&mut self.store, ptr as usize, &input)?;
+
+            let forward = self.cost.call(&mut self.store, (ptr, len, 0))?;
+            let backward = self.cost.call(&mut self.store, (ptr, len, 1))?;
+            // Each direction is independent
+            output_batch.push((
+                usize::try_from(forward).ok(),
+                usize::try_from(backward).ok(),
+            ));
+        }
+        Ok(output_batch)
+    }
+
+    /// Returns a pointer to at least `len` bytes, only calling `alloc` when the current buffer
+    /// is too small
+    fn buffer_for(&mut self, len: i32) -> Result<i32> {
+        let capacity = len.max(self.buffer.map_or(1024, |(_, old)| old.saturating_mul(2)));
+        if let Some((ptr, old)) = self.buffer {
+            if len <= old {
+                return Ok(ptr);
+            }
+            if let Some(ref dealloc) = self.dealloc {
+                dealloc.call(&mut self.store, (ptr, old))?;
+            }
+        }
+        let ptr = self.alloc.call(&mut self.store, capacity)?;
+        self.buffer = Some((ptr, capacity));
+        Ok(ptr)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for WasmPlugin {
+    fn drop(&mut self) {
+        if let (Some((ptr, capacity)), Some(dealloc)) = (self.buffer, self.dealloc.as_ref()) {
+            let _ = dealloc.call(&mut self.store, (ptr, capacity));
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct EdgeInput<'a> {
     osm_tags: &'a HashMap<String, String>,
@@ -147,4 +379,76 @@ struct EdgeInput<'a> {
     length_meters: f64,
     slope: Option<f64>,
     slope_factor: Option<(f64, f64)>,
+    aadt: Option<f64>,
+    exposure: &'a BTreeMap<String, f64>,
+}
+
+impl<'a> EdgeInput<'a> {
+    fn new(edge: &'a Edge, ebike: bool) -> Self {
+        Self {
+            osm_tags: edge.tags.inner(),
+            lts: edge.lts,
+            nearby_amenities: edge.nearby_amenities,
+            length_meters: edge.length_meters,
+            slope: edge.slope,
+            slope_factor: slope_factor(edge, ebike),
+            aadt: edge.aadt,
+            exposure: &edge.exposure,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::make_network;
+
+    /// A module with `alloc` always returning 1024, and `cost` returning the length of the input
+    /// forwards and -1 backwards
+    const ONE_WAY_PLUGIN: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // Header
+        0x01, 0x0d, 0x02, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x60, 0x03, 0x7f, 0x7f, 0x7f, 0x01,
+        0x7e, // Types
+        0x03, 0x03, 0x02, 0x00, 0x01, // Functions
+        0x05, 0x03, 0x01, 0x00, 0x01, // One page of memory
+        0x07, 0x19, 0x03, 0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00, 0x05, b'a', b'l',
+        b'l', b'o', b'c', 0x00, 0x00, 0x04, b'c', b'o', b's', b't', 0x00, 0x01, // Exports
+        0x0a, 0x15, 0x02, 0x05, 0x00, 0x41, 0x80, 0x08, 0x0b, 0x0d, 0x00, 0x20, 0x02, 0x04, 0x7e,
+        0x42, 0x7f, 0x05, 0x20, 0x01, 0xad, 0x0b, 0x0b, // Code
+    ];
+
+    #[test]
+    fn test_wasm_plugin() {
+        let path = std::env::temp_dir()
+            .join(format!("od2net_test_plugin_{}.wasm", std::process::id()))
+            .display()
+            .to_string();
+        fs_err::write(&path, ONE_WAY_PLUGIN).unwrap();
+
+        let network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (2, 3)],
+        );
+        let edges: Vec<&Edge> = network.edges.values().collect();
+        // Only the backward direction is ruled out
+        let expected: Vec<(Option<usize>, Option<usize>)> = edges
+            .iter()
+            .map(|edge| {
+                let input = serde_json::to_vec(&EdgeInput::new(edge, false)).unwrap();
+                (Some(input.len()), None)
+            })
+            .collect();
+
+        for cost in [
+            CostFunction::WasmPlugin(path.clone()),
+            CostFunction::EBike(Box::new(CostFunction::WasmPlugin(path.clone()))),
+        ] {
+            let mut calculator = CostCalculator::new(&cost).unwrap();
+            // The same instance handles every batch
+            for _ in 0..2 {
+                assert_eq!(calculator.calculate_batch(edges.clone()).unwrap(), expected);
+            }
+        }
+        fs_err::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,286 @@
+//! A small arithmetic language for cost functions, like
+//! `length_meters * slope_factor * lts_weight[lts] / (1 + 0.1 * nearby_amenities)`. It supports
+//! numbers, `+ - * /`, parentheses, variables, indexing into named tables, and `min(a, b)` and
+//! `max(a, b)`.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+#[derive(Debug, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Variable(String),
+    /// `table[index]`, with the index rounded to the nearest integer
+    Index(String, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Negate(Box<Expr>),
+    Binary(Box<Expr>, char, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Symbol(char),
+}
+
+impl Expr {
+    pub fn parse(input: &str) -> Result<Expr> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            pos: 0,
+        };
+        let expr = parser.expr()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            bail!("Unexpected {token:?} in cost expression {input}");
+        }
+        Ok(expr)
+    }
+
+    /// Fails if the expression uses a variable, table, or function that doesn't exist
+    pub fn check(&self, variables: &[&str], tables: &BTreeMap<String, Vec<f64>>) -> Result<()> {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Variable(name) => {
+                if !variables.contains(&name.as_str()) {
+                    bail!(
+                        "Unknown variable {name} in cost expression; use one of {}",
+                        variables.join(", ")
+                    );
+                }
+            }
+            Expr::Index(table, index) => {
+                if !tables.contains_key(table) {
+                    bail!("Cost expression uses {table}[...], but there's no table called {table}");
+                }
+                index.check(variables, tables)?;
+            }
+            Expr::Call(function, args) => {
+                if !["min", "max"].contains(&function.as_str()) || args.len() != 2 {
+                    bail!("Cost expression can only call min(a, b) or max(a, b), not {function}");
+                }
+                for arg in args {
+                    arg.check(variables, tables)?;
+                }
+            }
+            Expr::Negate(x) => x.check(variables, tables)?,
+            Expr::Binary(a, _, b) => {
+                a.check(variables, tables)?;
+                b.check(variables, tables)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Assumes `check` passed. Indexing outside a table gives NaN.
+    pub fn eval<F: Fn(&str) -> f64>(
+        &self,
+        variable: &F,
+        tables: &BTreeMap<String, Vec<f64>>,
+    ) -> f64 {
+        match self {
+            Expr::Number(x) => *x,
+            Expr::Variable(name) => variable(name),
+            Expr::Index(table, index) => {
+                let index = index.eval(variable, tables).round();
+                if index < 0.0 {
+                    return f64::NAN;
+                }
+                tables[table]
+                    .get(index as usize)
+                    .cloned()
+                    .unwrap_or(f64::NAN)
+            }
+            Expr::Call(function, args) => {
+                let a = args[0].eval(variable, tables);
+                let b = args[1].eval(variable, tables);
+                if function == "min" {
+                    a.min(b)
+                } else {
+                    a.max(b)
+                }
+            }
+            Expr::Negate(x) => -x.eval(variable, tables),
+            Expr::Binary(a, op, b) => {
+                let a = a.eval(variable, tables);
+                let b = b.eval(variable, tables);
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    _ => a / b,
+                }
+            }
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c.is_ascii_digit() || c == '.' {
+            let mut number = c.to_string();
+            while let Some(next) = chars.next_if(|x| x.is_ascii_digit() || *x == '.') {
+                number.push(next);
+            }
+            tokens.push(Token::Number(number.parse().map_err(|_| {
+                anyhow!("Bad number {number} in cost expression {input}")
+            })?));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut name = c.to_string();
+            while let Some(next) = chars.next_if(|x| x.is_alphanumeric() || *x == '_') {
+                name.push(next);
+            }
+            tokens.push(Token::Name(name));
+        } else if "+-*/()[],".contains(c) {
+            tokens.push(Token::Symbol(c));
+        } else {
+            bail!("Unexpected {c} in cost expression {input}");
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next_is(&mut self, symbol: char) -> bool {
+        if self.tokens.get(self.pos) == Some(&Token::Symbol(symbol)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<()> {
+        if !self.next_is(symbol) {
+            bail!("Expected {symbol} in cost expression");
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let mut expr = self.term()?;
+        loop {
+            let op = if self.next_is('+') {
+                '+'
+            } else if self.next_is('-') {
+                '-'
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = if self.next_is('*') {
+                '*'
+            } else if self.next_is('/') {
+                '/'
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.next_is('-') {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        if self.next_is('(') {
+            let expr = self.expr()?;
+            self.expect(')')?;
+            return Ok(expr);
+        }
+
+        let Some(token) = self.tokens.get(self.pos) else {
+            bail!("Cost expression ended early");
+        };
+        self.pos += 1;
+        match token {
+            Token::Number(x) => Ok(Expr::Number(*x)),
+            Token::Name(name) => {
+                let name = name.clone();
+                if self.next_is('[') {
+                    let index = self.expr()?;
+                    self.expect(']')?;
+                    Ok(Expr::Index(name, Box::new(index)))
+                } else if self.next_is('(') {
+                    let mut args = vec![self.expr()?];
+                    while self.next_is(',') {
+                        args.push(self.expr()?);
+                    }
+                    self.expect(')')?;
+                    Ok(Expr::Call(name, args))
+                } else {
+                    Ok(Expr::Variable(name))
+                }
+            }
+            Token::Symbol(c) => bail!("Unexpected {c} in cost expression"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval() {
+        let tables: BTreeMap<String, Vec<f64>> =
+            [("lts_weight".to_string(), vec![0.0, 1.0, 1.5, 3.0, 10.0])]
+                .into_iter()
+                .collect();
+        let variable = |name: &str| match name {
+            "length_meters" => 100.0,
+            "lts" => 2.0,
+            "nearby_amenities" => 10.0,
+            _ => unreachable!(),
+        };
+        let eval = |input: &str| {
+            let expr = Expr::parse(input).unwrap();
+            expr.check(&["length_meters", "lts", "nearby_amenities"], &tables)
+                .unwrap();
+            expr.eval(&variable, &tables)
+        };
+
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("-2 - -3"), 1.0);
+        assert_eq!(eval("10 / 4 / 5"), 0.5);
+        assert_eq!(
+            eval("length_meters * lts_weight[lts] / (1 + 0.1 * nearby_amenities)"),
+            75.0
+        );
+        assert_eq!(eval("max(length_meters, 200) + min(1, 2)"), 201.0);
+        assert!(eval("lts_weight[lts + 5]").is_nan());
+
+        assert!(Expr::parse("1 +").is_err());
+        assert!(Expr::parse("(1 + 2").is_err());
+        assert!(Expr::parse("1 2").is_err());
+        assert!(Expr::parse("1 % 2").is_err());
+        let expr = Expr::parse("speed * 2").unwrap();
+        assert!(expr.check(&["length_meters"], &tables).is_err());
+        let expr = Expr::parse("weights[lts]").unwrap();
+        assert!(expr.check(&["lts"], &tables).is_err());
+        let expr = Expr::parse("exposure_noise + lts").unwrap();
+        assert!(expr.check(&["lts", "exposure_noise"], &tables).is_ok());
+        // A layer that isn't configured
+        assert!(expr.check(&["lts", "exposure_no2"], &tables).is_err());
+        let expr = Expr::parse("sqrt(lts)").unwrap();
+        assert!(expr.check(&["lts"], &tables).is_err());
+    }
+}
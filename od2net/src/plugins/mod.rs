@@ -1,4 +1,5 @@
 pub mod cost;
+pub mod expression;
 pub mod lts;
 pub mod uptake;
 
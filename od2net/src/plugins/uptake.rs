@@ -1,7 +1,15 @@
-use crate::config::Uptake;
+use std::io::Read;
 
-/// Given stats about a route, calculate its "uptake", between 0 and 1.
-pub fn calculate_uptake(uptake: &Uptake, total_distance_meters: f64) -> f64 {
+use anyhow::Result;
+use fs_err::File;
+use serde::Deserialize;
+
+use crate::config::{LogisticUptake, Uptake};
+use crate::utils;
+
+/// Given stats about a route, calculate its "uptake", between 0 and 1. `purpose` comes from the
+/// request, and is only used by `Uptake::ByPurpose`.
+pub fn calculate_uptake(uptake: &Uptake, total_distance_meters: f64, purpose: Option<&str>) -> f64 {
     // TODO Find a data source and calculate this
     let gradient = 0.0;
 
@@ -16,9 +24,125 @@ pub fn calculate_uptake(uptake: &Uptake, total_distance_meters: f64) -> f64 {
         }
         Uptake::GovTargetPCT => pct_gov_target(total_distance_meters, gradient),
         Uptake::GoDutchPCT => pct_go_dutch(total_distance_meters, gradient),
+        Uptake::EBikePCT => pct_ebike(total_distance_meters, gradient),
+        Uptake::WalkingHalfDistanceMeters(half) => 0.5_f64.powf(total_distance_meters / half),
+        Uptake::ByPurpose { purposes, default } => {
+            let uptake = purpose
+                .and_then(|purpose| purposes.get(purpose))
+                .unwrap_or(default);
+            calculate_uptake(uptake, total_distance_meters, purpose)
+        }
+        Uptake::Logistic(model) => logistic(model, total_distance_meters, gradient),
+        Uptake::LookupTable(table) => {
+            let per_gradient: Vec<(f64, f64)> = table
+                .curves
+                .iter()
+                .map(|(gradient, curve)| (*gradient, interpolate(curve, total_distance_meters)))
+                .collect();
+            interpolate(&per_gradient, gradient)
+        }
     }
 }
 
+/// Reads the CSV file for any `Uptake::LookupTable`, resolving relative paths against
+/// `input_directory`
+pub fn load_tables(uptake: &mut Uptake, input_directory: &str) -> Result<()> {
+    match uptake {
+        Uptake::LookupTable(table) => {
+            let path = utils::resolve_path(input_directory, &table.path);
+            table.curves = read_table(&path)?;
+        }
+        Uptake::ByPurpose { purposes, default } => {
+            for uptake in purposes.values_mut() {
+                load_tables(uptake, input_directory)?;
+            }
+            load_tables(default, input_directory)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Fails if any `Uptake::LookupTable` hasn't had its CSV file read yet, with `load_tables`
+pub fn check_tables_loaded(uptake: &Uptake) -> Result<()> {
+    match uptake {
+        Uptake::LookupTable(table) => {
+            if table.curves.is_empty() {
+                bail!(
+                    "The uptake lookup table {} hasn't been loaded; call InputConfig::load_uptake_tables first",
+                    table.path
+                );
+            }
+        }
+        Uptake::ByPurpose { purposes, default } => {
+            for uptake in purposes.values() {
+                check_tables_loaded(uptake)?;
+            }
+            check_tables_loaded(default)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct TableRow {
+    distance_meters: f64,
+    #[serde(default)]
+    gradient_percent: f64,
+    probability: f64,
+}
+
+fn read_table(path: &str) -> Result<Vec<(f64, Vec<(f64, f64)>)>> {
+    read_table_from(File::open(path)?, path)
+}
+
+/// Parses a lookup table CSV with `distance_meters`, `probability`, and optional
+/// `gradient_percent` columns. `path` is only used in errors.
+pub fn read_table_from<R: Read>(reader: R, path: &str) -> Result<Vec<(f64, Vec<(f64, f64)>)>> {
+    let mut curves: Vec<(f64, Vec<(f64, f64)>)> = Vec::new();
+    for rec in csv::Reader::from_reader(reader).deserialize() {
+        let row: TableRow = rec?;
+        if !(0.0..=1.0).contains(&row.probability) {
+            bail!(
+                "{path} has probability {}, which isn't between 0 and 1",
+                row.probability
+            );
+        }
+        let point = (row.distance_meters, row.probability);
+        match curves
+            .iter_mut()
+            .find(|(gradient, _)| *gradient == row.gradient_percent)
+        {
+            Some((_, curve)) => curve.push(point),
+            None => curves.push((row.gradient_percent, vec![point])),
+        }
+    }
+    if curves.is_empty() {
+        bail!("{path} has no rows");
+    }
+    curves.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    for (_, curve) in &mut curves {
+        curve.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    }
+    Ok(curves)
+}
+
+/// Linearly interpolates between `(x, y)` points sorted by `x`. Outside of the points, the
+/// nearest `y` is used.
+fn interpolate(points: &[(f64, f64)], x: f64) -> f64 {
+    let idx = points.partition_point(|(x1, _)| *x1 < x);
+    if idx == 0 {
+        return points[0].1;
+    }
+    if idx == points.len() {
+        return points[idx - 1].1;
+    }
+    let (x1, y1) = points[idx - 1];
+    let (x2, y2) = points[idx];
+    y1 + (y2 - y1) * (x - x1) / (x2 - x1)
+}
+
 // Everything below from
 // https://github.com/ITSLeeds/pct/blob/e630464efeaef539b18647b10745b863c9cd9948/R/uptake.R
 // TODO Switch to 2020 variations
@@ -26,52 +150,70 @@ pub fn calculate_uptake(uptake: &Uptake, total_distance_meters: f64) -> f64 {
 // TODO What does gradient represent -- an average or total or something over the entire route?
 // gradient should be in [0, 100]
 // This returns [0.0, 1.0]
-fn pct_gov_target(distance_meters: f64, gradient_percent: f64) -> f64 {
-    let alpha = -3.959;
-    let d1 = -0.5963;
-    let d2 = 1.866;
-    let d3 = 0.008050;
-    let h1 = -0.2710;
-    let i1 = 0.009394;
-    let i2 = -0.05135;
-
-    // TODO Why clamp to 30km?
-    let distance_km = (distance_meters / 1000.0).min(30.0);
-
-    let p = alpha
-        + (d1 * distance_km)
-        + (d2 * distance_km.sqrt())
-        + (d3 * distance_km.powi(2))
-        + (h1 * gradient_percent)
-        + (i1 * distance_km * gradient_percent)
-        + (i2 * distance_km.sqrt() * gradient_percent);
+fn logistic(model: &LogisticUptake, distance_meters: f64, gradient_percent: f64) -> f64 {
+    let mut distance_km = distance_meters / 1000.0;
+    if let Some(max) = model.max_distance_km {
+        distance_km = distance_km.min(max);
+    }
+
+    let p = model.intercept
+        + (model.distance * distance_km)
+        + (model.sqrt_distance * distance_km.sqrt())
+        + (model.distance_squared * distance_km.powi(2))
+        + (model.gradient * gradient_percent)
+        + (model.distance_gradient * distance_km * gradient_percent)
+        + (model.sqrt_distance_gradient * distance_km.sqrt() * gradient_percent);
     inverse_logit(p)
 }
 
+fn pct_gov_target(distance_meters: f64, gradient_percent: f64) -> f64 {
+    let model = LogisticUptake {
+        intercept: -3.959,
+        distance: -0.5963,
+        sqrt_distance: 1.866,
+        distance_squared: 0.008050,
+        gradient: -0.2710,
+        distance_gradient: 0.009394,
+        sqrt_distance_gradient: -0.05135,
+        // TODO Why clamp to 30km?
+        max_distance_km: Some(30.0),
+    };
+    logistic(&model, distance_meters, gradient_percent)
+}
+
 fn pct_go_dutch(distance_meters: f64, gradient_percent: f64) -> f64 {
-    let alpha = -3.959 + 2.523;
-    let d1 = -0.5963 - 0.07626;
-    let d2 = 1.866;
-    let d3 = 0.008050;
-    let h1 = -0.2710;
-    let i1 = 0.009394;
-    let i2 = -0.05135;
-
-    // TODO Why clamp to 30km?
-    let distance_km = (distance_meters / 1000.0).min(30.0);
-
-    let p = alpha
-        + (d1 * distance_km)
-        + (d2 * distance_km.sqrt())
-        + (d3 * distance_km.powi(2))
-        + (h1 * gradient_percent)
-        + (i1 * distance_km * gradient_percent)
-        + (i2 * distance_km.sqrt() * gradient_percent);
-    inverse_logit(p)
+    let model = LogisticUptake {
+        intercept: -3.959 + 2.523,
+        distance: -0.5963 - 0.07626,
+        sqrt_distance: 1.866,
+        distance_squared: 0.008050,
+        gradient: -0.2710,
+        distance_gradient: 0.009394,
+        sqrt_distance_gradient: -0.05135,
+        // TODO Why clamp to 30km?
+        max_distance_km: Some(30.0),
+    };
+    logistic(&model, distance_meters, gradient_percent)
+}
+
+// E-bikes make longer and hillier trips more likely
+fn pct_ebike(distance_meters: f64, gradient_percent: f64) -> f64 {
+    let model = LogisticUptake {
+        intercept: -3.959 + 2.523,
+        distance: -0.5963 - 0.07626 + 0.05509,
+        sqrt_distance: 1.866,
+        distance_squared: 0.008050 - 0.000295,
+        gradient: -0.2710 + 0.1812,
+        distance_gradient: 0.009394,
+        sqrt_distance_gradient: -0.05135,
+        max_distance_km: Some(30.0),
+    };
+    logistic(&model, distance_meters, gradient_percent)
 }
 
 fn inverse_logit(p: f64) -> f64 {
-    let result = p.exp() / (1.0 + p.exp());
+    // exp(p) / (1 + exp(p)) would be infinity / infinity for large p
+    let result = 1.0 / (1.0 + (-p).exp());
     if result < 0.0 || result > 1.0 {
         panic!("inverse_logit({p}) = {result}, which isn't between 0 and 1");
     }
@@ -84,12 +226,96 @@ mod tests {
     use fs_err::File;
     use std::io::Write;
 
+    #[test]
+    fn test_by_purpose() {
+        let uptake = Uptake::ByPurpose {
+            purposes: [(
+                "school".to_string(),
+                Uptake::CutoffMaxDistanceMeters(2000.0),
+            )]
+            .into_iter()
+            .collect(),
+            default: Box::new(Uptake::Identity),
+        };
+        assert_eq!(calculate_uptake(&uptake, 5000.0, Some("school")), 0.0);
+        assert_eq!(calculate_uptake(&uptake, 1000.0, Some("school")), 1.0);
+        assert_eq!(calculate_uptake(&uptake, 5000.0, Some("commute")), 1.0);
+        assert_eq!(calculate_uptake(&uptake, 5000.0, None), 1.0);
+    }
+
+    #[test]
+    fn test_lookup_table() {
+        let path = std::env::temp_dir().join("od2net_test_uptake_table.csv");
+        fs_err::write(
+            &path,
+            "distance_meters,gradient_percent,probability\n\
+             1000,0,0.5\n\
+             3000,0,0.1\n\
+             1000,4,0.3\n\
+             3000,4,0.1\n",
+        )
+        .unwrap();
+        let mut uptake = Uptake::LookupTable(path.display().to_string().into());
+        load_tables(&mut uptake, "unused").unwrap();
+
+        // Gradient is always 0 for now
+        assert_eq!(calculate_uptake(&uptake, 0.0, None), 0.5);
+        assert!((calculate_uptake(&uptake, 2000.0, None) - 0.3).abs() < 1e-9);
+        assert_eq!(calculate_uptake(&uptake, 5000.0, None), 0.1);
+
+        let Uptake::LookupTable(ref table) = uptake else {
+            unreachable!()
+        };
+        let per_gradient: Vec<(f64, f64)> = table
+            .curves
+            .iter()
+            .map(|(gradient, curve)| (*gradient, interpolate(curve, 1000.0)))
+            .collect();
+        assert!((interpolate(&per_gradient, 2.0) - 0.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_logistic() {
+        // With the same coefficients, the custom model reproduces the built-in one
+        let go_dutch: Uptake = serde_json::from_str(
+            r#"{ "Logistic": {
+                "intercept": -1.436,
+                "distance": -0.67256,
+                "sqrt_distance": 1.866,
+                "distance_squared": 0.00805,
+                "gradient": -0.271,
+                "distance_gradient": 0.009394,
+                "sqrt_distance_gradient": -0.05135,
+                "max_distance_km": 30.0
+            } }"#,
+        )
+        .unwrap();
+        for distance_meters in [0.0, 1500.0, 8000.0, 45000.0] {
+            let expected = pct_go_dutch(distance_meters, 0.0);
+            let actual = calculate_uptake(&go_dutch, distance_meters, None);
+            assert!((expected - actual).abs() < 1e-9);
+        }
+
+        // Unset coefficients are 0
+        let constant: Uptake = serde_json::from_str(r#"{ "Logistic": {} }"#).unwrap();
+        assert_eq!(calculate_uptake(&constant, 5000.0, None), 0.5);
+
+        // Extreme coefficients saturate instead of giving NaN
+        let certain: Uptake =
+            serde_json::from_str(r#"{ "Logistic": { "intercept": 1000.0 } }"#).unwrap();
+        assert_eq!(calculate_uptake(&certain, 5000.0, None), 1.0);
+        let impossible: Uptake =
+            serde_json::from_str(r#"{ "Logistic": { "intercept": -1000.0 } }"#).unwrap();
+        assert_eq!(calculate_uptake(&impossible, 5000.0, None), 0.0);
+    }
+
     // Load the resulting CSV files with https://www.csvplot.com to manually check
     #[test]
     fn test_pct() {
         for (path, model) in [
             ("gov_target.csv", pct_gov_target as fn(f64, f64) -> f64),
             ("go_dutch.csv", pct_go_dutch),
+            ("ebike.csv", pct_ebike),
         ] {
             let mut file = File::create(path).unwrap();
             writeln!(file, "distance_km,gradient_percent,pcycle").unwrap();
@@ -0,0 +1,112 @@
+//! Encode and decode the [Google polyline
+//! format](https://developers.google.com/maps/documentation/utilities/polylinealgorithm).
+
+use anyhow::Result;
+
+/// Encodes (lon, lat) points, using `precision` decimal places. The standard precision is 5.
+pub fn encode(pts: &[(f64, f64)], precision: u32) -> String {
+    let factor = 10_f64.powi(precision as i32);
+    let mut output = String::new();
+    let mut previous = (0, 0);
+    for (lon, lat) in pts {
+        // The format stores latitude first
+        let lat = (lat * factor).round() as i64;
+        let lon = (lon * factor).round() as i64;
+        encode_value(lat - previous.0, &mut output);
+        encode_value(lon - previous.1, &mut output);
+        previous = (lat, lon);
+    }
+    output
+}
+
+/// Decodes a polyline into (lon, lat) points. `precision` must match what was used to encode it.
+/// Fails if the input is cut off or has characters that can't appear in a polyline.
+pub fn decode(input: &str, precision: u32) -> Result<Vec<(f64, f64)>> {
+    let factor = 10_f64.powi(precision as i32);
+    let mut bytes = input.bytes().peekable();
+    let mut pts = Vec::new();
+    let mut current: (i64, i64) = (0, 0);
+    while bytes.peek().is_some() {
+        let lat = decode_value(&mut bytes)?;
+        if bytes.peek().is_none() {
+            bail!("Polyline ends with a latitude and no longitude");
+        }
+        let lon = decode_value(&mut bytes)?;
+        let (Some(lat), Some(lon)) = (current.0.checked_add(lat), current.1.checked_add(lon))
+        else {
+            bail!("Polyline has a coordinate out of range");
+        };
+        current = (lat, lon);
+        pts.push((current.1 as f64 / factor, current.0 as f64 / factor));
+    }
+    Ok(pts)
+}
+
+fn encode_value(delta: i64, output: &mut String) {
+    let mut value = if delta < 0 { !(delta << 1) } else { delta << 1 };
+    while value >= 0x20 {
+        output.push((((value & 0x1f) | 0x20) as u8 + 63) as char);
+        value >>= 5;
+    }
+    output.push((value as u8 + 63) as char);
+}
+
+fn decode_value<I: Iterator<Item = u8>>(bytes: &mut I) -> Result<i64> {
+    let mut result = 0;
+    let mut shift = 0;
+    loop {
+        let Some(byte) = bytes.next() else {
+            bail!("Polyline ends in the middle of a value");
+        };
+        if !(63..=126).contains(&byte) {
+            bail!("Polyline has an invalid character {:?}", byte as char);
+        }
+        // Values fit in 64 bits, 5 bits at a time
+        if shift > 60 {
+            bail!("Polyline has a value that's too long");
+        }
+        let chunk = (byte - 63) as i64;
+        result |= (chunk & 0x1f) << shift;
+        shift += 5;
+        if chunk < 0x20 {
+            break;
+        }
+    }
+    Ok(if result & 1 == 1 {
+        !(result >> 1)
+    } else {
+        result >> 1
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_polyline() {
+        // The example from Google's documentation
+        let pts = vec![(-120.2, 38.5), (-120.95, 40.7), (-126.453, 43.252)];
+        let encoded = encode(&pts, 5);
+        assert_eq!(encoded, "_p~iF~ps|U_ulLnnqC_mqNvxq`@");
+
+        let decoded = decode(&encoded, 5).unwrap();
+        assert_eq!(decoded.len(), pts.len());
+        for (expected, actual) in pts.iter().zip(decoded) {
+            assert!((expected.0 - actual.0).abs() < 1e-5);
+            assert!((expected.1 - actual.1).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_malformed_polyline() {
+        assert_eq!(decode("", 5).unwrap(), Vec::new());
+        // Cut off in the middle of a value, cut off after a latitude, and characters outside the
+        // format
+        for input in ["_p~iF~ps|U_ulLnnqC_", "_p~iF", "_p~iF ~ps|U", "\u{e9}"] {
+            assert!(decode(input, 5).is_err(), "{input} decoded");
+        }
+        // A value that never ends
+        assert!(decode(&"~".repeat(20), 5).is_err());
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Write};
+
+use anyhow::Result;
+use fast_paths::PathCalculator;
+use fs_err::File;
+use geojson::FeatureWriter;
+use indicatif::ParallelProgressIterator;
+use osm_reader::NodeID;
+use rayon::prelude::*;
+use rstar::RTree;
+
+use super::config::{CostFunction, InputConfig};
+use super::network::{Counts, Network};
+use super::requests::Request;
+use super::router::{
+    build_ch, build_closest_intersection, just_build_ch, route_all, IntersectionLocation,
+    PreparedCH, RoutingExtras,
+};
+use super::timer::Timer;
+use super::utils;
+
+/// Everything needed to route with one cost function
+pub struct PreparedProfile {
+    pub name: String,
+    pub prepared_ch: PreparedCH,
+    pub closest_intersection: RTree<IntersectionLocation>,
+}
+
+impl PreparedProfile {
+    /// Recalculates the network's cost using this profile, then builds a CH for it. If `ch_path`
+    /// is specified, the CH is loaded from or saved to that file.
+    pub fn new(
+        name: String,
+        cost: &CostFunction,
+        network: &mut Network,
+        ch_path: Option<&str>,
+        timer: &mut Timer,
+    ) -> Result<Self> {
+        timer.start(format!("Preparing profile {name}"));
+        let mut cost = cost.clone();
+        network.recalculate_cost(&mut cost)?;
+        let prepared_ch = match ch_path {
+            Some(path) => build_ch(path, network, timer)?,
+            None => just_build_ch(network, timer),
+        };
+        let closest_intersection =
+            build_closest_intersection(network, &prepared_ch.node_map, timer);
+        timer.stop();
+        Ok(Self {
+            name,
+            prepared_ch,
+            closest_intersection,
+        })
+    }
+}
+
+/// Describes one route
+#[derive(Clone, Copy, Debug)]
+pub struct RouteSummary {
+    pub length_meters: f64,
+    /// The sum of each edge's length multiplied by its LTS. Divide by `length_meters` to get the
+    /// average LTS along the route.
+    pub lts_exposure: f64,
+}
+
+/// Routes every request using every profile, and writes one CSV row per request, with the route
+/// length and LTS exposure for each profile.
+pub fn run(
+    profiles: &BTreeMap<String, CostFunction>,
+    network: &mut Network,
+    requests: Vec<Request>,
+    intermediate_directory: String,
+    output_path: String,
+    timer: &mut Timer,
+) -> Result<()> {
+    if profiles.is_empty() {
+        bail!("No profiles are defined in the config");
+    }
+
+    let mut prepared = Vec::new();
+    for (name, cost) in profiles {
+        prepared.push(PreparedProfile::new(
+            name.clone(),
+            cost,
+            network,
+            Some(&format!("{intermediate_directory}/ch_{name}.bin")),
+            timer,
+        )?);
+    }
+
+    timer.start("Routing every request with every profile");
+    let rows = compare(&prepared, network, &requests);
+    timer.stop();
+
+    timer.start(format!("Writing {output_path}"));
+    let mut file = BufWriter::new(File::create(output_path)?);
+    write!(file, "request,x1,y1,x2,y2")?;
+    for profile in &prepared {
+        write!(
+            file,
+            ",{}_length_meters,{}_lts_exposure",
+            profile.name, profile.name
+        )?;
+    }
+    writeln!(file)?;
+    for (idx, (req, row)) in requests.iter().zip(rows).enumerate() {
+        write!(file, "{idx},{},{},{},{}", req.x1, req.y1, req.x2, req.y2)?;
+        for summary in row {
+            // Leave the cells empty if there's no route
+            match summary {
+                Some(summary) => {
+                    write!(file, ",{},{}", summary.length_meters, summary.lts_exposure)?
+                }
+                None => write!(file, ",,")?,
+            }
+        }
+        writeln!(file)?;
+    }
+    timer.stop();
+
+    Ok(())
+}
+
+/// How one edge's usage changes between two profiles
+pub struct EdgeDelta {
+    pub node1: NodeID,
+    pub node2: NodeID,
+    pub count_before: f64,
+    pub count_after: f64,
+    /// The change in count, multiplied by the edge's length and LTS. Summing this over all edges
+    /// gives the change in total LTS exposure.
+    pub lts_exposure_delta: f64,
+}
+
+/// Calculates counts for all requests with the `before` and `after` profiles, then writes a
+/// GeoJSON file with the change in count and LTS exposure for every edge used by either.
+#[allow(clippy::too_many_arguments)]
+pub fn run_edge_delta(
+    before: &str,
+    after: &str,
+    config: &InputConfig,
+    network: &mut Network,
+    requests: Vec<Request>,
+    intermediate_directory: String,
+    output_path: String,
+    timer: &mut Timer,
+) -> Result<()> {
+    let mut counts = Vec::new();
+    for name in [before, after] {
+        let Some(cost) = config.profiles.get(name) else {
+            bail!("The config doesn't define a profile called {name}");
+        };
+        let profile = PreparedProfile::new(
+            name.to_string(),
+            cost,
+            network,
+            Some(&format!("{intermediate_directory}/ch_{name}.bin")),
+            timer,
+        )?;
+        timer.start(format!("Routing with profile {name}"));
+        counts.push(route_all(
+            &profile.prepared_ch,
+            &profile.closest_intersection,
+            &RoutingExtras::default(),
+            network,
+            requests.clone(),
+            config,
+            None,
+        ));
+        timer.stop();
+    }
+
+    timer.start(format!("Writing {output_path}"));
+    let deltas = edge_deltas(network, &counts[0], &counts[1]);
+    let total: f64 = deltas.iter().map(|d| d.lts_exposure_delta).sum();
+    println!("Total change in LTS exposure from {before} to {after}: {total:.1}");
+
+    let mut writer = FeatureWriter::from_writer(BufWriter::new(File::create(output_path)?));
+    for delta in deltas {
+        let edge = &network.edges[&(delta.node1, delta.node2)];
+        let mut feature = edge.to_geojson_for_detailed_output(delta.node1, delta.node2, true);
+        feature.set_property("count_before", delta.count_before);
+        feature.set_property("count_after", delta.count_after);
+        feature.set_property("count_delta", delta.count_after - delta.count_before);
+        feature.set_property("lts_exposure_delta", delta.lts_exposure_delta);
+        writer.write_feature(&feature)?;
+    }
+    writer.finish()?;
+    timer.stop();
+
+    Ok(())
+}
+
+/// Compares counts from two profiles for every edge used by either. Counts in both directions of
+/// an edge are summed.
+pub fn edge_deltas(network: &Network, before: &Counts, after: &Counts) -> Vec<EdgeDelta> {
+    let total = |counts: &Counts, node1, node2| {
+        counts.count_per_edge.get(&(node1, node2)).unwrap_or(&0.0)
+            + counts.count_per_edge.get(&(node2, node1)).unwrap_or(&0.0)
+    };
+
+    let mut deltas = Vec::new();
+    for ((node1, node2), edge) in &network.edges {
+        let count_before = total(before, *node1, *node2);
+        let count_after = total(after, *node1, *node2);
+        if count_before == 0.0 && count_after == 0.0 {
+            continue;
+        }
+        deltas.push(EdgeDelta {
+            node1: *node1,
+            node2: *node2,
+            count_before,
+            count_after,
+            lts_exposure_delta: (count_after - count_before)
+                * edge.length_meters
+                * (edge.lts as u8 as f64),
+        });
+    }
+    deltas
+}
+
+/// For every request (in the same order), returns the route from each profile (in the same order)
+pub fn compare(
+    profiles: &[PreparedProfile],
+    network: &Network,
+    requests: &[Request],
+) -> Vec<Vec<Option<RouteSummary>>> {
+    let progress = utils::progress_bar_for_count(requests.len());
+    requests
+        .par_iter()
+        .progress_with(progress)
+        .map_init(
+            || {
+                profiles
+                    .iter()
+                    .map(|p| fast_paths::create_calculator(&p.prepared_ch.ch))
+                    .collect::<Vec<_>>()
+            },
+            |path_calcs, req| {
+                profiles
+                    .iter()
+                    .zip(path_calcs.iter_mut())
+                    .map(|(profile, path_calc)| summarize_route(req, profile, path_calc, network))
+                    .collect()
+            },
+        )
+        .collect()
+}
+
+fn summarize_route(
+    req: &Request,
+    profile: &PreparedProfile,
+    path_calc: &mut PathCalculator,
+    network: &Network,
+) -> Option<RouteSummary> {
+    let start = profile
+        .closest_intersection
+        .nearest_neighbor(&[req.x1, req.y1])
+        .unwrap()
+        .data;
+    let end = profile
+        .closest_intersection
+        .nearest_neighbor(&[req.x2, req.y2])
+        .unwrap()
+        .data;
+    if start == end {
+        return None;
+    }
+    let path = profile.prepared_ch.calc_path(path_calc, start, end)?;
+
+    let mut summary = RouteSummary {
+        length_meters: 0.0,
+        lts_exposure: 0.0,
+    };
+    for pair in path.windows(2) {
+        let (i1, i2) = (pair[0], pair[1]);
+        let edge = network
+            .edges
+            .get(&(i1, i2))
+            .or_else(|| network.edges.get(&(i2, i1)))
+            .unwrap();
+        summary.length_meters += edge.length_meters;
+        summary.lts_exposure += edge.length_meters * (edge.lts as u8 as f64);
+    }
+    Some(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config_with;
+    use crate::network::tests::make_network;
+    use lts::LTS;
+
+    #[test]
+    fn test_compare_two_profiles() {
+        // A direct but stressful road from 1 to 2, and a quiet detour through 3
+        let mut network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.002, 0.0), (3, 0.001, 0.001)],
+            &[(1, 2), (1, 3), (3, 2)],
+        );
+        network.edges.get_mut(&(NodeID(1), NodeID(2))).unwrap().lts = LTS::LTS4;
+
+        let mut timer = Timer::new();
+        let profiles = vec![
+            PreparedProfile::new(
+                "direct".to_string(),
+                &CostFunction::Distance,
+                &mut network,
+                None,
+                &mut timer,
+            )
+            .unwrap(),
+            PreparedProfile::new(
+                "quiet".to_string(),
+                &CostFunction::ByLTS {
+                    lts1: 1.0,
+                    lts2: 1.0,
+                    lts3: 10.0,
+                    lts4: 10.0,
+                },
+                &mut network,
+                None,
+                &mut timer,
+            )
+            .unwrap(),
+        ];
+
+        let requests = vec![
+            Request::new(0.0, 0.0, 0.002, 0.0),
+            Request::new(0.0, 0.0, 0.001, 0.001),
+        ];
+        let rows = compare(&profiles, &network, &requests);
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|row| row.len() == 2));
+
+        let direct = rows[0][0].unwrap();
+        let quiet = rows[0][1].unwrap();
+        assert!(direct.length_meters < quiet.length_meters);
+        assert_eq!(direct.lts_exposure, direct.length_meters * 4.0);
+        assert_eq!(quiet.lts_exposure, quiet.length_meters);
+
+        // Both profiles agree on the second request
+        assert_eq!(
+            rows[1][0].unwrap().length_meters,
+            rows[1][1].unwrap().length_meters
+        );
+    }
+
+    #[test]
+    fn test_profile_ch_is_rebuilt_when_cost_changes() {
+        let mut network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.002, 0.0), (3, 0.001, 0.001)],
+            &[(1, 2), (1, 3), (3, 2)],
+        );
+        network.edges.get_mut(&(NodeID(1), NodeID(2))).unwrap().lts = LTS::LTS4;
+        let requests = vec![Request::new(0.0, 0.0, 0.002, 0.0)];
+        let path =
+            std::env::temp_dir().join(format!("od2net_test_ch_profile_{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // The same profile name is used with a different cost function, like after editing the
+        // config. The CH saved the first time must not be reused.
+        let mut timer = Timer::new();
+        let mut lengths = Vec::new();
+        for cost in [
+            CostFunction::Distance,
+            CostFunction::ByLTS {
+                lts1: 1.0,
+                lts2: 1.0,
+                lts3: 10.0,
+                lts4: 10.0,
+            },
+        ] {
+            let profile = PreparedProfile::new(
+                "profile".to_string(),
+                &cost,
+                &mut network,
+                Some(path),
+                &mut timer,
+            )
+            .unwrap();
+            let rows = compare(&[profile], &network, &requests);
+            lengths.push(rows[0][0].unwrap().length_meters);
+        }
+        fs_err::remove_file(path).unwrap();
+        assert!(lengths[0] < lengths[1]);
+    }
+
+    #[test]
+    fn test_edge_deltas() {
+        let mut network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.002, 0.0), (3, 0.001, 0.001)],
+            &[(1, 2), (1, 3), (3, 2)],
+        );
+        network.edges.get_mut(&(NodeID(1), NodeID(2))).unwrap().lts = LTS::LTS4;
+        let requests = vec![
+            Request::new(0.0, 0.0, 0.002, 0.0),
+            Request::new(0.002, 0.0, 0.0, 0.0),
+        ];
+
+        let config = config_with("");
+        let mut timer = Timer::new();
+        let mut counts = Vec::new();
+        for cost in [
+            CostFunction::Distance,
+            CostFunction::ByLTS {
+                lts1: 1.0,
+                lts2: 1.0,
+                lts3: 10.0,
+                lts4: 10.0,
+            },
+        ] {
+            let profile =
+                PreparedProfile::new("x".to_string(), &cost, &mut network, None, &mut timer)
+                    .unwrap();
+            counts.push(route_all(
+                &profile.prepared_ch,
+                &profile.closest_intersection,
+                &RoutingExtras::default(),
+                &network,
+                requests.clone(),
+                &config,
+                None,
+            ));
+        }
+
+        let deltas = edge_deltas(&network, &counts[0], &counts[1]);
+        assert_eq!(deltas.len(), 3);
+        for delta in &deltas {
+            let edge = &network.edges[&(delta.node1, delta.node2)];
+            let exposure = |count: f64| count * edge.length_meters * (edge.lts as u8 as f64);
+            assert_eq!(
+                delta.lts_exposure_delta,
+                exposure(delta.count_after) - exposure(delta.count_before)
+            );
+        }
+        // Everyone moves off the stressful road
+        let direct = deltas
+            .iter()
+            .find(|d| d.node1 == NodeID(1) && d.node2 == NodeID(2))
+            .unwrap();
+        assert_eq!(direct.count_before, 2.0);
+        assert_eq!(direct.count_after, 0.0);
+
+        // The total matches the per-LTS distances from both runs
+        let total_exposure = |counts: &Counts| -> f64 {
+            counts
+                .total_distance_by_lts
+                .iter()
+                .enumerate()
+                .map(|(lts, distance)| lts as f64 * distance)
+                .sum()
+        };
+        let total_delta: f64 = deltas.iter().map(|d| d.lts_exposure_delta).sum();
+        assert!(
+            (total_delta - (total_exposure(&counts[1]) - total_exposure(&counts[0]))).abs() < 1e-6
+        );
+    }
+}
@@ -1,17 +1,35 @@
 use anyhow::Result;
 use fs_err::File;
 
+use geo::{HaversineDistance, Point};
 use geojson::{Feature, FeatureReader, Geometry, Value};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Request {
     pub x1: f64,
     pub y1: f64,
     pub x2: f64,
     pub y2: f64,
+    /// Why this trip is made, like `commute` or `school`. Used by `Uptake::ByPurpose`.
+    pub purpose: Option<String>,
+    /// How many trips this request represents. The route's uptake is multiplied by this.
+    pub weight: f64,
 }
 
 impl Request {
+    /// One trip from (x1, y1) to (x2, y2), in WGS84, with no purpose
+    pub fn new(x1: f64, y1: f64, x2: f64, y2: f64) -> Self {
+        Self {
+            x1,
+            y1,
+            x2,
+            y2,
+            purpose: None,
+            weight: 1.0,
+        }
+    }
+
     pub fn as_geojson_string(&self) -> String {
         let geometry = Geometry::new(Value::LineString(vec![
             vec![self.x1, self.y1],
@@ -21,31 +39,137 @@ impl Request {
     }
 
     pub fn as_feature(&self) -> Feature {
-        Feature::from(Geometry::new(Value::LineString(vec![
+        let mut feature = Feature::from(Geometry::new(Value::LineString(vec![
             vec![self.x1, self.y1],
             vec![self.x2, self.y2],
-        ])))
+        ])));
+        if let Some(ref purpose) = self.purpose {
+            feature.set_property("purpose", purpose.clone());
+        }
+        if self.weight != 1.0 {
+            feature.set_property("count", self.weight);
+        }
+        feature
+    }
+
+    /// The straight-line distance between the origin and destination
+    pub fn crow_flies_distance_meters(&self) -> f64 {
+        Point::new(self.x1, self.y1).haversine_distance(&Point::new(self.x2, self.y2))
     }
 
+    /// Reads LineStrings with exactly 2 points. An optional numeric `count` property says how
+    /// many trips each one represents.
     pub fn load_from_geojson(path: String) -> Result<Vec<Self>> {
-        let reader = FeatureReader::from_reader(std::io::BufReader::new(File::open(path)?));
+        let reader = FeatureReader::from_reader(std::io::BufReader::new(File::open(&path)?));
         let mut requests = Vec::new();
         for feature in reader.features() {
             let feature = feature?;
-            if let Some(geometry) = feature.geometry {
-                if let Value::LineString(line_string) = geometry.value {
+            if let Some(ref geometry) = feature.geometry {
+                if let Value::LineString(ref line_string) = geometry.value {
                     if line_string.len() != 2 {
                         bail!("LineString doesn't have exactly 2 points");
                     }
+                    let weight = match feature.property("count") {
+                        Some(count) => match count.as_f64() {
+                            Some(count) if count >= 0.0 => count,
+                            _ => bail!("A LineString in {path} has an invalid count: {count}"),
+                        },
+                        None => 1.0,
+                    };
                     requests.push(Request {
                         x1: line_string[0][0],
                         y1: line_string[0][1],
                         x2: line_string[1][0],
                         y2: line_string[1][1],
+                        purpose: feature
+                            .property("purpose")
+                            .and_then(|x| x.as_str())
+                            .map(|x| x.to_string()),
+                        weight,
                     });
                 }
             }
         }
         Ok(requests)
     }
+
+    /// Reads a CSV file with columns `x1`, `y1`, `x2`, and `y2` in WGS84, and optionally `count`
+    /// and `purpose`
+    pub fn load_from_csv(path: &str) -> Result<Vec<Self>> {
+        let mut requests = Vec::new();
+        for rec in csv::Reader::from_reader(File::open(path)?).deserialize() {
+            let row: CsvRow = rec?;
+            if row.count < 0.0 {
+                bail!("{path} has a negative count {}", row.count);
+            }
+            requests.push(Request {
+                x1: row.x1,
+                y1: row.y1,
+                x2: row.x2,
+                y2: row.y2,
+                purpose: row.purpose.filter(|x| !x.is_empty()),
+                weight: row.count,
+            });
+        }
+        Ok(requests)
+    }
+}
+
+#[derive(Deserialize)]
+struct CsvRow {
+    x1: f64,
+    y1: f64,
+    x2: f64,
+    y2: f64,
+    #[serde(default = "default_count")]
+    count: f64,
+    purpose: Option<String>,
+}
+
+fn default_count() -> f64 {
+    1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geojson_round_trip() {
+        let request = |weight| Request {
+            x1: 0.0,
+            y1: 0.0,
+            x2: 1.0,
+            y2: 1.0,
+            purpose: None,
+            weight,
+        };
+        let requests = vec![request(2500.0), request(1.0)];
+
+        let path = std::env::temp_dir().join(format!(
+            "od2net_test_requests_{}.geojson",
+            std::process::id()
+        ));
+        let collection = geojson::FeatureCollection {
+            bbox: None,
+            features: requests.iter().map(|req| req.as_feature()).collect(),
+            foreign_members: None,
+        };
+        fs_err::write(&path, collection.to_string()).unwrap();
+        let copy = Request::load_from_geojson(path.display().to_string()).unwrap();
+        fs_err::remove_file(&path).unwrap();
+        let weights: Vec<f64> = copy.iter().map(|req| req.weight).collect();
+        assert_eq!(weights, vec![2500.0, 1.0]);
+    }
+
+    #[test]
+    fn test_load_from_csv() {
+        let path =
+            std::env::temp_dir().join(format!("od2net_test_requests_{}.csv", std::process::id()));
+        fs_err::write(&path, "x1,y1,x2,y2,count\n0,0,1,1,2500\n1,1,0,0,1\n").unwrap();
+        let requests = Request::load_from_csv(&path.display().to_string()).unwrap();
+        fs_err::remove_file(&path).unwrap();
+        let weights: Vec<f64> = requests.iter().map(|req| req.weight).collect();
+        assert_eq!(weights, vec![2500.0, 1.0]);
+    }
 }
@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Write};
+
+use anyhow::Result;
+use fs_err::File;
+use geo::{HaversineDistance, Point};
+use osm_reader::NodeID;
+use serde::{Deserialize, Serialize};
+
+use super::requests::Request;
+
+/// Describes the route found for one request. With `alternatives`, this is the average over the
+/// routes, weighted by their share.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RouteSummary {
+    pub x1: f64,
+    pub y1: f64,
+    pub x2: f64,
+    pub y2: f64,
+    pub length_meters: f64,
+    /// The straight-line distance between the request's endpoints
+    pub crow_flies_meters: f64,
+    /// Meters along the route on each LTS, indexed by LTS as u8
+    pub meters_by_lts: [f64; 5],
+    /// The first and last intersections along the route, or `None` if it stays on one edge
+    pub origin_node: Option<NodeID>,
+    pub destination_node: Option<NodeID>,
+    /// The sum of edge costs along the route, in the direction crossed. Edges only partly used
+    /// count for that fraction of their cost. Turn costs aren't included.
+    pub cost: f64,
+    /// Meters climbed along the route, or `None` if any edge along it has no elevation data
+    pub elevation_gain_meters: Option<f64>,
+}
+
+impl RouteSummary {
+    /// Starts an empty summary for a request
+    pub fn new(req: &Request) -> Self {
+        Self {
+            x1: req.x1,
+            y1: req.y1,
+            x2: req.x2,
+            y2: req.y2,
+            length_meters: 0.0,
+            crow_flies_meters: Point::new(req.x1, req.y1)
+                .haversine_distance(&Point::new(req.x2, req.y2)),
+            meters_by_lts: [0.0; 5],
+            origin_node: None,
+            destination_node: None,
+            cost: 0.0,
+            elevation_gain_meters: Some(0.0),
+        }
+    }
+
+    /// How much longer the route is than a straight line. `None` if the endpoints are in the same
+    /// place.
+    pub fn circuity(&self) -> Option<f64> {
+        (self.crow_flies_meters > 0.0).then(|| self.length_meters / self.crow_flies_meters)
+    }
+
+    /// The highest LTS used along the route, as u8
+    pub fn max_lts(&self) -> u8 {
+        (0..self.meters_by_lts.len())
+            .rev()
+            .find(|lts| self.meters_by_lts[*lts] > 0.0)
+            .unwrap_or(0) as u8
+    }
+
+    /// The percent of the route's length on some LTS
+    pub fn percent_on_lts(&self, lts: usize) -> f64 {
+        if self.length_meters == 0.0 {
+            return 0.0;
+        }
+        100.0 * self.meters_by_lts[lts] / self.length_meters
+    }
+}
+
+/// Summarizes a set of values
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Distribution {
+    pub min: f64,
+    pub p10: f64,
+    pub median: f64,
+    pub mean: f64,
+    pub p90: f64,
+    pub max: f64,
+}
+
+/// Each bucket in a `Histogram` covers values up to this factor bigger than the last
+const BUCKET_GROWTH: f64 = 1.01;
+
+/// Summarizes values as they're added, without keeping them all. Values are grouped into buckets
+/// about 1% wide, so percentiles are close to but not always exactly the real values.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Histogram {
+    /// For each bucket with values, how many there are and the smallest one
+    buckets: BTreeMap<i32, (u64, f64)>,
+    count: u64,
+    sum: f64,
+    max: f64,
+}
+
+impl Histogram {
+    pub fn add(&mut self, value: f64) {
+        // Zero and negative values share one bucket
+        let bucket = if value > 0.0 {
+            (value.ln() / BUCKET_GROWTH.ln()).floor() as i32
+        } else {
+            i32::MIN
+        };
+        let entry = self.buckets.entry(bucket).or_insert((0, value));
+        entry.0 += 1;
+        entry.1 = entry.1.min(value);
+        if self.count == 0 || value > self.max {
+            self.max = value;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    /// Adds other to this one
+    pub fn combine(&mut self, other: Histogram) {
+        for (bucket, (count, min)) in other.buckets {
+            let entry = self.buckets.entry(bucket).or_insert((0, min));
+            entry.0 += count;
+            entry.1 = entry.1.min(min);
+        }
+        if other.count > 0 && (self.count == 0 || other.max > self.max) {
+            self.max = other.max;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+
+    /// `None` if there are no values
+    pub fn distribution(&self) -> Option<Distribution> {
+        if self.count == 0 {
+            return None;
+        }
+        // The nearest-rank percentile, rounded down to the smallest value in its bucket
+        let percentile = |p: f64| {
+            let rank = ((p / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+            let mut seen = 0;
+            for (count, min) in self.buckets.values() {
+                seen += count;
+                if seen >= rank {
+                    return *min;
+                }
+            }
+            self.max
+        };
+        Some(Distribution {
+            min: percentile(0.0),
+            p10: percentile(10.0),
+            median: percentile(50.0),
+            mean: self.sum / self.count as f64,
+            p90: percentile(90.0),
+            max: self.max,
+        })
+    }
+}
+
+/// Distributions of per-route summaries, built up while routing in constant memory
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RouteHistograms {
+    length_meters: Histogram,
+    circuity: Histogram,
+    /// For LTS 1 to 4
+    percent_lts: [Histogram; 4],
+}
+
+impl RouteHistograms {
+    pub fn add(&mut self, summary: &RouteSummary) {
+        self.length_meters.add(summary.length_meters);
+        if let Some(circuity) = summary.circuity() {
+            self.circuity.add(circuity);
+        }
+        for (idx, histogram) in self.percent_lts.iter_mut().enumerate() {
+            histogram.add(summary.percent_on_lts(idx + 1));
+        }
+    }
+
+    /// Adds other to this one
+    pub fn combine(&mut self, other: RouteHistograms) {
+        self.length_meters.combine(other.length_meters);
+        self.circuity.combine(other.circuity);
+        for (histogram, other) in self.percent_lts.iter_mut().zip(other.percent_lts) {
+            histogram.combine(other);
+        }
+    }
+}
+
+/// Distributions of per-route summaries, for sanity-checking a run. Every successful request
+/// counts once, regardless of its weight or uptake. Each field is `None` when there are no
+/// routes.
+#[derive(Serialize)]
+pub struct RouteStats {
+    pub length_meters: Option<Distribution>,
+    /// Only from requests with endpoints in different places
+    pub circuity: Option<Distribution>,
+    pub percent_lts1: Option<Distribution>,
+    pub percent_lts2: Option<Distribution>,
+    pub percent_lts3: Option<Distribution>,
+    pub percent_lts4: Option<Distribution>,
+}
+
+impl RouteStats {
+    pub fn new(histograms: &RouteHistograms) -> Self {
+        let percent = |lts: usize| histograms.percent_lts[lts - 1].distribution();
+        Self {
+            length_meters: histograms.length_meters.distribution(),
+            circuity: histograms.circuity.distribution(),
+            percent_lts1: percent(1),
+            percent_lts2: percent(2),
+            percent_lts3: percent(3),
+            percent_lts4: percent(4),
+        }
+    }
+}
+
+/// Writes one CSV row per summary
+pub fn write_csv(path: &str, summaries: &[RouteSummary]) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(
+        file,
+        "x1,y1,x2,y2,length_meters,crow_flies_meters,circuity,percent_lts1,percent_lts2,percent_lts3,percent_lts4"
+    )?;
+    for s in summaries {
+        writeln!(
+            file,
+            "{},{},{},{},{},{},{},{},{},{},{}",
+            s.x1,
+            s.y1,
+            s.x2,
+            s.y2,
+            s.length_meters,
+            s.crow_flies_meters,
+            s.circuity().map(|x| x.to_string()).unwrap_or_default(),
+            s.percent_on_lts(1),
+            s.percent_on_lts(2),
+            s.percent_on_lts(3),
+            s.percent_on_lts(4)
+        )?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distribution() {
+        assert!(Histogram::default().distribution().is_none());
+
+        let mut histogram = Histogram::default();
+        for x in (1..=10).rev() {
+            histogram.add(x as f64);
+        }
+        let dist = histogram.distribution().unwrap();
+        assert_eq!(dist.min, 1.0);
+        assert_eq!(dist.p10, 1.0);
+        assert_eq!(dist.median, 5.0);
+        assert_eq!(dist.mean, 5.5);
+        assert_eq!(dist.p90, 9.0);
+        assert_eq!(dist.max, 10.0);
+
+        // Combining gives the same result as adding everything to one
+        let mut first = Histogram::default();
+        let mut second = Histogram::default();
+        for x in 1..=10 {
+            if x % 2 == 0 {
+                first.add(x as f64);
+            } else {
+                second.add(x as f64);
+            }
+        }
+        first.combine(second);
+        assert_eq!(first.distribution(), Some(dist));
+
+        // Close values share a bucket, so percentiles are approximate
+        let mut histogram = Histogram::default();
+        for x in [1000.0, 1001.0, 1002.0, 0.0] {
+            histogram.add(x);
+        }
+        let dist = histogram.distribution().unwrap();
+        assert_eq!(dist.min, 0.0);
+        assert_eq!(dist.median, 1000.0);
+        assert_eq!(dist.p90, 1000.0);
+        assert_eq!(dist.max, 1002.0);
+    }
+}
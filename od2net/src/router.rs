@@ -1,57 +1,135 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::io::{BufReader, BufWriter};
+use std::sync::Arc;
 
 use anyhow::Result;
 use fast_paths::{FastGraph, InputGraph, PathCalculator};
 use fs_err::File;
-use indicatif::ParallelProgressIterator;
-use osm_reader::NodeID;
+use geo::{HaversineDistance, LineInterpolatePoint, LineLocatePoint, LineString, Point};
+use osm_reader::{NodeID, WayID};
 use rayon::prelude::*;
-use rstar::primitives::GeomWithData;
+use rstar::primitives::{GeomWithData, Line};
 use rstar::RTree;
 use serde::{Deserialize, Serialize};
 
-use super::config::Uptake;
-use super::network::{Counts, Network, Position};
+use super::alternatives;
+use super::checkpoint::{self, Checkpoints};
+use super::config::{InputConfig, Router, SameEndpoints, SnapTo};
+use super::dijkstra::{self, PlainGraph};
+use super::many_to_many::{RouteMatrix, UpwardGraph};
+use super::network::{
+    CountArea, Counts, EdgeLocation, FarSnap, Network, Position, TurnRestrictions,
+};
 use super::node_map::{deserialize_nodemap, NodeMap};
 use super::plugins::uptake;
 use super::requests::Request;
+use super::route_stats::RouteSummary;
 use super::timer::Timer;
 use super::utils;
+use super::zones::ZoneIndex;
 
 // TODO Vary ch_path with CostFunction
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     ch_path: &str,
     network: &Network,
     requests: Vec<Request>,
-    uptake: &Uptake,
+    config: &InputConfig,
+    count_area: Option<&CountArea>,
+    zones: Option<&ZoneIndex>,
+    checkpoints: Option<&Checkpoints>,
     timer: &mut Timer,
 ) -> Result<Counts> {
-    let prepared_ch = build_ch(ch_path, network, timer)?;
+    if let Some(ref breakdown) = config.flow_breakdown {
+        breakdown.validate()?;
+    }
+    if config.router == Router::Dijkstra {
+        if config.snap_to == SnapTo::Edges {
+            bail!("The Dijkstra router doesn't support snapping to edges yet");
+        }
+        let router = dijkstra::DijkstraRouter::new(network, timer);
+        return checkpoint::route_in_chunks(checkpoints, requests, network, config, |chunk| {
+            router.route_all(network, chunk, config, count_area, zones)
+        });
+    }
+
+    let prepared_ch = build_ch_for_router(ch_path, network, config.router, timer)?;
     let closest_intersection = build_closest_intersection(network, &prepared_ch.node_map, timer);
+    let mut extras = RoutingExtras::new(network, config, timer);
+    extras.zones = zones;
+    checkpoint::route_in_chunks(checkpoints, requests, network, config, |chunk| {
+        route_all(
+            &prepared_ch,
+            &closest_intersection,
+            &extras,
+            network,
+            chunk,
+            config,
+            count_area,
+        )
+    })
+}
 
+/// Routes every request in parallel, summing up the counts
+pub fn route_all(
+    prepared_ch: &PreparedCH,
+    closest_intersection: &RTree<IntersectionLocation>,
+    extras: &RoutingExtras,
+    network: &Network,
+    requests: Vec<Request>,
+    config: &InputConfig,
+    count_area: Option<&CountArea>,
+) -> Counts {
     let progress = utils::progress_bar_for_count(requests.len());
     let num_requests = requests.len();
+    // Routes between intersections are found a batch of requests at a time
+    let upward_graph = if extras.closest_edge.is_none() && num_requests > 1 {
+        match UpwardGraph::new(&prepared_ch.ch) {
+            Ok(graph) => Some(graph),
+            Err(err) => {
+                warn!("Routing each request separately: {err}");
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-    let counts = requests
+    let mut counts = requests
         .into_par_iter()
+        .chunks(REQUESTS_PER_BATCH)
         // Split the work evenly among CPUs. Otherwise rayon fold too eagerly splits, creating too
         // many PerThreadStates in-memory. See
         // https://users.rust-lang.org/t/rayon-with-expensive-to-construct-combine-accumulator/78252/3.
-        .with_min_len(num_requests / num_cpus::get())
-        .progress_with(progress)
-        .fold(PerThreadState::new, |mut acc, req| {
+        .with_min_len(num_requests / REQUESTS_PER_BATCH / num_cpus::get())
+        .fold(PerThreadState::new, |mut acc, batch| {
             if acc.path_calc.is_none() {
                 acc.path_calc = Some(fast_paths::create_calculator(&prepared_ch.ch));
             }
-            handle_request(
-                req,
-                &mut acc.counts,
-                acc.path_calc.as_mut().unwrap(),
-                &closest_intersection,
-                &prepared_ch,
-                uptake,
-                network,
-            );
+            let matrix = match upward_graph {
+                Some(ref graph) => RouteMatrix::new(
+                    graph,
+                    prepared_ch,
+                    &intersection_pairs(&batch, closest_intersection, extras, network),
+                ),
+                None => RouteMatrix::default(),
+            };
+            progress.inc(batch.len() as u64);
+            for req in batch {
+                handle_request(
+                    req,
+                    &mut acc.counts,
+                    acc.path_calc.as_mut().unwrap(),
+                    &matrix,
+                    closest_intersection,
+                    extras,
+                    prepared_ch,
+                    config,
+                    count_area,
+                    network,
+                );
+            }
             acc
         })
         .reduce_with(|mut acc1, acc2| {
@@ -60,8 +138,69 @@ pub fn run(
         })
         .unwrap()
         .counts;
+    progress.finish();
+    counts.uptake_scenarios = config.uptake_scenarios.keys().cloned().collect();
+    counts.set_flow_groups(config.flow_breakdown.as_ref());
+    counts
+}
+
+/// How many requests `route_all` finds routes for at once. Bigger batches share more endpoints,
+/// but the many-to-many search keeps the search space of every destination in memory.
+const REQUESTS_PER_BATCH: usize = 1000;
+
+/// The pairs of different intersections that each request snaps to, when snapping to
+/// intersections
+fn intersection_pairs(
+    batch: &[Request],
+    closest_intersection: &RTree<IntersectionLocation>,
+    extras: &RoutingExtras,
+    network: &Network,
+) -> Vec<(usize, usize)> {
+    batch
+        .iter()
+        .filter_map(
+            |req| match snap_request(req, closest_intersection, extras, network)? {
+                (Snap::Intersection(start), Snap::Intersection(end)) if start.data != end.data => {
+                    Some((start.data, end.data))
+                }
+                _ => None,
+            },
+        )
+        .collect()
+}
 
-    Ok(counts)
+/// Optional structures used by `handle_request`, depending on the config
+#[derive(Default)]
+pub struct RoutingExtras<'a> {
+    /// If specified, endpoints snap to the closest point along an edge, instead of the closest
+    /// intersection
+    pub closest_edge: Option<RTree<EdgeLocation>>,
+    /// If specified, counts are split between alternative routes
+    pub alternatives: Option<PlainGraph>,
+    /// If specified, routes crossing each zone are counted
+    pub zones: Option<&'a ZoneIndex>,
+}
+
+impl RoutingExtras<'_> {
+    pub fn new(network: &Network, config: &InputConfig, timer: &mut Timer) -> Self {
+        let closest_edge = match config.snap_to {
+            SnapTo::Intersections => None,
+            SnapTo::Edges => Some(build_closest_edge(network, timer)),
+        };
+        let alternatives = if config.alternatives.is_some() {
+            timer.start("Build graph for alternative routes");
+            let graph = PlainGraph::new(network);
+            timer.stop();
+            Some(graph)
+        } else {
+            None
+        };
+        Self {
+            closest_edge,
+            alternatives,
+            zones: None,
+        }
+    }
 }
 
 struct PerThreadState {
@@ -78,82 +217,715 @@ impl PerThreadState {
     }
 }
 
+/// If `count_area` is specified, only edges, intersections, origins, and destinations in it get
+/// counts. Routes between intersections come from `matrix` if it has them.
+#[allow(clippy::too_many_arguments)]
 pub fn handle_request(
     req: Request,
     counts: &mut Counts,
     path_calc: &mut fast_paths::PathCalculator,
+    matrix: &RouteMatrix,
     closest_intersection: &RTree<IntersectionLocation>,
+    extras: &RoutingExtras,
     prepared_ch: &PreparedCH,
-    uptake: &Uptake,
+    config: &InputConfig,
+    count_area: Option<&CountArea>,
     network: &Network,
 ) {
-    let start = closest_intersection
-        .nearest_neighbor(&[req.x1, req.y1])
-        .unwrap()
-        .data;
-    let end = closest_intersection
-        .nearest_neighbor(&[req.x2, req.y2])
-        .unwrap()
-        .data;
-    if start == end {
-        counts.errors_same_endpoints.push(req);
+    let Some((start, end)) = snap_request(&req, closest_intersection, extras, network) else {
+        // The network is empty
+        counts.errors_no_path.push(req);
+        return;
+    };
+    let Some((origin_snap_meters, destination_snap_meters)) =
+        check_snap_distances(&req, counts, config, start.position(), end.position())
+    else {
         return;
+    };
+
+    let route = match (&start, &end) {
+        (Snap::Intersection(start), Snap::Intersection(end)) => {
+            if start.data == end.data {
+                let node = prepared_ch.node_map.translate_id(start.data);
+                handle_same_endpoints(
+                    req,
+                    counts,
+                    Some(node),
+                    count_area.map_or(true, |area| area.contains_node(node)),
+                    config,
+                    origin_snap_meters,
+                    destination_snap_meters,
+                );
+                return;
+            }
+
+            // A sanity check that snapping works -- manually check these:
+            if false {
+                debug!(
+                    "req from {}, {} -> {}, {} snaps to http://openstreetmap.org/node/{}",
+                    req.x1,
+                    req.y1,
+                    req.x2,
+                    req.y2,
+                    prepared_ch.node_map.translate_id(start.data)
+                );
+            }
+
+            let path = matrix.get(start.data, end.data).unwrap_or_else(|| {
+                prepared_ch
+                    .calc_path(path_calc, start.data, end.data)
+                    .map(|path| path.into())
+            });
+            let Some(path) = path else {
+                counts.errors_no_path.push(req);
+                return;
+            };
+            Route::from_path(path)
+        }
+        (Snap::Edge(start), Snap::Edge(end)) => {
+            if start.edge == end.edge && start.fraction == end.fraction {
+                handle_same_endpoints(
+                    req,
+                    counts,
+                    None,
+                    count_area.map_or(true, |area| area.contains_edge(start.edge.0, start.edge.1)),
+                    config,
+                    origin_snap_meters,
+                    destination_snap_meters,
+                );
+                return;
+            }
+            let Some((route, _)) = route_between_edges(start, end, path_calc, prepared_ch, network)
+            else {
+                counts.errors_no_path.push(req);
+                return;
+            };
+            route
+        }
+        _ => unreachable!(),
+    };
+
+    count_route(
+        req,
+        route,
+        counts,
+        extras.alternatives.as_ref(),
+        extras.zones,
+        config,
+        count_area,
+        network,
+        origin_snap_meters,
+        destination_snap_meters,
+    );
+}
+
+/// Returns how far each end of the request snapped, in meters. If either is farther than
+/// `max_snap_meters`, records the request as failed and returns None.
+pub(crate) fn check_snap_distances(
+    req: &Request,
+    counts: &mut Counts,
+    config: &InputConfig,
+    snapped_origin: (f64, f64),
+    snapped_destination: (f64, f64),
+) -> Option<(f64, f64)> {
+    let origin_snap_meters = snap_distance(req.x1, req.y1, snapped_origin);
+    let destination_snap_meters = snap_distance(req.x2, req.y2, snapped_destination);
+    if let Some(max) = config.max_snap_meters {
+        if origin_snap_meters > max || destination_snap_meters > max {
+            counts.errors_far_snap.push(FarSnap {
+                req: req.clone(),
+                snapped_origin,
+                snapped_destination,
+                origin_snap_meters,
+                destination_snap_meters,
+            });
+            return None;
+        }
     }
+    Some((origin_snap_meters, destination_snap_meters))
+}
+
+/// Counts a route through these intersections for a request
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn count_path(
+    req: Request,
+    nodes: Vec<NodeID>,
+    counts: &mut Counts,
+    alternatives: Option<&PlainGraph>,
+    zones: Option<&ZoneIndex>,
+    config: &InputConfig,
+    count_area: Option<&CountArea>,
+    network: &Network,
+    origin_snap_meters: f64,
+    destination_snap_meters: f64,
+) {
+    count_route(
+        req,
+        Route::from_path(nodes.into()),
+        counts,
+        alternatives,
+        zones,
+        config,
+        count_area,
+        network,
+        origin_snap_meters,
+        destination_snap_meters,
+    );
+}
+
+/// Counts a route for a request, first splitting it between alternative routes if `alternatives`
+/// is specified
+#[allow(clippy::too_many_arguments)]
+fn count_route(
+    req: Request,
+    route: Route,
+    counts: &mut Counts,
+    alternatives: Option<&PlainGraph>,
+    zones: Option<&ZoneIndex>,
+    config: &InputConfig,
+    count_area: Option<&CountArea>,
+    network: &Network,
+    origin_snap_meters: f64,
+    destination_snap_meters: f64,
+) {
+    // Alternatives start and end the same way
+    let in_area = match count_area {
+        Some(area) => (route.origin_in_area(area), route.destination_in_area(area)),
+        None => (true, true),
+    };
+    let routes = split_alternatives(route, alternatives, config);
 
-    // A sanity check that snapping works -- manually check these:
-    if false {
-        debug!(
-            "req from {}, {} -> {}, {} snaps to http://openstreetmap.org/node/{}",
-            req.x1,
-            req.y1,
-            req.x2,
-            req.y2,
-            prepared_ch.node_map.translate_id(start)
+    let mut summary = RouteSummary::new(&req);
+    // Alternatives start and end at the same intersections
+    summary.origin_node = routes[0].0.nodes.first().copied();
+    summary.destination_node = routes[0].0.nodes.last().copied();
+    let mut count = 0.0;
+    for (route, share) in routes {
+        count += add_route_counts(
+            &req,
+            &route,
+            share,
+            &mut summary,
+            counts,
+            zones,
+            config,
+            count_area,
+            network,
         );
     }
-
-    let Some(path) = path_calc.calc_path(&prepared_ch.ch, start, end) else {
-        counts.errors_no_path.push(req);
+    counts.route_histograms.add(&summary);
+    if config.collect.route_summaries {
+        counts.route_summaries.push(summary);
+    }
+    // Endpoint counts only use the main uptake
+    if count == 0.0 {
         return;
-    };
+    }
+    add_endpoint_counts(
+        &req,
+        counts,
+        count,
+        config,
+        in_area,
+        origin_snap_meters,
+        destination_snap_meters,
+    );
+}
+
+/// Returns the routes a request is split between, with each one's share. That's alternatives to
+/// `route` if the config asks for them, or just `route`.
+pub(crate) fn split_alternatives(
+    route: Route,
+    alternatives: Option<&PlainGraph>,
+    config: &InputConfig,
+) -> Vec<(Route, f64)> {
+    match (alternatives, &config.alternatives) {
+        (Some(graph), Some(alternatives)) if route.nodes.len() >= 2 => {
+            // Search from the copy of the first intersection the CH started at
+            let start = graph.start(route.nodes[0], route.start.map(|((from, _), _)| from));
+            alternatives::find(graph, start, route.nodes.to_vec(), alternatives)
+                .into_iter()
+                .map(|(nodes, share)| {
+                    (
+                        Route {
+                            nodes: nodes.into(),
+                            start: route.start,
+                            end: route.end,
+                        },
+                        share,
+                    )
+                })
+                .collect()
+        }
+        _ => vec![(route, 1.0)],
+    }
+}
+
+/// Adds counts along one route for a request, scaled by `share`, and returns the main count. The
+/// route's length and LTS are added to `summary`, also scaled by `share`.
+#[allow(clippy::too_many_arguments)]
+fn add_route_counts(
+    req: &Request,
+    route: &Route,
+    share: f64,
+    summary: &mut RouteSummary,
+    counts: &mut Counts,
+    zones: Option<&ZoneIndex>,
+    config: &InputConfig,
+    count_area: Option<&CountArea>,
+    network: &Network,
+) -> f64 {
+    let edges = route.edges(network);
+
     // fast_paths returns the total cost, but it's not necessarily the right unit. Calculate how
     // long this route is.
     let mut total_distance = 0.0;
-    for pair in path.get_nodes().windows(2) {
-        let i1 = prepared_ch.node_map.translate_id(pair[0]);
-        let i2 = prepared_ch.node_map.translate_id(pair[1]);
+    for ((i1, i2), meters) in &edges {
         let edge = network
             .edges
-            .get(&(i1, i2))
-            .or_else(|| network.edges.get(&(i2, i1)))
+            .get(&(*i1, *i2))
+            .or_else(|| network.edges.get(&(*i2, *i1)))
             .unwrap();
-        total_distance += edge.length_meters;
+        total_distance += meters;
 
-        counts.total_distance_by_lts[edge.lts as u8 as usize] += edge.length_meters;
+        counts.total_distance_by_lts[edge.lts as u8 as usize] += share * meters;
+        summary.length_meters += share * meters;
+        summary.meters_by_lts[edge.lts as u8 as usize] += share * meters;
+
+        let forwards = network.edges.contains_key(&(*i1, *i2));
+        let fraction = if edge.length_meters > 0.0 {
+            meters / edge.length_meters
+        } else {
+            1.0
+        };
+        let cost = if forwards {
+            edge.forward_cost
+        } else {
+            edge.backward_cost
+        };
+        summary.cost += share * fraction * cost.unwrap_or(0) as f64;
+        summary.elevation_gain_meters = match (summary.elevation_gain_meters, edge.elevation_gain) {
+            (Some(total), Some((gain, loss))) => {
+                Some(total + share * fraction * if forwards { gain } else { loss })
+            }
+            _ => None,
+        };
     }
 
-    let count = uptake::calculate_uptake(uptake, total_distance);
+    let count = share
+        * req.weight
+        * uptake::calculate_uptake(&config.uptake, total_distance, req.purpose.as_deref());
+    // The route doesn't depend on uptake, so evaluate every scenario for it now
+    let scenario_counts: Vec<f64> = config
+        .uptake_scenarios
+        .values()
+        .map(|uptake| {
+            share
+                * req.weight
+                * uptake::calculate_uptake(uptake, total_distance, req.purpose.as_deref())
+        })
+        .collect();
     // TODO Pick an epsilon based on the final rounding we do... though it's possible 1e6 trips
     // cross a segment each with probability 1e-6?
-    if count == 0.0 {
-        return;
+    if count == 0.0 && scenario_counts.iter().all(|x| *x == 0.0) {
+        return 0.0;
     }
 
-    for pair in path.get_nodes().windows(2) {
-        // TODO Actually, don't do this translation until the very end
-        let i1 = prepared_ch.node_map.translate_id(pair[0]);
-        let i2 = prepared_ch.node_map.translate_id(pair[1]);
+    // Zone counts only use the main uptake
+    if let Some(zones) = zones {
+        if count != 0.0 {
+            zones.add_route_counts(req, &edges, count, network, count_area, counts);
+        }
+    }
+    // Flow breakdowns only use the main uptake
+    let flow_group = config
+        .flow_breakdown
+        .as_ref()
+        .filter(|_| count != 0.0)
+        .map(|breakdown| {
+            counts.flow_group_index(breakdown, total_distance, req.purpose.as_deref())
+        });
+    for ((i1, i2), _) in edges {
+        if count_area.is_some_and(|area| !area.contains_edge(i1, i2)) {
+            continue;
+        }
         *counts.count_per_edge.entry((i1, i2)).or_insert(0.0) += count;
+        if !scenario_counts.is_empty() {
+            let totals = counts
+                .uptake_scenario_count_per_edge
+                .entry((i1, i2))
+                .or_insert_with(|| vec![0.0; scenario_counts.len()]);
+            for (total, count) in totals.iter_mut().zip(&scenario_counts) {
+                *total += count;
+            }
+        }
+        if let Some(group) = flow_group {
+            let totals = counts
+                .flow_group_count_per_edge
+                .entry((i1, i2))
+                .or_default();
+            if totals.len() <= group {
+                totals.resize(group + 1, 0.0);
+            }
+            totals[group] += count;
+        }
+    }
+    // Intersection counts only use the main uptake
+    if count == 0.0 {
+        return 0.0;
+    }
+    let in_area = |id: NodeID| count_area.map_or(true, |area| area.contains_node(id));
+    for id in route.nodes.iter() {
+        if in_area(*id) {
+            *counts.count_per_node.entry(*id).or_insert(0.0) += count;
+        }
+    }
+    if config.collect.movements {
+        // Edges only partly used still enter or leave an intersection
+        let mut sequence = Vec::with_capacity(route.nodes.len() + 2);
+        sequence.extend(route.start.map(|((from, _), _)| from));
+        sequence.extend(route.nodes.iter().copied());
+        sequence.extend(route.end.map(|((_, to), _)| to));
+        for movement in sequence.windows(3) {
+            if !in_area(movement[1]) {
+                continue;
+            }
+            *counts
+                .count_per_movement
+                .entry((movement[0], movement[1], movement[2]))
+                .or_insert(0.0) += count;
+        }
+    }
+    count
+}
+
+/// Handles a request with both ends snapped to the same place. `node` is the intersection there,
+/// if any. Nothing is counted for zero-distance trips unless the place is `in_area`.
+pub(crate) fn handle_same_endpoints(
+    req: Request,
+    counts: &mut Counts,
+    node: Option<NodeID>,
+    in_area: bool,
+    config: &InputConfig,
+    origin_snap_meters: f64,
+    destination_snap_meters: f64,
+) {
+    match config.same_endpoints {
+        SameEndpoints::Fail => {
+            counts.errors_same_endpoints.push(req);
+        }
+        SameEndpoints::Drop => {
+            counts.num_same_endpoints_dropped += 1;
+        }
+        SameEndpoints::ZeroDistance => {
+            counts.num_same_endpoints_zero_distance += 1;
+            let count =
+                req.weight * uptake::calculate_uptake(&config.uptake, 0.0, req.purpose.as_deref());
+            if count != 0.0 && in_area {
+                if let Some(node) = node {
+                    *counts.count_per_node.entry(node).or_insert(0.0) += count;
+                }
+                add_endpoint_counts(
+                    &req,
+                    counts,
+                    count,
+                    config,
+                    (true, true),
+                    origin_snap_meters,
+                    destination_snap_meters,
+                );
+            }
+        }
+    }
+}
+
+/// `in_area` says whether to count the origin and destination
+fn add_endpoint_counts(
+    req: &Request,
+    counts: &mut Counts,
+    count: f64,
+    config: &InputConfig,
+    in_area: (bool, bool),
+    origin_snap_meters: f64,
+    destination_snap_meters: f64,
+) {
+    let (origin_weight, destination_weight) = match config.snap_distance_decay {
+        Some(decay) => (
+            decay.weight(origin_snap_meters),
+            decay.weight(destination_snap_meters),
+        ),
+        None => (1.0, 1.0),
+    };
+    if in_area.0 {
+        *counts
+            .count_per_origin
+            .entry(Position::from_degrees(req.x1, req.y1))
+            .or_insert(0.0) += count * origin_weight;
+    }
+    if in_area.1 {
+        *counts
+            .count_per_destination
+            .entry(Position::from_degrees(req.x2, req.y2))
+            .or_insert(0.0) += count * destination_weight;
+    }
+}
+
+/// In meters, from a point to where it snapped
+pub(crate) fn snap_distance(x: f64, y: f64, snapped: (f64, f64)) -> f64 {
+    Point::new(x, y).haversine_distance(&Point::new(snapped.0, snapped.1))
+}
+
+/// Where one end of a request snapped to
+pub(crate) enum Snap<'a> {
+    Intersection(&'a IntersectionLocation),
+    Edge(EdgeSnap),
+}
+
+/// Snaps both ends of a request to intersections, or to edges if `extras` has `closest_edge`.
+/// Returns None if the network is empty.
+pub(crate) fn snap_request<'a>(
+    req: &Request,
+    closest_intersection: &'a RTree<IntersectionLocation>,
+    extras: &RoutingExtras,
+    network: &Network,
+) -> Option<(Snap<'a>, Snap<'a>)> {
+    Some(match extras.closest_edge {
+        Some(ref closest_edge) => (
+            Snap::Edge(snap_to_edge(closest_edge, network, req.x1, req.y1)?),
+            Snap::Edge(snap_to_edge(closest_edge, network, req.x2, req.y2)?),
+        ),
+        None => (
+            Snap::Intersection(closest_intersection.nearest_neighbor(&[req.x1, req.y1])?),
+            Snap::Intersection(closest_intersection.nearest_neighbor(&[req.x2, req.y2])?),
+        ),
+    })
+}
+
+impl Snap<'_> {
+    pub(crate) fn position(&self) -> (f64, f64) {
+        match self {
+            Snap::Intersection(location) => {
+                let [x, y] = *location.geom();
+                (x, y)
+            }
+            Snap::Edge(snap) => snap.position,
+        }
     }
+}
 
-    *counts
-        .count_per_origin
-        .entry(Position::from_degrees(req.x1, req.y1))
-        .or_insert(0.0) += count;
-    *counts
-        .count_per_destination
-        .entry(Position::from_degrees(req.x2, req.y2))
-        .or_insert(0.0) += count;
+/// A point projected onto an edge, acting like a virtual node splitting it
+pub(crate) struct EdgeSnap {
+    /// The key of the edge in `Network::edges`. Its geometry goes from the first node to the second.
+    pub(crate) edge: (NodeID, NodeID),
+    /// From 0 at the first node to 1 at the second
+    pub(crate) fraction: f64,
+    position: (f64, f64),
+}
+
+fn snap_to_edge(
+    closest_edge: &RTree<EdgeLocation>,
+    network: &Network,
+    x: f64,
+    y: f64,
+) -> Option<EdgeSnap> {
+    let edge = closest_edge.nearest_neighbor(&[x, y])?.data;
+    let line_string: LineString = network.edges[&edge]
+        .geometry()
+        .iter()
+        .map(|pt| pt.to_degrees())
+        .collect();
+    let pt = Point::new(x, y);
+    let fraction = line_string.line_locate_point(&pt).unwrap_or(0.0);
+    let position = line_string
+        .line_interpolate_point(fraction)
+        .map(|pt| pt.into())
+        .unwrap_or((x, y));
+    Some(EdgeSnap {
+        edge,
+        fraction,
+        position,
+    })
+}
+
+/// A route through intersections, maybe starting and ending partway along an edge
+pub(crate) struct Route {
+    /// The intersections passed through. Shared with `RouteMatrix`.
+    pub(crate) nodes: Arc<[NodeID]>,
+    /// When snapping to edges, the directed edges partly used before the first intersection and
+    /// after the last, with the meters used on each
+    pub(crate) start: Option<((NodeID, NodeID), f64)>,
+    pub(crate) end: Option<((NodeID, NodeID), f64)>,
+}
+
+impl Route {
+    pub(crate) fn from_path(nodes: Arc<[NodeID]>) -> Self {
+        Self {
+            nodes,
+            start: None,
+            end: None,
+        }
+    }
+
+    /// The directed edges crossed, with the meters used on each
+    fn edges(&self, network: &Network) -> Vec<((NodeID, NodeID), f64)> {
+        let mut edges = Vec::new();
+        edges.extend(self.start);
+        for pair in self.nodes.windows(2) {
+            edges.push(((pair[0], pair[1]), edge_length(network, pair[0], pair[1])));
+        }
+        edges.extend(self.end);
+        edges
+    }
+
+    /// Whether the route starts on an edge or at an intersection in the area
+    fn origin_in_area(&self, area: &CountArea) -> bool {
+        match (self.start, self.nodes.first()) {
+            (Some(((i1, i2), _)), _) => area.contains_edge(i1, i2),
+            (None, Some(id)) => area.contains_node(*id),
+            (None, None) => false,
+        }
+    }
+
+    /// Whether the route ends on an edge or at an intersection in the area
+    fn destination_in_area(&self, area: &CountArea) -> bool {
+        match (self.end, self.nodes.last()) {
+            (Some(((i1, i2), _)), _) => area.contains_edge(i1, i2),
+            (None, Some(id)) => area.contains_node(*id),
+            // Both ends on the same edge
+            (None, None) => self.origin_in_area(area),
+        }
+    }
+}
+
+fn edge_length(network: &Network, i1: NodeID, i2: NodeID) -> f64 {
+    network
+        .edges
+        .get(&(i1, i2))
+        .or_else(|| network.edges.get(&(i2, i1)))
+        .unwrap()
+        .length_meters
+}
+
+/// Routes between two points along edges, returning the route and its cost. The CH searches from
+/// both ends of the start edge to both ends of the end edge, starting with the cost of the partial
+/// edges. When both points are on the same edge, going directly is only used if it's no worse than
+/// the CH's route.
+pub(crate) fn route_between_edges(
+    start: &EdgeSnap,
+    end: &EdgeSnap,
+    path_calc: &mut PathCalculator,
+    prepared_ch: &PreparedCH,
+    network: &Network,
+) -> Option<(Route, usize)> {
+    let partial_cost = |cost: usize, fraction: f64| (cost as f64 * fraction).round() as usize;
+
+    // Both points on the same edge, in a direction that's allowed
+    let mut direct = None;
+    if start.edge == end.edge {
+        let edge = &network.edges[&start.edge];
+        let forwards = end.fraction > start.fraction;
+        let cost = if forwards {
+            edge.forward_cost
+        } else {
+            edge.backward_cost
+        };
+        if let Some(cost) = cost {
+            let (n1, n2) = start.edge;
+            let meters = (end.fraction - start.fraction).abs() * edge.length_meters;
+            let key = if forwards { (n1, n2) } else { (n2, n1) };
+            let route = Route {
+                nodes: Arc::new([]),
+                start: Some((key, meters)),
+                end: None,
+            };
+            direct = Some((
+                partial_cost(cost, (end.fraction - start.fraction).abs()),
+                route,
+            ));
+        }
+    }
+
+    // Leaving the start edge means arriving at one of its intersections from the other end, so
+    // start from the copy for that turn
+    let start_edge = &network.edges[&start.edge];
+    let mut sources = Vec::new();
+    let mut add_source = |node: NodeID, from: NodeID, cost: usize| {
+        let id = prepared_ch
+            .arrival_copies
+            .get(&(node, from))
+            .cloned()
+            .unwrap_or_else(|| prepared_ch.node_map.get(node));
+        sources.push((id, cost));
+    };
+    if let Some(cost) = start_edge.forward_cost {
+        add_source(
+            start.edge.1,
+            start.edge.0,
+            partial_cost(cost, 1.0 - start.fraction),
+        );
+    }
+    if let Some(cost) = start_edge.backward_cost {
+        add_source(
+            start.edge.0,
+            start.edge.1,
+            partial_cost(cost, start.fraction),
+        );
+    }
+
+    let end_edge = &network.edges[&end.edge];
+    let mut targets = Vec::new();
+    let mut add_target = |node: NodeID, cost: usize| {
+        let id = prepared_ch.node_map.get(node);
+        match prepared_ch.turn_copies.get(&id) {
+            Some(copies) => targets.extend(copies.iter().map(|copy| (*copy, cost))),
+            None => targets.push((id, cost)),
+        }
+    };
+    if let Some(cost) = end_edge.forward_cost {
+        add_target(end.edge.0, partial_cost(cost, end.fraction));
+    }
+    if let Some(cost) = end_edge.backward_cost {
+        add_target(end.edge.1, partial_cost(cost, 1.0 - end.fraction));
+    }
+
+    let path = if sources.is_empty() || targets.is_empty() {
+        None
+    } else {
+        path_calc.calc_path_multiple_sources_and_targets(&prepared_ch.ch, sources, targets)
+    };
+    let path = match (path, direct) {
+        (Some(path), Some((cost, route))) if cost <= path.get_weight() => {
+            return Some((route, cost))
+        }
+        (Some(path), _) => path,
+        (None, direct) => return direct.map(|(cost, route)| (route, cost)),
+    };
+    let nodes: Vec<NodeID> = path
+        .get_nodes()
+        .iter()
+        .map(|id| prepared_ch.node_map.translate_id(*id))
+        .collect();
+
+    let (n1, n2) = start.edge;
+    let start = if nodes[0] == n2 {
+        ((n1, n2), (1.0 - start.fraction) * start_edge.length_meters)
+    } else {
+        ((n2, n1), start.fraction * start_edge.length_meters)
+    };
+    let (n1, n2) = end.edge;
+    let end = if *nodes.last().unwrap() == n1 {
+        ((n1, n2), end.fraction * end_edge.length_meters)
+    } else {
+        ((n2, n1), (1.0 - end.fraction) * end_edge.length_meters)
+    };
+
+    // Points right at an intersection don't use any of their edge
+    let route = Route {
+        nodes: nodes.into(),
+        start: Some(start).filter(|(_, meters)| *meters > 0.0),
+        end: Some(end).filter(|(_, meters)| *meters > 0.0),
+    };
+    Some((route, path.get_weight()))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -161,23 +933,105 @@ pub struct PreparedCH {
     pub ch: FastGraph,
     #[serde(deserialize_with = "deserialize_nodemap")]
     pub node_map: NodeMap<NodeID>,
+    /// Intersections with turn restrictions are split into one copy per incoming edge, so each
+    /// copy can only leave on the allowed edges. The original only has outgoing edges, for routes
+    /// starting there. Routes ending there need to reach any copy.
+    pub turn_copies: HashMap<usize, Vec<usize>>,
+    /// The copy of an intersection reached by arriving from a neighbor, keyed by (intersection,
+    /// neighbor)
+    pub arrival_copies: HashMap<(NodeID, NodeID), usize>,
+    /// Identifies the network, edge costs, and turn costs this was built from, so a stale
+    /// `ch.bin` can be detected
+    pub cache_key: u32,
+    /// Like `cache_key`, but ignoring the values of costs. When only this matches, the node
+    /// ordering can be reused to re-contract much faster.
+    pub structure_key: u32,
+    /// A customizable CH orders nodes using only the shape of the network, and keeps every
+    /// shortcut, so changing costs only needs a quick customization, recalculating the cost of
+    /// each shortcut. Queries are slower than for a normal CH.
+    pub customizable: bool,
+}
+
+impl PreparedCH {
+    /// Finds a route between two fast_paths nodes, returning the OSM nodes along it
+    pub fn calc_path(
+        &self,
+        path_calc: &mut PathCalculator,
+        start: usize,
+        end: usize,
+    ) -> Option<Vec<NodeID>> {
+        self.calc_path_with_cost(path_calc, start, end)
+            .map(|(nodes, _)| nodes)
+    }
+
+    /// Like `calc_path`, but also returns the route's cost, including turn costs
+    pub fn calc_path_with_cost(
+        &self,
+        path_calc: &mut PathCalculator,
+        start: usize,
+        end: usize,
+    ) -> Option<(Vec<NodeID>, usize)> {
+        let path = match self.turn_copies.get(&end) {
+            Some(copies) => path_calc.calc_path_multiple_sources_and_targets(
+                &self.ch,
+                vec![(start, 0)],
+                copies.iter().map(|id| (*id, 0)).collect(),
+            )?,
+            None => path_calc.calc_path(&self.ch, start, end)?,
+        };
+        let nodes = path
+            .get_nodes()
+            .iter()
+            .map(|id| self.node_map.translate_id(*id))
+            .collect();
+        Some((nodes, path.get_weight()))
+    }
 }
 
+/// Loads the CH cached at `path` if it matches the network and costs, or builds and saves a new
+/// one. Either kind of CH is accepted. When rebuilding, a customizable CH stays customizable.
 pub fn build_ch(path: &str, network: &Network, timer: &mut Timer) -> Result<PreparedCH> {
+    load_or_build_ch(path, network, None, timer)
+}
+
+/// Like `build_ch`, but the CH must be customizable or not, depending on the router
+pub fn build_ch_for_router(
+    path: &str,
+    network: &Network,
+    router: Router,
+    timer: &mut Timer,
+) -> Result<PreparedCH> {
+    let customizable = router == Router::CustomizableContractionHierarchy;
+    load_or_build_ch(path, network, Some(customizable), timer)
+}
+
+fn load_or_build_ch(
+    path: &str,
+    network: &Network,
+    customizable: Option<bool>,
+    timer: &mut Timer,
+) -> Result<PreparedCH> {
     println!("Trying to load CH from {path}");
-    match File::open(path)
+    let result = match File::open(path)
         .map_err(|err| err.into())
-        .and_then(|f| bincode::deserialize_from(BufReader::new(f)))
+        .and_then(|f| bincode::deserialize_from::<_, PreparedCH>(BufReader::new(f)))
     {
-        Ok(ch) => {
+        Ok(ch) if customizable.map_or(false, |x| x != ch.customizable) => {
+            println!("It was built for a different router, so regenerating it");
+            new_ch(network, !ch.customizable, timer)
+        }
+        Ok(ch) if ch.cache_key == ch_cache_key(network, false) => {
             return Ok(ch);
         }
+        Ok(previous) => {
+            println!("It was built for a different network or costs, so regenerating it");
+            rebuild_ch(network, &previous, timer)
+        }
         Err(err) => {
             println!("That failed, so regenerating it: {err}");
+            new_ch(network, customizable.unwrap_or(false), timer)
         }
-    }
-
-    let result = just_build_ch(network, timer);
+    };
     let writer = BufWriter::new(File::create(path)?);
     bincode::serialize_into(writer, &result)?;
     Ok(result)
@@ -185,34 +1039,350 @@ pub fn build_ch(path: &str, network: &Network, timer: &mut Timer) -> Result<Prep
 
 // No IO
 pub fn just_build_ch(network: &Network, timer: &mut Timer) -> PreparedCH {
+    build_ch_with_order(network, None, false, timer)
+}
+
+/// Builds a customizable CH (see `PreparedCH::customizable`). This is slower to query than a
+/// normal CH, but `rebuild_ch` can update it for new costs much faster. No IO.
+pub fn just_build_cch(network: &Network, timer: &mut Timer) -> PreparedCH {
+    build_ch_with_order(network, None, true, timer)
+}
+
+fn new_ch(network: &Network, customizable: bool, timer: &mut Timer) -> PreparedCH {
+    if customizable {
+        just_build_cch(network, timer)
+    } else {
+        just_build_ch(network, timer)
+    }
+}
+
+/// Rebuilds a CH after edge or turn costs change. If the network's structure is the same, the
+/// node ordering of `previous` is reused. For a normal CH, only the contraction has to be redone,
+/// which is much faster than finding a new ordering. For a customizable CH, contracting is just
+/// recomputing the cost of every shortcut, which is faster still.
+pub fn rebuild_ch(network: &Network, previous: &PreparedCH, timer: &mut Timer) -> PreparedCH {
+    if previous.structure_key != ch_cache_key(network, true) {
+        return new_ch(network, previous.customizable, timer);
+    }
+    let order = fast_paths::get_node_ordering(&previous.ch);
+    build_ch_with_order(network, Some(&order), previous.customizable, timer)
+}
+
+fn build_ch_with_order(
+    network: &Network,
+    order: Option<&[usize]>,
+    customizable: bool,
+    timer: &mut Timer,
+) -> PreparedCH {
     timer.start("Building InputGraph");
+    let graph = TurnGraph::new(network);
     let mut input_graph = InputGraph::new();
-    let mut node_map = NodeMap::new();
-    for ((node1, node2), edge) in &network.edges {
-        // Put every node in the CH, even if we wind up with no edges there
-        let node1 = node_map.get_or_insert(*node1);
-        let node2 = node_map.get_or_insert(*node2);
-
-        if let (Some(forward_cost), Some(backward_cost)) = (edge.forward_cost, edge.backward_cost) {
-            // Everything bidirectional for now!
-            input_graph.add_edge(node1, node2, forward_cost);
-            input_graph.add_edge(node2, node1, backward_cost);
-        }
+    for (from, to, cost) in &graph.edges {
+        input_graph.add_edge(*from, *to, *cost);
     }
     input_graph.freeze();
     timer.stop();
 
-    timer.start("Preparing the CH");
-    let ch = fast_paths::prepare(&input_graph);
-    timer.stop();
+    let ch = if customizable {
+        let order = match order {
+            Some(order) if order.len() == input_graph.get_num_nodes() => order.to_vec(),
+            _ => {
+                timer.start("Ordering nodes for a customizable CH");
+                let order = metric_independent_order(&input_graph);
+                timer.stop();
+                order
+            }
+        };
+        timer.start("Customizing the CH");
+        // Without any witness searches, every possible shortcut is kept, so the shape of the
+        // hierarchy doesn't depend on costs. Contracting is then just finding the cheapest way
+        // through each shortcut. This only fails if the order has the wrong length.
+        let ch = fast_paths::prepare_with_order_with_params(
+            &input_graph,
+            &order,
+            &fast_paths::ParamsWithOrder::new(0),
+        )
+        .unwrap();
+        timer.stop();
+        Some(ch)
+    } else {
+        match order {
+            Some(order) => {
+                timer.start("Preparing the CH with the previous node ordering");
+                let result = fast_paths::prepare_with_order(&input_graph, order);
+                timer.stop();
+                match result {
+                    Ok(ch) => Some(ch),
+                    Err(err) => {
+                        println!("Couldn't reuse the node ordering, so starting over: {err}");
+                        None
+                    }
+                }
+            }
+            None => None,
+        }
+    };
+    let ch = ch.unwrap_or_else(|| {
+        timer.start("Preparing the CH");
+        let ch = fast_paths::prepare(&input_graph);
+        timer.stop();
+        ch
+    });
+
+    PreparedCH {
+        ch,
+        node_map: graph.node_map,
+        turn_copies: graph.turn_copies,
+        arrival_copies: graph.arrival_copies,
+        cache_key: ch_cache_key(network, false),
+        structure_key: ch_cache_key(network, true),
+        customizable,
+    }
+}
+
+/// Orders nodes for a customizable CH using only the shape of the graph, so the same order works
+/// well for any costs. Repeatedly picks the node with the fewest neighbors left, then connects
+/// those neighbors to each other like contracting it would. This "minimum degree" heuristic keeps
+/// the number of shortcuts down.
+fn metric_independent_order(input_graph: &InputGraph) -> Vec<usize> {
+    let num_nodes = input_graph.get_num_nodes();
+    let mut neighbors: Vec<HashSet<usize>> = vec![HashSet::new(); num_nodes];
+    for edge in input_graph.get_edges() {
+        neighbors[edge.from].insert(edge.to);
+        neighbors[edge.to].insert(edge.from);
+    }
+
+    let mut queue: BinaryHeap<Reverse<(usize, usize)>> = (0..num_nodes)
+        .map(|node| Reverse((neighbors[node].len(), node)))
+        .collect();
+    let mut done = vec![false; num_nodes];
+    let mut order = Vec::with_capacity(num_nodes);
+    while let Some(Reverse((degree, node))) = queue.pop() {
+        // Skip outdated entries
+        if done[node] || degree != neighbors[node].len() {
+            continue;
+        }
+        done[node] = true;
+        order.push(node);
+        let remaining: Vec<usize> = std::mem::take(&mut neighbors[node]).into_iter().collect();
+        for a in &remaining {
+            neighbors[*a].remove(&node);
+            for b in &remaining {
+                if a != b {
+                    neighbors[*a].insert(*b);
+                }
+            }
+            queue.push(Reverse((neighbors[*a].len(), *a)));
+        }
+    }
+    order
+}
 
-    PreparedCH { ch, node_map }
+/// The directed graph that both the CH and `PlainGraph` route on. Intersections where the cost
+/// depends on how a route arrives are copied once per incoming edge, so each copy only has the
+/// allowed turns leaving it, with their turn costs.
+pub struct TurnGraph {
+    pub node_map: NodeMap<NodeID>,
+    /// (from, to, cost), using IDs from `node_map`
+    pub edges: Vec<(usize, usize, usize)>,
+    /// See `PreparedCH::turn_copies`
+    pub turn_copies: HashMap<usize, Vec<usize>>,
+    /// See `PreparedCH::arrival_copies`
+    pub arrival_copies: HashMap<(NodeID, NodeID), usize>,
+}
+
+impl TurnGraph {
+    pub fn new(network: &Network) -> Self {
+        let mut node_map = NodeMap::new();
+        let mut directed_edges = Vec::new();
+        let mut degree: HashMap<NodeID, usize> = HashMap::new();
+        // Assign IDs in a fixed order, so a node ordering from a previous CH still lines up
+        let mut edges: Vec<_> = network.edges.iter().collect();
+        edges.sort_by_key(|((node1, node2), _)| (node1.0, node2.0));
+        for ((node1, node2), edge) in edges {
+            // Put every node in the CH, even if we wind up with no edges there
+            node_map.get_or_insert(*node1);
+            node_map.get_or_insert(*node2);
+            *degree.entry(*node1).or_insert(0) += 1;
+            *degree.entry(*node2).or_insert(0) += 1;
+
+            // One-way edges only have a cost in one direction
+            let pts = edge.geometry();
+            let n = pts.len();
+            if let Some(cost) = edge.forward_cost {
+                directed_edges.push(DirectedEdge {
+                    from: *node1,
+                    to: *node2,
+                    way: edge.way_id,
+                    cost,
+                    depart_bearing: bearing(pts[0], pts[1]),
+                    arrive_bearing: bearing(pts[n - 2], pts[n - 1]),
+                });
+            }
+            if let Some(cost) = edge.backward_cost {
+                directed_edges.push(DirectedEdge {
+                    from: *node2,
+                    to: *node1,
+                    way: edge.way_id,
+                    cost,
+                    depart_bearing: bearing(pts[n - 1], pts[n - 2]),
+                    arrive_bearing: bearing(pts[1], pts[0]),
+                });
+            }
+        }
+
+        // Intersections where the cost depends on how a route arrives are copied once per incoming
+        // edge, keyed by (intersection, from)
+        let restrictions = TurnRestrictions::new(&network.turn_restrictions);
+        let turn_costs = &network.turn_costs;
+        let is_expanded = |node: NodeID| {
+            restrictions.is_restricted(node)
+                || (!turn_costs.is_zero()
+                    && (degree[&node] >= 3 || network.traffic_signals.contains(&node)))
+        };
+        let mut copies: HashMap<(NodeID, NodeID), usize> = HashMap::new();
+        let mut incoming: HashMap<NodeID, Vec<&DirectedEdge>> = HashMap::new();
+        let mut turn_copies: HashMap<usize, Vec<usize>> = HashMap::new();
+        for edge in &directed_edges {
+            if is_expanded(edge.to) {
+                let copy = node_map.add_copy(edge.to);
+                copies.insert((edge.to, edge.from), copy);
+                incoming.entry(edge.to).or_default().push(edge);
+                turn_copies
+                    .entry(node_map.get(edge.to))
+                    .or_default()
+                    .push(copy);
+            }
+        }
+
+        let mut edges = Vec::new();
+        for edge in &directed_edges {
+            let target = copies
+                .get(&(edge.to, edge.from))
+                .cloned()
+                .unwrap_or_else(|| node_map.get(edge.to));
+            // Routes starting here don't turn
+            edges.push((node_map.get(edge.from), target, edge.cost));
+            for before in incoming.get(&edge.from).into_iter().flatten() {
+                if !restrictions.allows(before.way, edge.from, edge.way) {
+                    continue;
+                }
+                let mut cost = edge.cost;
+                if degree[&edge.from] >= 3 {
+                    cost += turn_costs.cost(turn_angle(before.arrive_bearing, edge.depart_bearing));
+                }
+                if network.traffic_signals.contains(&edge.from) {
+                    cost += turn_costs.traffic_signals;
+                }
+                edges.push((copies[&(edge.from, before.from)], target, cost));
+            }
+        }
+
+        Self {
+            node_map,
+            edges,
+            turn_copies,
+            arrival_copies: copies,
+        }
+    }
+}
+
+/// Identifies everything the CH is built from: the network, its current edge costs, and turn
+/// costs. If `structure_only`, the values of costs are ignored, leaving only the shape of the
+/// routing graph.
+fn ch_cache_key(network: &Network, structure_only: bool) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(&network.cache_key.to_le_bytes());
+
+    let mut edges: Vec<_> = network.edges.iter().collect();
+    edges.sort_by_key(|((node1, node2), _)| (node1.0, node2.0));
+    for ((node1, node2), edge) in edges {
+        for x in [
+            node1.0,
+            node2.0,
+            edge.way_id.0,
+            edge.forward_cost
+                .map_or(-1, |cost| if structure_only { 0 } else { cost as i64 }),
+            edge.backward_cost
+                .map_or(-1, |cost| if structure_only { 0 } else { cost as i64 }),
+        ] {
+            crc.update(&x.to_le_bytes());
+        }
+    }
+
+    let mut signals: Vec<i64> = network.traffic_signals.iter().map(|node| node.0).collect();
+    signals.sort();
+    for node in signals {
+        crc.update(&node.to_le_bytes());
+    }
+    crc.update(
+        serde_json::to_string(&network.turn_restrictions)
+            .unwrap()
+            .as_bytes(),
+    );
+    // Turn costs only change the structure when they start or stop being used
+    let turn_costs = if structure_only {
+        serde_json::to_string(&network.turn_costs.is_zero())
+    } else {
+        serde_json::to_string(&network.turn_costs)
+    };
+    crc.update(turn_costs.unwrap().as_bytes());
+    crc.sum()
+}
+
+struct DirectedEdge {
+    from: NodeID,
+    to: NodeID,
+    way: WayID,
+    cost: usize,
+    /// In degrees clockwise from north, leaving `from`
+    depart_bearing: f64,
+    /// In degrees clockwise from north, arriving at `to`
+    arrive_bearing: f64,
+}
+
+/// Good enough for comparing nearby directions
+fn bearing(pt1: Position, pt2: Position) -> f64 {
+    let (x1, y1) = pt1.to_degrees();
+    let (x2, y2) = pt2.to_degrees();
+    let dx = (x2 - x1) * y1.to_radians().cos();
+    dx.atan2(y2 - y1).to_degrees()
+}
+
+/// From -180 to 180 degrees. Positive angles are right turns.
+fn turn_angle(arrive_bearing: f64, depart_bearing: f64) -> f64 {
+    let angle = (depart_bearing - arrive_bearing).rem_euclid(360.0);
+    if angle > 180.0 {
+        angle - 360.0
+    } else {
+        angle
+    }
 }
 
 // fast_paths ID representing the OSM node ID as the data
 // TODO We may be able to override the distance function? Does it work with WGS84?
 pub type IntersectionLocation = GeomWithData<[f64; 2], usize>;
 
+/// Only includes edges that can be used in at least one direction
+pub fn build_closest_edge(network: &Network, timer: &mut Timer) -> RTree<EdgeLocation> {
+    timer.start("Building RTree for matching request points to edges");
+    let mut lines = Vec::new();
+    for (id, edge) in &network.edges {
+        if edge.forward_cost.is_none() && edge.backward_cost.is_none() {
+            continue;
+        }
+        for pair in edge.geometry().windows(2) {
+            lines.push(EdgeLocation::new(
+                Line::new(pair[0].to_degrees_array(), pair[1].to_degrees_array()),
+                *id,
+            ));
+        }
+    }
+    let rtree = RTree::bulk_load(lines);
+    timer.stop();
+    rtree
+}
+
 pub fn build_closest_intersection(
     network: &Network,
     node_map: &NodeMap<NodeID>,
@@ -230,3 +1400,728 @@ pub fn build_closest_intersection(
     timer.stop();
     rtree
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config_with;
+    use crate::config::{TurnCosts, Uptake};
+    use crate::network::tests::make_network;
+    use crate::network::TurnRestriction;
+
+    #[test]
+    fn test_count_per_node() {
+        // A T-junction at node 2, plus a dead-end spur off node 3 that nobody uses
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.001, 0.001),
+                (5, 0.002, 0.001),
+            ],
+            &[(1, 2), (2, 3), (2, 4), (3, 5)],
+        );
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+        let mut path_calc = fast_paths::create_calculator(&prepared_ch.ch);
+
+        let mut config = config_with("");
+        config.collect.movements = true;
+        let mut counts = Counts::new();
+        for (x1, y1, x2, y2) in [
+            (0.0, 0.0, 0.002, 0.0),
+            (0.001, 0.001, 0.002, 0.0),
+            (0.0, 0.0, 0.001, 0.001),
+        ] {
+            handle_request(
+                Request::new(x1, y1, x2, y2),
+                &mut counts,
+                &mut path_calc,
+                &RouteMatrix::default(),
+                &closest_intersection,
+                &RoutingExtras::default(),
+                &prepared_ch,
+                &config,
+                None,
+                &network,
+            );
+        }
+
+        assert_eq!(counts.count_per_node[&NodeID(2)], 3.0);
+        assert_eq!(counts.count_per_node[&NodeID(3)], 2.0);
+        assert!(!counts.count_per_node.contains_key(&NodeID(5)));
+
+        // Each route makes one movement at node 2
+        assert_eq!(counts.count_per_movement.len(), 3);
+        for movement in [(1, 2, 3), (4, 2, 3), (1, 2, 4)] {
+            let key = (NodeID(movement.0), NodeID(movement.1), NodeID(movement.2));
+            assert_eq!(counts.count_per_movement[&key], 1.0);
+        }
+    }
+
+    #[test]
+    fn test_same_endpoints() {
+        let network = make_network(&[(1, 0.0, 0.0), (2, 0.001, 0.0)], &[(1, 2)]);
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+        let mut path_calc = fast_paths::create_calculator(&prepared_ch.ch);
+
+        let mut results = Vec::new();
+        for policy in ["Fail", "Drop", "ZeroDistance"] {
+            let config = config_with(&format!(r#", "same_endpoints": "{policy}""#));
+            let mut counts = Counts::new();
+            // Both points snap to node 1
+            handle_request(
+                Request::new(0.0, 0.0, 0.0001, 0.0),
+                &mut counts,
+                &mut path_calc,
+                &RouteMatrix::default(),
+                &closest_intersection,
+                &RoutingExtras::default(),
+                &prepared_ch,
+                &config,
+                None,
+                &network,
+            );
+            results.push(counts);
+        }
+
+        let fail = &results[0];
+        assert_eq!(fail.errors_same_endpoints.len(), 1);
+        assert!(fail.count_per_origin.is_empty());
+
+        let drop = &results[1];
+        assert!(drop.errors_same_endpoints.is_empty());
+        assert_eq!(drop.num_same_endpoints_dropped, 1);
+        assert!(drop.count_per_origin.is_empty());
+
+        let zero = &results[2];
+        assert!(zero.errors_same_endpoints.is_empty());
+        assert_eq!(zero.num_same_endpoints_zero_distance, 1);
+        assert!(zero.count_per_edge.is_empty());
+        assert_eq!(zero.count_per_node[&NodeID(1)], 1.0);
+        assert_eq!(zero.count_per_origin.values().sum::<f64>(), 1.0);
+        assert_eq!(zero.count_per_destination.values().sum::<f64>(), 1.0);
+    }
+
+    #[test]
+    fn test_count_area() {
+        // A straight road, with only the middle inside the area
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.003, 0.0),
+                (5, 0.004, 0.0),
+            ],
+            &[(1, 2), (2, 3), (3, 4), (4, 5)],
+        );
+        let area = geo::MultiPolygon::from(geo::Rect::new(
+            geo::coord! { x: 0.0015, y: -0.001 },
+            geo::coord! { x: 0.0025, y: 0.001 },
+        ));
+        let count_area = CountArea::new(network.edges_in_area(&area));
+
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+        let mut path_calc = fast_paths::create_calculator(&prepared_ch.ch);
+
+        let config = config_with("");
+        let mut counts = Counts::new();
+        handle_request(
+            Request::new(0.0, 0.0, 0.004, 0.0),
+            &mut counts,
+            &mut path_calc,
+            &RouteMatrix::default(),
+            &closest_intersection,
+            &RoutingExtras::default(),
+            &prepared_ch,
+            &config,
+            Some(&count_area),
+            &network,
+        );
+
+        let mut counted: Vec<(i64, i64)> = counts
+            .count_per_edge
+            .keys()
+            .map(|(n1, n2)| (n1.0.min(n2.0), n1.0.max(n2.0)))
+            .collect();
+        counted.sort();
+        assert_eq!(counted, vec![(2, 3), (3, 4)]);
+        // The route still travels the full length
+        let total_distance: f64 = counts.total_distance_by_lts.iter().sum();
+        let full_length: f64 = network.edges.values().map(|e| e.length_meters).sum();
+        assert!((total_distance - full_length).abs() < 1e-6);
+        let mut nodes: Vec<i64> = counts.count_per_node.keys().map(|n| n.0).collect();
+        nodes.sort();
+        assert_eq!(nodes, vec![2, 3, 4]);
+        let mut movements: Vec<i64> = counts
+            .count_per_movement
+            .keys()
+            .map(|(_, via, _)| via.0)
+            .collect();
+        movements.sort();
+        assert_eq!(movements, vec![2, 3, 4]);
+        // Both ends are outside the area
+        assert!(counts.count_per_origin.is_empty());
+        assert!(counts.count_per_destination.is_empty());
+    }
+
+    #[test]
+    fn test_uptake_scenarios() {
+        // About 222m long
+        let network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (2, 3)],
+        );
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+
+        // The main uptake rejects the trip, but one scenario still counts it
+        let mut config = config_with(
+            r#", "uptake_scenarios": { "long": "Identity", "short": { "CutoffMaxDistanceMeters": 50.0 } }"#,
+        );
+        config.uptake = Uptake::CutoffMaxDistanceMeters(100.0);
+        let counts = route_all(
+            &prepared_ch,
+            &closest_intersection,
+            &RoutingExtras::default(),
+            &network,
+            vec![Request::new(0.0, 0.0, 0.002, 0.0)],
+            &config,
+            None,
+        );
+        assert_eq!(counts.uptake_scenarios, vec!["long", "short"]);
+        assert_eq!(counts.count_per_edge.len(), 2);
+        for (node1, node2) in counts.count_per_edge.keys() {
+            assert_eq!(counts.count_per_edge[&(*node1, *node2)], 0.0);
+            let scenario_counts: Vec<f64> = counts
+                .scenario_counts(*node1, *node2)
+                .into_iter()
+                .map(|(_, count)| count)
+                .collect();
+            assert_eq!(scenario_counts, vec![1.0, 0.0]);
+        }
+        // Intersections only use the main uptake
+        assert!(counts.count_per_node.is_empty());
+    }
+
+    #[test]
+    fn test_flow_breakdown() {
+        // Each edge is about 111m long
+        let network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (2, 3)],
+        );
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+
+        let config = config_with(r#", "flow_breakdown": { "LengthBands": [150.0] }"#);
+        let request = |x2: f64| Request::new(0.0, 0.0, x2, 0.0);
+        let counts = route_all(
+            &prepared_ch,
+            &closest_intersection,
+            &RoutingExtras::default(),
+            &network,
+            vec![request(0.001), request(0.002)],
+            &config,
+            None,
+        );
+        assert_eq!(counts.flow_groups, vec!["0-150m", "150m+"]);
+        let groups = |node1, node2| -> Vec<f64> {
+            counts
+                .flow_group_counts(NodeID(node1), NodeID(node2))
+                .into_iter()
+                .map(|(_, count)| count)
+                .collect()
+        };
+        // Both routes use the first edge, but only the longer one continues
+        assert_eq!(groups(1, 2), vec![1.0, 1.0]);
+        assert_eq!(groups(2, 3), vec![0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_snap_distance_decay() {
+        let network = make_network(&[(1, 0.0, 0.0), (2, 0.001, 0.0)], &[(1, 2)]);
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+        let mut path_calc = fast_paths::create_calculator(&prepared_ch.ch);
+
+        let config =
+            config_with(r#", "snap_distance_decay": { "Exponential": { "meters": 50.0 } }"#);
+        let mut counts = Counts::new();
+        // One origin right on node 1, and another about 55m away from it
+        for y1 in [0.0, 0.0005] {
+            handle_request(
+                Request::new(0.0, y1, 0.001, 0.0),
+                &mut counts,
+                &mut path_calc,
+                &RouteMatrix::default(),
+                &closest_intersection,
+                &RoutingExtras::default(),
+                &prepared_ch,
+                &config,
+                None,
+                &network,
+            );
+        }
+
+        let near = counts.count_per_origin[&Position::from_degrees(0.0, 0.0)];
+        let far = counts.count_per_origin[&Position::from_degrees(0.0, 0.0005)];
+        assert_eq!(near, 1.0);
+        assert!(far < 0.5 && far > 0.0);
+        // Edges still get the full count
+        assert_eq!(counts.count_per_edge.values().sum::<f64>(), 2.0);
+    }
+
+    #[test]
+    fn test_snap_to_edges() {
+        // A straight road, about 111m between each node
+        let network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (2, 3)],
+        );
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+        let mut path_calc = fast_paths::create_calculator(&prepared_ch.ch);
+
+        let mut config = config_with("");
+        config.snap_to = SnapTo::Edges;
+        config.collect.route_summaries = true;
+        config.collect.movements = true;
+        let extras = RoutingExtras::new(&network, &config, &mut timer);
+        let route = |x1: f64, x2: f64, path_calc: &mut PathCalculator| {
+            let mut counts = Counts::new();
+            handle_request(
+                Request::new(x1, 0.0001, x2, 0.0001),
+                &mut counts,
+                path_calc,
+                &RouteMatrix::default(),
+                &closest_intersection,
+                &extras,
+                &prepared_ch,
+                &config,
+                None,
+                &network,
+            );
+            counts
+        };
+
+        // Across the middle intersection. Snapping to intersections would route the whole way
+        // from node 1 to 3.
+        let counts = route(0.0003, 0.0017, &mut path_calc);
+        let meters = counts.total_distance_by_lts.iter().sum::<f64>();
+        assert!((meters - 155.7).abs() < 1.0, "{meters}");
+        // Straight along the road
+        let summary = &counts.route_summaries[0];
+        assert!((summary.length_meters - meters).abs() < 1e-9);
+        assert!((summary.circuity().unwrap() - 1.0).abs() < 0.01);
+        assert_eq!(summary.percent_on_lts(1), 100.0);
+        assert_eq!(counts.count_per_edge.len(), 2);
+        assert_eq!(counts.count_per_edge[&(NodeID(1), NodeID(2))], 1.0);
+        assert_eq!(counts.count_per_edge[&(NodeID(2), NodeID(3))], 1.0);
+        assert_eq!(
+            counts.count_per_node.keys().collect::<Vec<_>>(),
+            vec![&NodeID(2)]
+        );
+        // Going straight through 2, even though neither edge is used completely
+        assert_eq!(
+            counts.count_per_movement.keys().collect::<Vec<_>>(),
+            vec![&(NodeID(1), NodeID(2), NodeID(3))]
+        );
+
+        // Backwards along one edge, which would snap to the same intersection
+        let counts = route(0.0018, 0.0012, &mut path_calc);
+        let meters = counts.total_distance_by_lts.iter().sum::<f64>();
+        assert!((meters - 66.7).abs() < 1.0, "{meters}");
+        assert_eq!(
+            counts.count_per_edge.keys().collect::<Vec<_>>(),
+            vec![&(NodeID(3), NodeID(2))]
+        );
+        assert!(counts.count_per_node.is_empty());
+        assert!(counts.errors_same_endpoints.is_empty());
+    }
+
+    #[test]
+    fn test_snap_to_edges_uses_turns() {
+        // Heading east along 1-2, with 4 north of 2. 1-5-4 is a longer detour.
+        let mut network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (4, 0.001, 0.001),
+                (5, 0.0, 0.002),
+            ],
+            &[(1, 2), (2, 4), (1, 5), (5, 4)],
+        );
+        // No left turn from 1-2 onto 2-4
+        network.turn_restrictions.push(TurnRestriction {
+            from: WayID(1),
+            via: NodeID(2),
+            to: WayID(2),
+            only: false,
+        });
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+
+        let mut config = config_with("");
+        config.snap_to = SnapTo::Edges;
+        let extras = RoutingExtras::new(&network, &config, &mut timer);
+        let route = |network: &Network, prepared_ch: &PreparedCH, req: Request| {
+            let mut counts = Counts::new();
+            handle_request(
+                req,
+                &mut counts,
+                &mut fast_paths::create_calculator(&prepared_ch.ch),
+                &RouteMatrix::default(),
+                &closest_intersection,
+                &extras,
+                prepared_ch,
+                &config,
+                None,
+                network,
+            );
+            let mut edges: Vec<(i64, i64)> = counts
+                .count_per_edge
+                .keys()
+                .map(|(n1, n2)| (n1.0, n2.0))
+                .collect();
+            edges.sort();
+            edges
+        };
+
+        // Starting partway along 1-2 can't turn left at 2, so it has to go back around
+        assert_eq!(
+            route(
+                &network,
+                &prepared_ch,
+                Request::new(0.0007, 0.00001, 0.00099, 0.0003)
+            ),
+            vec![(1, 5), (2, 1), (4, 2), (5, 4)]
+        );
+
+        // When 1-2 is very expensive, going around beats staying on it
+        network.turn_restrictions.clear();
+        let edge = network.edges.get_mut(&(NodeID(1), NodeID(2))).unwrap();
+        edge.forward_cost = edge.forward_cost.map(|cost| cost * 100);
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        assert_eq!(
+            route(
+                &network,
+                &prepared_ch,
+                Request::new(0.00005, 0.00001, 0.00095, 0.00001)
+            ),
+            vec![(1, 5), (2, 1), (4, 2), (5, 4)]
+        );
+    }
+
+    #[test]
+    fn test_max_snap_meters() {
+        let network = make_network(&[(1, 0.0, 0.0), (2, 0.001, 0.0)], &[(1, 2)]);
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+        let mut path_calc = fast_paths::create_calculator(&prepared_ch.ch);
+
+        let config = config_with(r#", "max_snap_meters": 100.0"#);
+        let mut counts = Counts::new();
+        // One origin about 55m from node 1, and another about 555m away
+        for y1 in [0.0005, 0.005] {
+            handle_request(
+                Request::new(0.0, y1, 0.001, 0.0),
+                &mut counts,
+                &mut path_calc,
+                &RouteMatrix::default(),
+                &closest_intersection,
+                &RoutingExtras::default(),
+                &prepared_ch,
+                &config,
+                None,
+                &network,
+            );
+        }
+
+        assert_eq!(counts.count_per_edge.values().sum::<f64>(), 1.0);
+        assert_eq!(counts.errors_far_snap.len(), 1);
+        let snap = &counts.errors_far_snap[0];
+        assert_eq!(snap.req.y1, 0.005);
+        assert!((snap.origin_snap_meters - 556.0).abs() < 5.0);
+        assert!(snap.destination_snap_meters < 1.0);
+        assert_eq!(snap.snapped_origin, (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_alternatives() {
+        // Two ways around a block, with the one via node 4 a bit longer
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.001, 0.001),
+                (4, 0.0, 0.0012),
+            ],
+            &[(1, 2), (2, 3), (1, 4), (4, 3)],
+        );
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let closest_intersection =
+            build_closest_intersection(&network, &prepared_ch.node_map, &mut timer);
+        let mut path_calc = fast_paths::create_calculator(&prepared_ch.ch);
+
+        let mut route = |split: &str| {
+            let config = config_with(&format!(
+                r#", "alternatives": {{ "k": 2, "split": {split} }}"#
+            ));
+            let extras = RoutingExtras::new(&network, &config, &mut timer);
+            let mut counts = Counts::new();
+            handle_request(
+                Request::new(0.0, 0.0, 0.001, 0.001),
+                &mut counts,
+                &mut path_calc,
+                &RouteMatrix::default(),
+                &closest_intersection,
+                &extras,
+                &prepared_ch,
+                &config,
+                None,
+                &network,
+            );
+            counts
+        };
+
+        let counts = route(r#""Equal""#);
+        assert_eq!(counts.count_per_edge[&(NodeID(1), NodeID(2))], 0.5);
+        assert_eq!(counts.count_per_edge[&(NodeID(1), NodeID(4))], 0.5);
+        assert_eq!(counts.count_per_node[&NodeID(1)], 1.0);
+        assert_eq!(counts.count_per_origin.values().sum::<f64>(), 1.0);
+
+        let counts = route(r#"{ "Logit": { "theta": 5.0 } }"#);
+        let cheapest = counts.count_per_edge[&(NodeID(1), NodeID(2))];
+        let longer = counts.count_per_edge[&(NodeID(1), NodeID(4))];
+        assert!(longer < cheapest);
+        assert!((cheapest + longer - 1.0).abs() < 1e-9);
+    }
+
+    fn route_nodes(prepared_ch: &PreparedCH, from: i64, to: i64) -> Vec<i64> {
+        let mut path_calc = fast_paths::create_calculator(&prepared_ch.ch);
+        prepared_ch
+            .calc_path(
+                &mut path_calc,
+                prepared_ch.node_map.get(NodeID(from)),
+                prepared_ch.node_map.get(NodeID(to)),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|node| node.0)
+            .collect()
+    }
+
+    #[test]
+    fn test_turn_restrictions() {
+        // Heading east along 1-2-3, with 4 north of 2. 1-5-4 is a longer detour.
+        let mut network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.001, 0.001),
+                (5, 0.0, 0.002),
+            ],
+            &[(1, 2), (2, 3), (2, 4), (1, 5), (5, 4)],
+        );
+        // No left turn from 1-2 onto 2-4
+        network.turn_restrictions.push(TurnRestriction {
+            from: WayID(1),
+            via: NodeID(2),
+            to: WayID(3),
+            only: false,
+        });
+
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let route = |from, to| route_nodes(&prepared_ch, from, to);
+
+        assert_eq!(route(1, 4), vec![1, 5, 4]);
+        // The opposite turn, going straight, and starting or ending at the restricted node are fine
+        assert_eq!(route(4, 1), vec![4, 2, 1]);
+        assert_eq!(route(1, 3), vec![1, 2, 3]);
+        assert_eq!(route(2, 4), vec![2, 4]);
+        assert_eq!(route(1, 2), vec![1, 2]);
+
+        // Only straight on from 1-2, so the right turn from 3-2 onto 2-4 is still allowed
+        network.turn_restrictions = vec![TurnRestriction {
+            from: WayID(1),
+            via: NodeID(2),
+            to: WayID(2),
+            only: true,
+        }];
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let route = |from, to| route_nodes(&prepared_ch, from, to);
+        assert_eq!(route(1, 4), vec![1, 5, 4]);
+        assert_eq!(route(1, 3), vec![1, 2, 3]);
+        assert_eq!(route(3, 4), vec![3, 2, 4]);
+    }
+
+    #[test]
+    fn test_turn_costs() {
+        // Heading east along 1-2-3, with 4 north of 2. 1-5-4 is a slightly longer detour.
+        let mut network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.001, 0.001),
+                (5, 0.0, 0.0012),
+            ],
+            &[(1, 2), (2, 3), (2, 4), (1, 5), (5, 4)],
+        );
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        assert_eq!(route_nodes(&prepared_ch, 1, 4), vec![1, 2, 4]);
+
+        // Turning left at 2 is worse than the detour, but turning right onto 2-4 is still fine
+        network.turn_costs.left = 100;
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        assert_eq!(route_nodes(&prepared_ch, 1, 4), vec![1, 5, 4]);
+        assert_eq!(route_nodes(&prepared_ch, 3, 4), vec![3, 2, 4]);
+
+        // The same with a traffic signal instead
+        network.turn_costs.left = 0;
+        network.turn_costs.traffic_signals = 100;
+        network.traffic_signals.insert(NodeID(2));
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        assert_eq!(route_nodes(&prepared_ch, 1, 4), vec![1, 5, 4]);
+        // Starting at the signal doesn't count
+        assert_eq!(route_nodes(&prepared_ch, 2, 4), vec![2, 4]);
+    }
+
+    #[test]
+    fn test_stale_ch() {
+        let mut network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (2, 3)],
+        );
+        let path = std::env::temp_dir()
+            .join("od2net_test_ch.bin")
+            .display()
+            .to_string();
+        let _ = fs_err::remove_file(&path);
+        let mut timer = Timer::new();
+        let first = build_ch(&path, &network, &mut timer).unwrap();
+        assert_eq!(
+            build_ch(&path, &network, &mut timer).unwrap().cache_key,
+            first.cache_key
+        );
+
+        // Changing a cost rebuilds the CH, instead of loading the stale one
+        network.edges.values_mut().next().unwrap().forward_cost = Some(1);
+        let second = build_ch(&path, &network, &mut timer).unwrap();
+        assert_ne!(second.cache_key, first.cache_key);
+        assert_eq!(second.cache_key, ch_cache_key(&network, false));
+        // But the structure is the same, so the node ordering was reused
+        assert_eq!(second.structure_key, first.structure_key);
+        assert_eq!(
+            fast_paths::get_node_ordering(&second.ch),
+            fast_paths::get_node_ordering(&first.ch)
+        );
+        assert_eq!(route_nodes(&second, 1, 3), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_customizable_ch() {
+        // A grid, so there are a few ways between most places
+        let mut nodes = Vec::new();
+        let mut edges = Vec::new();
+        for x in 0..4 {
+            for y in 0..4 {
+                nodes.push((10 * x + y, x as f64 * 0.001, y as f64 * 0.001));
+                if x < 3 {
+                    edges.push((10 * x + y, 10 * (x + 1) + y));
+                }
+                if y < 3 {
+                    edges.push((10 * x + y, 10 * x + y + 1));
+                }
+            }
+        }
+        let mut network = make_network(&nodes, &edges);
+        // The cost of the cheapest route between every pair of intersections
+        let all_costs = |prepared_ch: &PreparedCH| -> Vec<Option<usize>> {
+            let mut costs = Vec::new();
+            for (from, _, _) in &nodes {
+                for (to, _, _) in &nodes {
+                    costs.push(
+                        fast_paths::calc_path(
+                            &prepared_ch.ch,
+                            prepared_ch.node_map.get(NodeID(*from)),
+                            prepared_ch.node_map.get(NodeID(*to)),
+                        )
+                        .map(|path| path.get_weight()),
+                    );
+                }
+            }
+            costs
+        };
+
+        let mut timer = Timer::new();
+        let cch = just_build_cch(&network, &mut timer);
+        assert!(cch.customizable);
+        assert_eq!(
+            all_costs(&cch),
+            all_costs(&just_build_ch(&network, &mut timer))
+        );
+
+        // After changing costs, customizing keeps the same order and finds the same routes as
+        // starting over
+        for edge in network.edges.values_mut().step_by(3) {
+            edge.forward_cost = edge.forward_cost.map(|cost| cost * 10);
+        }
+        let customized = rebuild_ch(&network, &cch, &mut timer);
+        assert!(customized.customizable);
+        assert_eq!(
+            fast_paths::get_node_ordering(&customized.ch),
+            fast_paths::get_node_ordering(&cch.ch)
+        );
+        assert_eq!(
+            all_costs(&customized),
+            all_costs(&just_build_ch(&network, &mut timer))
+        );
+    }
+
+    #[test]
+    fn test_turn_angle() {
+        let costs = TurnCosts {
+            straight: 1,
+            left: 2,
+            right: 3,
+            u_turn: 4,
+            traffic_signals: 0,
+        };
+        // Heading east, then north, south, east, or back west
+        assert_eq!(costs.cost(turn_angle(90.0, 0.0)), 2);
+        assert_eq!(costs.cost(turn_angle(90.0, 180.0)), 3);
+        assert_eq!(costs.cost(turn_angle(90.0, 100.0)), 1);
+        assert_eq!(costs.cost(turn_angle(90.0, 270.0)), 4);
+        // Wrapping around north
+        assert_eq!(costs.cost(turn_angle(350.0, 80.0)), 3);
+        assert_eq!(costs.cost(turn_angle(10.0, -80.0)), 2);
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use anyhow::Result;
+use geojson::FeatureCollection;
+use instant::Instant;
+
+use super::api::Router;
+use super::config::InputConfig;
+use super::dijkstra::PlainGraph;
+use super::isochrones::isochrones;
+use super::network::Network;
+use super::requests::Request;
+use super::router::PreparedCH;
+use super::timer::Timer;
+
+/// Answers routing queries over HTTP, with the network and CH loaded once. Every response is
+/// GeoJSON, using the same cost function and uptake model as a normal run.
+///
+/// - `GET /route?x1=&y1=&x2=&y2=` returns one LineString per edge of the route between two
+///   points, like `--detailed-routes`
+/// - `GET /isochrone?x=&y=&costs=1000,2000` returns the area reachable from a point within each
+///   cost, like `--isochrones`
+/// - `POST /od` with a JSON array of requests, each with `x1`, `y1`, `x2`, `y2`, and `weight`,
+///   returns the edges with counts, like `output.geojson`
+pub struct Server {
+    router: Router,
+    /// For isochrones
+    graph: PlainGraph,
+    /// `POST /od` rejects more requests than this
+    max_od_requests: usize,
+}
+
+/// Bodies are rejected once they're longer than this many bytes per request allowed in `POST /od`,
+/// without reading the rest
+const MAX_BODY_BYTES_PER_REQUEST: usize = 1024;
+
+/// A failed query, sent back as `{"error": message}`
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self {
+            status: 400,
+            message: message.into(),
+        }
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self {
+            status: 404,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for HttpError {
+    fn from(err: anyhow::Error) -> Self {
+        Self {
+            status: 500,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl Server {
+    pub fn new(
+        network: Network,
+        config: InputConfig,
+        prepared_ch: PreparedCH,
+        max_od_requests: usize,
+        timer: &mut Timer,
+    ) -> Result<Self> {
+        timer.start("Build graph for isochrones");
+        let graph = PlainGraph::new(&network);
+        timer.stop();
+        Ok(Self {
+            router: Router::with_ch(network, config, prepared_ch, timer)?,
+            graph,
+            max_od_requests,
+        })
+    }
+
+    /// Answers queries one at a time until the process is stopped. `address` is like
+    /// `127.0.0.1:8080`.
+    pub fn run(&mut self, address: &str) -> Result<()> {
+        let server = match tiny_http::Server::http(address) {
+            Ok(server) => server,
+            Err(err) => bail!("Couldn't listen on {address}: {err}"),
+        };
+        println!("Listening on http://{address}");
+
+        for mut request in server.incoming_requests() {
+            let start = Instant::now();
+            let (status, response) = match self.read_body(request.as_reader()) {
+                Ok(body) => self.handle(request.method().as_str(), request.url(), &body),
+                Err(err) => (err.status, error_json(&err.message)),
+            };
+            println!(
+                "{} {} -> {status} in {:.1}ms",
+                request.method(),
+                request.url(),
+                Instant::now().duration_since(start).as_secs_f32() * 1000.0
+            );
+
+            let response = tiny_http::Response::from_string(response)
+                .with_status_code(status)
+                .with_header(header("Content-Type", "application/geo+json"))
+                // So the web app can query a server running somewhere else
+                .with_header(header("Access-Control-Allow-Origin", "*"));
+            // Don't stop the server because one client went away
+            if let Err(err) = request.respond(response) {
+                println!("Couldn't respond: {err}");
+            }
+        }
+        Ok(())
+    }
+
+    fn read_body(&self, reader: &mut dyn Read) -> Result<String, HttpError> {
+        let limit = self
+            .max_od_requests
+            .saturating_mul(MAX_BODY_BYTES_PER_REQUEST) as u64;
+        let mut body = String::new();
+        reader
+            .take(limit.saturating_add(1))
+            .read_to_string(&mut body)
+            .map_err(|err| HttpError::bad_request(format!("Couldn't read the body: {err}")))?;
+        if body.len() as u64 > limit {
+            return Err(HttpError {
+                status: 413,
+                message: format!("The body is longer than {limit} bytes"),
+            });
+        }
+        Ok(body)
+    }
+
+    /// Returns the HTTP status and the JSON response
+    fn handle(&mut self, method: &str, url: &str, body: &str) -> (u16, String) {
+        let (path, params) = parse_url(url);
+        let result = match (method, path) {
+            ("GET", "/route") => self.route(&params),
+            ("GET", "/isochrone") => self.isochrone(&params),
+            ("POST", "/od") => self.od(body),
+            _ => Err(HttpError::not_found(format!(
+                "No endpoint for {method} {path}"
+            ))),
+        };
+        match result {
+            Ok(gj) => (200, gj),
+            Err(err) => (err.status, error_json(&err.message)),
+        }
+    }
+
+    fn route(&mut self, params: &HashMap<&str, &str>) -> Result<String, HttpError> {
+        let (x1, y1) = (number(params, "x1")?, number(params, "y1")?);
+        let (x2, y2) = (number(params, "x2")?, number(params, "y2")?);
+        let Some(gj) = self.router.route_geojson(x1, y1, x2, y2, None) else {
+            return Err(HttpError::not_found(
+                "No route between the points, or they snap to the same place or too far away",
+            ));
+        };
+        Ok(serde_json::to_string(&gj).map_err(anyhow::Error::from)?)
+    }
+
+    fn isochrone(&self, params: &HashMap<&str, &str>) -> Result<String, HttpError> {
+        let mut thresholds = Vec::new();
+        for cost in params.get("costs").copied().unwrap_or("").split(',') {
+            match cost.parse::<f64>() {
+                Ok(cost) if cost > 0.0 => thresholds.push(cost),
+                _ => {
+                    return Err(HttpError::bad_request(
+                        "costs must be positive numbers separated by commas, like costs=1000,2000",
+                    ))
+                }
+            }
+        }
+        thresholds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        thresholds.dedup();
+
+        let Some(start) = self.router.snap(number(params, "x")?, number(params, "y")?) else {
+            return Err(HttpError::not_found("The network is empty"));
+        };
+        let mut features = isochrones(&self.graph, self.router.network(), start, &thresholds);
+        // Draw the biggest area first, so the smaller ones are on top
+        features.reverse();
+        let gj = FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: None,
+        };
+        Ok(serde_json::to_string(&gj).map_err(anyhow::Error::from)?)
+    }
+
+    fn od(&self, body: &str) -> Result<String, HttpError> {
+        let requests: Vec<Request> = serde_json::from_str(body)
+            .map_err(|err| HttpError::bad_request(format!("Invalid requests: {err}")))?;
+        if requests.is_empty() || requests.len() > self.max_od_requests {
+            return Err(HttpError::bad_request(format!(
+                "Send between 1 and {} requests",
+                self.max_od_requests
+            )));
+        }
+
+        let num_requests = requests.len();
+        let routing_start = Instant::now();
+        let counts = self.router.route_all(requests);
+        let routing_time = Instant::now().duration_since(routing_start);
+        let config = self.router.config();
+        let output_metadata =
+            super::OutputMetadata::new(config.clone(), &counts, num_requests, routing_time);
+
+        let mut gj_bytes = Vec::new();
+        self.router.network().write_geojson(
+            geojson::FeatureWriter::from_writer(&mut gj_bytes),
+            counts,
+            true,
+            true,
+            config.sort_output,
+            config.output_properties.as_deref(),
+            &output_metadata,
+        )?;
+        Ok(String::from_utf8(gj_bytes).map_err(anyhow::Error::from)?)
+    }
+}
+
+/// Splits `/path?key=value&...` into the path and parameters. Values aren't percent-decoded,
+/// since they're all numbers.
+fn parse_url(url: &str) -> (&str, HashMap<&str, &str>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let params = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .collect();
+    (path, params)
+}
+
+fn number(params: &HashMap<&str, &str>, key: &str) -> Result<f64, HttpError> {
+    params
+        .get(key)
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|value| value.is_finite())
+        .ok_or_else(|| HttpError::bad_request(format!("{key} must be a number")))
+}
+
+fn error_json(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+fn header(key: &str, value: &str) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(key.as_bytes(), value.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config_with;
+    use crate::network::tests::make_network;
+    use crate::router::just_build_ch;
+
+    #[test]
+    fn test_endpoints() {
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.1, 0.1),
+                (5, 0.101, 0.1),
+            ],
+            &[(1, 2), (2, 3), (4, 5)],
+        );
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let mut server = Server::new(network, config_with(""), prepared_ch, 2, &mut timer).unwrap();
+
+        let (status, response) = server.handle("GET", "/route?x1=0&y1=0&x2=0.002&y2=0", "");
+        assert_eq!(status, 200);
+        let gj: FeatureCollection = response
+            .parse::<geojson::GeoJson>()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(gj.features.len(), 2);
+
+        let (status, response) = server.handle("GET", "/route?x1=0&y1=0&x2=0", "");
+        assert_eq!(status, 400);
+        assert_eq!(response, r#"{"error":"y2 must be a number"}"#);
+        // Nodes 4 and 5 aren't connected to the rest
+        assert_eq!(
+            server.handle("GET", "/route?x1=0&y1=0&x2=0.1&y2=0.1", "").0,
+            404
+        );
+
+        let (status, response) = server.handle("GET", "/isochrone?x=0&y=0&costs=150,50", "");
+        assert_eq!(status, 200);
+        let gj: FeatureCollection = response
+            .parse::<geojson::GeoJson>()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let thresholds: Vec<f64> = gj
+            .features
+            .iter()
+            .map(|f| f.property("threshold").unwrap().as_f64().unwrap())
+            .collect();
+        assert_eq!(thresholds, vec![150.0, 50.0]);
+        assert_eq!(
+            server.handle("GET", "/isochrone?x=0&y=0&costs=-1", "").0,
+            400
+        );
+
+        let request = r#"{"x1": 0, "y1": 0, "x2": 0.002, "y2": 0, "weight": 1}"#;
+        let (status, response) = server.handle("POST", "/od", &format!("[{request}]"));
+        assert_eq!(status, 200);
+        assert!(response.contains(r#""metadata""#));
+        assert_eq!(
+            server
+                .handle("POST", "/od", &format!("[{request},{request},{request}]"))
+                .0,
+            400
+        );
+
+        assert_eq!(server.handle("DELETE", "/od", "").0, 404);
+
+        // Two requests are allowed, so the body can be up to 2KB
+        let body = "x".repeat(2048);
+        assert_eq!(
+            server.read_body(&mut body.as_bytes()).ok(),
+            Some(body.clone())
+        );
+        let body = "x".repeat(2049);
+        assert_eq!(
+            server
+                .read_body(&mut body.as_bytes())
+                .map_err(|err| err.status),
+            Err(413)
+        );
+    }
+
+    #[test]
+    fn test_route_follows_snapping() {
+        let network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (2, 3)],
+        );
+        let mut timer = Timer::new();
+        let prepared_ch = just_build_ch(&network, &mut timer);
+        let config = config_with(r#", "max_snap_meters": 100.0"#);
+        let mut server = Server::new(network, config, prepared_ch, 2, &mut timer).unwrap();
+
+        assert_eq!(
+            server.handle("GET", "/route?x1=0&y1=0&x2=0.002&y2=0", "").0,
+            200
+        );
+        // The start is about 1km from the network
+        assert_eq!(
+            server
+                .handle("GET", "/route?x1=0&y1=0.01&x2=0.002&y2=0", "")
+                .0,
+            404
+        );
+    }
+}
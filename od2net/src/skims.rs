@@ -0,0 +1,135 @@
+use std::io::{BufWriter, Write};
+
+use anyhow::Result;
+use fs_err::File;
+
+use super::config::InputConfig;
+use super::network::Network;
+use super::requests::Request;
+use super::router;
+use super::timer::Timer;
+
+/// Routes every request the same way as a normal run, and writes `skims.csv` with one row per
+/// request describing its route. With `alternatives`, each row is the average over the routes,
+/// weighted by their share.
+pub fn run(
+    ch_path: &str,
+    network: &Network,
+    requests: Vec<Request>,
+    config: &InputConfig,
+    output_directory: String,
+    timer: &mut Timer,
+) -> Result<()> {
+    let mut config = config.clone();
+    config.collect.route_summaries = true;
+    let counts = router::run(
+        ch_path,
+        network,
+        requests.clone(),
+        &config,
+        None,
+        None,
+        None,
+        timer,
+    )?;
+
+    timer.start(format!("Writing {output_directory}/skims.csv"));
+    let mut file = BufWriter::new(File::create(format!("{output_directory}/skims.csv"))?);
+    writeln!(
+        file,
+        "request,x1,y1,x2,y2,origin_node,destination_node,distance_meters,cost,elevation_gain_meters,max_lts"
+    )?;
+    // Routes are summarized in the order of the requests, skipping requests that failed. Requests
+    // between the same points are routed the same way, so they can't be mixed up.
+    let mut summaries = counts.route_summaries.iter().peekable();
+    for (idx, req) in requests.iter().enumerate() {
+        write!(file, "{idx},{},{},{},{}", req.x1, req.y1, req.x2, req.y2)?;
+        let summary =
+            summaries.next_if(|s| (s.x1, s.y1, s.x2, s.y2) == (req.x1, req.y1, req.x2, req.y2));
+        // Leave the cells empty if there's no route
+        match summary {
+            Some(summary) => {
+                let node = |node: Option<osm_reader::NodeID>| {
+                    node.map(|node| node.0.to_string()).unwrap_or_default()
+                };
+                writeln!(
+                    file,
+                    ",{},{},{},{},{},{}",
+                    node(summary.origin_node),
+                    node(summary.destination_node),
+                    summary.length_meters,
+                    summary.cost,
+                    summary
+                        .elevation_gain_meters
+                        .map(|x| x.to_string())
+                        .unwrap_or_default(),
+                    summary.max_lts()
+                )?
+            }
+            None => writeln!(file, ",,,,,,")?,
+        }
+    }
+    file.flush()?;
+    timer.stop();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config_with;
+    use crate::network::tests::make_network;
+    use lts::LTS;
+    use osm_reader::NodeID;
+
+    #[test]
+    fn test_skims() {
+        let mut network = make_network(
+            &[(1, 0.0, 0.0), (2, 0.001, 0.0), (3, 0.002, 0.0)],
+            &[(1, 2), (3, 2)],
+        );
+        for edge in network.edges.values_mut() {
+            edge.elevation_gain = Some((5.0, 2.0));
+        }
+        network.edges.get_mut(&(NodeID(3), NodeID(2))).unwrap().lts = LTS::LTS3;
+
+        let directory = std::env::temp_dir().join("od2net_test_skims");
+        fs_err::create_dir_all(&directory).unwrap();
+        let directory = directory.to_str().unwrap().to_string();
+        let requests = vec![
+            Request::new(0.0, 0.0, 0.002, 0.0),
+            // Both ends snap to node 1
+            Request::new(0.0, 0.0, 0.0001, 0.0),
+            Request::new(0.0, 0.0, 0.002, 0.0),
+        ];
+        run(
+            &format!("{directory}/ch.bin"),
+            &network,
+            requests,
+            &config_with(""),
+            directory.clone(),
+            &mut Timer::new(),
+        )
+        .unwrap();
+
+        let csv = fs_err::read_to_string(format!("{directory}/skims.csv")).unwrap();
+        let rows: Vec<Vec<&str>> = csv
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').collect())
+            .collect();
+        assert_eq!(rows.len(), 3);
+        for row in [&rows[0], &rows[2]] {
+            assert_eq!(row[5..7], ["1", "3"]);
+            let lengths: f64 = network.edges.values().map(|e| e.length_meters).sum();
+            assert!((row[7].parse::<f64>().unwrap() - lengths).abs() < 1e-6);
+            assert_eq!(row[8], "222");
+            // 1 to 2 is crossed forwards, climbing 5m, and 3 to 2 backwards, climbing 2m
+            assert_eq!(row[9], "7");
+            assert_eq!(row[10], "3");
+        }
+        assert_eq!(rows[1][5..], ["", "", "", "", "", ""]);
+        fs_err::remove_dir_all(directory).unwrap();
+    }
+}
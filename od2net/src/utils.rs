@@ -1,6 +1,85 @@
+use std::io::{BufWriter, Write};
+
+use anyhow::Result;
+use fs_err::File;
 use indicatif::{ProgressBar, ProgressStyle};
 
+use crate::config::OutputCompression;
+
 pub fn progress_bar_for_count(count: usize) -> ProgressBar {
     ProgressBar::new(count as u64).with_style(ProgressStyle::with_template(
         "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, {eta})").unwrap())
 }
+
+/// Resolves a path from the config. Absolute paths are used as-is, and relative paths are joined
+/// onto `directory`.
+pub fn resolve_path(directory: &str, path: &str) -> String {
+    if std::path::Path::new(path).is_absolute() {
+        path.to_string()
+    } else {
+        format!("{directory}/{path}")
+    }
+}
+
+/// Creates a file to write, compressing everything written to it if asked. Call
+/// `OutputFile::finish` when done, or the end of the file may be lost.
+pub fn create_output_file(
+    path: &str,
+    compression: Option<OutputCompression>,
+) -> Result<OutputFile> {
+    let file = BufWriter::new(File::create(path)?);
+    Ok(match compression {
+        None => OutputFile::Plain(file),
+        Some(OutputCompression::Gzip) => OutputFile::Gzip(flate2::write::GzEncoder::new(
+            file,
+            flate2::Compression::default(),
+        )),
+        #[cfg(not(target_arch = "wasm32"))]
+        Some(OutputCompression::Zstd) => OutputFile::Zstd(zstd::Encoder::new(file, 0)?),
+        #[cfg(target_arch = "wasm32")]
+        Some(OutputCompression::Zstd) => anyhow::bail!("Zstd compression isn't supported here"),
+    })
+}
+
+/// A file from `create_output_file`
+pub enum OutputFile {
+    Plain(BufWriter<File>),
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl OutputFile {
+    /// Finishes any compression and flushes everything to disk, returning any errors. Dropping
+    /// the file instead ignores them.
+    pub fn finish(self) -> Result<()> {
+        let mut file = match self {
+            OutputFile::Plain(file) => file,
+            OutputFile::Gzip(encoder) => encoder.finish()?,
+            #[cfg(not(target_arch = "wasm32"))]
+            OutputFile::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputFile::Plain(file) => file.write(buf),
+            OutputFile::Gzip(encoder) => encoder.write(buf),
+            #[cfg(not(target_arch = "wasm32"))]
+            OutputFile::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputFile::Plain(file) => file.flush(),
+            OutputFile::Gzip(encoder) => encoder.flush(),
+            #[cfg(not(target_arch = "wasm32"))]
+            OutputFile::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+/// Something that went wrong but didn't stop the run, summarized so pipelines can check for it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Warning {
+    pub category: WarningCategory,
+    pub message: String,
+    /// How many times this happened
+    pub count: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningCategory {
+    /// A cached intermediate file existed but couldn't be loaded, so it was regenerated
+    StaleCache,
+    /// OSM ways left out of the network
+    DroppedWays,
+    /// Edges without elevation data, when elevation was configured
+    MissingElevation,
+    /// Requests with both ends snapped to the same intersection
+    RejectedSnaps,
+    /// Requests without any route
+    NoPath,
+    /// An optional output that couldn't be made, so it was skipped
+    SkippedOutput,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Warnings(pub Vec<Warning>);
+
+impl Warnings {
+    /// Records a warning, unless `count` is 0
+    pub fn add<I: Into<String>>(&mut self, category: WarningCategory, message: I, count: usize) {
+        if count > 0 {
+            self.0.push(Warning {
+                category,
+                message: message.into(),
+                count,
+            });
+        }
+    }
+
+    pub fn extend(&mut self, other: &Warnings) {
+        self.0.extend(other.0.iter().cloned());
+    }
+
+    /// The total count of warnings in one category
+    pub fn count(&self, category: WarningCategory) -> usize {
+        self.0
+            .iter()
+            .filter(|w| w.category == category)
+            .map(|w| w.count)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config_with;
+    use crate::network::{Counts, Network};
+    use crate::requests::Request;
+    use crate::timer::Timer;
+
+    #[test]
+    fn test_warnings_for_dropped_ways_and_snaps() {
+        // Way 2 references node 99, which is outside the extract. Way 3 is a difficult path.
+        let osm_xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+  <node id="1" lat="0.0" lon="0.0"/>
+  <node id="2" lat="0.0" lon="0.001"/>
+  <node id="3" lat="0.001" lon="0.001"/>
+  <way id="1">
+    <nd ref="1"/>
+    <nd ref="2"/>
+    <tag k="highway" v="residential"/>
+  </way>
+  <way id="2">
+    <nd ref="2"/>
+    <nd ref="99"/>
+    <tag k="highway" v="residential"/>
+  </way>
+  <way id="3">
+    <nd ref="2"/>
+    <nd ref="3"/>
+    <tag k="highway" v="path"/>
+    <tag k="sac_scale" v="alpine_hiking"/>
+  </way>
+</osm>"#;
+        let mut config = config_with(r#", "path_difficulty": { "max_sac_scale": "hiking" }"#);
+        let network = Network::make_from_osm(
+            &[osm_xml.as_bytes()],
+            &config.lts,
+            config.profile,
+            &config.path_difficulty,
+            None,
+            &mut config.cost,
+            &[],
+            &config.oneway,
+            &config.barriers,
+            &mut Timer::new(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(network.edges.len(), 1);
+        assert_eq!(network.warnings.count(WarningCategory::DroppedWays), 2);
+        assert_eq!(
+            network
+                .warnings
+                .0
+                .iter()
+                .filter(|w| w.category == WarningCategory::DroppedWays)
+                .count(),
+            2
+        );
+
+        let mut counts = Counts::new();
+        for _ in 0..3 {
+            counts
+                .errors_same_endpoints
+                .push(Request::new(0.0, 0.0, 0.0, 0.0));
+        }
+        let metadata =
+            crate::OutputMetadata::new(config, &counts, 5, instant::Duration::from_secs(1));
+        assert_eq!(metadata.warnings.count(WarningCategory::RejectedSnaps), 3);
+        assert_eq!(metadata.warnings.count(WarningCategory::NoPath), 0);
+        assert_eq!(metadata.warnings.0.len(), 1);
+    }
+}
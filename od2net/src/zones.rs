@@ -0,0 +1,298 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::BufWriter;
+
+use anyhow::Result;
+use fs_err::File;
+use geo::{BoundingRect, Contains, LineInterpolatePoint, LineString, MultiPolygon, Point};
+use geojson::{Feature, FeatureWriter, Geometry};
+use osm_reader::NodeID;
+use rstar::primitives::{GeomWithData, Rectangle};
+use rstar::RTree;
+
+use super::network::{CountArea, Counts, Network};
+use super::od::load_zones;
+use super::requests::Request;
+
+/// Named zones, with every edge of a network assigned to the zone containing its midpoint
+#[derive(Clone)]
+pub struct ZoneIndex {
+    /// Sorted by name
+    names: Vec<String>,
+    polygons: Vec<MultiPolygon>,
+    bboxes: RTree<GeomWithData<Rectangle<[f64; 2]>, usize>>,
+    /// Keyed like `Network::edges`. Edges outside every zone are missing.
+    edge_zone: HashMap<(NodeID, NodeID), usize>,
+}
+
+impl ZoneIndex {
+    /// Reads zones from a GeoJSON file with a "name" property, like the one used to generate
+    /// requests
+    pub fn load(path: &str, network: &Network) -> Result<Self> {
+        Ok(Self::new(load_zones(path)?, network))
+    }
+
+    pub fn new(zones: HashMap<String, MultiPolygon>, network: &Network) -> Self {
+        let mut zones: Vec<(String, MultiPolygon)> = zones.into_iter().collect();
+        zones.sort_by(|a, b| a.0.cmp(&b.0));
+        let (names, polygons): (Vec<_>, Vec<_>) = zones.into_iter().unzip();
+
+        let bboxes = RTree::bulk_load(
+            polygons
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, polygon)| {
+                    let bbox = polygon.bounding_rect()?;
+                    Some(GeomWithData::new(
+                        Rectangle::from_corners(bbox.min().into(), bbox.max().into()),
+                        idx,
+                    ))
+                })
+                .collect(),
+        );
+        let mut index = Self {
+            names,
+            polygons,
+            bboxes,
+            edge_zone: HashMap::new(),
+        };
+
+        for (key, edge) in &network.edges {
+            let line = LineString::from(
+                edge.geometry()
+                    .iter()
+                    .map(|pt| pt.to_degrees())
+                    .collect::<Vec<_>>(),
+            );
+            if let Some(zone) = line
+                .line_interpolate_point(0.5)
+                .and_then(|pt| index.zone_of(pt.x(), pt.y()))
+            {
+                index.edge_zone.insert(*key, zone);
+            }
+        }
+        index
+    }
+
+    /// The index of the zone containing a point in WGS84. If zones overlap, any one of them is
+    /// returned.
+    pub fn zone_of(&self, x: f64, y: f64) -> Option<usize> {
+        let pt = Point::new(x, y);
+        self.bboxes
+            .locate_all_at_point(&[x, y])
+            .map(|bbox| bbox.data)
+            .find(|idx| self.polygons[*idx].contains(&pt))
+    }
+
+    /// Adds `count` to every zone that a route through these edges crosses, besides the zones
+    /// containing the request's origin and destination. Each zone counts once per route, even if
+    /// the route leaves and re-enters it. Also adds the distance along each edge, times `count`,
+    /// to the zone containing the edge, if the edge is in `count_area`.
+    pub(crate) fn add_route_counts(
+        &self,
+        req: &Request,
+        edges: &[((NodeID, NodeID), f64)],
+        count: f64,
+        network: &Network,
+        count_area: Option<&CountArea>,
+        counts: &mut Counts,
+    ) {
+        let origin = self.zone_of(req.x1, req.y1);
+        let destination = self.zone_of(req.x2, req.y2);
+        let mut crossed = BTreeSet::new();
+        for ((i1, i2), meters) in edges {
+            let Some(zone) = self.edge_zone(*i1, *i2) else {
+                continue;
+            };
+            if Some(zone) != origin && Some(zone) != destination {
+                crossed.insert(zone);
+            }
+            if count_area.map_or(true, |area| area.contains_edge(*i1, *i2)) {
+                let edge = network
+                    .edges
+                    .get(&(*i1, *i2))
+                    .or_else(|| network.edges.get(&(*i2, *i1)))
+                    .unwrap();
+                counts.meters_by_lts_in_zone.entry(zone).or_insert([0.0; 5])
+                    [edge.lts as u8 as usize] += count * meters;
+            }
+        }
+        for zone in crossed {
+            *counts.count_through_zone.entry(zone).or_insert(0.0) += count;
+        }
+    }
+
+    fn edge_zone(&self, i1: NodeID, i2: NodeID) -> Option<usize> {
+        self.edge_zone
+            .get(&(i1, i2))
+            .or_else(|| self.edge_zone.get(&(i2, i1)))
+            .copied()
+    }
+
+    /// Sums up trips and distance cycled in every zone
+    pub fn totals(&self, counts: &Counts) -> Vec<ZoneTotals> {
+        let mut totals: Vec<ZoneTotals> = self
+            .names
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| ZoneTotals {
+                name: name.clone(),
+                trips_originating: 0.0,
+                trips_terminating: 0.0,
+                trips_passing_through: counts.count_through_zone.get(&idx).copied().unwrap_or(0.0),
+                km_by_lts: counts
+                    .meters_by_lts_in_zone
+                    .get(&idx)
+                    .map_or([0.0; 5], |meters| meters.map(|x| x / 1000.0)),
+            })
+            .collect();
+
+        for (pt, count) in &counts.count_per_origin {
+            let (x, y) = pt.to_degrees();
+            if let Some(zone) = self.zone_of(x, y) {
+                totals[zone].trips_originating += count;
+            }
+        }
+        for (pt, count) in &counts.count_per_destination {
+            let (x, y) = pt.to_degrees();
+            if let Some(zone) = self.zone_of(x, y) {
+                totals[zone].trips_terminating += count;
+            }
+        }
+        totals
+    }
+
+    /// Writes `zones.geojson`, with the zone polygons, and `zones.csv`, both with the totals from
+    /// `totals`
+    pub fn write_outputs(&self, output_directory: &str, counts: &Counts) -> Result<()> {
+        let totals = self.totals(counts);
+
+        let mut writer = FeatureWriter::from_writer(BufWriter::new(File::create(format!(
+            "{output_directory}/zones.geojson"
+        ))?));
+        for (polygon, zone) in self.polygons.iter().zip(&totals) {
+            let mut feature = Feature::from(Geometry::from(polygon));
+            feature.set_property("name", zone.name.clone());
+            feature.set_property("trips_originating", zone.trips_originating);
+            feature.set_property("trips_terminating", zone.trips_terminating);
+            feature.set_property("trips_passing_through", zone.trips_passing_through);
+            for lts in 1..=4 {
+                feature.set_property(format!("km_lts{lts}"), zone.km_by_lts[lts]);
+            }
+            writer.write_feature(&feature)?;
+        }
+        writer.finish()?;
+
+        let mut file =
+            csv::Writer::from_writer(File::create(format!("{output_directory}/zones.csv"))?);
+        file.write_record([
+            "name",
+            "trips_originating",
+            "trips_terminating",
+            "trips_passing_through",
+            "km_lts1",
+            "km_lts2",
+            "km_lts3",
+            "km_lts4",
+        ])?;
+        for zone in totals {
+            file.write_record([
+                zone.name,
+                zone.trips_originating.to_string(),
+                zone.trips_terminating.to_string(),
+                zone.trips_passing_through.to_string(),
+                zone.km_by_lts[1].to_string(),
+                zone.km_by_lts[2].to_string(),
+                zone.km_by_lts[3].to_string(),
+                zone.km_by_lts[4].to_string(),
+            ])?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Totals for one zone
+pub struct ZoneTotals {
+    pub name: String,
+    /// From origin points in the zone. Like the origin counts in the main output, this includes
+    /// `snap_distance_decay`.
+    pub trips_originating: f64,
+    pub trips_terminating: f64,
+    /// Trips with a route crossing the zone that neither start nor end there
+    pub trips_passing_through: f64,
+    /// The count times the distance used along every edge with its midpoint in the zone,
+    /// indexed by LTS as u8. Only edges in the `count_area` count.
+    pub km_by_lts: [f64; 5],
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::make_network;
+    use crate::network::Position;
+    use geo::Rect;
+
+    fn square(x1: f64, x2: f64) -> MultiPolygon {
+        Rect::new((x1, -1.0), (x2, 1.0)).to_polygon().into()
+    }
+
+    #[test]
+    fn test_zone_totals() {
+        // Three zones side-by-side, with one edge in each
+        let network = make_network(
+            &[
+                (1, 0.0, 0.0),
+                (2, 0.001, 0.0),
+                (3, 0.002, 0.0),
+                (4, 0.003, 0.0),
+            ],
+            &[(1, 2), (2, 3), (3, 4)],
+        );
+        let zones = ZoneIndex::new(
+            HashMap::from([
+                ("a".to_string(), square(-0.0001, 0.0011)),
+                ("b".to_string(), square(0.0011, 0.0021)),
+                ("c".to_string(), square(0.0021, 0.0031)),
+            ]),
+            &network,
+        );
+
+        let req = Request::new(0.0, 0.0, 0.003, 0.0);
+        // The route only uses half of the last edge
+        let edges: Vec<((NodeID, NodeID), f64)> = [(1, 2), (2, 3), (3, 4)]
+            .into_iter()
+            .map(|(i1, i2)| {
+                let length = network.edges[&(NodeID(i1), NodeID(i2))].length_meters;
+                let meters = if i1 == 3 { length / 2.0 } else { length };
+                ((NodeID(i1), NodeID(i2)), meters)
+            })
+            .collect();
+        let mut counts = Counts::new();
+        zones.add_route_counts(&req, &edges, 2.0, &network, None, &mut counts);
+        for (key, _) in &edges {
+            counts.count_per_edge.insert(*key, 2.0);
+        }
+        counts
+            .count_per_origin
+            .insert(Position::from_degrees(req.x1, req.y1), 2.0);
+        counts
+            .count_per_destination
+            .insert(Position::from_degrees(req.x2, req.y2), 2.0);
+
+        let totals = zones.totals(&counts);
+        let names: Vec<&str> = totals.iter().map(|z| z.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        assert_eq!(totals[0].trips_originating, 2.0);
+        assert_eq!(totals[0].trips_passing_through, 0.0);
+        assert_eq!(totals[1].trips_passing_through, 2.0);
+        assert_eq!(totals[1].trips_originating, 0.0);
+        assert_eq!(totals[2].trips_terminating, 2.0);
+        assert_eq!(totals[2].trips_passing_through, 0.0);
+
+        let km = 2.0 * network.edges[&(NodeID(2), NodeID(3))].length_meters / 1000.0;
+        assert_eq!(totals[1].km_by_lts[1], km);
+        let km = network.edges[&(NodeID(3), NodeID(4))].length_meters / 1000.0;
+        assert!((totals[2].km_by_lts[1] - km).abs() < 1e-9);
+        assert_eq!(zones.zone_of(0.01, 0.0), None);
+    }
+}
@@ -0,0 +1,328 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use od2net::config::InputConfig;
+use od2net::network::{GeoTiffFiles, Network};
+use od2net::requests::Request;
+use od2net::timer::Timer;
+use od2net::NetworkBuilder;
+
+/// Routes over one network, keeping it and the contraction hierarchy in memory between calls.
+/// GeoJSON results are returned as dictionaries, which `geopandas.GeoDataFrame.from_features`
+/// accepts directly.
+#[pyclass(unsendable)]
+struct Router {
+    router: od2net::Router,
+    /// Relative paths in the config are resolved against this
+    directory: String,
+}
+
+#[pymethods]
+impl Router {
+    /// Builds a network from the bytes of an osm.pbf or OSM XML file. `config_json` has the same
+    /// format as `config.json`, and relative paths in it are resolved against `directory`.
+    #[staticmethod]
+    #[pyo3(signature = (osm_bytes, config_json, directory = "."))]
+    fn from_osm(
+        py: Python<'_>,
+        osm_bytes: &[u8],
+        config_json: &str,
+        directory: &str,
+    ) -> PyResult<Self> {
+        let config = load_config(config_json, directory)?;
+        // Other Python threads can run while this takes a while
+        py.allow_threads(|| {
+            let mut timer = Timer::new();
+            let network =
+                NetworkBuilder::from_config(&config).build_from_osm(&[osm_bytes], &mut timer)?;
+            Self::new(network, config, directory, &mut timer)
+        })
+    }
+
+    /// Builds a network from the bytes of a GeoJSON file, using the columns in the config's
+    /// `geojson_network`
+    #[staticmethod]
+    #[pyo3(signature = (geojson_bytes, config_json, directory = "."))]
+    fn from_geojson(
+        py: Python<'_>,
+        geojson_bytes: &[u8],
+        config_json: &str,
+        directory: &str,
+    ) -> PyResult<Self> {
+        let config = load_config(config_json, directory)?;
+        let Some(columns) = config.geojson_network.clone() else {
+            return Err(PyValueError::new_err(
+                "The config needs geojson_network to build from GeoJSON",
+            ));
+        };
+        py.allow_threads(|| {
+            let mut timer = Timer::new();
+            let network = NetworkBuilder::from_config(&config).build_from_geojson(
+                geojson_bytes,
+                &columns,
+                &mut timer,
+            )?;
+            Self::new(network, config, directory, &mut timer)
+        })
+    }
+
+    /// Generates requests from the config's `requests` section, returning a dictionary of
+    /// columns (`x1`, `y1`, `x2`, `y2`, `weight`, and `purpose`) that can be passed to
+    /// `pandas.DataFrame`
+    #[pyo3(signature = (rng_seed = 42))]
+    fn generate_requests(&self, py: Python<'_>, rng_seed: u64) -> PyResult<PyObject> {
+        let (router, directory) = (&self.router, &self.directory);
+        let requests = py.allow_threads(|| {
+            od2net::od::generate_requests(
+                &router.config().requests,
+                router.config().input_directory(directory),
+                router.network(),
+                rng_seed,
+                &mut Timer::new(),
+            )
+        })?;
+
+        let columns = PyDict::new_bound(py);
+        columns.set_item("x1", requests.iter().map(|r| r.x1).collect::<Vec<_>>())?;
+        columns.set_item("y1", requests.iter().map(|r| r.y1).collect::<Vec<_>>())?;
+        columns.set_item("x2", requests.iter().map(|r| r.x2).collect::<Vec<_>>())?;
+        columns.set_item("y2", requests.iter().map(|r| r.y2).collect::<Vec<_>>())?;
+        columns.set_item(
+            "weight",
+            requests.iter().map(|r| r.weight).collect::<Vec<_>>(),
+        )?;
+        columns.set_item(
+            "purpose",
+            requests.into_iter().map(|r| r.purpose).collect::<Vec<_>>(),
+        )?;
+        Ok(columns.into_any().unbind())
+    }
+
+    /// Returns the route between two points as a GeoJSON FeatureCollection, with one LineString
+    /// per edge, or `None` if there's no route. Points snap like the config says, and `None` is
+    /// also returned if they snap to the same place or farther than `max_snap_meters`.
+    #[pyo3(signature = (x1, y1, x2, y2, purpose = None))]
+    fn route(
+        &mut self,
+        py: Python<'_>,
+        x1: f64,
+        y1: f64,
+        x2: f64,
+        y2: f64,
+        purpose: Option<&str>,
+    ) -> PyResult<Option<PyObject>> {
+        let router = &mut self.router;
+        let Some(gj) = py.allow_threads(|| router.route_geojson(x1, y1, x2, y2, purpose)) else {
+            return Ok(None);
+        };
+        Ok(Some(json_to_python(py, gj.to_string())?))
+    }
+
+    /// Routes many requests in parallel, given as columns like the ones from
+    /// `generate_requests`. Pandas columns need `.tolist()`. Returns a GeoJSON FeatureCollection
+    /// of the edges with counts, like `output.geojson`.
+    #[pyo3(signature = (x1, y1, x2, y2, weight = None, purpose = None, osm_tags = false))]
+    #[allow(clippy::too_many_arguments)]
+    fn route_all(
+        &self,
+        py: Python<'_>,
+        x1: Vec<f64>,
+        y1: Vec<f64>,
+        x2: Vec<f64>,
+        y2: Vec<f64>,
+        weight: Option<Vec<f64>>,
+        purpose: Option<Vec<Option<String>>>,
+        osm_tags: bool,
+    ) -> PyResult<PyObject> {
+        let n = x1.len();
+        if [y1.len(), x2.len(), y2.len()].iter().any(|len| *len != n)
+            || weight.as_ref().is_some_and(|x| x.len() != n)
+            || purpose.as_ref().is_some_and(|x| x.len() != n)
+        {
+            return Err(PyValueError::new_err(
+                "Every column must be the same length",
+            ));
+        }
+        let requests: Vec<Request> = (0..n)
+            .map(|i| Request {
+                x1: x1[i],
+                y1: y1[i],
+                x2: x2[i],
+                y2: y2[i],
+                purpose: purpose.as_ref().and_then(|x| x[i].clone()),
+                weight: weight.as_ref().map(|x| x[i]).unwrap_or(1.0),
+            })
+            .collect();
+
+        let router = &self.router;
+        let gj_bytes = py.allow_threads(|| -> PyResult<Vec<u8>> {
+            let routing_start = std::time::Instant::now();
+            let counts = router.route_all(requests);
+            let config = router.config();
+            let output_metadata =
+                od2net::OutputMetadata::new(config.clone(), &counts, n, routing_start.elapsed());
+
+            let mut gj_bytes = Vec::new();
+            router.network().write_geojson(
+                geojson::FeatureWriter::from_writer(&mut gj_bytes),
+                counts,
+                false,
+                osm_tags,
+                config.sort_output,
+                config.output_properties.as_deref(),
+                &output_metadata,
+            )?;
+            Ok(gj_bytes)
+        })?;
+        json_to_python(py, String::from_utf8(gj_bytes)?)
+    }
+}
+
+impl Router {
+    /// Applies the parts of the config that aren't part of the network itself, like turn costs
+    /// and exposure layers, then builds the contraction hierarchy
+    fn new(
+        mut network: Network,
+        mut config: InputConfig,
+        directory: &str,
+        timer: &mut Timer,
+    ) -> PyResult<Self> {
+        od2net::api::prepare_network(
+            &mut network,
+            &mut config,
+            directory,
+            &mut GeoTiffFiles::default(),
+            timer,
+        )?;
+        Ok(Self {
+            router: od2net::Router::new(network, config, timer)?,
+            directory: directory.to_string(),
+        })
+    }
+}
+
+fn load_config(config_json: &str, directory: &str) -> PyResult<InputConfig> {
+    let mut config: InputConfig = serde_json::from_str(config_json)
+        .map_err(|err| PyValueError::new_err(format!("Invalid config: {err}")))?;
+    config.load_uptake_tables(directory)?;
+    Ok(config)
+}
+
+/// Parses with Python's own JSON module, so the result is plain dictionaries and lists
+fn json_to_python(py: Python<'_>, json: String) -> PyResult<PyObject> {
+    Ok(py
+        .import_bound("json")?
+        .call_method1("loads", (json,))?
+        .unbind())
+}
+
+/// Build route networks from origin/destination data with od2net
+#[pymodule]
+fn pyod2net(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Router>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two roads meeting at a right angle
+    const ROADS: &str = r#"{
+  "type": "FeatureCollection",
+  "features": [
+    {
+      "type": "Feature",
+      "geometry": { "type": "LineString", "coordinates": [[0.0, 0.0], [0.001, 0.0]] },
+      "properties": { "stress": 1 }
+    },
+    {
+      "type": "Feature",
+      "geometry": { "type": "LineString", "coordinates": [[0.001, 0.0], [0.001, 0.001]] },
+      "properties": { "stress": 1 }
+    }
+  ]
+}"#;
+
+    fn config(extra: &str) -> String {
+        format!(
+            r#"{{
+                "requests": {{
+                    "description": "test",
+                    "pattern": "FromEveryOriginToOneDestination",
+                    "origins_path": "origins.geojson",
+                    "destinations_path": "destinations.geojson"
+                }},
+                "cost": "Distance",
+                "uptake": "Identity",
+                "lts": "BikeOttawa",
+                "geojson_network": {{ "path": "roads.geojson", "lts_property": "stress" }}
+                {extra}
+            }}"#
+        )
+    }
+
+    #[test]
+    fn test_router() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let config = config(r#", "turn_costs": { "left": 50 }"#);
+            let mut router = Router::from_geojson(py, ROADS.as_bytes(), &config, ".").unwrap();
+            // Settings that aren't part of the network itself are still applied
+            assert_eq!(router.router.network().turn_costs.left, 50);
+
+            let route = router
+                .route(py, 0.0, 0.0, 0.001, 0.001, None)
+                .unwrap()
+                .unwrap();
+            let route = route.bind(py);
+            assert_eq!(route.get_item("features").unwrap().len().unwrap(), 2);
+            assert!(router
+                .route(py, 0.0, 0.0, 0.0, 0.0, None)
+                .unwrap()
+                .is_none());
+
+            let counts = router
+                .route_all(
+                    py,
+                    vec![0.0],
+                    vec![0.0],
+                    vec![0.001],
+                    vec![0.001],
+                    None,
+                    None,
+                    false,
+                )
+                .unwrap();
+            let counts = counts.bind(py);
+            assert_eq!(counts.get_item("features").unwrap().len().unwrap(), 2);
+
+            // Every column must be the same length
+            assert!(router
+                .route_all(
+                    py,
+                    vec![0.0, 0.0],
+                    vec![0.0],
+                    vec![0.001],
+                    vec![0.001],
+                    None,
+                    None,
+                    false
+                )
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn test_geojson_needs_columns() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let config = config("").replace(
+                r#""geojson_network": { "path": "roads.geojson", "lts_property": "stress" }"#,
+                r#""profile": "Cycling""#,
+            );
+            assert!(Router::from_geojson(py, ROADS.as_bytes(), &config, ".").is_err());
+        });
+    }
+}
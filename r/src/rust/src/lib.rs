@@ -0,0 +1,162 @@
+use extendr_api::prelude::*;
+
+use od2net::config::InputConfig;
+use od2net::network::GeoTiffFiles;
+use od2net::requests::Request;
+use od2net::timer::Timer;
+use od2net::NetworkBuilder;
+
+/// Keeps a network and its contraction hierarchy in memory between calls. The R functions in
+/// `R/routing.R` wrap this and turn the GeoJSON strings into `sf` data frames.
+struct Od2netRouter {
+    router: od2net::Router,
+    /// Relative paths in the config are resolved against this
+    directory: String,
+}
+
+#[extendr]
+impl Od2netRouter {
+    /// Builds a network from an osm.pbf or OSM XML file
+    fn from_osm(osm_path: &str, config_json: &str, directory: &str) -> Result<Self> {
+        let mut config = load_config(config_json, directory)?;
+        let osm_bytes = std::fs::read(osm_path)
+            .map_err(|err| Error::Other(format!("Couldn't read {osm_path}: {err}")))?;
+        let mut timer = Timer::new();
+        let mut network = NetworkBuilder::from_config(&config)
+            .build_from_osm(&[&osm_bytes], &mut timer)
+            .map_err(to_r_error)?;
+        // Turn costs, exposure layers, and the like aren't part of the network itself
+        od2net::api::prepare_network(
+            &mut network,
+            &mut config,
+            directory,
+            &mut GeoTiffFiles::default(),
+            &mut timer,
+        )
+        .map_err(to_r_error)?;
+        let router = od2net::Router::new(network, config, &mut timer).map_err(to_r_error)?;
+        Ok(Self {
+            router,
+            directory: directory.to_string(),
+        })
+    }
+
+    /// Returns a list of columns: `x1`, `y1`, `x2`, `y2`, `weight`, and `purpose`
+    fn generate_requests(&self, rng_seed: f64) -> Result<List> {
+        let config = self.router.config();
+        let requests = od2net::od::generate_requests(
+            &config.requests,
+            config.input_directory(&self.directory),
+            self.router.network(),
+            rng_seed as u64,
+            &mut Timer::new(),
+        )
+        .map_err(to_r_error)?;
+
+        let column = |f: fn(&Request) -> f64| requests.iter().map(f).collect::<Vec<_>>();
+        let purpose = Strings::from_values(requests.iter().map(|r| match r.purpose {
+            Some(ref purpose) => Rstr::from(purpose.as_str()),
+            None => Rstr::na(),
+        }));
+        Ok(list!(
+            x1 = column(|r| r.x1),
+            y1 = column(|r| r.y1),
+            x2 = column(|r| r.x2),
+            y2 = column(|r| r.y2),
+            weight = column(|r| r.weight),
+            purpose = purpose
+        ))
+    }
+
+    /// Returns the route between two points as GeoJSON, or `NULL` if there's no route or the
+    /// points snap to the same place or farther than `max_snap_meters`. `purpose` is only used by
+    /// `ByPurpose` uptake.
+    fn route(
+        &mut self,
+        x1: f64,
+        y1: f64,
+        x2: f64,
+        y2: f64,
+        purpose: Nullable<String>,
+    ) -> Nullable<String> {
+        let purpose = match purpose {
+            Nullable::NotNull(ref purpose) => Some(purpose.as_str()),
+            Nullable::Null => None,
+        };
+        match self.router.route_geojson(x1, y1, x2, y2, purpose) {
+            Some(gj) => Nullable::NotNull(gj.to_string()),
+            None => Nullable::Null,
+        }
+    }
+
+    /// Routes many requests in parallel, returning the edges with counts as GeoJSON, like
+    /// `output.geojson`. `NA` purposes use the default uptake.
+    fn route_all(
+        &self,
+        x1: Vec<f64>,
+        y1: Vec<f64>,
+        x2: Vec<f64>,
+        y2: Vec<f64>,
+        weight: Vec<f64>,
+        purpose: Strings,
+    ) -> Result<String> {
+        let n = x1.len();
+        if [y1.len(), x2.len(), y2.len(), weight.len(), purpose.len()]
+            .iter()
+            .any(|len| *len != n)
+        {
+            return Err(Error::Other(
+                "Every column must be the same length".to_string(),
+            ));
+        }
+        let requests: Vec<Request> = (0..n)
+            .map(|i| Request {
+                x1: x1[i],
+                y1: y1[i],
+                x2: x2[i],
+                y2: y2[i],
+                purpose: Some(purpose.elt(i))
+                    .filter(|purpose| !purpose.is_na())
+                    .map(|purpose| purpose.as_str().to_string()),
+                weight: weight[i],
+            })
+            .collect();
+
+        let routing_start = std::time::Instant::now();
+        let counts = self.router.route_all(requests);
+        let config = self.router.config();
+        let output_metadata =
+            od2net::OutputMetadata::new(config.clone(), &counts, n, routing_start.elapsed());
+
+        let mut gj_bytes = Vec::new();
+        self.router
+            .network()
+            .write_geojson(
+                geojson::FeatureWriter::from_writer(&mut gj_bytes),
+                counts,
+                false,
+                false,
+                config.sort_output,
+                config.output_properties.as_deref(),
+                &output_metadata,
+            )
+            .map_err(to_r_error)?;
+        String::from_utf8(gj_bytes).map_err(|err| Error::Other(err.to_string()))
+    }
+}
+
+fn load_config(config_json: &str, directory: &str) -> Result<InputConfig> {
+    let mut config: InputConfig = serde_json::from_str(config_json)
+        .map_err(|err| Error::Other(format!("Invalid config: {err}")))?;
+    config.load_uptake_tables(directory).map_err(to_r_error)?;
+    Ok(config)
+}
+
+fn to_r_error(err: anyhow::Error) -> Error {
+    Error::Other(format!("{err:#}"))
+}
+
+extendr_module! {
+    mod rod2net;
+    impl Od2netRouter;
+}
@@ -8,11 +8,17 @@ use rstar::RTree;
 use serde::Deserialize;
 use wasm_bindgen::prelude::*;
 
-use od2net::config::{CostFunction, InputConfig};
+use od2net::config::{CostFunction, InputConfig, LtsMapping, Uptake};
+use od2net::many_to_many::RouteMatrix;
 use od2net::network::{Counts, Network};
 use od2net::requests::Request;
-use od2net::router::{IntersectionLocation, PreparedCH};
+use od2net::router::{IntersectionLocation, PreparedCH, RoutingExtras};
 use od2net::timer::Timer;
+use od2net::NetworkBuilder;
+
+mod router;
+
+pub use router::JsRouter;
 
 static START: Once = Once::new();
 
@@ -51,14 +57,9 @@ impl JsNetwork {
         info!("Got {} bytes, parsing as an osm.pbf", input_bytes.len());
         let mut timer = Timer::new();
         // TODO Default config
-        let network = Network::make_from_osm(
-            input_bytes,
-            &od2net::config::LtsMapping::BikeOttawa,
-            &mut CostFunction::Distance,
-            &mut timer,
-            None,
-        )
-        .map_err(err_to_js)?;
+        let network = NetworkBuilder::new()
+            .build_from_osm(&[input_bytes], &mut timer)
+            .map_err(err_to_js)?;
 
         Ok(JsNetwork {
             network,
@@ -82,7 +83,11 @@ impl JsNetwork {
             self.network
                 .recalculate_cost(&mut self.last_cost)
                 .map_err(err_to_js)?;
-            self.prepared_ch = Some(od2net::router::just_build_ch(&self.network, &mut timer));
+            // Changing the cost keeps the same graph, so a customizable CH can be updated quickly
+            self.prepared_ch = Some(match self.prepared_ch.take() {
+                Some(previous) => od2net::router::rebuild_ch(&self.network, &previous, &mut timer),
+                None => od2net::router::just_build_cch(&self.network, &mut timer),
+            });
             self.closest_intersection = Some(od2net::router::build_closest_intersection(
                 &self.network,
                 &self.prepared_ch.as_ref().unwrap().node_map,
@@ -95,18 +100,11 @@ impl JsNetwork {
         let num_requests = requests.len();
         info!("Made up {num_requests} requests");
         // TODO Everything here is placeholder
-        let config = InputConfig {
-            requests: od2net::config::Requests {
-                description: "placeholder".to_string(),
-                pattern: od2net::config::ODPattern::FromEveryOriginToOneDestination,
-                origins_path: "".to_string(),
-                destinations_path: "".to_string(),
-            },
-            cost: self.last_cost.clone(),
-            elevation_geotiff: None,
-            uptake: od2net::config::Uptake::Identity,
-            lts: od2net::config::LtsMapping::BikeOttawa,
-        };
+        let config = make_config(
+            self.last_cost.clone(),
+            Uptake::Identity,
+            LtsMapping::BikeOttawa,
+        );
 
         // Calculate single-threaded, until we figure out web workers
         let mut path_calc = fast_paths::create_calculator(&self.prepared_ch.as_ref().unwrap().ch);
@@ -117,9 +115,12 @@ impl JsNetwork {
                 request,
                 &mut counts,
                 &mut path_calc,
+                &RouteMatrix::default(),
                 self.closest_intersection.as_ref().unwrap(),
+                &RoutingExtras::default(),
                 self.prepared_ch.as_ref().unwrap(),
-                &config.uptake,
+                &config,
+                None,
                 &self.network,
             );
         }
@@ -142,6 +143,8 @@ impl JsNetwork {
                 counts,
                 true,
                 true,
+                false,
+                output_metadata.config.output_properties.as_deref(),
                 &output_metadata,
             )
             .map_err(err_to_js)?;
@@ -190,7 +193,7 @@ impl JsNetwork {
         let mut requests = Vec::new();
         for i in self.network.intersections.values() {
             let (x1, y1) = i.to_degrees();
-            requests.push(Request { x1, y1, x2, y2 });
+            requests.push(Request::new(x1, y1, x2, y2));
             if requests.len() == max_requests {
                 break;
             }
@@ -199,6 +202,63 @@ impl JsNetwork {
     }
 }
 
+/// A config without any input files, for routing requests made up in the browser
+fn make_config(cost: CostFunction, uptake: Uptake, lts: LtsMapping) -> InputConfig {
+    InputConfig {
+        requests: od2net::config::Requests {
+            description: "placeholder".to_string(),
+            pattern: od2net::config::ODPattern::FromEveryOriginToOneDestination,
+            origins_path: "".to_string(),
+            destinations_path: "".to_string(),
+            min_od_distance_meters: None,
+            max_od_distance_meters: None,
+            centroid_jitter_meters: None,
+            point_weight_property: None,
+            origin_raster: None,
+            intrazonal: Default::default(),
+            max_per_od: None,
+            ipf_margins_path: None,
+        },
+        cost,
+        profile: Default::default(),
+        elevation_geotiff: None,
+        elevation_tiles: None,
+        download_elevation: false,
+        osm_inputs: None,
+        osm: None,
+        turn_costs: Default::default(),
+        oneway: Default::default(),
+        barriers: Default::default(),
+        surface_multipliers: Default::default(),
+        lighting: Default::default(),
+        exposure_layers: Vec::new(),
+        traffic_counts: None,
+        cost_overrides: None,
+        geojson_network: None,
+        clip_boundary: None,
+        paths_relative_to_config: false,
+        pmtiles: Default::default(),
+        profiles: Default::default(),
+        scenarios: Vec::new(),
+        same_endpoints: Default::default(),
+        snap_to: Default::default(),
+        router: Default::default(),
+        count_area: None,
+        snap_distance_decay: None,
+        max_snap_meters: None,
+        alternatives: None,
+        flow_breakdown: None,
+        sort_output: false,
+        output_properties: None,
+        output_compression: None,
+        collect: Default::default(),
+        uptake,
+        uptake_scenarios: Default::default(),
+        lts,
+        path_difficulty: Default::default(),
+    }
+}
+
 fn err_to_js<E: std::fmt::Display>(err: E) -> JsValue {
     JsValue::from_str(&err.to_string())
 }
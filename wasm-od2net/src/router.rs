@@ -0,0 +1,139 @@
+use instant::Instant;
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+
+use od2net::config::{CostFunction, InputConfig, LtsMapping, TurnCosts, Uptake};
+use od2net::detailed_route_output::route_to_geojson;
+use od2net::dijkstra::DijkstraRouter;
+use od2net::network::Network;
+use od2net::requests::Request;
+use od2net::timer::Timer;
+use od2net::{NetworkBuilder, UptakeModel};
+
+use super::{err_to_js, make_config, START};
+
+/// Routes small runs entirely in the browser. It uses Dijkstra's algorithm directly on the
+/// network, so there's no contraction hierarchy to wait for, and it works on one thread. Routes
+/// follow OSM turn restrictions and any turn costs, like the CLI. Results are GeoJSON strings, in
+/// the same format as the CLI's output.
+#[wasm_bindgen]
+pub struct JsRouter {
+    network: Network,
+    router: DijkstraRouter,
+    config: InputConfig,
+}
+
+/// Every field is optional, defaulting to what the CLI would use
+#[derive(Default, Deserialize)]
+struct RouterOptions {
+    cost: Option<CostFunction>,
+    uptake: Option<Uptake>,
+    lts: Option<LtsMapping>,
+    turn_costs: Option<TurnCosts>,
+}
+
+#[wasm_bindgen]
+impl JsRouter {
+    /// Call with bytes of an osm.pbf or osm.xml string, and optionally an object with `cost`,
+    /// `uptake`, `lts`, and `turn_costs`, in the same format as the config
+    #[wasm_bindgen(constructor)]
+    pub fn new(input_bytes: &[u8], options: JsValue) -> Result<JsRouter, JsValue> {
+        console_error_panic_hook::set_once();
+        START.call_once(|| {
+            console_log::init_with_level(log::Level::Info).unwrap();
+        });
+
+        let options: RouterOptions = if options.is_undefined() || options.is_null() {
+            RouterOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options)?
+        };
+        let uptake = options.uptake.unwrap_or(Uptake::Identity);
+        check_uptake(&uptake)?;
+        let mut config = make_config(
+            options.cost.unwrap_or(CostFunction::Distance),
+            uptake,
+            options.lts.unwrap_or(LtsMapping::BikeOttawa),
+        );
+        config.turn_costs = options.turn_costs.unwrap_or_default();
+
+        let mut timer = Timer::new();
+        let mut network = NetworkBuilder::from_config(&config)
+            .build_from_osm(&[input_bytes], &mut timer)
+            .map_err(err_to_js)?;
+        network.turn_costs = config.turn_costs.clone();
+        let router = DijkstraRouter::new(&network, &mut timer);
+        Ok(JsRouter {
+            network,
+            router,
+            config,
+        })
+    }
+
+    /// Changes the uptake model, in the same format as the config. Use `setUptakeTable` for
+    /// lookup tables.
+    #[wasm_bindgen(js_name = setUptake)]
+    pub fn set_uptake(&mut self, uptake: JsValue) -> Result<(), JsValue> {
+        let uptake: Uptake = serde_wasm_bindgen::from_value(uptake)?;
+        check_uptake(&uptake)?;
+        self.config.uptake = uptake;
+        Ok(())
+    }
+
+    /// Uses an uptake lookup table, given the contents of the CSV file
+    #[wasm_bindgen(js_name = setUptakeTable)]
+    pub fn set_uptake_table(&mut self, csv: &str) -> Result<(), JsValue> {
+        let model = UptakeModel::lookup_table(csv.as_bytes()).map_err(err_to_js)?;
+        self.config.uptake = model.into();
+        Ok(())
+    }
+
+    /// Returns the route between the intersections closest to two points as a GeoJSON
+    /// FeatureCollection, with one LineString per edge, or `undefined` if there's no route
+    pub fn route(&self, x1: f64, y1: f64, x2: f64, y2: f64) -> Option<String> {
+        let path = self.router.route(x1, y1, x2, y2)?;
+        Some(route_to_geojson(&path, &self.network, &self.config.uptake, None, None).to_string())
+    }
+
+    /// Takes an array of requests, each with `x1`, `y1`, `x2`, `y2`, and `weight`, and returns
+    /// GeoJSON of the edges with counts, like `output.geojson`
+    #[wasm_bindgen(js_name = routeAll)]
+    pub fn route_all(&self, requests: JsValue) -> Result<String, JsValue> {
+        let requests: Vec<Request> = serde_wasm_bindgen::from_value(requests)?;
+        let num_requests = requests.len();
+
+        let routing_start = Instant::now();
+        let counts = self
+            .router
+            .route_all(&self.network, requests, &self.config, None, None);
+        let routing_time = Instant::now().duration_since(routing_start);
+        info!(
+            "Routed {num_requests} requests in {:.1}s",
+            routing_time.as_secs_f32()
+        );
+
+        let output_metadata =
+            od2net::OutputMetadata::new(self.config.clone(), &counts, num_requests, routing_time);
+        let mut gj_bytes = Vec::new();
+        self.network
+            .write_geojson(
+                geojson::FeatureWriter::from_writer(&mut gj_bytes),
+                counts,
+                true,
+                true,
+                false,
+                None,
+                &output_metadata,
+            )
+            .map_err(err_to_js)?;
+        String::from_utf8(gj_bytes).map_err(err_to_js)
+    }
+}
+
+/// Lookup tables are normally read from a file, which doesn't exist in the browser. This includes
+/// ones nested in `ByPurpose`.
+fn check_uptake(uptake: &Uptake) -> Result<(), JsValue> {
+    od2net::plugins::uptake::check_tables_loaded(uptake).map_err(|_| {
+        JsValue::from_str("Pass the contents of lookup tables to setUptakeTable instead")
+    })
+}